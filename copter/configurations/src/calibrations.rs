@@ -1,20 +1,117 @@
 use std::default::Default;
-use std::fs::{File, OpenOptions};
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 use toml;
 use std::io::prelude::*;
 use std::string::String;
 
 use na::{Matrix3, Vector3};
 
+use config::{SerialCommunication, Sensor};
+
+// Files without a version are version 1, from before records.
+pub const CALIBRATION_VERSION: u32 = 2;
+// In the configuration directory.
+pub const CALIBRATIONS_FILE: &str = "calibrations.toml";
+pub const HISTORY_DIRECTORY: &str = "calibration_history";
+// Older saves are deleted.
+const MAX_HISTORY: usize = 20;
+const G_TO_MPSPS: f64 = 9.80665;
+
+fn legacy_version() -> u32 {
+    1
+}
+
+pub fn calibrations_path(config_directory: &Path) -> PathBuf {
+    config_directory.join(CALIBRATIONS_FILE)
+}
+
+pub fn history_directory(config_directory: &Path) -> PathBuf {
+    config_directory.join(HISTORY_DIRECTORY)
+}
+
+// Which sensor a calibration was taken with, when, and how well it fit.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Record {
+    pub sensor: String,           // Model, as named in the configuration
+    pub bus: String,
+    pub address: Option<u16>,     // I2C only
+    pub time: u64,                // Seconds since the Unix epoch
+    pub temperature: Option<f64>, // Die temperature in C, if it could be read
+    pub residual: Option<f64>,    // RMS fit error, in the units of the sensor
+}
+
+// Seconds since the Unix epoch, or 0 if the clock is before it.
+fn now() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => since_epoch.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+impl Record {
+    pub fn new(sensor: &Sensor, default_address: u16) -> Record {
+        Record {
+            sensor: sensor.name.clone(),
+            bus: String::from(sensor.bus()),
+            address: match sensor.serial {
+                SerialCommunication::SPI => None,
+                _ => Some(sensor.address(default_address)),
+            },
+            time: now(),
+            temperature: None,
+            residual: None,
+        }
+    }
+
+    // The same sensor, calibrated again now.
+    pub fn renewed(&self) -> Record {
+        Record {
+            sensor: self.sensor.clone(),
+            bus: self.bus.clone(),
+            address: self.address,
+            time: now(),
+            temperature: None,
+            residual: None,
+        }
+    }
+
+    // How the configured sensor differs from the one calibrated, if it does.
+    pub fn mismatch(&self, sensor: &Sensor, default_address: u16) -> Option<String> {
+        let configured = Record::new(sensor, default_address);
+        let describe = |record: &Record| match record.address {
+            Some(address) => format!("{} at {:#x} on {}", record.sensor, address, record.bus),
+            None => format!("{} on {}", record.sensor, record.bus),
+        };
+        if configured.sensor == self.sensor && configured.bus == self.bus
+            && configured.address == self.address
+        {
+            None
+        } else {
+            Some(format!(
+                "calibrated {}, configured {}",
+                describe(self),
+                describe(&configured)
+            ))
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Simple {
     pub offsets: Vec<f64>,
+    pub record: Option<Record>,
 }
 
 impl Simple {
     pub fn new(offsets: Vector3<f64>) -> Simple {
         Simple {
             offsets: offsets.as_slice().to_vec(),
+            record: None,
         }
     }
 
@@ -28,6 +125,8 @@ pub struct Ellipsoid {
     pub offsets: Vec<f64>,
     pub rotation: Vec<f64>,
     pub gains: Vec<f64>,
+    pub residual: Option<f64>, // RMS fit error, in the units of the sensor
+    pub record: Option<Record>,
 }
 
 impl Ellipsoid {
@@ -36,6 +135,8 @@ impl Ellipsoid {
             offsets: offsets.as_slice().to_vec(),
             rotation: rotation.as_slice().to_vec(),
             gains: gains.as_slice().to_vec(),
+            residual: None,
+            record: None,
         }
     }
 
@@ -50,77 +151,300 @@ impl Ellipsoid {
     pub fn get_gains(&self) -> Vector3<f64> {
         Vector3::from_column_slice(&self.gains)
     }
+
+    // The matrix applied after removing the offsets, the rotation with each row
+    // divided by its gain. None if the rotation or gains were never fitted, as in
+    // offset only calibrations.
+    pub fn get_correction(&self) -> Option<Matrix3<f64>> {
+        let rotation = self.get_rotation();
+        let gains = self.get_gains();
+        if gains.iter().any(|gain| *gain == 0.0 || !gain.is_finite()) {
+            return None;
+        }
+        let correction = Matrix3::from_fn(|r, c| rotation[(r, c)] / gains[r]);
+        match correction.try_inverse() {
+            Some(_) => Some(correction),
+            None => None,
+        }
+    }
+}
+
+// Bias drift with die temperature. Each axis has coefficients of the first and higher
+// powers of the difference from the reference temperature, so there is no drift at
+// the reference. Temperatures outside the calibrated range are clamped to it.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Thermal {
+    pub reference_temperature: f64,
+    pub min_temperature: f64,
+    pub max_temperature: f64,
+    pub x: Vec<f64>,
+    pub y: Vec<f64>,
+    pub z: Vec<f64>,
+    pub record: Option<Record>,
+}
+
+impl Thermal {
+    pub fn get_drift(&self, temperature: f64) -> Vector3<f64> {
+        let difference = temperature
+            .max(self.min_temperature)
+            .min(self.max_temperature) - self.reference_temperature;
+        let polynomial = |coefficients: &Vec<f64>| {
+            coefficients
+                .iter()
+                .enumerate()
+                .fold(0.0, |drift, (power, coefficient)| {
+                    drift + coefficient * difference.powi(power as i32 + 1)
+                })
+        };
+        Vector3::new(
+            polynomial(&self.x),
+            polynomial(&self.y),
+            polynomial(&self.z),
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CalibrationError {
+    Missing,
+    Corrupt(String),
+    UnsupportedVersion(u32),
+    Io(String),
+}
+
+impl fmt::Display for CalibrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CalibrationError::Missing => write!(f, "There are no calibrations."),
+            CalibrationError::Corrupt(ref e) => write!(f, "The calibrations are corrupt: {}", e),
+            CalibrationError::UnsupportedVersion(version) => write!(
+                f,
+                "The calibrations are version {}, newer than version {}.",
+                version, CALIBRATION_VERSION
+            ),
+            CalibrationError::Io(ref e) => write!(f, "Couldn't read the calibrations: {}", e),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Calibrations {
+    #[serde(default = "legacy_version")]
+    pub version: u32,
     pub gyroscope: Option<Simple>,
     pub accelerometer: Option<Ellipsoid>,
     pub magnetometer: Option<Ellipsoid>,
+    pub gyroscope_thermal: Option<Thermal>,
+    pub accelerometer_thermal: Option<Thermal>,
 }
 
 impl Calibrations {
-    pub fn new() -> Result<Calibrations, String> {
-        let mut calibration_file: File = match OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open("configuration/calibrations.toml")
-        {
-            Ok(file) => file,
-            Err(e) => {
-                println!("1: {:?}", e);
-                return Err(e.to_string());
-            }
-        };
+    // No calibrations yet is the same as empty ones.
+    pub fn new(config_directory: &Path) -> Result<Calibrations, String> {
+        match Calibrations::load(&calibrations_path(config_directory)) {
+            Ok(calibrations) => Ok(calibrations),
+            Err(CalibrationError::Missing) => Ok(Calibrations::default()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
 
+    // An empty file is missing, older versions of save created one.
+    pub fn load(path: &Path) -> Result<Calibrations, CalibrationError> {
         let mut calibration_string = String::new();
-        match calibration_file.read_to_string(&mut calibration_string) {
-            Ok(_size) => {}
-            Err(e) => {
-                println!("2: {:?}", e);
-                return Err(e.to_string());
+        match File::open(path).and_then(|mut file| file.read_to_string(&mut calibration_string)) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(CalibrationError::Missing)
             }
+            Err(e) => return Err(CalibrationError::Io(e.to_string())),
         };
+        if calibration_string.trim().is_empty() {
+            return Err(CalibrationError::Missing);
+        }
 
-        match toml::from_str(calibration_string.as_ref()) {
-            Ok(calib) => Ok(calib),
+        let mut calibrations: Calibrations = match toml::from_str(calibration_string.as_ref()) {
+            Ok(calibrations) => calibrations,
+            Err(e) => return Err(CalibrationError::Corrupt(e.to_string())),
+        };
+        if calibrations.version > CALIBRATION_VERSION {
+            return Err(CalibrationError::UnsupportedVersion(calibrations.version));
+        }
+        if calibrations.version < 2 {
+            migrate_accelerometer_offsets(&mut calibrations);
+        }
+        // Version 1 also lacks records, which stay empty. Its offset-only accelerometer
+        // offsets were in g, so they're migrated to m/s^2 above.
+        calibrations.version = CALIBRATION_VERSION;
+        Ok(calibrations)
+    }
+
+    pub fn save(&self, config_directory: &Path) -> Result<(), String> {
+        self.save_to(
+            &calibrations_path(config_directory),
+            &history_directory(config_directory),
+        )
+    }
+
+    // The previous file goes to the history first, then the new one replaces it in a
+    // single rename so a crash can't leave it half written.
+    pub fn save_to(&self, path: &Path, history_directory: &Path) -> Result<(), String> {
+        let calibration_string: String = match toml::to_string(self) {
+            Ok(calibration_string) => format!(
+                "# This file is automatically generated. Do not edit!\n\n{}",
+                calibration_string
+            ),
+            Err(e) => return Err(e.to_string()),
+        };
+
+        match archive(path, history_directory) {
+            Ok(()) => {}
+            Err(e) => return Err(format!("Couldn't keep the previous calibrations: {}", e)),
+        };
+
+        let mut temporary_path = path.as_os_str().to_owned();
+        temporary_path.push(".tmp");
+        let written = File::create(&temporary_path).and_then(|mut file| {
+            file.write_all(calibration_string.as_bytes())?;
+            file.sync_all()
+        });
+        match written.and_then(|()| fs::rename(&temporary_path, path)) {
+            Ok(()) => Ok(()),
             Err(e) => {
-                println!("3: {:?}", e);
-                return Err(e.to_string());
+                let _ = fs::remove_file(&temporary_path);
+                Err(e.to_string())
             }
         }
     }
 
-    pub fn save(&self) -> Result<(), String> {
-        let mut calibration_file: File = match OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open("configuration/calibrations.toml")
-        {
-            Ok(file) => file,
+    // Saved calibrations, oldest first.
+    pub fn history(history_directory: &Path) -> Result<Vec<PathBuf>, String> {
+        let entries = match fs::read_dir(history_directory) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
             Err(e) => return Err(e.to_string()),
         };
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| match path.extension() {
+                Some(extension) => extension == "toml",
+                None => false,
+            })
+            .collect();
+        // The names start with the time, zero padded.
+        paths.sort();
+        Ok(paths)
+    }
+}
 
-        let calibration_string: String = format!(
-            "# This file is automatically generated. Do not edit!\n\n{}",
-            toml::to_string(self).unwrap()
-        );
+// Offset only accelerometer calibrations were taken with x and y in g and z in m/s^2.
+// Ones with a fitted correction came after every axis was read in m/s^2.
+fn migrate_accelerometer_offsets(calibrations: &mut Calibrations) {
+    match calibrations.accelerometer {
+        Some(ref mut accelerometer) if accelerometer.get_correction().is_none() => {
+            for offset in accelerometer.offsets.iter_mut().take(2) {
+                *offset *= G_TO_MPSPS;
+            }
+        }
+        _ => {}
+    };
+}
 
-        calibration_file
-            .write_all(calibration_string.as_bytes())
-            .unwrap();
-        Ok(())
+fn archive(path: &Path, history_directory: &Path) -> Result<(), String> {
+    match fs::metadata(path) {
+        Ok(ref metadata) if metadata.len() > 0 => {}
+        _ => return Ok(()),
+    };
+    match fs::create_dir_all(history_directory) {
+        Ok(()) => {}
+        Err(e) => return Err(e.to_string()),
+    };
+    let since_epoch = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => since_epoch,
+        Err(_) => return Err(String::from("The clock is before 1970.")),
+    };
+    let archived = history_directory.join(format!(
+        "{:012}-{:09}-calibrations.toml",
+        since_epoch.as_secs(),
+        since_epoch.subsec_nanos()
+    ));
+    match fs::copy(path, &archived) {
+        Ok(_) => {}
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let history = Calibrations::history(history_directory)?;
+    if history.len() > MAX_HISTORY {
+        for old in &history[..history.len() - MAX_HISTORY] {
+            match fs::remove_file(old) {
+                Ok(()) => {}
+                Err(e) => return Err(e.to_string()),
+            };
+        }
     }
+    Ok(())
 }
 
 impl Default for Calibrations {
     fn default() -> Calibrations {
         Calibrations {
+            version: CALIBRATION_VERSION,
             gyroscope: None,
             accelerometer: None,
             magnetometer: None,
+            gyroscope_thermal: None,
+            accelerometer_thermal: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn load_str(name: &str, contents: &str) -> Result<Calibrations, CalibrationError> {
+        let path = env::temp_dir().join(format!("{}_{}.toml", name, process::id()));
+        File::create(&path)
+            .and_then(|mut file| file.write_all(contents.as_bytes()))
+            .unwrap();
+        let calibrations = Calibrations::load(&path);
+        fs::remove_file(&path).unwrap();
+        calibrations
+    }
+
+    #[test]
+    fn legacy_accelerometer_offsets_are_read_in_mpsps() {
+        let calibrations = load_str(
+            "legacy_offsets",
+            "[accelerometer]
+offsets = [0.01, -0.02, 0.3]
+rotation = [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]
+gains = [0.0, 0.0, 0.0]
+",
+        ).unwrap();
+        assert_eq!(calibrations.version, CALIBRATION_VERSION);
+        let offsets = calibrations.accelerometer.unwrap().get_offsets();
+        let expected = Vector3::new(0.01 * G_TO_MPSPS, -0.02 * G_TO_MPSPS, 0.3);
+        assert!((offsets - expected).norm() < 1e-12);
+    }
+
+    #[test]
+    fn fitted_accelerometer_offsets_are_kept() {
+        let fitted = "[accelerometer]
+offsets = [0.1, -0.2, 0.3]
+rotation = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0]
+gains = [1.0, 1.0, 1.0]
+";
+        // Unversioned files with a correction were saved after the units changed.
+        for contents in [fitted.to_string(), format!("version = 2\n{}", fitted)].iter() {
+            let calibrations = load_str("fitted_offsets", contents).unwrap();
+            let offsets = calibrations.accelerometer.unwrap().get_offsets();
+            assert_eq!(offsets, Vector3::new(0.1, -0.2, 0.3));
         }
+        assert_eq!(
+            load_str("newer", "version = 3\n").unwrap_err(),
+            CalibrationError::UnsupportedVersion(3)
+        );
     }
 }
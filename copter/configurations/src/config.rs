@@ -2,17 +2,21 @@ use std::default::Default;
 use std::fs::File;
 use toml;
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use layers;
+use layers::{Arguments, DEFAULT_CONFIG_DIRECTORY};
 
 /*----- Flight -----*/
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct PID {
     pub p: Option<f32>,
     pub i: Option<f32>,
     pub d: Option<f32>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Flight {
     pub roll: PID,
     pub pitch: PID,
@@ -21,39 +25,173 @@ pub struct Flight {
 
 /*----- Hardware -----*/
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub enum SerialCommunication {
     UART, // Unused currently
     I2C,
-    SPI, // Unused currently
+    SPI,
+}
+
+pub const DEFAULT_I2C_BUS: &str = "/dev/i2c-1";
+pub const DEFAULT_SPI_BUS: &str = "/dev/spidev0.0";
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+pub enum Oversampling {
+    UltraLowPower,
+    Standard,
+    HighResolution,
+    UltraHighResolution,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Sensor {
     pub name: String,
-    pub update_rate: Option<i32>,    //Unused
-    pub serial: SerialCommunication, // Unused
-    pub slave_address: u16,          // Unused
+    pub update_rate: Option<i32>,           // Hz. Chip default if not set
+    pub serial: SerialCommunication,
+    pub bus: Option<String>,                // Defaults to /dev/i2c-1 or /dev/spidev0.0
+    pub slave_address: u16,                 // Chip default if 0. Ignored for SPI
+    pub full_scale: Option<i32>,            // g or dps. Chip default if not set
+    pub oversampling: Option<Oversampling>, // Barometers only
+}
+
+impl Sensor {
+    pub fn bus(&self) -> &str {
+        match self.bus {
+            Some(ref bus) => bus.as_ref(),
+            None => match self.serial {
+                SerialCommunication::SPI => DEFAULT_SPI_BUS,
+                _ => DEFAULT_I2C_BUS,
+            },
+        }
+    }
+
+    pub fn address(&self, default_address: u16) -> u16 {
+        if self.slave_address == 0 {
+            default_address
+        } else {
+            self.slave_address
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum MotorProtocol {
+    PWM, // 50 to 490 Hz
+    OneShot125,
+    OneShot42,
+    Multishot,
+    DShot150,
+    DShot300,
+    DShot600,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+// Motor commands, as PWM pulse lengths in microseconds. Other protocols are scaled
+// from this range.
+pub const IDLE_POWER: f64 = 1000.0;
+pub const FULL_POWER: f64 = 2000.0;
+
+// Static thrust of one motor and propeller, measured on the bench. u is the motor
+// command scaled to 0 to 1, i.e. (command - 1000) / 1000.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ThrustModel {
+    pub reference_voltage: Option<f32>, // Battery voltage when measured. Optional
+    pub polynomial: Option<Vec<f32>>,   // Thrust in N = c0 + c1 u + c2 u^2 + ...
+    pub table: Option<Vec<(f32, f32)>>, // (command, thrust in N), increasing
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Motors {
     pub pins: Vec<u8>,
     pub serial_pwm: bool,
-    pub serial_controller: Option<Sensor>,
+    pub serial_controller: Option<Sensor>,  // update_rate is the pulse rate for analog protocols
+    pub protocol: Option<MotorProtocol>,    // PWM if not set
+    pub dshot_outputs: Option<Vec<String>>, // One spidev per motor, in the same order as pins
+    pub min_power: Option<f32>,             // Lowest armed command, 1000 if not set
+    pub max_power: Option<f32>,             // Highest command, 2000 if not set
+    pub max_slew_rate: Option<f32>,         // Command units per second. Unlimited if not set
+    pub command_timeout_ms: Option<u64>,    // Motors stop if no command arrives in time
+    pub thrust_models: Option<Vec<ThrustModel>>, // One per motor, or one for all motors
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Battery {
-    pub cells: i32,
-    pub warning_voltage: f32,  // Per cell
-    pub critical_voltage: f32, // Per cell
+    pub cells: i32,                       // Detected from the first reading if 0
+    pub warning_voltage: f32,             // Per cell
+    pub critical_voltage: f32,            // Per cell
+    pub update_rate: Option<i32>,         // Hz, 5 if not set
+    pub divider_ratio: Option<f32>,       // Battery volts per ADC volt, 1 if not set
+    pub voltage_channel: Option<u8>,      // ADS111X input, 0 if not set
+    pub current_channel: Option<u8>,      // ADS111X input of a current sensor. Optional
+    pub current_scale: Option<f32>,       // Amps per ADC volt
+    pub current_offset: Option<f32>,      // ADC volts at zero current, 0 if not set
+    pub capacity_mah: Option<f32>,        // Used with current sensing. Optional
+    pub internal_resistance: Option<f32>, // Pack ohms for sag compensation. Optional
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Notch {
+    pub center_hz: f32,
+    pub q: f32,
+    pub accelerometer: bool, // Also filter the accelerometer
+}
+
+// Notch that follows motor speed between min_hz at idle and max_hz at full throttle.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DynamicNotch {
+    pub min_hz: f32,
+    pub max_hz: f32,
+    pub q: f32,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Filters {
+    pub gyroscope_lowpass_hz: Option<f32>,
+    pub accelerometer_lowpass_hz: Option<f32>,
+    pub notches: Vec<Notch>,
+    pub dynamic_notch: Option<DynamicNotch>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum GpsBackend {
+    Gpsd,   // localhost:2947
+    Serial, // NMEA or UBX straight from the receiver
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum GpsProtocol {
+    NMEA,
+    UBX, // u-blox binary
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum GpsDynamicModel {
+    Portable,
+    Stationary,
+    Pedestrian,
+    Automotive,
+    Sea,
+    Airborne1G,
+    Airborne2G,
+    Airborne4G,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct GpsReceiver {
+    pub backend: GpsBackend,
+    pub port: Option<String>,                   // Serial only. /dev/ttyS0 if not set
+    pub baud_rate: Option<u32>,                 // Serial only. 9600 if not set
+    pub protocol: Option<GpsProtocol>,          // Serial only. NMEA if not set
+    pub update_rate: Option<i32>,               // Hz. Set over UBX if set
+    pub dynamic_model: Option<GpsDynamicModel>, // Set over UBX if set
+    pub gpsd_address: Option<String>,           // gpsd only. localhost:2947 if not set
+    pub replay: Option<String>,                 // gpsd only. Track or log served by a fake gpsd
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Hardware {
     pub gps: bool,
     pub wifi_gps: bool,
+    pub gps_receiver: Option<GpsReceiver>, // gpsd if not set
     pub barometer: Sensor,
     pub gyroscope: Sensor,
     pub accelerometer: Sensor,
@@ -61,11 +199,12 @@ pub struct Hardware {
     pub analog_converter: Option<Sensor>,
     pub motors: Motors,
     pub battery: Battery,
+    pub filters: Option<Filters>,
 }
 
 /*----- Networking -----*/
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Networking {
     pub server_ip: String,
     pub server_port: i32,
@@ -73,7 +212,7 @@ pub struct Networking {
 
 /*----- Debug -----*/
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Debug {
     pub live_debugging: bool,
     pub debug_websocket_port: i32,
@@ -81,37 +220,101 @@ pub struct Debug {
     pub motors_off: bool,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Config {
     pub flight: Flight,
     pub hardware: Hardware,
     pub networking: Networking,
     pub debug: Debug,
+    // Where it was read from, so calibrations and tuning are kept next to it.
+    #[serde(skip)]
+    pub directory: PathBuf,
 }
 
 impl Config {
-    pub fn new() -> Result<Config, String> {
-        let mut config_file: File = match File::open("configuration/config.toml") {
-            Ok(file) => file,
-            Err(_) => {
-                println!("Couldn't open config.toml! Opening config_default.toml.");
-                File::open("configuration/config_default.toml").unwrap()
+    // The layers in the configuration directory merged, with the --set overrides on top.
+    // The default layer only has placeholders, so validating it fails until config.toml
+    // is written. Read once at startup and passed to whatever needs it.
+    pub fn new(arguments: &Arguments) -> Result<Config, String> {
+        Config::merged(arguments).map(|(_layers, cfg)| cfg)
+    }
+
+    fn merged(arguments: &Arguments) -> Result<(Vec<PathBuf>, Config), String> {
+        let (layers, merged) = layers::merged(arguments)?;
+        match merged.try_into::<Config>() {
+            Ok(mut cfg) => {
+                cfg.directory = arguments.config_directory();
+                Ok((layers, cfg))
             }
+            Err(e) => Err(format!("{} merged: {}", describe_layers(&layers), e)),
+        }
+    }
+
+    // What Config::new reads, as TOML, with where it came from.
+    pub fn effective(arguments: &Arguments) -> Result<String, String> {
+        let (layers, config) = Config::merged(arguments)?;
+        let config_string = match config.to_toml() {
+            Ok(config_string) => config_string,
+            Err(e) => return Err(e),
+        };
+        let mut effective = format!("# Merged from {}\n", describe_layers(&layers));
+        for assignment in &arguments.overrides {
+            effective.push_str(&format!("# --set {}\n", assignment));
+        }
+        effective.push_str("\n");
+        effective.push_str(&config_string);
+        Ok(effective)
+    }
+
+    // A file in the configuration directory.
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.directory.join(name)
+    }
+
+    // Through a Value, which puts plain values before tables. Serializing the structs
+    // directly fails where an optional table comes before a plain value.
+    pub fn to_toml(&self) -> Result<String, String> {
+        match toml::Value::try_from(self).and_then(|value| toml::to_string(&value)) {
+            Ok(config_string) => Ok(config_string),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    // A single file. Parse errors name the key and line, e.g. "missing field `cells`
+    // for key `hardware.battery`".
+    pub fn load(path: &str) -> Result<Config, String> {
+        let mut config_file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) => return Err(format!("Couldn't open {}: {}", path, e)),
         };
 
         let mut config_string = String::new();
         match config_file.read_to_string(&mut config_string) {
             Ok(_size) => {}
-            Err(e) => return Err(e.to_string()),
+            Err(e) => return Err(format!("Couldn't read {}: {}", path, e)),
         };
 
-        match toml::from_str(config_string.as_ref()) {
-            Ok(cfg) => Ok(cfg),
-            Err(e) => Err(e.to_string()),
+        match toml::from_str::<Config>(config_string.as_ref()) {
+            Ok(mut cfg) => {
+                cfg.directory = match Path::new(path).parent() {
+                    Some(directory) => directory.to_path_buf(),
+                    None => PathBuf::new(),
+                };
+                Ok(cfg)
+            }
+            Err(e) => Err(format!("{}: {}", path, e)),
         }
     }
 }
 
+fn describe_layers(layers: &[PathBuf]) -> String {
+    layers
+        .iter()
+        .map(|layer| layer.display().to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
 impl Default for Config {
     fn default() -> Config {
         Config {
@@ -135,35 +338,60 @@ impl Default for Config {
             hardware: Hardware {
                 gps: false,
                 wifi_gps: false,
+                gps_receiver: Some(GpsReceiver {
+                    backend: GpsBackend::Gpsd,
+                    port: None,
+                    baud_rate: None,
+                    protocol: None,
+                    update_rate: None,
+                    dynamic_model: None,
+                    gpsd_address: None,
+                    replay: None,
+                }),
                 barometer: Sensor {
                     name: String::from("Barometer Model"),
                     update_rate: Some(100),
                     serial: SerialCommunication::I2C,
+                    bus: Some(String::from(DEFAULT_I2C_BUS)),
                     slave_address: 0,
+                    full_scale: None,
+                    oversampling: Some(Oversampling::Standard),
                 },
                 gyroscope: Sensor {
                     name: String::from("Gyroscope Model"),
                     update_rate: Some(100),
                     serial: SerialCommunication::I2C,
+                    bus: Some(String::from(DEFAULT_I2C_BUS)),
                     slave_address: 0,
+                    full_scale: Some(500),
+                    oversampling: None,
                 },
                 accelerometer: Sensor {
                     name: String::from("Accelerometer Model"),
                     update_rate: Some(100),
                     serial: SerialCommunication::I2C,
+                    bus: Some(String::from(DEFAULT_I2C_BUS)),
                     slave_address: 0,
+                    full_scale: Some(4),
+                    oversampling: None,
                 },
                 magnetometer: Sensor {
                     name: String::from("Magnetometer Model"),
                     update_rate: Some(100),
                     serial: SerialCommunication::I2C,
+                    bus: Some(String::from(DEFAULT_I2C_BUS)),
                     slave_address: 0,
+                    full_scale: None,
+                    oversampling: None,
                 },
                 analog_converter: Some(Sensor {
                     name: String::from("Analog to Digital Converter"),
                     update_rate: Some(100),
                     serial: SerialCommunication::I2C,
+                    bus: Some(String::from(DEFAULT_I2C_BUS)),
                     slave_address: 0,
+                    full_scale: None,
+                    oversampling: None,
                 }),
                 motors: Motors {
                     pins: vec![1, 2, 3, 4],
@@ -172,14 +400,38 @@ impl Default for Config {
                         name: String::from("PWM Controller"),
                         update_rate: None,
                         serial: SerialCommunication::I2C,
+                        bus: Some(String::from(DEFAULT_I2C_BUS)),
                         slave_address: 0,
+                        full_scale: None,
+                        oversampling: None,
                     }),
+                    protocol: Some(MotorProtocol::PWM),
+                    dshot_outputs: None,
+                    min_power: Some(1000.0),
+                    max_power: Some(2000.0),
+                    max_slew_rate: None,
+                    command_timeout_ms: Some(500),
+                    thrust_models: None,
                 },
                 battery: Battery {
                     cells: 0,
                     warning_voltage: 0.0,
                     critical_voltage: 0.0,
+                    update_rate: Some(5),
+                    divider_ratio: Some(1.0),
+                    voltage_channel: Some(0),
+                    current_channel: None,
+                    current_scale: None,
+                    current_offset: None,
+                    capacity_mah: None,
+                    internal_resistance: None,
                 },
+                filters: Some(Filters {
+                    gyroscope_lowpass_hz: Some(30.0),
+                    accelerometer_lowpass_hz: Some(20.0),
+                    notches: vec![],
+                    dynamic_notch: None,
+                }),
             },
             networking: Networking {
                 server_ip: String::from("0.0.0.0"),
@@ -191,6 +443,7 @@ impl Default for Config {
                 logging: true,
                 motors_off: false,
             },
+            directory: PathBuf::from(DEFAULT_CONFIG_DIRECTORY),
         }
     }
 }
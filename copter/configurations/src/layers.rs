@@ -0,0 +1,279 @@
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use toml;
+use toml::Value;
+
+// The flag wins over the variable, which wins over the directory the binary is run from.
+pub const CONFIG_DIRECTORY_VARIABLE: &str = "COPTER_CONFIG_DIR";
+pub const DEFAULT_CONFIG_DIRECTORY: &str = "configuration";
+
+pub const DEFAULT_LAYER: &str = "config_default.toml";
+pub const VEHICLE_LAYER: &str = "config.toml";
+pub const LOCAL_LAYER: &str = "config_local.toml";
+// Later layers override earlier ones key by key, so they only need what differs.
+pub const LAYERS: &[&str] = &[DEFAULT_LAYER, VEHICLE_LAYER, LOCAL_LAYER];
+
+pub const USAGE: &str = "Options:
+  --config-dir <directory>  Read configuration and calibrations from <directory>.
+                            COPTER_CONFIG_DIR if not set, ./configuration otherwise.
+  --set <key.path=value>    Override one value, e.g. --set hardware.battery.cells=4.
+                            Values are TOML, anything else is a string.
+  --print-config            Print the merged configuration and exit.";
+
+#[derive(Debug, Default)]
+pub struct Arguments {
+    pub config_directory: Option<String>,
+    pub overrides: Vec<String>,
+    pub print_config: bool,
+    pub rest: Vec<String>, // Anything that isn't a configuration option
+}
+
+impl Arguments {
+    pub fn parse(args: &[String]) -> Result<Arguments, String> {
+        let mut arguments = Arguments::default();
+        let mut i = 0;
+        while i < args.len() {
+            match args[i].as_ref() {
+                "--config-dir" if i + 1 < args.len() => {
+                    arguments.config_directory = Some(args[i + 1].clone());
+                    i += 1;
+                }
+                "--set" if i + 1 < args.len() => {
+                    if !args[i + 1].contains('=') {
+                        return Err(format!("--set {} needs a key.path=value.", args[i + 1]));
+                    }
+                    arguments.overrides.push(args[i + 1].clone());
+                    i += 1;
+                }
+                "--print-config" => arguments.print_config = true,
+                "--config-dir" | "--set" => return Err(format!("{} needs a value.", args[i])),
+                other => arguments.rest.push(String::from(other)),
+            }
+            i += 1;
+        }
+        Ok(arguments)
+    }
+
+    pub fn config_directory(&self) -> PathBuf {
+        match self.config_directory {
+            Some(ref directory) => PathBuf::from(directory),
+            None => match env::var(CONFIG_DIRECTORY_VARIABLE) {
+                Ok(ref directory) if !directory.is_empty() => PathBuf::from(directory),
+                _ => PathBuf::from(DEFAULT_CONFIG_DIRECTORY),
+            },
+        }
+    }
+}
+
+// The layers that were found, in order, and everything merged with the overrides on top.
+pub fn merged(arguments: &Arguments) -> Result<(Vec<PathBuf>, Value), String> {
+    let directory = arguments.config_directory();
+    let mut found = Vec::new();
+    let mut merged = Value::Table(toml::value::Table::new());
+    for layer in LAYERS {
+        let path = directory.join(layer);
+        let mut layer_string = String::new();
+        match File::open(&path).and_then(|mut file| file.read_to_string(&mut layer_string)) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(format!("Couldn't read {}: {}", path.display(), e)),
+        };
+        let value: Value = match toml::from_str(layer_string.as_ref()) {
+            Ok(value) => value,
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+        merge(&mut merged, value);
+        found.push(path);
+    }
+    if found.is_empty() {
+        return Err(format!(
+            "No configuration in {}. Expected one of {}.",
+            directory.display(),
+            LAYERS.join(", ")
+        ));
+    }
+
+    for assignment in &arguments.overrides {
+        match set(&mut merged, assignment) {
+            Ok(()) => {}
+            Err(e) => return Err(format!("--set {}: {}", assignment, e)),
+        };
+    }
+    Ok((found, merged))
+}
+
+// Writes values into the local layer, keeping what else is there. The local layer
+// overrides the others, so the values are read back on the next start.
+pub fn write_local(directory: &Path, assignments: &[(String, Value)]) -> Result<PathBuf, String> {
+    let path = directory.join(LOCAL_LAYER);
+    let mut layer_string = String::new();
+    match File::open(&path).and_then(|mut file| file.read_to_string(&mut layer_string)) {
+        Ok(_) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(format!("Couldn't read {}: {}", path.display(), e)),
+    };
+    let mut layer: Value = match toml::from_str(layer_string.as_ref()) {
+        Ok(layer) => layer,
+        Err(e) => return Err(format!("{}: {}", path.display(), e)),
+    };
+    for &(ref key, ref value) in assignments {
+        let keys: Vec<&str> = key.split('.').collect();
+        set_keys(&mut layer, &keys, 0, value.clone())?;
+    }
+    let layer_string = match toml::to_string(&layer) {
+        Ok(layer_string) => layer_string,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    // Replaced in a single rename, like the calibrations.
+    let mut temporary_path = path.as_os_str().to_owned();
+    temporary_path.push(".tmp");
+    let written = File::create(&temporary_path).and_then(|mut file| {
+        file.write_all(layer_string.as_bytes())?;
+        file.sync_all()
+    });
+    match written.and_then(|()| fs::rename(&temporary_path, &path)) {
+        Ok(()) => Ok(path),
+        Err(e) => {
+            let _ = fs::remove_file(&temporary_path);
+            Err(format!("Couldn't write {}: {}", path.display(), e))
+        }
+    }
+}
+
+// Tables are merged key by key, anything else is replaced, arrays included.
+fn merge(base: &mut Value, layer: Value) {
+    match (base, layer) {
+        (&mut Value::Table(ref mut base), Value::Table(layer)) => for (key, value) in layer {
+            match base.get_mut(&key) {
+                Some(existing) => {
+                    merge(existing, value);
+                    continue;
+                }
+                None => {}
+            };
+            base.insert(key, value);
+        },
+        (base, layer) => *base = layer,
+    }
+}
+
+// Sets key.path=value, making tables on the way if they aren't there.
+pub fn set(root: &mut Value, assignment: &str) -> Result<(), String> {
+    let mut parts = assignment.splitn(2, '=');
+    let path = parts.next().unwrap_or("").trim();
+    let value = match parts.next() {
+        Some(value) => parse_value(value.trim()),
+        None => return Err(String::from("Needs a key.path=value.")),
+    };
+    let keys: Vec<&str> = path.split('.').collect();
+    if keys.iter().any(|key| key.is_empty()) {
+        return Err(format!("\"{}\" isn't a key path.", path));
+    }
+
+    set_keys(root, &keys, 0, value)
+}
+
+fn set_keys(table: &mut Value, keys: &[&str], depth: usize, value: Value) -> Result<(), String> {
+    match *table {
+        Value::Table(ref mut entries) => {
+            let key = String::from(keys[depth]);
+            if depth == keys.len() - 1 {
+                entries.insert(key, value);
+                return Ok(());
+            }
+            let next = entries
+                .entry(key)
+                .or_insert_with(|| Value::Table(toml::value::Table::new()));
+            set_keys(next, keys, depth + 1, value)
+        }
+        _ => Err(format!("{} isn't a table.", keys[..depth].join("."))),
+    }
+}
+
+// Anything that doesn't parse as a TOML value is taken as a string, so names don't
+// need quoting on the command line.
+fn parse_value(value: &str) -> Value {
+    let document: Result<Value, _> = toml::from_str(&format!("value = {}", value));
+    match document {
+        Ok(Value::Table(mut table)) => match table.remove("value") {
+            Some(value) => value,
+            None => Value::String(String::from(value)),
+        },
+        _ => Value::String(String::from(value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn read(path: &Path) -> Value {
+        let mut layer_string = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut layer_string))
+            .unwrap();
+        toml::from_str(&layer_string).unwrap()
+    }
+
+    #[test]
+    fn local_values_are_merged_into_the_existing_layer() {
+        let directory = env::temp_dir().join(format!("layers_merge_{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        File::create(directory.join(LOCAL_LAYER))
+            .and_then(|mut file| {
+                file.write_all(b"[flight.roll]\np = 0.5\ni = 0.1\n\n[debug]\nlogging = false\n")
+            })
+            .unwrap();
+
+        let path = write_local(
+            &directory,
+            &[
+                (String::from("flight.roll.p"), Value::Float(0.75)),
+                (String::from("flight.yaw.d"), Value::Float(0.25)),
+            ],
+        ).unwrap();
+        let layer = read(&path);
+        fs::remove_dir_all(&directory).unwrap();
+
+        let expected: Value = toml::from_str(concat!(
+            "[flight.roll]\np = 0.75\ni = 0.1\n\n",
+            "[flight.yaw]\nd = 0.25\n\n",
+            "[debug]\nlogging = false\n",
+        )).unwrap();
+        assert_eq!(path, directory.join(LOCAL_LAYER));
+        assert_eq!(layer, expected);
+    }
+
+    #[test]
+    fn a_missing_local_layer_is_created() {
+        let directory = env::temp_dir().join(format!("layers_create_{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path =
+            write_local(&directory, &[(String::from("flight.yaw.p"), Value::Float(1.5))]).unwrap();
+        let layer = read(&path);
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(layer, toml::from_str::<Value>("[flight.yaw]\np = 1.5").unwrap());
+    }
+
+    #[test]
+    fn overrides_are_set_on_the_merged_value() {
+        let mut merged: Value = toml::from_str("[hardware.battery]\ncells = 3").unwrap();
+        set(&mut merged, "hardware.battery.cells=4").unwrap();
+        set(&mut merged, "hardware.gyroscope.name=LSM9DS0").unwrap();
+        assert!(set(&mut merged, "hardware.battery.cells.x=1").is_err());
+        assert!(set(&mut merged, "hardware..cells=1").is_err());
+
+        let expected: Value = toml::from_str(concat!(
+            "[hardware.battery]\ncells = 4\n\n",
+            "[hardware.gyroscope]\nname = \"LSM9DS0\"",
+        )).unwrap();
+        assert_eq!(merged, expected);
+    }
+}
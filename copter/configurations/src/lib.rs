@@ -6,8 +6,17 @@ extern crate nalgebra as na;
 
 pub mod config;
 pub mod calibrations;
+pub mod layers;
+pub mod parameters;
+pub mod thrust;
+pub mod validation;
 
 pub type Config = config::Config;
 pub type Calibrations = calibrations::Calibrations;
 pub type Ellipsoid = calibrations::Ellipsoid;
 pub type Simple = calibrations::Simple;
+pub type Thermal = calibrations::Thermal;
+pub type Record = calibrations::Record;
+pub type CalibrationError = calibrations::CalibrationError;
+pub type ConfigError = validation::ConfigError;
+pub type ParameterStore = parameters::ParameterStore;
@@ -1,18 +1,47 @@
-extern crate config;
-extern crate toml;
+extern crate configurations;
 
-use config::Config;
+use configurations::Config;
+use configurations::layers::{Arguments, DEFAULT_LAYER};
+use std::env;
 use std::fs::{File, OpenOptions};
+use std::process::exit;
 use std::string::String;
 use std::io::Write;
 
+const USAGE: &str = "Usage: default_generator [--config-dir <directory>]
+  --config-dir <directory>  Write to <directory>. COPTER_CONFIG_DIR if not set,
+                            ./configuration otherwise.";
+
+fn fail(message: &str) -> ! {
+    println!("{}\n{}", message, USAGE);
+    exit(1);
+}
+
+// Writes config_default.toml to the configuration directory, e.g. with
+// --config-dir ../configuration from this crate.
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let arguments = match Arguments::parse(&args) {
+        Ok(arguments) => arguments,
+        Err(e) => fail(&e),
+    };
+    if !arguments.rest.is_empty() {
+        fail(&format!("Unknown arguments {}.", arguments.rest.join(" ")));
+    }
+    // The defaults are written as they are, there's nothing merged to override or print.
+    if !arguments.overrides.is_empty() || arguments.print_config {
+        fail("--set and --print-config only apply to the merged configuration.");
+    }
+
+    let path = arguments.config_directory().join(DEFAULT_LAYER);
     let config = Config::default();
     let mut default_file: File = OpenOptions::new()
         .create(true)
         .write(true)
-        .open("../configuration/config_default.toml")
+        .truncate(true)
+        .open(&path)
         .unwrap();
-    let config_str: String = toml::to_string(&config).unwrap();
+    let config_str: String = config.to_toml().unwrap();
     default_file.write_all(config_str.as_bytes()).unwrap();
+    println!("Wrote {}.", path.display());
 }
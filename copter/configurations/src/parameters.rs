@@ -0,0 +1,300 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use toml::Value;
+
+use config::{Config, PID};
+use layers;
+use validation::MAX_PID_GAIN;
+
+// Validation needs p positive, so a tuned value has to be too.
+const MIN_P_GAIN: f32 = 0.001;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Axis {
+    Roll,
+    Pitch,
+    Yaw,
+}
+
+impl Axis {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Axis::Roll => "roll",
+            Axis::Pitch => "pitch",
+            Axis::Yaw => "yaw",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Gains {
+    pub p: f32,
+    pub i: f32,
+    pub d: f32,
+}
+
+// A value that can be changed in flight. The name is its key in the configuration.
+#[derive(Debug, Clone)]
+pub struct Parameter {
+    pub name: String,
+    pub value: f32,
+    pub min: f32,
+    pub max: f32,
+    pub description: &'static str,
+}
+
+impl fmt::Display for Parameter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} = {} ({} to {}) {}",
+            self.name, self.value, self.min, self.max, self.description
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ParameterChange {
+    pub name: String,
+    pub value: f32,
+}
+
+struct Subscriber {
+    prefix: String,
+    changes: Sender<ParameterChange>,
+}
+
+struct Parameters {
+    parameters: Vec<Parameter>,
+    subscribers: Vec<Subscriber>,
+    changed: Vec<String>, // Names set since the start, the ones persist writes
+}
+
+// Shared between the threads that read parameters and whoever tunes them. Clones refer
+// to the same values.
+#[derive(Clone)]
+pub struct ParameterStore {
+    shared: Arc<Mutex<Parameters>>,
+    directory: PathBuf, // Where persisted values are written
+}
+
+impl ParameterStore {
+    // Gains that aren't configured start at their minimum.
+    pub fn from_config(config: &Config) -> ParameterStore {
+        let mut parameters = Vec::new();
+        for &(axis, pid) in [
+            (Axis::Roll, &config.flight.roll),
+            (Axis::Pitch, &config.flight.pitch),
+            (Axis::Yaw, &config.flight.yaw),
+        ].iter()
+        {
+            add_gains(&mut parameters, axis, pid);
+        }
+        ParameterStore {
+            shared: Arc::new(Mutex::new(Parameters {
+                parameters: parameters,
+                subscribers: Vec::new(),
+                changed: Vec::new(),
+            })),
+            directory: config.directory.clone(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Result<f32, String> {
+        let shared = self.shared.lock().unwrap();
+        match shared.parameters.iter().find(|parameter| parameter.name == name) {
+            Some(parameter) => Ok(parameter.value),
+            None => Err(unknown(name)),
+        }
+    }
+
+    // Subscribers are told after the value changes. Setting the current value again
+    // isn't a change.
+    pub fn set(&self, name: &str, value: f32) -> Result<(), String> {
+        let mut shared = self.shared.lock().unwrap();
+        {
+            let parameter = match shared
+                .parameters
+                .iter_mut()
+                .find(|parameter| parameter.name == name)
+            {
+                Some(parameter) => parameter,
+                None => return Err(unknown(name)),
+            };
+            if !(value >= parameter.min && value <= parameter.max) {
+                return Err(format!(
+                    "{} is outside {} to {} for {}.",
+                    value, parameter.min, parameter.max, name
+                ));
+            }
+            if parameter.value == value {
+                return Ok(());
+            }
+            parameter.value = value;
+        }
+        if !shared.changed.iter().any(|changed| changed == name) {
+            shared.changed.push(String::from(name));
+        }
+
+        // Receivers that were dropped are forgotten.
+        shared.subscribers.retain(|subscriber| {
+            !name.starts_with(subscriber.prefix.as_str())
+                || subscriber
+                    .changes
+                    .send(ParameterChange {
+                        name: String::from(name),
+                        value: value,
+                    })
+                    .is_ok()
+        });
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<Parameter> {
+        self.shared.lock().unwrap().parameters.clone()
+    }
+
+    // Changes to parameters whose names start with prefix, e.g. "flight." for the gains.
+    pub fn subscribe(&self, prefix: &str) -> Receiver<ParameterChange> {
+        let (changes_tx, changes_rx) = channel();
+        self.shared.lock().unwrap().subscribers.push(Subscriber {
+            prefix: String::from(prefix),
+            changes: changes_tx,
+        });
+        changes_rx
+    }
+
+    pub fn gains(&self, axis: Axis) -> Gains {
+        let gain = |name: &str| {
+            self.get(&format!("flight.{}.{}", axis.name(), name))
+                .unwrap()
+        };
+        Gains {
+            p: gain("p"),
+            i: gain("i"),
+            d: gain("d"),
+        }
+    }
+
+    // Writes the values changed with set to the local layer so they're used on the next
+    // start. The rest stay wherever they're configured.
+    pub fn persist(&self) -> Result<PathBuf, String> {
+        let assignments: Vec<(String, Value)> = {
+            let shared = self.shared.lock().unwrap();
+            shared
+                .parameters
+                .iter()
+                .filter(|parameter| shared.changed.contains(&parameter.name))
+                .map(|parameter| (parameter.name.clone(), Value::Float(parameter.value as f64)))
+                .collect()
+        };
+        if assignments.is_empty() {
+            return Err(String::from("Nothing was changed, there's nothing to save."));
+        }
+        layers::write_local(&self.directory, &assignments)
+    }
+}
+
+fn add_gains(parameters: &mut Vec<Parameter>, axis: Axis, pid: &PID) {
+    for &(name, value, min, description) in [
+        ("p", pid.p, MIN_P_GAIN, "Proportional gain"),
+        ("i", pid.i, 0.0, "Integral gain"),
+        ("d", pid.d, 0.0, "Derivative gain"),
+    ].iter()
+    {
+        parameters.push(Parameter {
+            name: format!("flight.{}.{}", axis.name(), name),
+            value: value.unwrap_or(min),
+            min: min,
+            max: MAX_PID_GAIN,
+            description: description,
+        });
+    }
+}
+
+fn unknown(name: &str) -> String {
+    format!("There's no parameter {}. List them to see the names.", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::process;
+    use toml;
+
+    fn store(name: &str) -> ParameterStore {
+        let mut config = Config::default();
+        config.flight.roll.p = Some(0.5);
+        config.flight.pitch.p = None;
+        config.directory = env::temp_dir().join(format!("{}_{}", name, process::id()));
+        ParameterStore::from_config(&config)
+    }
+
+    #[test]
+    fn values_outside_the_bounds_are_refused() {
+        let parameters = store("parameter_bounds");
+        assert!(parameters.set("flight.roll.p", 0.0).is_err());
+        assert!(parameters.set("flight.roll.i", -0.1).is_err());
+        assert!(parameters.set("flight.roll.d", MAX_PID_GAIN + 1.0).is_err());
+        assert!(parameters.set("flight.roll.d", ::std::f32::NAN).is_err());
+        assert!(parameters.set("flight.roll.x", 1.0).is_err());
+        assert_eq!(parameters.get("flight.roll.p"), Ok(0.5));
+        assert_eq!(parameters.get("flight.roll.d"), Ok(0.0));
+
+        assert_eq!(parameters.set("flight.roll.d", MAX_PID_GAIN), Ok(()));
+        assert_eq!(parameters.get("flight.roll.d"), Ok(MAX_PID_GAIN));
+    }
+
+    #[test]
+    fn a_missing_p_starts_at_its_minimum() {
+        let parameters = store("parameter_missing_p");
+        assert_eq!(parameters.get("flight.pitch.p"), Ok(MIN_P_GAIN));
+    }
+
+    #[test]
+    fn subscribers_are_told_about_changes_under_their_prefix() {
+        let parameters = store("parameter_prefix");
+        let roll = parameters.subscribe("flight.roll.");
+        let flight = parameters.subscribe("flight.");
+        parameters.set("flight.roll.p", 1.0).unwrap();
+        parameters.set("flight.roll.p", 1.0).unwrap();
+        parameters.set("flight.yaw.d", 0.2).unwrap();
+
+        let roll_changes: Vec<String> = roll.try_iter().map(|change| change.name).collect();
+        assert_eq!(roll_changes, vec!["flight.roll.p"]);
+        let flight_changes: Vec<(String, f32)> = flight
+            .try_iter()
+            .map(|change| (change.name, change.value))
+            .collect();
+        assert_eq!(
+            flight_changes,
+            vec![
+                (String::from("flight.roll.p"), 1.0),
+                (String::from("flight.yaw.d"), 0.2),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_changed_values_are_persisted() {
+        let parameters = store("parameter_persist");
+        assert!(parameters.persist().is_err());
+
+        let directory = parameters.directory.clone();
+        fs::create_dir_all(&directory).unwrap();
+        parameters.set("flight.yaw.d", 0.25).unwrap();
+        let path = parameters.persist().unwrap();
+        let mut local = String::new();
+        File::open(&path).unwrap().read_to_string(&mut local).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        let layer: Value = toml::from_str(&local).unwrap();
+        assert_eq!(layer, toml::from_str("[flight.yaw]\nd = 0.25").unwrap());
+    }
+}
@@ -0,0 +1,273 @@
+use std::io::BufRead;
+
+use config::{FULL_POWER, IDLE_POWER};
+
+// Fits thrust models from bench measurements. Commands are 1000 to 2000 and are
+// scaled to u = (command - 1000) / 1000 for the polynomial.
+
+const COMMAND_RANGE: f64 = FULL_POWER - IDLE_POWER;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ThrustSample {
+    pub command: f64,
+    pub thrust: f64,
+    pub voltage: Option<f64>,
+}
+
+pub fn command_fraction(command: f64) -> f64 {
+    (command - IDLE_POWER) / COMMAND_RANGE
+}
+
+fn column(header: &[String], name: &str) -> Option<usize> {
+    header.iter().position(|column| column == name)
+}
+
+// Reads a CSV with a header containing command and thrust columns, and optionally
+// voltage and motor columns. Only rows for the given motor are kept if one is given,
+// which needs the motor column. Every value has to be a finite number.
+pub fn read_samples<R: BufRead>(reader: R, motor: Option<u32>) -> Result<Vec<ThrustSample>, String> {
+    let mut lines = reader.lines();
+    let header: Vec<String> = match lines.next() {
+        Some(Ok(line)) => line.split(',').map(|name| name.trim().to_lowercase()).collect(),
+        _ => return Err(String::from("The CSV file is empty.")),
+    };
+
+    let command_column = match column(&header, "command") {
+        Some(index) => index,
+        None => return Err(String::from("The CSV file has no command column.")),
+    };
+    let thrust_column = match column(&header, "thrust") {
+        Some(index) => index,
+        None => return Err(String::from("The CSV file has no thrust column.")),
+    };
+    let voltage_column = column(&header, "voltage");
+    let motor_column = column(&header, "motor");
+    if motor.is_some() && motor_column.is_none() {
+        return Err(String::from(
+            "The CSV file has no motor column, so there's no motor to pick.",
+        ));
+    }
+
+    let mut samples = Vec::new();
+    for (number, line) in lines.enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => return Err(e.to_string()),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+        // Line 1 is the header.
+        let parse = |index: usize| -> Result<Option<f64>, String> {
+            match fields.get(index) {
+                Some(field) if field.is_empty() => Ok(None),
+                Some(field) => match field.parse::<f64>() {
+                    Ok(value) if value.is_finite() => Ok(Some(value)),
+                    _ => Err(format!("Line {}: {} is not a number.", number + 2, field)),
+                },
+                None => Ok(None),
+            }
+        };
+
+        match (motor, motor_column) {
+            (Some(motor), Some(index)) => match parse(index)? {
+                Some(value) if value as u32 == motor => {}
+                _ => continue,
+            },
+            _ => {}
+        };
+
+        let command = parse(command_column)?;
+        let thrust = parse(thrust_column)?;
+        let voltage = match voltage_column {
+            Some(index) => parse(index)?,
+            None => None,
+        };
+        match (command, thrust) {
+            (Some(command), Some(thrust)) => samples.push(ThrustSample {
+                command: command,
+                thrust: thrust,
+                voltage: voltage,
+            }),
+            _ => return Err(format!("Line {}: missing command or thrust.", number + 2)),
+        }
+    }
+
+    if samples.is_empty() {
+        return Err(String::from("The CSV file has no samples."));
+    }
+    Ok(samples)
+}
+
+pub fn mean_voltage(samples: &[ThrustSample]) -> Option<f64> {
+    let voltages: Vec<f64> = samples.iter().filter_map(|sample| sample.voltage).collect();
+    if voltages.is_empty() {
+        None
+    } else {
+        Some(voltages.iter().sum::<f64>() / voltages.len() as f64)
+    }
+}
+
+// Thrust scales roughly with the square of the battery voltage at a fixed command.
+// Returns (command, thrust) as if every sample had been taken at the reference voltage.
+pub fn normalize_to_voltage(samples: &[ThrustSample], reference_voltage: Option<f64>) -> Vec<(f64, f64)> {
+    samples
+        .iter()
+        .map(|sample| match (reference_voltage, sample.voltage) {
+            (Some(reference), Some(voltage)) if voltage > 0.0 => {
+                let ratio = reference / voltage;
+                (sample.command, sample.thrust * ratio * ratio)
+            }
+            _ => (sample.command, sample.thrust),
+        })
+        .collect()
+}
+
+// Solves a square system with Gaussian elimination and partial pivoting.
+fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Result<Vec<f64>, String> {
+    let n = b.len();
+    for column in 0..n {
+        let pivot = (column..n)
+            .max_by(|&i, &j| a[i][column].abs().partial_cmp(&a[j][column].abs()).unwrap())
+            .unwrap();
+        if a[pivot][column].abs() < 1e-12 {
+            return Err(String::from(
+                "Not enough distinct commands to fit a polynomial of this degree.",
+            ));
+        }
+        a.swap(column, pivot);
+        b.swap(column, pivot);
+
+        for row in column + 1..n {
+            let factor = a[row][column] / a[column][column];
+            for k in column..n {
+                a[row][k] -= factor * a[column][k];
+            }
+            b[row] -= factor * b[column];
+        }
+    }
+
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let sum: f64 = (row + 1..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    Ok(x)
+}
+
+pub fn evaluate_polynomial(coefficients: &[f64], u: f64) -> f64 {
+    coefficients.iter().rev().fold(0.0, |value, c| value * u + c)
+}
+
+// Least squares fit of thrust against u. Coefficients are in increasing order.
+pub fn fit_polynomial(points: &[(f64, f64)], degree: usize) -> Result<Vec<f64>, String> {
+    let n = degree + 1;
+    if points.len() < n {
+        return Err(format!(
+            "A degree {} polynomial needs at least {} samples.",
+            degree, n
+        ));
+    }
+
+    // Normal equations. u is between 0 and 1, so they are well conditioned for low degrees.
+    let mut a = vec![vec![0.0; n]; n];
+    let mut b = vec![0.0; n];
+    for &(command, thrust) in points {
+        let u = command_fraction(command);
+        let powers: Vec<f64> = (0..n).map(|i| u.powi(i as i32)).collect();
+        for i in 0..n {
+            for j in 0..n {
+                a[i][j] += powers[i] * powers[j];
+            }
+            b[i] += powers[i] * thrust;
+        }
+    }
+    solve(a, b)
+}
+
+pub fn rms_residual(points: &[(f64, f64)], coefficients: &[f64]) -> f64 {
+    let sum: f64 = points
+        .iter()
+        .map(|&(command, thrust)| {
+            let error = evaluate_polynomial(coefficients, command_fraction(command)) - thrust;
+            error * error
+        })
+        .sum();
+    (sum / points.len() as f64).sqrt()
+}
+
+// Averages the samples at each command, sorted by command.
+pub fn table(points: &[(f64, f64)]) -> Vec<(f64, f64)> {
+    let mut sorted = points.to_vec();
+    sorted.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+    let mut table: Vec<(f64, f64, u32)> = Vec::new();
+    for (command, thrust) in sorted {
+        let same_command = match table.last() {
+            Some(&(last, _, _)) => (last - command).abs() < 0.5,
+            None => false,
+        };
+        if same_command {
+            let entry = table.last_mut().unwrap();
+            entry.1 += thrust;
+            entry.2 += 1;
+        } else {
+            table.push((command, thrust, 1));
+        }
+    }
+    table
+        .iter()
+        .map(|&(command, total, count)| (command, total / count as f64))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_are_read_by_column_name() {
+        let csv = "Motor, Thrust, Command, Voltage
+1, 2.5, 1500, 12.0
+2, 3.0, 1500,
+
+1, 5.0, 2000, 11.5
+";
+        let samples = read_samples(csv.as_bytes(), Some(1)).unwrap();
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[1].command, 2000.0);
+        assert_eq!(samples[1].thrust, 5.0);
+        assert_eq!(samples[1].voltage, Some(11.5));
+
+        let samples = read_samples(csv.as_bytes(), None).unwrap();
+        assert_eq!(samples.len(), 3);
+        assert_eq!(samples[1].voltage, None);
+    }
+
+    #[test]
+    fn non_finite_rows_are_rejected() {
+        for value in ["NaN", "inf", "-inf", "x"].iter() {
+            let csv = format!("command,thrust\n1500,2.5\n1600,{}\n", value);
+            assert_eq!(
+                read_samples(csv.as_bytes(), None).unwrap_err(),
+                format!("Line 3: {} is not a number.", value)
+            );
+        }
+        let csv = "command,thrust,motor\n1500,2.5,NaN\n";
+        assert!(read_samples(csv.as_bytes(), Some(1)).is_err());
+    }
+
+    #[test]
+    fn a_motor_needs_the_motor_column() {
+        let csv = "command,thrust\n1500,2.5\n";
+        assert!(read_samples(csv.as_bytes(), Some(1)).is_err());
+        assert_eq!(read_samples(csv.as_bytes(), None).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn table_averages_each_command_in_order() {
+        let points = [(2000.0, 5.0), (1500.0, 2.0), (2000.0, 6.0), (1500.2, 3.0)];
+        assert_eq!(table(&points), vec![(1500.0, 2.5), (2000.0, 5.5)]);
+    }
+}
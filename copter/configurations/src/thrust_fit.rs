@@ -0,0 +1,98 @@
+extern crate configurations;
+
+use configurations::thrust::{fit_polynomial, mean_voltage, normalize_to_voltage, read_samples,
+                             rms_residual, table};
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+use std::process::exit;
+
+const DEFAULT_DEGREE: usize = 2;
+
+const USAGE: &str = "Usage: thrust_fit <measurements.csv> [--degree N] [--motor N] [--table]
+The CSV needs command and thrust (N) columns. Optional voltage and motor columns are used
+for voltage compensation and to pick one motor. Prints a thrust_models entry for
+config.toml.";
+
+fn fail(message: &str) -> ! {
+    println!("{}", message);
+    exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut path: Option<String> = None;
+    let mut degree = DEFAULT_DEGREE;
+    let mut motor: Option<u32> = None;
+    let mut as_table = false;
+
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_ref() {
+            "--degree" if i + 1 < args.len() => {
+                degree = match args[i + 1].parse() {
+                    Ok(degree) => degree,
+                    Err(_) => fail(USAGE),
+                };
+                i += 1;
+            }
+            "--motor" if i + 1 < args.len() => {
+                motor = match args[i + 1].parse() {
+                    Ok(motor) => Some(motor),
+                    Err(_) => fail(USAGE),
+                };
+                i += 1;
+            }
+            "--table" => as_table = true,
+            argument if path.is_none() && !argument.starts_with("--") => {
+                path = Some(String::from(argument))
+            }
+            _ => fail(USAGE),
+        };
+        i += 1;
+    }
+
+    let path = match path {
+        Some(path) => path,
+        None => fail(USAGE),
+    };
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) => fail(&format!("Couldn't open {}: {}", path, e)),
+    };
+    let samples = match read_samples(BufReader::new(file), motor) {
+        Ok(samples) => samples,
+        Err(e) => fail(&e),
+    };
+
+    let reference_voltage = mean_voltage(&samples);
+    let points = normalize_to_voltage(&samples, reference_voltage);
+
+    // Fit before printing anything so errors don't leave half an entry behind.
+    let model = if as_table {
+        let entries: Vec<String> = table(&points)
+            .iter()
+            .map(|&(command, thrust)| format!("[{:.1}, {:.4}]", command, thrust))
+            .collect();
+        format!("table = [{}]", entries.join(", "))
+    } else {
+        let coefficients = match fit_polynomial(&points, degree) {
+            Ok(coefficients) => coefficients,
+            Err(e) => fail(&e),
+        };
+        let formatted: Vec<String> = coefficients.iter().map(|c| format!("{:.6}", c)).collect();
+        format!(
+            "polynomial = [{}]\n# {} samples, RMS residual {:.4} N",
+            formatted.join(", "),
+            points.len(),
+            rms_residual(&points, &coefficients)
+        )
+    };
+
+    println!("[[hardware.motors.thrust_models]]");
+    match reference_voltage {
+        Some(voltage) => println!("reference_voltage = {:.2}", voltage),
+        None => {}
+    };
+    println!("{}", model);
+}
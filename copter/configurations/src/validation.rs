@@ -0,0 +1,592 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use config::{Battery, Config, Filters, Motors, MotorProtocol, Networking, Debug, PID, Sensor,
+             SerialCommunication, FULL_POWER, IDLE_POWER};
+
+// Drivers the hardware module has for each sensor.
+pub const BAROMETERS: &[&str] = &["BMP180", "BMP280"];
+pub const GYROSCOPES: &[&str] = &["LSM9DS0"];
+pub const ACCELEROMETERS: &[&str] = &["LSM9DS0"];
+pub const MAGNETOMETERS: &[&str] = &["LSM9DS0"];
+
+// The mixer drives a quadcopter, so there are always four motors.
+pub const AIRFRAME_MOTORS: usize = 4;
+const PCA9685_CHANNELS: u8 = 16;
+const ADS111X_CHANNELS: u8 = 4;
+// Fully charged high voltage LiPo.
+const MAX_CELL_VOLTAGE: f32 = 4.35;
+pub const MAX_PID_GAIN: f32 = 100.0;
+// 7 bit I2C addresses, without the reserved ones.
+const MIN_I2C_ADDRESS: u16 = 0x03;
+const MAX_I2C_ADDRESS: u16 = 0x77;
+
+// A problem with one value, by its key in the TOML file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub key: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+struct Problems {
+    errors: Vec<ConfigError>,
+}
+
+impl Problems {
+    fn add(&mut self, key: &str, message: String) {
+        self.errors.push(ConfigError {
+            key: String::from(key),
+            message: message,
+        });
+    }
+
+    fn positive(&mut self, key: &str, value: Option<f32>) {
+        match value {
+            Some(value) if !(value > 0.0) || !value.is_finite() => {
+                self.add(key, format!("{} must be positive.", value))
+            }
+            _ => {}
+        };
+    }
+
+    fn positive_integer(&mut self, key: &str, value: Option<i32>) {
+        match value {
+            Some(value) if value <= 0 => self.add(key, format!("{} must be positive.", value)),
+            _ => {}
+        };
+    }
+}
+
+impl Config {
+    // Checks everything that can be checked without the hardware, so a bad file is
+    // caught before anything is powered. All problems are reported at once.
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut problems = Problems { errors: Vec::new() };
+
+        validate_pid(&mut problems, "flight.roll", &self.flight.roll);
+        validate_pid(&mut problems, "flight.pitch", &self.flight.pitch);
+        validate_pid(&mut problems, "flight.yaw", &self.flight.yaw);
+
+        let hardware = &self.hardware;
+        validate_sensor(&mut problems, "hardware.barometer", &hardware.barometer, BAROMETERS);
+        validate_sensor(&mut problems, "hardware.gyroscope", &hardware.gyroscope, GYROSCOPES);
+        validate_sensor(
+            &mut problems,
+            "hardware.accelerometer",
+            &hardware.accelerometer,
+            ACCELEROMETERS,
+        );
+        validate_sensor(
+            &mut problems,
+            "hardware.magnetometer",
+            &hardware.magnetometer,
+            MAGNETOMETERS,
+        );
+        match hardware.gps_receiver {
+            Some(ref receiver) => {
+                problems.positive_integer("hardware.gps_receiver.update_rate", receiver.update_rate);
+                match receiver.baud_rate {
+                    Some(0) => problems.add(
+                        "hardware.gps_receiver.baud_rate",
+                        String::from("0 must be positive."),
+                    ),
+                    _ => {}
+                };
+            }
+            None => {}
+        };
+        validate_motors(&mut problems, &hardware.motors);
+        validate_battery(&mut problems, &hardware.battery);
+        match hardware.filters {
+            Some(ref filters) => validate_filters(&mut problems, filters),
+            None => {}
+        };
+
+        validate_networking(&mut problems, &self.networking);
+        validate_debug(&mut problems, &self.debug);
+
+        if problems.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.errors)
+        }
+    }
+}
+
+fn validate_pid(problems: &mut Problems, key: &str, pid: &PID) {
+    match pid.p {
+        Some(p) if p > 0.0 => {}
+        Some(p) => problems.add(
+            &format!("{}.p", key),
+            format!("{} must be positive, the axis isn't controlled without it.", p),
+        ),
+        None => problems.add(
+            &format!("{}.p", key),
+            String::from("Missing, the axis isn't controlled without it."),
+        ),
+    };
+    for &(name, gain) in [("p", pid.p), ("i", pid.i), ("d", pid.d)].iter() {
+        let gain_key = format!("{}.{}", key, name);
+        match gain {
+            Some(gain) if !gain.is_finite() || gain < 0.0 => {
+                problems.add(&gain_key, format!("{} can't be negative.", gain))
+            }
+            Some(gain) if gain > MAX_PID_GAIN => problems.add(
+                &gain_key,
+                format!("{} is above {}, check the units.", gain, MAX_PID_GAIN),
+            ),
+            _ => {}
+        };
+    }
+}
+
+fn validate_sensor(problems: &mut Problems, key: &str, sensor: &Sensor, drivers: &[&str]) {
+    if !drivers.contains(&sensor.name.as_ref()) {
+        problems.add(
+            &format!("{}.name", key),
+            format!(
+                "Unknown model \"{}\". Use one of {}.",
+                sensor.name,
+                drivers.join(", ")
+            ),
+        );
+    }
+    match sensor.serial {
+        SerialCommunication::UART => problems.add(
+            &format!("{}.serial", key),
+            String::from("UART isn't supported. Use I2C or SPI."),
+        ),
+        SerialCommunication::I2C => validate_i2c_address(problems, key, sensor.slave_address),
+        SerialCommunication::SPI => {}
+    };
+    problems.positive_integer(&format!("{}.update_rate", key), sensor.update_rate);
+    problems.positive_integer(&format!("{}.full_scale", key), sensor.full_scale);
+}
+
+// 0 is the chip default.
+fn validate_i2c_address(problems: &mut Problems, key: &str, address: u16) {
+    if address != 0 && (address < MIN_I2C_ADDRESS || address > MAX_I2C_ADDRESS) {
+        problems.add(
+            &format!("{}.slave_address", key),
+            format!(
+                "{:#x} isn't a 7 bit I2C address. Use {:#x} to {:#x}, or 0 for the chip default.",
+                address, MIN_I2C_ADDRESS, MAX_I2C_ADDRESS
+            ),
+        );
+    }
+}
+
+fn validate_motors(problems: &mut Problems, motors: &Motors) {
+    if motors.pins.len() != AIRFRAME_MOTORS {
+        problems.add(
+            "hardware.motors.pins",
+            format!(
+                "Found {} pins, the quadcopter airframe has {} motors.",
+                motors.pins.len(),
+                AIRFRAME_MOTORS
+            ),
+        );
+    }
+    let mut pins = HashSet::new();
+    for pin in &motors.pins {
+        if !pins.insert(pin) {
+            problems.add(
+                "hardware.motors.pins",
+                format!("Pin {} is used for more than one motor.", pin),
+            );
+        }
+        if motors.serial_pwm && *pin >= PCA9685_CHANNELS {
+            problems.add(
+                "hardware.motors.pins",
+                format!(
+                    "Pin {} doesn't exist on the PCA9685. Use 0 to {}.",
+                    pin,
+                    PCA9685_CHANNELS - 1
+                ),
+            );
+        }
+    }
+    match motors.serial_controller {
+        Some(ref controller) => {
+            validate_i2c_address(problems, "hardware.motors.serial_controller", controller.slave_address);
+            problems.positive_integer(
+                "hardware.motors.serial_controller.update_rate",
+                controller.update_rate,
+            );
+        }
+        None => {}
+    };
+
+    let dshot = match motors.protocol {
+        Some(MotorProtocol::DShot150)
+        | Some(MotorProtocol::DShot300)
+        | Some(MotorProtocol::DShot600) => true,
+        _ => false,
+    };
+    match motors.dshot_outputs {
+        Some(ref outputs) if dshot && outputs.len() != motors.pins.len() => problems.add(
+            "hardware.motors.dshot_outputs",
+            format!(
+                "Found {} outputs for {} motors. Use one per motor, in the same order as pins.",
+                outputs.len(),
+                motors.pins.len()
+            ),
+        ),
+        None if dshot => problems.add(
+            "hardware.motors.dshot_outputs",
+            String::from("Missing, DShot needs one output per motor."),
+        ),
+        _ => {}
+    };
+
+    for &(key, power) in [
+        ("hardware.motors.min_power", motors.min_power),
+        ("hardware.motors.max_power", motors.max_power),
+    ].iter()
+    {
+        match power.map(|power| power as f64) {
+            Some(power) if !(power >= IDLE_POWER && power <= FULL_POWER) => problems.add(
+                key,
+                format!("{} is outside {} to {}.", power, IDLE_POWER, FULL_POWER),
+            ),
+            _ => {}
+        };
+    }
+    let min_power = motors.min_power.map_or(IDLE_POWER, |power| power as f64);
+    let max_power = motors.max_power.map_or(FULL_POWER, |power| power as f64);
+    if min_power >= max_power {
+        problems.add(
+            "hardware.motors.min_power",
+            format!(
+                "{} must be below max_power, {}.",
+                min_power, max_power
+            ),
+        );
+    }
+    problems.positive("hardware.motors.max_slew_rate", motors.max_slew_rate);
+    match motors.command_timeout_ms {
+        Some(0) => problems.add(
+            "hardware.motors.command_timeout_ms",
+            String::from("0 stops the motors on every command. Use a positive timeout."),
+        ),
+        _ => {}
+    };
+    match motors.thrust_models {
+        Some(ref models) if models.len() != 1 && models.len() != motors.pins.len() => {
+            problems.add(
+                "hardware.motors.thrust_models",
+                format!(
+                    "Found {} thrust models for {} motors. Use one per motor or one for all.",
+                    models.len(),
+                    motors.pins.len()
+                ),
+            )
+        }
+        _ => {}
+    };
+}
+
+fn validate_battery(problems: &mut Problems, battery: &Battery) {
+    if battery.cells < 0 {
+        problems.add(
+            "hardware.battery.cells",
+            format!("{} can't be negative. Use 0 to detect it.", battery.cells),
+        );
+    }
+    if !(battery.critical_voltage > 0.0) {
+        problems.add(
+            "hardware.battery.critical_voltage",
+            format!("{} must be positive.", battery.critical_voltage),
+        );
+    }
+    if !(battery.critical_voltage < battery.warning_voltage) {
+        problems.add(
+            "hardware.battery.critical_voltage",
+            format!(
+                "{} must be below warning_voltage, {}.",
+                battery.critical_voltage, battery.warning_voltage
+            ),
+        );
+    }
+    if battery.warning_voltage > MAX_CELL_VOLTAGE {
+        problems.add(
+            "hardware.battery.warning_voltage",
+            format!(
+                "{} is above a full cell, {}. The voltages are per cell.",
+                battery.warning_voltage, MAX_CELL_VOLTAGE
+            ),
+        );
+    }
+    problems.positive_integer("hardware.battery.update_rate", battery.update_rate);
+    problems.positive("hardware.battery.divider_ratio", battery.divider_ratio);
+    for &(key, channel) in [
+        ("hardware.battery.voltage_channel", battery.voltage_channel),
+        ("hardware.battery.current_channel", battery.current_channel),
+    ].iter()
+    {
+        match channel {
+            Some(channel) if channel >= ADS111X_CHANNELS => problems.add(
+                key,
+                format!(
+                    "Channel {} doesn't exist. Use 0 to {}.",
+                    channel,
+                    ADS111X_CHANNELS - 1
+                ),
+            ),
+            _ => {}
+        };
+    }
+    if battery.current_channel.is_some()
+        && battery.current_channel == Some(battery.voltage_channel.unwrap_or(0))
+    {
+        problems.add(
+            "hardware.battery.current_channel",
+            String::from("Can't be the same as voltage_channel."),
+        );
+    }
+    if battery.current_channel.is_some() && battery.current_scale.is_none() {
+        problems.add(
+            "hardware.battery.current_scale",
+            String::from("Missing, current sensing needs it."),
+        );
+    }
+    problems.positive("hardware.battery.current_scale", battery.current_scale);
+    problems.positive("hardware.battery.capacity_mah", battery.capacity_mah);
+    problems.positive("hardware.battery.internal_resistance", battery.internal_resistance);
+}
+
+fn validate_filters(problems: &mut Problems, filters: &Filters) {
+    problems.positive(
+        "hardware.filters.gyroscope_lowpass_hz",
+        filters.gyroscope_lowpass_hz,
+    );
+    problems.positive(
+        "hardware.filters.accelerometer_lowpass_hz",
+        filters.accelerometer_lowpass_hz,
+    );
+    for (i, notch) in filters.notches.iter().enumerate() {
+        problems.positive(
+            &format!("hardware.filters.notches[{}].center_hz", i),
+            Some(notch.center_hz),
+        );
+        problems.positive(&format!("hardware.filters.notches[{}].q", i), Some(notch.q));
+    }
+    match filters.dynamic_notch {
+        Some(ref notch) => {
+            problems.positive("hardware.filters.dynamic_notch.min_hz", Some(notch.min_hz));
+            problems.positive("hardware.filters.dynamic_notch.q", Some(notch.q));
+            if !(notch.min_hz < notch.max_hz) {
+                problems.add(
+                    "hardware.filters.dynamic_notch.min_hz",
+                    format!("{} must be below max_hz, {}.", notch.min_hz, notch.max_hz),
+                );
+            }
+        }
+        None => {}
+    };
+}
+
+fn validate_port(problems: &mut Problems, key: &str, port: i32) {
+    if port < 1 || port > 65535 {
+        problems.add(key, format!("{} isn't a port. Use 1 to 65535.", port));
+    }
+}
+
+fn validate_networking(problems: &mut Problems, networking: &Networking) {
+    if networking.server_ip.trim().is_empty() {
+        problems.add("networking.server_ip", String::from("Missing."));
+    }
+    validate_port(problems, "networking.server_port", networking.server_port);
+}
+
+// The websocket is only opened for live debugging.
+fn validate_debug(problems: &mut Problems, debug: &Debug) {
+    if debug.live_debugging {
+        validate_port(problems, "debug.debug_websocket_port", debug.debug_websocket_port);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use toml;
+
+    const VALID: &str = r#"
+[flight.roll]
+p = 0.5
+i = 0.1
+d = 0.05
+
+[flight.pitch]
+p = 0.5
+i = 0.1
+d = 0.05
+
+[flight.yaw]
+p = 1.0
+d = 0.0
+
+[hardware]
+gps = false
+wifi_gps = false
+
+[hardware.barometer]
+name = "BMP280"
+update_rate = 100
+serial = "I2C"
+bus = "/dev/i2c-1"
+slave_address = 0x76
+
+[hardware.gyroscope]
+name = "LSM9DS0"
+update_rate = 100
+serial = "I2C"
+bus = "/dev/i2c-1"
+slave_address = 0
+full_scale = 500
+
+[hardware.accelerometer]
+name = "LSM9DS0"
+update_rate = 100
+serial = "I2C"
+bus = "/dev/i2c-1"
+slave_address = 0
+full_scale = 4
+
+[hardware.magnetometer]
+name = "LSM9DS0"
+update_rate = 100
+serial = "I2C"
+bus = "/dev/i2c-1"
+slave_address = 0
+
+[hardware.motors]
+pins = [0, 1, 2, 3]
+serial_pwm = true
+protocol = "PWM"
+min_power = 1000.0
+max_power = 2000.0
+command_timeout_ms = 500
+
+[hardware.battery]
+cells = 3
+warning_voltage = 3.5
+critical_voltage = 3.3
+voltage_channel = 0
+
+[networking]
+server_ip = "192.168.1.2"
+server_port = 8080
+
+[debug]
+live_debugging = false
+debug_websocket_port = 0
+logging = true
+motors_off = false
+"#;
+
+    // The keys reported for VALID with one piece of it replaced.
+    fn reported(old: &str, new: &str) -> Vec<String> {
+        assert!(VALID.contains(old), "{} isn't in the valid configuration", old);
+        let config: Config = toml::from_str(&VALID.replacen(old, new, 1)).unwrap();
+        match config.validate() {
+            Ok(()) => vec![],
+            Err(errors) => errors.into_iter().map(|error| error.key).collect(),
+        }
+    }
+
+    #[test]
+    fn valid_configuration_passes() {
+        let config: Config = toml::from_str(VALID).unwrap();
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn missing_and_out_of_range_gains_are_reported() {
+        assert_eq!(reported("p = 0.5\ni = 0.1", "i = 0.1"), vec!["flight.roll.p"]);
+        assert_eq!(reported("p = 1.0", "p = 0.0"), vec!["flight.yaw.p"]);
+        assert_eq!(reported("d = 0.05", "d = -1.0"), vec!["flight.roll.d"]);
+        assert_eq!(reported("i = 0.1", "i = 1000.0"), vec!["flight.roll.i"]);
+        assert_eq!(reported("d = 0.05", "d = nan"), vec!["flight.roll.d"]);
+    }
+
+    #[test]
+    fn sensor_problems_are_reported_by_key() {
+        assert_eq!(
+            reported("name = \"BMP280\"", "name = \"BMP999\""),
+            vec!["hardware.barometer.name"]
+        );
+        assert_eq!(
+            reported("slave_address = 0x76", "slave_address = 0x80"),
+            vec!["hardware.barometer.slave_address"]
+        );
+        assert_eq!(
+            reported("serial = \"I2C\"", "serial = \"UART\""),
+            vec!["hardware.barometer.serial"]
+        );
+        assert_eq!(
+            reported("full_scale = 500", "full_scale = 0"),
+            vec!["hardware.gyroscope.full_scale"]
+        );
+    }
+
+    #[test]
+    fn motor_problems_are_reported_by_key() {
+        assert_eq!(
+            reported("pins = [0, 1, 2, 3]", "pins = [0, 1, 2]"),
+            vec!["hardware.motors.pins"]
+        );
+        assert_eq!(
+            reported("pins = [0, 1, 2, 3]", "pins = [0, 1, 1, 16]"),
+            vec!["hardware.motors.pins", "hardware.motors.pins"]
+        );
+        assert_eq!(
+            reported("min_power = 1000.0", "min_power = 900.0"),
+            vec!["hardware.motors.min_power"]
+        );
+        assert_eq!(
+            reported("max_power = 2000.0", "max_power = 1000.0"),
+            vec!["hardware.motors.min_power"]
+        );
+        assert_eq!(
+            reported("protocol = \"PWM\"", "protocol = \"DShot600\""),
+            vec!["hardware.motors.dshot_outputs"]
+        );
+        assert_eq!(
+            reported("command_timeout_ms = 500", "command_timeout_ms = 0"),
+            vec!["hardware.motors.command_timeout_ms"]
+        );
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let config: Config = toml::from_str(
+            &VALID
+                .replacen("critical_voltage = 3.3", "critical_voltage = 3.6", 1)
+                .replacen("voltage_channel = 0", "voltage_channel = 4", 1)
+                .replacen("server_port = 8080", "server_port = 0", 1)
+                .replacen("live_debugging = false", "live_debugging = true", 1),
+        ).unwrap();
+        let keys: Vec<String> = config
+            .validate()
+            .unwrap_err()
+            .into_iter()
+            .map(|error| error.key)
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                "hardware.battery.critical_voltage",
+                "hardware.battery.voltage_channel",
+                "networking.server_port",
+                "debug.debug_websocket_port",
+            ]
+        );
+    }
+}
@@ -4,7 +4,7 @@ use std::thread;
 use std::sync::mpsc::{Sender, Receiver, channel};
 use std::string::String;
 
-use websocket::Message;
+use websocket::{Message, OwnedMessage};
 use websocket::sync::Server;
 use websocket::sync::Client;
 use websocket::stream::sync::TcpStream;
@@ -66,6 +66,7 @@ pub enum Signal {
 }
 
 const DEBUG_PROTOCOL: &str = "drone-debug";
+const CALIBRATION_PROTOCOL: &str = "drone-calibration";
 
 fn shutdown_port(client: &mut Client<TcpStream>) -> Result<(),()> {
     match client.shutdown() {
@@ -80,15 +81,15 @@ fn shutdown_port(client: &mut Client<TcpStream>) -> Result<(),()> {
     }
 }
 
-fn start_port(server: &mut Server<NoTlsAcceptor>) -> Result<Client<TcpStream>,()> {
+fn start_port(server: &mut Server<NoTlsAcceptor>, protocol: &str) -> Result<Client<TcpStream>,()> {
     println!("[Debug]: Debug port waiting for a connection.");
     'search: loop {
         match server.accept() {
             Ok(upgrade) => {
-                if !upgrade.protocols().contains(&String::from(DEBUG_PROTOCOL)) {
+                if !upgrade.protocols().contains(&String::from(protocol)) {
                     continue 'search;
                 }
-                match upgrade.use_protocol(DEBUG_PROTOCOL).accept() {
+                match upgrade.use_protocol(protocol).accept() {
                     Ok(client) => {
                         let ip = client.peer_addr().unwrap();
                         println!("[Debug]: Debug connection from {}", ip);
@@ -102,6 +103,56 @@ fn start_port(server: &mut Server<NoTlsAcceptor>) -> Result<Client<TcpStream>,()
     }
 }
 
+// Relays a calibration session to the first client on the port. Text sent on the
+// returned Sender goes to the client, and the client's text messages come back on the
+// Receiver, which closes when the client leaves.
+pub fn serve_calibration(port: u16) -> Result<(Sender<String>, Receiver<String>), String> {
+    let mut server = match Server::bind(("0.0.0.0", port)) {
+        Ok(server) => server,
+        Err(e) => return Err(format!("Couldn't listen on port {}: {}", port, e)),
+    };
+    let (outgoing_tx, outgoing_rx): (Sender<String>, Receiver<String>) = channel();
+    let (incoming_tx, incoming_rx): (Sender<String>, Receiver<String>) = channel();
+    thread::spawn(move || {
+        let client = match start_port(&mut server, CALIBRATION_PROTOCOL) {
+            Ok(client) => client,
+            Err(()) => return,
+        };
+        let (mut reader, mut writer) = match client.split() {
+            Ok(halves) => halves,
+            Err(e) => {
+                println!("[Debug]: Couldn't use the calibration client: {:?}", e);
+                return;
+            }
+        };
+        thread::spawn(move || {
+            loop {
+                match reader.recv_message() {
+                    Ok(OwnedMessage::Text(text)) => {
+                        if incoming_tx.send(text).is_err() {
+                            return;
+                        }
+                    },
+                    Ok(OwnedMessage::Close(_)) | Err(_) => return,
+                    Ok(_) => { }
+                }
+            }
+        });
+        // Events sent before the client connected were queued.
+        for text in outgoing_rx.iter() {
+            match writer.send_message(&Message::text(text)) {
+                Ok(()) => { },
+                Err(e) => {
+                    println!("[Debug]: Calibration client lost: {:?}", e);
+                    return;
+                }
+            }
+        }
+        let _ = writer.send_message(&Message::close());
+    });
+    Ok((outgoing_tx, incoming_rx))
+}
+
 pub struct Logger {}
 
 impl Logger {
@@ -1,4 +1,5 @@
 use std::fs::{File, OpenOptions};
+use std::io::Write;
 use time;
 
 pub struct FlightLogger {
@@ -17,8 +18,12 @@ impl FlightLogger {
         }
     }
 
-    pub fn log(&self) {
-        let current_time = time::now();
-        let log_msg = format!("[{}:{}:{}], {}", current_time.tm_hour, current_time.tm_min, current_time.tm_sec, "L O G G");
+    // The timestamp is seconds on the flight clock, the same time base as the sensor readings.
+    pub fn log(&self, timestamp: f64, message: &str) {
+        let log_msg = format!("[{:.6}], {}\n", timestamp, message);
+        match (&self.log_file).write_all(log_msg.as_bytes()) {
+            Ok(()) => {}
+            Err(e) => println!("[FlightLogger]: Couldn't write to the flight log: {}", e),
+        }
     }
 }
@@ -0,0 +1,277 @@
+use std::ops::{Add, AddAssign};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const NANOSECONDS_PER_SECOND: f64 = 1000000000.0;
+
+// Monotonic time since the clock started. Unlike Instant it can be created by a
+// simulated clock and written to a log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(Duration);
+
+impl Timestamp {
+    pub fn zero() -> Timestamp {
+        Timestamp(Duration::new(0, 0))
+    }
+
+    pub fn from_duration(since_start: Duration) -> Timestamp {
+        Timestamp(since_start)
+    }
+
+    pub fn from_seconds(seconds: f64) -> Timestamp {
+        let seconds = seconds.max(0.0);
+        let whole = seconds.trunc();
+        Timestamp(Duration::new(
+            whole as u64,
+            ((seconds - whole) * NANOSECONDS_PER_SECOND) as u32,
+        ))
+    }
+
+    pub fn since_start(&self) -> Duration {
+        self.0
+    }
+
+    pub fn as_seconds(&self) -> f64 {
+        self.0.as_secs() as f64 + self.0.subsec_nanos() as f64 / NANOSECONDS_PER_SECOND
+    }
+
+    // Zero if earlier is actually later.
+    pub fn duration_since(&self, earlier: Timestamp) -> Duration {
+        if self.0 > earlier.0 {
+            self.0 - earlier.0
+        } else {
+            Duration::new(0, 0)
+        }
+    }
+
+    pub fn seconds_since(&self, earlier: Timestamp) -> f64 {
+        Timestamp(self.duration_since(earlier)).as_seconds()
+    }
+}
+
+impl Add<Duration> for Timestamp {
+    type Output = Timestamp;
+
+    fn add(self, duration: Duration) -> Timestamp {
+        Timestamp(self.0 + duration)
+    }
+}
+
+impl AddAssign<Duration> for Timestamp {
+    fn add_assign(&mut self, duration: Duration) {
+        self.0 += duration;
+    }
+}
+
+// A value together with the time it was acquired.
+#[derive(Debug, Clone, Copy)]
+pub struct Timestamped<T> {
+    pub value: T,
+    pub timestamp: Timestamp,
+}
+
+impl<T> Timestamped<T> {
+    pub fn new(value: T, timestamp: Timestamp) -> Timestamped<T> {
+        Timestamped {
+            value: value,
+            timestamp: timestamp,
+        }
+    }
+}
+
+// Source of time for the hardware, flight and logging threads. Everything that needs
+// the time or needs to wait goes through the clock so a simulation can control time.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Timestamp;
+
+    fn sleep(&self, duration: Duration);
+
+    // False if time is driven by a simulation.
+    fn is_real_time(&self) -> bool;
+}
+
+pub type SharedClock = Arc<Clock>;
+
+pub struct RealClock {
+    start: Instant,
+}
+
+impl RealClock {
+    pub fn new() -> RealClock {
+        RealClock {
+            start: Instant::now(),
+        }
+    }
+
+    pub fn shared() -> SharedClock {
+        Arc::new(RealClock::new())
+    }
+}
+
+impl Clock for RealClock {
+    fn now(&self) -> Timestamp {
+        Timestamp(self.start.elapsed())
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+
+    fn is_real_time(&self) -> bool {
+        true
+    }
+}
+
+// Time only moves when the simulation advances it. Sleeping threads wake up once the
+// simulated time reaches their deadline, so a simulation runs as fast as it is driven.
+pub struct SimulatedClock {
+    time: Mutex<SimulatedTime>,
+    advanced: Condvar,
+    slept: Condvar,
+}
+
+struct SimulatedTime {
+    now: Timestamp,
+    deadlines: Vec<Timestamp>, // Of the sleeping threads
+}
+
+impl SimulatedClock {
+    pub fn new() -> SimulatedClock {
+        SimulatedClock {
+            time: Mutex::new(SimulatedTime {
+                now: Timestamp::zero(),
+                deadlines: Vec::new(),
+            }),
+            advanced: Condvar::new(),
+            slept: Condvar::new(),
+        }
+    }
+
+    pub fn shared() -> Arc<SimulatedClock> {
+        Arc::new(SimulatedClock::new())
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut time = self.time.lock().unwrap();
+        time.now += duration;
+        self.advanced.notify_all();
+    }
+
+    // Never moves backwards.
+    pub fn set(&self, timestamp: Timestamp) {
+        let mut time = self.time.lock().unwrap();
+        if timestamp > time.now {
+            time.now = timestamp;
+            self.advanced.notify_all();
+        }
+    }
+
+    // Blocks until at least count threads are sleeping towards a deadline that hasn't
+    // been reached yet, i.e. waiting for time to move.
+    pub fn wait_for_sleepers(&self, count: usize) {
+        let mut time = self.time.lock().unwrap();
+        while time.deadlines.iter().filter(|deadline| **deadline > time.now).count() < count {
+            time = self.slept.wait(time).unwrap();
+        }
+    }
+
+    // Advances the clock by step every step / speedup of real time, until the driver
+    // is dropped.
+    pub fn drive(clock: Arc<SimulatedClock>, step: Duration, speedup: u32) -> ClockDriver {
+        let speedup = if speedup == 0 { 1 } else { speedup };
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let handle = thread::Builder::new()
+            .name(String::from("Simulated Clock"))
+            .spawn(move || {
+                while !stopped.load(Ordering::SeqCst) {
+                    thread::sleep(step / speedup);
+                    clock.advance(step);
+                }
+            })
+            .unwrap();
+        ClockDriver {
+            stop: stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+// Keeps a simulated clock moving. Dropping it stops the thread that drives it.
+pub struct ClockDriver {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for ClockDriver {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        match self.handle.take() {
+            Some(handle) => {
+                let _ = handle.join();
+            }
+            None => {}
+        };
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> Timestamp {
+        self.time.lock().unwrap().now
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let mut time = self.time.lock().unwrap();
+        let deadline = time.now + duration;
+        time.deadlines.push(deadline);
+        self.slept.notify_all();
+        while time.now < deadline {
+            time = self.advanced.wait(time).unwrap();
+        }
+        let index = time.deadlines.iter().position(|sleeper| *sleeper == deadline);
+        match index {
+            Some(index) => {
+                time.deadlines.swap_remove(index);
+            }
+            None => {}
+        };
+    }
+
+    fn is_real_time(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sleepers_wake_once_the_simulated_time_reaches_their_deadline() {
+        let clock = SimulatedClock::shared();
+        let sleeper_clock = clock.clone();
+        let sleeper = thread::spawn(move || sleeper_clock.sleep(Duration::from_secs(2)));
+
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_secs(1));
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_secs(1));
+        sleeper.join().unwrap();
+        assert_eq!(clock.now(), Timestamp::from_duration(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn dropping_the_driver_stops_the_clock() {
+        let clock = SimulatedClock::shared();
+        let driver = SimulatedClock::drive(clock.clone(), Duration::from_millis(10), 10);
+        clock.sleep(Duration::from_millis(50));
+        drop(driver);
+
+        let stopped = clock.now();
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(clock.now(), stopped);
+    }
+}
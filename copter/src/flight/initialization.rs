@@ -0,0 +1,236 @@
+use std::f64;
+
+use na::geometry::{Rotation3, UnitQuaternion};
+use na::{Matrix3, Vector3};
+use num::traits::Zero;
+
+use clock::Timestamp;
+use hardware::{PredictionReading, UpdateReading};
+
+const G_TO_MPSPS: f64 = 9.80665;
+// Averaged for the gyro bias and attitude once the vehicle is still.
+const INITIALIZATION_SECONDS: f64 = 2.0;
+// Stillness is checked over windows this long before averaging starts.
+const STATIONARY_WINDOW_SECONDS: f64 = 0.5;
+const STATIONARY_TIMEOUT_SECONDS: f64 = 30.0;
+// Standard deviations of a still vehicle, in rad/s and m/s^2.
+const GYROSCOPE_STATIONARY_THRESHOLD: f64 = 0.02;
+const ACCELEROMETER_STATIONARY_THRESHOLD: f64 = 0.1;
+// Any single reading this far from the average means the vehicle was moved.
+const GYROSCOPE_MOTION_THRESHOLD: f64 = 0.1;
+const ACCELEROMETER_MOTION_THRESHOLD: f64 = 1.0;
+// The measured gravity has to be this close to g.
+const GRAVITY_TOLERANCE: f64 = 0.5;
+
+// Uncertainties the covariance starts with, for what averaging can't tell.
+const POSITION_STD: f64 = 0.1; // m
+const VELOCITY_STD: f64 = 0.05; // m/s
+const TILT_STD: f64 = 0.02; // rad, about the accelerometer calibration
+const HEADING_STD: f64 = 0.1; // rad, with a magnetometer
+const ACCELEROMETER_BIAS_STD: f64 = 0.1; // m/s^2
+const MAGNETIC_FIELD_STD: f64 = 0.1; // Of the normalized field
+
+// Running mean and variance of each axis.
+#[derive(Debug, Clone, Copy)]
+struct Statistics {
+    count: usize,
+    mean: Vector3<f64>,
+    squares: Vector3<f64>,
+}
+
+impl Statistics {
+    fn new() -> Statistics {
+        Statistics {
+            count: 0,
+            mean: Vector3::zero(),
+            squares: Vector3::zero(),
+        }
+    }
+
+    fn add(&mut self, value: Vector3<f64>) {
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.squares += delta.component_mul(&(value - self.mean));
+    }
+
+    fn variance(&self) -> Vector3<f64> {
+        if self.count < 2 {
+            return Vector3::zero();
+        }
+        self.squares / (self.count - 1) as f64
+    }
+
+    fn deviation(&self) -> f64 {
+        let variance = self.variance();
+        variance.x.max(variance.y).max(variance.z).sqrt()
+    }
+}
+
+// What the filter starts from. The variances are in the order of the error state.
+#[derive(Debug, Clone, Copy)]
+pub struct InitialState {
+    pub attitude: UnitQuaternion<f64>,
+    pub gyro_bias: Vector3<f64>,
+    pub magnetic_field: Option<Vector3<f64>>,
+    pub gyro_bias_variance: Vector3<f64>,
+    pub attitude_variance: Vector3<f64>,
+    pub position_variance: f64,
+    pub velocity_variance: f64,
+    pub acc_bias_variance: f64,
+    pub magnetic_field_variance: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    Waiting,
+    Averaging,
+}
+
+// Watches the IMU until the vehicle has kept still for a while, then averages the
+// readings. Moving it once averaging has started is an error rather than a restart,
+// so whoever moved it knows to put it down and start again.
+pub struct StationaryInitializer {
+    phase: Phase,
+    started: Option<Timestamp>,
+    window_start: Option<Timestamp>,
+    angular_rate: Statistics,
+    acceleration: Statistics,
+    magnetic_reading: Statistics,
+}
+
+impl StationaryInitializer {
+    pub fn new() -> StationaryInitializer {
+        StationaryInitializer {
+            phase: Phase::Waiting,
+            started: None,
+            window_start: None,
+            angular_rate: Statistics::new(),
+            acceleration: Statistics::new(),
+            magnetic_reading: Statistics::new(),
+        }
+    }
+
+    fn restart_window(&mut self, timestamp: Timestamp) {
+        self.window_start = Some(timestamp);
+        self.angular_rate = Statistics::new();
+        self.acceleration = Statistics::new();
+        self.magnetic_reading = Statistics::new();
+    }
+
+    fn is_stationary(&self) -> bool {
+        self.angular_rate.deviation() < GYROSCOPE_STATIONARY_THRESHOLD
+            && self.acceleration.deviation() < ACCELEROMETER_STATIONARY_THRESHOLD
+            && (self.acceleration.mean.norm() - G_TO_MPSPS).abs() < GRAVITY_TOLERANCE
+    }
+
+    // Returns the initial state once it's done, or an error if the vehicle was moved
+    // or never kept still.
+    pub fn add(
+        &mut self,
+        prediction: &PredictionReading,
+        update: &UpdateReading,
+    ) -> Result<Option<InitialState>, String> {
+        let timestamp = prediction.timestamp;
+        let started = *self.started.get_or_insert(timestamp);
+        let window_start = match self.window_start {
+            Some(window_start) => window_start,
+            None => {
+                self.restart_window(timestamp);
+                timestamp
+            }
+        };
+
+        if self.phase == Phase::Averaging && self.acceleration.count > 0 {
+            let rate_error = (prediction.angular_rate - self.angular_rate.mean).norm();
+            let acceleration_error = (prediction.acceleration - self.acceleration.mean).norm();
+            if rate_error > GYROSCOPE_MOTION_THRESHOLD
+                || acceleration_error > ACCELEROMETER_MOTION_THRESHOLD
+            {
+                return Err(String::from("The vehicle moved during initialization."));
+            }
+        }
+
+        self.angular_rate.add(prediction.angular_rate);
+        self.acceleration.add(prediction.acceleration);
+        match update.magnetic_reading {
+            Some(ref reading) => self.magnetic_reading.add(reading.value),
+            None => {}
+        };
+
+        let elapsed = timestamp.seconds_since(window_start);
+        match self.phase {
+            Phase::Waiting => {
+                if elapsed < STATIONARY_WINDOW_SECONDS {
+                    return Ok(None);
+                }
+                if self.is_stationary() {
+                    // The window is kept, it's already still.
+                    self.phase = Phase::Averaging;
+                } else if timestamp.seconds_since(started) > STATIONARY_TIMEOUT_SECONDS {
+                    return Err(format!(
+                        "The vehicle didn't keep still for {} seconds.",
+                        STATIONARY_TIMEOUT_SECONDS
+                    ));
+                } else {
+                    self.restart_window(timestamp);
+                }
+                Ok(None)
+            }
+            Phase::Averaging => {
+                if elapsed < INITIALIZATION_SECONDS {
+                    return Ok(None);
+                }
+                if !self.is_stationary() {
+                    return Err(String::from("The vehicle moved during initialization."));
+                }
+                Ok(Some(self.initial_state()))
+            }
+        }
+    }
+
+    // Roll and pitch from gravity and yaw from the magnetometer. The attitude takes
+    // the body frame to one with z up and x along magnetic north, or along the nose
+    // if there's no magnetometer.
+    fn initial_state(&self) -> InitialState {
+        let up = self.acceleration.mean.normalize();
+        let magnetic_reading = match self.magnetic_reading.count {
+            0 => None,
+            _ => Some(self.magnetic_reading.mean),
+        };
+        let horizontal = |direction: Vector3<f64>| direction - up * direction.dot(&up);
+        let (north, heading_variance) = match magnetic_reading {
+            Some(reading) if horizontal(reading).norm() > 1e-6 => {
+                (horizontal(reading).normalize(), HEADING_STD * HEADING_STD)
+            }
+            _ => (
+                horizontal(Vector3::x()).normalize(),
+                f64::consts::PI * f64::consts::PI,
+            ),
+        };
+        let west = up.cross(&north);
+
+        // The rows are the new axes in the body frame.
+        #[rustfmt_skip]
+        let rotation = Matrix3::new(north.x, north.y, north.z,
+                                    west.x, west.y, west.z,
+                                    up.x, up.y, up.z);
+        let attitude =
+            UnitQuaternion::from_rotation_matrix(&Rotation3::from_matrix_unchecked(rotation));
+
+        let count = self.acceleration.count as f64;
+        let tilt_variance = TILT_STD * TILT_STD
+            + self.acceleration.variance().norm() / (count * G_TO_MPSPS * G_TO_MPSPS);
+        InitialState {
+            attitude: attitude,
+            gyro_bias: self.angular_rate.mean,
+            magnetic_field: magnetic_reading.map(|reading| (rotation * reading).normalize()),
+            gyro_bias_variance: self.angular_rate.variance() / count,
+            attitude_variance: Vector3::new(tilt_variance, tilt_variance, heading_variance),
+            position_variance: POSITION_STD * POSITION_STD,
+            velocity_variance: VELOCITY_STD * VELOCITY_STD,
+            acc_bias_variance: ACCELEROMETER_BIAS_STD * ACCELEROMETER_BIAS_STD,
+            magnetic_field_variance: MAGNETIC_FIELD_STD * MAGNETIC_FIELD_STD,
+        }
+    }
+}
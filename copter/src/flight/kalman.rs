@@ -12,6 +12,8 @@ use num::traits::Zero;
 
 use hardware::{PredictionReading, UpdateReading};
 use hardware::GPSData;
+use clock::Timestamp;
+use super::initialization::{InitialState, StationaryInitializer};
 
 use logger::ModuleLogger;
 
@@ -56,6 +58,8 @@ pub struct KalmanFilter {
     Q: CovarianceMatrix,
     H_field: MatrixMN<f64, U3, U19>,
     thrust: f64,
+    last_prediction: Option<Timestamp>,
+    last_update: Option<Timestamp>,
 }
 
 impl KalmanFilter {
@@ -116,9 +120,57 @@ impl KalmanFilter {
             Q: F_i * Q_i * F_i.transpose(),
             H_field: MatrixMN::zero(),
             thrust: 0.0,
+            last_prediction: None,
+            last_update: None,
         }
     }
 
+    // Takes readings until the vehicle has been still long enough to start from them.
+    // The readings are used up, so this comes before the first predict.
+    pub fn initialize(&mut self, initializer: &mut StationaryInitializer) -> Result<(), String> {
+        loop {
+            let (prediction, update) = match (self.prediction_rx.recv(), self.update_rx.recv()) {
+                (Ok(prediction), Ok(update)) => (prediction, update),
+                _ => return Err(String::from("The hardware stopped sending readings.")),
+            };
+            match initializer.add(&prediction, &update)? {
+                Some(initial) => {
+                    self.seed(&initial);
+                    self.last_prediction = Some(prediction.timestamp);
+                    self.last_update = Some(update.timestamp);
+                    self.u_p = PredictionReading {
+                        angular_rate: prediction.angular_rate - initial.gyro_bias,
+                        ..prediction
+                    };
+                    return Ok(());
+                }
+                None => {}
+            };
+        }
+    }
+
+    fn seed(&mut self, initial: &InitialState) {
+        self.x.attitude = initial.attitude;
+        self.x.gyro_bias = initial.gyro_bias;
+        self.x.acc_bias = Vector3::zero();
+        self.x.velocity = Vector3::zero();
+        match initial.magnetic_field {
+            Some(field) => self.x.magnetic_field = field,
+            None => {}
+        };
+
+        let mut P = CovarianceMatrix::zero();
+        for i in 0..3 {
+            P[(i, i)] = initial.position_variance;
+            P[(3 + i, 3 + i)] = initial.velocity_variance;
+            P[(6 + i, 6 + i)] = initial.attitude_variance[i];
+            P[(9 + i, 9 + i)] = initial.acc_bias_variance;
+            P[(12 + i, 12 + i)] = initial.gyro_bias_variance[i];
+            P[(15 + i, 15 + i)] = initial.magnetic_field_variance;
+        }
+        self.P = P;
+    }
+
     fn update_state_jacobian(&mut self) {
         let q: &Vector4<f64> = &self.x.attitude.coords;
 
@@ -152,9 +204,21 @@ impl KalmanFilter {
         UnitQuaternion::from_axis_angle(&Unit::new_normalize(w), w.norm())
     }
 
-    pub fn predict(&mut self, dt: f64) {
+    // dt is the time between samples, not between calls, so a late control loop
+    // doesn't distort the integration.
+    fn elapsed_seconds(last: &mut Option<Timestamp>, timestamp: Timestamp) -> f64 {
+        let dt = match *last {
+            Some(last) => timestamp.seconds_since(last),
+            None => 0.0,
+        };
+        *last = Some(timestamp);
+        dt
+    }
+
+    pub fn predict(&mut self) {
         // let current_state = self.get_state();
         let mut u = self.prediction_rx.recv().unwrap();
+        let dt = KalmanFilter::elapsed_seconds(&mut self.last_prediction, u.timestamp);
         u.angular_rate -= self.x.gyro_bias;
         u.acceleration -= self.x.acc_bias;
 
@@ -322,6 +386,10 @@ impl KalmanFilter {
     fn update_magnetometer(&mut self, magnetic_reading: Vector3<f64>) {}
 
     fn update_gps(&mut self, gps_measurement: GPSData) {
+        // Reports without a fix only carry satellites and DOP.
+        if !gps_measurement.has_position() {
+            return;
+        }
         let mut ned_measurement = Vector3::new(
             gps_measurement.latitude,
             gps_measurement.longitude,
@@ -354,8 +422,11 @@ impl KalmanFilter {
 
     // fn update_gps(&mut self, dt: f32) {}
 
-    pub fn update(&mut self, dt: f64) {
+    // Vibration isn't part of the state. It is handed back for telemetry.
+    // Returns the reading so the control loop gets the telemetry the filter doesn't use.
+    pub fn update(&mut self) -> UpdateReading {
         let update = self.update_rx.recv().unwrap();
+        let dt = KalmanFilter::elapsed_seconds(&mut self.last_update, update.timestamp);
 
         self.update_accelerometer(update.acceleration, dt);
         // if update.magnetic_reading.is_some() {
@@ -366,6 +437,8 @@ impl KalmanFilter {
         // if update.gps_information.is_some() {
         //     self.update_gps(dt);
         // }
+
+        update
     }
 
     // pub fn update_motors(&mut self, m1: f32, m2: f32, m3: f32, m4: f32) {}
@@ -1,21 +1,25 @@
 // mod pid;
 mod navigation;
 mod kalman;
+mod initialization;
 
 // use self::altitude::Altitude;
 // use self::pid::PID;
 // use self::imu::{Attitude, IMU};
 // use self::navigation::{lat_lon_bearing, lat_lon_distance, Destination, Navigator};
 use self::kalman::{KalmanFilter, State};
+use self::initialization::StationaryInitializer;
 use hardware::{MotorCommand, PredictionReading, UpdateReading};
 
 use na::geometry::{Quaternion, UnitQuaternion};
 use na::Vector3;
 
-use configurations::Config;
+use clock::SharedClock;
+use configurations::{Config, ParameterStore};
+use configurations::parameters::{Axis, Gains};
 use logger::ModuleLogger;
 use debug_server::{DebugInfo, Logger, Signal};
-use time::{Duration, PreciseTime};
+use time::Duration;
 
 use std::thread;
 use std::thread::{sleep, Builder};
@@ -26,8 +30,6 @@ use std::fmt;
 use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
 use std::io::prelude::*;
 
-const MICROSECONDS_PER_SECOND: f64 = 1000000.0;
-
 #[derive(Clone, Copy)]
 pub enum FlightMode {
     Shutdown,
@@ -40,48 +42,104 @@ pub enum FlightMode {
 
 
 pub fn start_flight_controller(
+    config: &Config,
     pred_rx: Receiver<PredictionReading>,
     update_rx: Receiver<UpdateReading>,
     motor_tx: Sender<MotorCommand>,
-) {
+    parameters: ParameterStore,
+    clock: SharedClock,
+) -> Sender<FlightMode> {
     let logger = ModuleLogger::new("Flight", None);
     logger.log("Initializing flight controller.");
 
     let (mode_tx, mode_rx): (Sender<FlightMode>, Receiver<FlightMode>) = channel();
     let mut kalman_filter = KalmanFilter::new(pred_rx, update_rx);
+    // Telemetry goes to the server.
+    let addr = format!(
+        "{}:{}",
+        config.networking.server_ip, config.networking.server_port
+    );
 
     Builder::new()
         .name(String::from("Control thread"))
         .spawn(move || {
-            control_loop(kalman_filter, motor_tx, mode_rx);
-        });
+            control_loop(kalman_filter, motor_tx, mode_rx, parameters, addr, clock);
+        })
+        .unwrap();
+    mode_tx
 }
 
 fn control_loop(
     mut kalman_filter: KalmanFilter,
     motor_tx: Sender<MotorCommand>,
     mode_rx: Receiver<FlightMode>,
+    parameters: ParameterStore,
+    addr: String,
+    clock: SharedClock,
 ) {
     let logger = ModuleLogger::new("Flight", None);
-    let config = Config::new().unwrap();
-    let addr = format!(
-        "{}:{}",
-        config.networking.server_ip, config.networking.server_port
-    );
     let local = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0);
     let mut client = UdpSocket::bind(local).unwrap();
 
     logger.log("Control loop started.");
-    let mut prev_time = PreciseTime::now();
-    sleep(Duration::milliseconds(10).to_std().unwrap());
+    clock.sleep(Duration::milliseconds(10).to_std().unwrap());
+
+    // The motors are only armed if the filter started from a stationary vehicle.
+    logger.log("Initializing. Keep the vehicle still.");
+    let initialized = match kalman_filter.initialize(&mut StationaryInitializer::new()) {
+        Ok(()) => match motor_tx.send(MotorCommand::Initialized) {
+            Ok(()) => {
+                logger.success("Initialized. Ready to arm.");
+                true
+            }
+            Err(_) => {
+                logger.error("The motor manager has stopped. Refusing to arm.");
+                false
+            }
+        },
+        Err(e) => {
+            logger.error(&format!("{} Refusing to arm.", e));
+            false
+        }
+    };
+
+    // The gains are picked up between iterations when they're tuned.
+    let gain_changes = parameters.subscribe("flight.");
+    let mut gains = attitude_gains(&parameters);
+    send_telemetry(&client, &addr, &gains_message(&gains), &logger);
+
     let mut count = 0;
     'control: loop {
-        let current_time = PreciseTime::now();
-        let diff = prev_time.to(current_time);
-        let dt = (diff.num_microseconds().unwrap() as f64) / MICROSECONDS_PER_SECOND;
-        kalman_filter.predict(dt);
-        kalman_filter.update(dt);
-        prev_time = current_time;
+        let mut tuned = false;
+        while let Ok(change) = gain_changes.try_recv() {
+            logger.log(&format!("{} set to {}.", change.name, change.value));
+            tuned = true;
+        }
+        if tuned {
+            gains = attitude_gains(&parameters);
+            send_telemetry(&client, &addr, &gains_message(&gains), &logger);
+        }
+
+        match mode_rx.try_recv() {
+            Ok(FlightMode::Shutdown) => {
+                motor_tx.send(MotorCommand::PowerDown);
+                break 'control;
+            }
+            Ok(FlightMode::Off) => {
+                motor_tx.send(MotorCommand::PowerDown);
+            }
+            Ok(_) if !initialized => {
+                logger.error("Initialization failed. Refusing to arm. Restart with the vehicle still.");
+            }
+            Ok(_) => {
+                motor_tx.send(MotorCommand::Arm);
+            }
+            Err(_) => {}
+        };
+
+        // The filter takes its time step from the sample timestamps.
+        kalman_filter.predict();
+        let update = kalman_filter.update();
 
         println!("{:?}", kalman_filter.x);
 
@@ -93,11 +151,78 @@ fn control_loop(
                 "{{ \"position\": [{}, {}, {}], \"attitude\": [{}, {}, {}, {}] }}",
                 0.0, 0.0, 0.0, att_vec.data[0], att_vec.data[1], att_vec.data[2], att_vec.data[3]
             );
-            client.send_to(msg.as_bytes(), &addr).unwrap();
+            send_telemetry(&client, &addr, &msg, &logger);
         }
+
+        match update.vibration {
+            Some(report) => {
+                let msg: String = format!(
+                    "{{ \"vibration\": {{ \"gyro_rms\": [{}, {}, {}], \"gyro_peak_hz\": [{}, {}, {}], \"accel_rms\": [{}, {}, {}], \"accel_peak_hz\": [{}, {}, {}], \"accel_clipping\": {} }} }}",
+                    report.gyroscope[0].rms,
+                    report.gyroscope[1].rms,
+                    report.gyroscope[2].rms,
+                    report.gyroscope[0].peak.frequency,
+                    report.gyroscope[1].peak.frequency,
+                    report.gyroscope[2].peak.frequency,
+                    report.accelerometer[0].rms,
+                    report.accelerometer[1].rms,
+                    report.accelerometer[2].rms,
+                    report.accelerometer[0].peak.frequency,
+                    report.accelerometer[1].peak.frequency,
+                    report.accelerometer[2].peak.frequency,
+                    report.accelerometer_clipping
+                );
+                send_telemetry(&client, &addr, &msg, &logger);
+            }
+            None => {}
+        };
+
+        match update.battery {
+            Some(battery) => {
+                let battery = battery.value;
+                let msg: String = format!(
+                    "{{ \"battery\": {{ \"voltage\": {}, \"cell_voltage\": {}, \"current\": {}, \"consumed_mah\": {}, \"remaining\": {}, \"status\": \"{:?}\" }} }}",
+                    battery.voltage,
+                    battery.cell_voltage,
+                    battery.current.unwrap_or(0.0),
+                    battery.consumed_mah.unwrap_or(0.0),
+                    battery.remaining,
+                    battery.status
+                );
+                send_telemetry(&client, &addr, &msg, &logger);
+            }
+            None => {}
+        };
         count += 1;
     }
 }
+
+// Telemetry is best effort, losing some mustn't stop the control loop.
+fn send_telemetry(client: &UdpSocket, addr: &str, msg: &str, logger: &ModuleLogger) {
+    match client.send_to(msg.as_bytes(), addr) {
+        Ok(_) => {}
+        Err(e) => logger.error(&format!("Couldn't send telemetry to {}: {}", addr, e)),
+    };
+}
+
+// Roll, pitch and yaw.
+fn attitude_gains(parameters: &ParameterStore) -> [Gains; 3] {
+    [
+        parameters.gains(Axis::Roll),
+        parameters.gains(Axis::Pitch),
+        parameters.gains(Axis::Yaw),
+    ]
+}
+
+fn gains_message(gains: &[Gains; 3]) -> String {
+    format!(
+        "{{ \"gains\": {{ \"roll\": [{}, {}, {}], \"pitch\": [{}, {}, {}], \"yaw\": [{}, {}, {}] }} }}",
+        gains[0].p, gains[0].i, gains[0].d,
+        gains[1].p, gains[1].i, gains[1].d,
+        gains[2].p, gains[2].i, gains[2].d
+    )
+}
+
 // pub fn start_flight() -> (Sender<FlightMode>, thread::JoinHandle<()>) {
 //     let (mode_tx, mode_rx): (Sender<FlightMode>, Receiver<FlightMode>) = channel();
 
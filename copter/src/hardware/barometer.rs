@@ -1,9 +1,11 @@
 use i2cdev_bmp180::*;
 use i2cdev_bmp280::*;
+use i2cdev::core::I2CDevice;
 use i2cdev::linux::{LinuxI2CDevice, LinuxI2CError};
-use i2csensors::Barometer;
+use i2csensors::{Barometer, Thermometer};
 
 use configurations::Config;
+use configurations::config::{Oversampling, SerialCommunication, Sensor};
 use logger::ModuleLogger;
 
 use std::rc::Rc;
@@ -12,37 +14,64 @@ use std::thread::sleep;
 use std::time::Duration;
 
 use super::mock::MockSensor;
+use super::bus::{get_i2c_device, get_spi_device};
+use super::spi::SpiProtocol;
+
+const BMP180_ADDRESS: u16 = 0x77;
+const BMP280_ADDRESS: u16 = 0x77;
 
 pub struct BarometerThermometer {
     barometer: Rc<RefCell<Barometer<Error = LinuxI2CError>>>,
+    thermometer: Rc<RefCell<Thermometer<Error = LinuxI2CError>>>,
     logger: ModuleLogger,
 }
 
 impl BarometerThermometer {
-    pub fn new() -> Result<BarometerThermometer, ()> {
-        let config = Config::new().unwrap();
+    pub fn new(config: &Config) -> Result<BarometerThermometer, ()> {
         let logger = ModuleLogger::new("Barometer", None);
 
         let mut barometer: Option<Rc<RefCell<Barometer<Error = LinuxI2CError>>>> = None;
+        let mut thermometer: Option<Rc<RefCell<Thermometer<Error = LinuxI2CError>>>> = None;
 
         #[cfg(not(target_arch = "arm"))]
         {
             logger.log("Initializing mock barometer.");
             let mock_sensor = Rc::new(RefCell::new(MockSensor::new()));
             barometer = Some(mock_sensor.clone());
+            thermometer = Some(mock_sensor.clone());
         }
 
         #[cfg(target_arch = "arm")]
         match config.hardware.barometer.name.as_ref() {
             "BMP180" => {
-                logger.error("BMP180 not implemented yet.");
-                return Err(());
+                logger.log("Initializing BMP180 barometer.");
+                match get_bmp180(&config.hardware.barometer) {
+                    Ok(bmp180) => {
+                        let bmp180_ref = Rc::new(RefCell::new(bmp180));
+                        barometer = Some(bmp180_ref.clone());
+                        thermometer = Some(bmp180_ref.clone());
+                    }
+                    Err(_) => {
+                        logger.error("Couldn't initialize BMP180. Check your hardware connection and you configuration file.");
+                        return Err(());
+                    }
+                }
             }
             "BMP280" => {
                 logger.log("Initializing BMP280 barometer.");
-                match get_bmp280() {
-                    Ok(bmp280) => {
-                        barometer = Some(Rc::new(RefCell::new(bmp280)));
+                let sensor = &config.hardware.barometer;
+                let bmp280 = match sensor.serial {
+                    SerialCommunication::SPI => get_spi_device(sensor, SpiProtocol::bmp280())
+                        .and_then(get_bmp280)
+                        .map(share_sensors),
+                    _ => get_i2c_device(sensor, BMP280_ADDRESS)
+                        .and_then(get_bmp280)
+                        .map(share_sensors),
+                };
+                match bmp280 {
+                    Ok((bmp280_baro, bmp280_thermo)) => {
+                        barometer = Some(bmp280_baro);
+                        thermometer = Some(bmp280_thermo);
                     }
                     Err(_) => {
                         logger.error("Couldn't initialize BMP280. Check your hardware connection and you configuration file.");
@@ -58,6 +87,7 @@ impl BarometerThermometer {
 
         let manager = BarometerThermometer {
             barometer: barometer.unwrap(),
+            thermometer: thermometer.unwrap(),
             logger: logger,
         };
 
@@ -71,6 +101,16 @@ impl BarometerThermometer {
             }
         }
 
+        sleep(Duration::from_millis(50));
+        match manager.thermometer.borrow_mut().temperature_celsius() {
+            Ok(_) => {
+                &manager.logger.log("Thermometer check.");
+            }
+            Err(_) => {
+                &manager.logger.error("Thermometer failed to read.");
+            }
+        }
+
         Ok(manager)
     }
 
@@ -83,16 +123,54 @@ impl BarometerThermometer {
             }
         }
     }
+
+    pub fn read_temperature(&mut self) -> f32 {
+        match self.thermometer.borrow_mut().temperature_celsius() {
+            Ok(temperature) => temperature,
+            Err(e) => {
+                self.logger.error("Couldn't read thermometer.");
+                panic!(e.to_string());
+            }
+        }
+    }
 }
 
+fn share_sensors<S>(
+    sensor: S,
+) -> (
+    Rc<RefCell<Barometer<Error = LinuxI2CError>>>,
+    Rc<RefCell<Thermometer<Error = LinuxI2CError>>>,
+)
+where
+    S: Barometer<Error = LinuxI2CError> + Thermometer<Error = LinuxI2CError> + 'static,
+{
+    let sensor_ref = Rc::new(RefCell::new(sensor));
+    (sensor_ref.clone(), sensor_ref.clone())
+}
+
+// The BMP180 has no SPI interface.
 #[cfg(target_arch = "arm")]
-fn get_bmp180() -> Option<BMP180BarometerThermometer<LinuxI2CDevice>> {
-    // Left for someone who owns a bmp180
-    None
+fn get_bmp180(sensor: &Sensor) -> Result<BMP180BarometerThermometer<LinuxI2CDevice>, ()> {
+    // More oversampling means less noise but a longer conversion time.
+    // Ultra high resolution takes 25.5 ms per pressure reading.
+    let pressure_mode = match sensor.oversampling {
+        Some(Oversampling::UltraLowPower) => BMP180PressureMode::BMP180UltraLowPower,
+        Some(Oversampling::Standard) | None => BMP180PressureMode::BMP180Standard,
+        Some(Oversampling::HighResolution) => BMP180PressureMode::BMP180HighResolution,
+        Some(Oversampling::UltraHighResolution) => {
+            BMP180PressureMode::BMP180UltraHighResolution
+        }
+    };
+
+    let baro = get_i2c_device(sensor, BMP180_ADDRESS)?;
+    match BMP180BarometerThermometer::new(baro, pressure_mode) {
+        Ok(bmp180) => Ok(bmp180),
+        Err(_) => Err(()),
+    }
 }
 
 #[cfg(target_arch = "arm")]
-fn get_bmp280() -> Result<BMP280<LinuxI2CDevice>, ()> {
+fn get_bmp280<T: I2CDevice<Error = LinuxI2CError> + Sized>(baro: T) -> Result<BMP280<T>, ()> {
     let settings = BMP280Settings {
         compensation: BMP280CompensationAlgorithm::B64,
         t_sb: BMP280Timing::ms0_5,
@@ -102,7 +180,6 @@ fn get_bmp280() -> Result<BMP280<LinuxI2CDevice>, ()> {
         power_mode: BMP280PowerMode::NormalMode,
     };
 
-    let baro = get_linux_bmp280_i2c_device().unwrap();
     match BMP280::new(baro, settings) {
         Ok(bmp280) => Ok(bmp280),
         Err(_) => Err(()),
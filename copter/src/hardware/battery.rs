@@ -1,71 +1,382 @@
+use std::fmt;
+
+use clock::Timestamp;
 use configurations::Config;
+use configurations::config::{Battery, DEFAULT_I2C_BUS};
 use logger::ModuleLogger;
 
 use ads111x::*;
 
+use super::bus::get_i2c_device;
+
+// Highest per cell voltage when detecting the cell count, so a charged HV cell
+// isn't counted twice.
+const MAX_CELL_VOLTAGE: f64 = 4.35;
+// Below this there's no battery to detect, e.g. when powered over USB.
+const MIN_DETECTION_VOLTAGE: f64 = 2.0;
+const VOLTAGE_TIME_CONSTANT: f64 = 2.0;
+const CURRENT_TIME_CONSTANT: f64 = 0.5;
+const MAX_ADS111X_CHANNEL: u8 = 3;
+
+// Resting LiPo cell voltage against state of charge.
+const LIPO_DISCHARGE_CURVE: [(f64, f64); 12] = [
+    (3.30, 0.00),
+    (3.50, 0.05),
+    (3.68, 0.10),
+    (3.74, 0.20),
+    (3.77, 0.30),
+    (3.79, 0.40),
+    (3.83, 0.50),
+    (3.87, 0.60),
+    (3.92, 0.70),
+    (3.97, 0.80),
+    (4.06, 0.90),
+    (4.20, 1.00),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 pub enum BatteryStatus {
     Full,
     Low,
     Critical,
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct BatteryReading {
+    pub voltage: f64,              // Filtered pack voltage
+    pub cell_voltage: f64,         // Filtered and sag compensated, per cell
+    pub cells: u32,
+    pub current: Option<f64>,      // Amps
+    pub consumed_mah: Option<f64>,
+    pub remaining: f64,            // Estimated fraction of capacity left
+    pub status: BatteryStatus,
+}
+
+impl fmt::Display for BatteryReading {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "battery, voltage={:.3}, cell_voltage={:.3}, cells={}, remaining={:.3}, status={:?}",
+            self.voltage, self.cell_voltage, self.cells, self.remaining, self.status
+        )?;
+        match self.current {
+            Some(current) => write!(f, ", current={:.3}", current)?,
+            None => {}
+        };
+        match self.consumed_mah {
+            Some(consumed) => write!(f, ", consumed_mah={:.1}", consumed),
+            None => Ok(()),
+        }
+    }
+}
+
+// State of charge from the resting cell voltage.
+pub fn charge_from_cell_voltage(cell_voltage: f64) -> f64 {
+    let curve = &LIPO_DISCHARGE_CURVE;
+    if cell_voltage <= curve[0].0 {
+        return 0.0;
+    }
+    for pair in curve.windows(2) {
+        if cell_voltage <= pair[1].0 {
+            let fraction = (cell_voltage - pair[0].0) / (pair[1].0 - pair[0].0);
+            return pair[0].1 + fraction * (pair[1].1 - pair[0].1);
+        }
+    }
+    1.0
+}
+
+// First order low pass that follows irregular sample times.
+fn smooth(previous: Option<f64>, value: f64, dt: f64, time_constant: f64) -> f64 {
+    match previous {
+        Some(previous) => previous + (value - previous) * (dt / (time_constant + dt)),
+        None => value,
+    }
+}
+
+// Turns raw pack voltage and current samples into a battery reading. Separate from
+// the ADC so it works the same with recorded samples.
+pub struct BatteryEstimator {
+    cells: Option<u32>,
+    warning_voltage: f64,
+    critical_voltage: f64,
+    capacity_mah: Option<f64>,
+    internal_resistance: Option<f64>,
+    voltage: Option<f64>,
+    current: Option<f64>,
+    consumed_mah: f64,
+    initial_charge: Option<f64>,
+    last_sample: Option<Timestamp>,
+    status: BatteryStatus,
+}
+
+impl BatteryEstimator {
+    pub fn new(config: &Battery) -> BatteryEstimator {
+        BatteryEstimator {
+            cells: if config.cells > 0 {
+                Some(config.cells as u32)
+            } else {
+                None
+            },
+            warning_voltage: config.warning_voltage as f64,
+            critical_voltage: config.critical_voltage as f64,
+            capacity_mah: match config.capacity_mah {
+                Some(capacity) if capacity > 0.0 => Some(capacity as f64),
+                _ => None,
+            },
+            internal_resistance: config.internal_resistance.map(|resistance| resistance as f64),
+            voltage: None,
+            current: None,
+            consumed_mah: 0.0,
+            initial_charge: None,
+            last_sample: None,
+            status: BatteryStatus::Full,
+        }
+    }
+
+    pub fn cells(&self) -> Option<u32> {
+        self.cells
+    }
+
+    // None until the cell count is known.
+    pub fn add_sample(
+        &mut self,
+        voltage: f64,
+        current: Option<f64>,
+        timestamp: Timestamp,
+    ) -> Option<BatteryReading> {
+        let cells = match self.cells {
+            Some(cells) => cells,
+            None if voltage >= MIN_DETECTION_VOLTAGE => {
+                let cells = (voltage / MAX_CELL_VOLTAGE).ceil() as u32;
+                self.cells = Some(cells);
+                cells
+            }
+            None => return None,
+        };
+
+        let dt = match self.last_sample {
+            Some(last) => timestamp.seconds_since(last),
+            None => 0.0,
+        };
+        self.last_sample = Some(timestamp);
+
+        let filtered_voltage = smooth(self.voltage, voltage, dt, VOLTAGE_TIME_CONSTANT);
+        self.voltage = Some(filtered_voltage);
+        let filtered_current = match current {
+            Some(current) => {
+                // mAh is integrated from the raw current so short spikes still count.
+                self.consumed_mah += current.max(0.0) * dt * 1000.0 / 3600.0;
+                let filtered = smooth(self.current, current, dt, CURRENT_TIME_CONSTANT);
+                self.current = Some(filtered);
+                Some(filtered)
+            }
+            None => None,
+        };
+
+        // The voltage the pack would rest at without the load.
+        let sag = match (filtered_current, self.internal_resistance) {
+            (Some(current), Some(resistance)) => current.max(0.0) * resistance,
+            _ => 0.0,
+        };
+        let cell_voltage = (filtered_voltage + sag) / cells as f64;
+
+        let voltage_charge = charge_from_cell_voltage(cell_voltage);
+        let remaining = match (filtered_current, self.capacity_mah) {
+            (Some(_), Some(capacity)) => {
+                // Coulomb counting from the charge the battery started with.
+                let initial = *self.initial_charge.get_or_insert(voltage_charge);
+                (initial - self.consumed_mah / capacity).max(0.0)
+            }
+            _ => voltage_charge,
+        };
+
+        // Voltage recovers when the throttle drops, so a worse status is kept.
+        let status = if cell_voltage < self.critical_voltage {
+            BatteryStatus::Critical
+        } else if cell_voltage < self.warning_voltage {
+            BatteryStatus::Low
+        } else {
+            BatteryStatus::Full
+        };
+        if status > self.status {
+            self.status = status;
+        }
+
+        Some(BatteryReading {
+            voltage: filtered_voltage,
+            cell_voltage: cell_voltage,
+            cells: cells,
+            current: filtered_current,
+            consumed_mah: filtered_current.map(|_| self.consumed_mah),
+            remaining: remaining,
+            status: self.status,
+        })
+    }
+}
+
+fn multiplexer_config(channel: u8) -> Result<ADS1115MultiplexerConfig, ()> {
+    match channel {
+        0 => Ok(ADS1115MultiplexerConfig::AIN0_GND),
+        1 => Ok(ADS1115MultiplexerConfig::AIN1_GND),
+        2 => Ok(ADS1115MultiplexerConfig::AIN2_GND),
+        3 => Ok(ADS1115MultiplexerConfig::AIN3_GND),
+        _ => Err(()),
+    }
+}
+
+// Each input gets its own handle since the multiplexer is part of the ADS111X config.
+fn open_converter(logger: &ModuleLogger, config: &Config, channel: u8) -> Result<ADS111X, ()> {
+    let multiplexer_config = match multiplexer_config(channel) {
+        Ok(multiplexer_config) => multiplexer_config,
+        Err(()) => {
+            logger.error(&format!(
+                "ADS111X channel {} doesn't exist. Use 0 to {}.",
+                channel, MAX_ADS111X_CHANNEL
+            ));
+            return Err(());
+        }
+    };
+    let ads1115_config = ADS111XConfig {
+        multiplexer_config: multiplexer_config,
+        gain_amplifier: ADS11145GainAmplifier::FS_6_144V,
+        operating_mode: ADS111XOperatingMode::SingleShot,
+        data_rate: ADS111XDataRate::DR_128SPS,
+    };
+
+    let device = match config.hardware.analog_converter {
+        Some(ref converter) => get_i2c_device(converter, DEFAULT_ADS1115_SLAVE_ADDRESS)?,
+        None => match LinuxI2CDevice::new(DEFAULT_I2C_BUS, DEFAULT_ADS1115_SLAVE_ADDRESS) {
+            Ok(device) => device,
+            Err(_) => {
+                logger.error("Couldn't open the analog to digital converter.");
+                return Err(());
+            }
+        },
+    };
+    match ADS111X::new(device, ads1115_config) {
+        Ok(analog_to_digital_converter) => Ok(analog_to_digital_converter),
+        Err(_) => {
+            logger.error(&format!(
+                "Couldn't start the analog to digital device on channel {}.",
+                channel
+            ));
+            Err(())
+        }
+    }
+}
+
 pub struct BatteryMonitor {
-    monitor: ADS111X,
-    warning_voltage: f32,
-    critical_voltage: f32,
+    voltage_monitor: ADS111X,
+    current_monitor: Option<ADS111X>,
+    divider_ratio: f32,
+    current_scale: f32,
+    current_offset: f32,
+    estimator: BatteryEstimator,
     logger: ModuleLogger,
 }
 
 impl BatteryMonitor {
-    pub fn new() -> Result<BatteryMonitor, ()> {
+    pub fn new(config: &Config) -> Result<BatteryMonitor, ()> {
         let logger = ModuleLogger::new("Battery", None);
-        let config = Config::new().unwrap();
+        let battery = &config.hardware.battery;
 
         logger.log("Initializing analog to digital converter.");
 
-        let ads1115_config = ADS111XConfig {
-            multiplexer_config: ADS1115MultiplexerConfig::AIN0_GND,
-            gain_amplifier: ADS11145GainAmplifier::FS_6_144V,
-            operating_mode: ADS111XOperatingMode::SingleShot,
-            data_rate: ADS111XDataRate::DR_128SPS,
+        let divider_ratio = match battery.divider_ratio {
+            Some(ratio) if ratio <= 0.0 => {
+                logger.error("Battery divider_ratio must be positive.");
+                return Err(());
+            }
+            Some(ratio) => ratio,
+            None => 1.0,
         };
+        let voltage_channel = match battery.voltage_channel {
+            Some(channel) => channel,
+            None => 0,
+        };
+        let voltage_monitor = open_converter(&logger, config, voltage_channel)?;
 
-        let device = LinuxI2CDevice::new("/dev/i2c-1", DEFAULT_ADS1115_SLAVE_ADDRESS).unwrap();
-        let ads1115 = match ADS111X::new(device, ads1115_config) {
-            Ok(analog_to_digital_converter) => analog_to_digital_converter,
-            Err(_) => {
-                logger.error(
-                    "Couldn't start the analog to digital device. Can't monitor battery voltage.",
-                );
+        let current_monitor = match (battery.current_channel, battery.current_scale) {
+            (Some(channel), Some(_)) if channel == voltage_channel => {
+                logger.error("The current sensor needs a different channel from the voltage.");
                 return Err(());
             }
+            (Some(channel), Some(_)) => Some(open_converter(&logger, config, channel)?),
+            (Some(_), None) => {
+                logger.error("current_channel needs a current_scale in amps per volt.");
+                return Err(());
+            }
+            (None, _) => None,
         };
 
-        let warning_voltage =
-            (config.hardware.battery.cells as f32) * config.hardware.battery.warning_voltage;
-        let critical_voltage =
-            (config.hardware.battery.cells as f32) * config.hardware.battery.critical_voltage;
-
         Ok(BatteryMonitor {
-            monitor: ads1115,
-            warning_voltage: warning_voltage,
-            critical_voltage: critical_voltage,
+            voltage_monitor: voltage_monitor,
+            current_monitor: current_monitor,
+            divider_ratio: divider_ratio,
+            current_scale: battery.current_scale.unwrap_or(0.0),
+            current_offset: battery.current_offset.unwrap_or(0.0),
+            estimator: BatteryEstimator::new(battery),
             logger: logger,
         })
     }
 
-    pub fn check_battery(&mut self) -> Result<BatteryStatus, ()> {
-        match self.monitor.read_voltage() {
-            Ok(voltage) => {
-                if voltage < self.critical_voltage {
-                    return Ok(BatteryStatus::Critical);
-                } else if voltage < self.warning_voltage {
-                    return Ok(BatteryStatus::Low);
-                } else {
-                    return Ok(BatteryStatus::Full);
-                }
+    // Pack voltage, before filtering.
+    pub fn read_voltage(&mut self) -> Result<f32, ()> {
+        match self.voltage_monitor.read_voltage() {
+            Ok(voltage) => Ok(voltage * self.divider_ratio),
+            Err(_) => {
+                self.logger.error("Couldn't read the battery voltage.");
+                Err(())
             }
-            Err(_) => Err(()),
         }
     }
+
+    // Amps, before filtering. None without a current sensor.
+    pub fn read_current(&mut self) -> Result<Option<f32>, ()> {
+        let reading = match self.current_monitor {
+            Some(ref mut monitor) => monitor.read_voltage(),
+            None => return Ok(None),
+        };
+        match reading {
+            Ok(voltage) => Ok(Some((voltage - self.current_offset) * self.current_scale)),
+            Err(_) => {
+                self.logger.error("Couldn't read the battery current.");
+                Err(())
+            }
+        }
+    }
+
+    // Samples the ADC. None until the cell count is known.
+    pub fn update(&mut self, timestamp: Timestamp) -> Result<Option<BatteryReading>, ()> {
+        let voltage = self.read_voltage()?;
+        let current = self.read_current()?.map(|current| current as f64);
+        let detected = self.estimator.cells().is_some();
+        let previous_status = self.estimator.status;
+
+        let reading = match self.estimator.add_sample(voltage as f64, current, timestamp) {
+            Some(reading) => reading,
+            None => return Ok(None),
+        };
+
+        if !detected {
+            self.logger.log(&format!(
+                "Detected a {} cell battery at {:.2} V.",
+                reading.cells, voltage
+            ));
+        }
+        if reading.status != previous_status {
+            match reading.status {
+                BatteryStatus::Low => self.logger.error(&format!(
+                    "Battery low: {:.2} V per cell.",
+                    reading.cell_voltage
+                )),
+                BatteryStatus::Critical => self.logger.error(&format!(
+                    "Battery critical: {:.2} V per cell. Land now.",
+                    reading.cell_voltage
+                )),
+                BatteryStatus::Full => {}
+            };
+        }
+        Ok(Some(reading))
+    }
 }
@@ -0,0 +1,314 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::time::Duration;
+
+use time;
+
+use clock::{SharedClock, Timestamp};
+use configurations::config::{FULL_POWER, IDLE_POWER};
+use logger::ModuleLogger;
+
+use super::battery::BatteryMonitor;
+use super::motors::MotorManager;
+use super::safety::SafeMotorManager;
+
+// Commands are repeated while a throttle is held so the safety timeout doesn't trip.
+const COMMAND_PERIOD_MS: u64 = 20;
+const SAMPLE_PERIOD_MS: u64 = 100;
+const PAUSE_BETWEEN_MOTORS_MS: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BenchTest {
+    // Motors are numbered from 1 in the order of hardware.motors.pins.
+    Spin {
+        motor: usize,
+        power: f64,
+        seconds: f64,
+    },
+    Order {
+        power: f64,
+        seconds: f64,
+    },
+    Sweep {
+        motor: usize,
+        from: f64,
+        to: f64,
+        step: f64,
+        seconds: f64,
+    },
+}
+
+pub const USAGE: &str = "Bench commands:
+  spin <motor> <power> <seconds>
+  order <power> <seconds>
+  sweep <motor> <from> <to> <step> <seconds per step>
+  quit
+Powers are 1000 to 2000.";
+
+fn parse_numbers(words: &[&str]) -> Option<Vec<f64>> {
+    let mut numbers = Vec::new();
+    for word in words {
+        match word.parse::<f64>() {
+            Ok(number) => numbers.push(number),
+            Err(_) => return None,
+        }
+    }
+    Some(numbers)
+}
+
+fn valid_power(power: f64) -> bool {
+    power >= IDLE_POWER && power <= FULL_POWER
+}
+
+impl BenchTest {
+    // None if the line isn't a valid command for the given number of motors.
+    pub fn parse(line: &str, motors: usize) -> Option<BenchTest> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            return None;
+        }
+        let numbers = parse_numbers(&words[1..])?;
+        let valid_motor = |motor: f64| motor >= 1.0 && motor as usize <= motors;
+
+        let test = match (words[0], numbers.len()) {
+            ("spin", 3) if valid_motor(numbers[0]) => BenchTest::Spin {
+                motor: numbers[0] as usize - 1,
+                power: numbers[1],
+                seconds: numbers[2],
+            },
+            ("order", 2) => BenchTest::Order {
+                power: numbers[0],
+                seconds: numbers[1],
+            },
+            ("sweep", 5) if valid_motor(numbers[0]) && numbers[3] > 0.0 => BenchTest::Sweep {
+                motor: numbers[0] as usize - 1,
+                from: numbers[1],
+                to: numbers[2],
+                step: numbers[3],
+                seconds: numbers[4],
+            },
+            _ => return None,
+        };
+
+        let valid = match test {
+            BenchTest::Spin { power, seconds, .. } | BenchTest::Order { power, seconds } => {
+                valid_power(power) && seconds > 0.0
+            }
+            BenchTest::Sweep {
+                from, to, seconds, ..
+            } => valid_power(from) && valid_power(to) && seconds > 0.0,
+        };
+        if valid {
+            Some(test)
+        } else {
+            None
+        }
+    }
+}
+
+// time, motor, pin, command, voltage, current
+struct BenchLog {
+    file: File,
+}
+
+impl BenchLog {
+    fn new() -> Result<BenchLog, ()> {
+        let log_name = format!("bench_{}.csv", time::now().rfc3339());
+        let mut file = match OpenOptions::new().create(true).write(true).open(&log_name) {
+            Ok(file) => file,
+            Err(_) => return Err(()),
+        };
+        match file.write_all(b"time,motor,pin,command,voltage,current\n") {
+            Ok(()) => Ok(BenchLog { file: file }),
+            Err(_) => Err(()),
+        }
+    }
+
+    fn write(
+        &mut self,
+        timestamp: Timestamp,
+        motor: usize,
+        pin: u8,
+        command: f64,
+        voltage: Option<f32>,
+        current: Option<f32>,
+    ) -> io::Result<()> {
+        let optional = |value: Option<f32>| match value {
+            Some(value) => format!("{:.3}", value),
+            None => String::new(),
+        };
+        let row = format!(
+            "{:.3},{},{},{:.0},{},{}\n",
+            timestamp.as_seconds(),
+            motor + 1,
+            pin,
+            command,
+            optional(voltage),
+            optional(current)
+        );
+        self.file.write_all(row.as_bytes())
+    }
+}
+
+pub struct MotorBench {
+    motors: SafeMotorManager,
+    battery: Option<BatteryMonitor>,
+    pins: Vec<u8>,
+    clock: SharedClock,
+    log: Option<BenchLog>,
+    logger: ModuleLogger,
+}
+
+impl MotorBench {
+    pub fn new(
+        motors: SafeMotorManager,
+        battery: Option<BatteryMonitor>,
+        pins: Vec<u8>,
+        clock: SharedClock,
+    ) -> MotorBench {
+        let logger = ModuleLogger::new("Bench", None);
+        let log = match BenchLog::new() {
+            Ok(log) => Some(log),
+            Err(()) => {
+                logger.error("Couldn't create the bench log. Results won't be saved.");
+                None
+            }
+        };
+        MotorBench {
+            motors: motors,
+            battery: battery,
+            pins: pins,
+            clock: clock,
+            log: log,
+            logger: logger,
+        }
+    }
+
+    fn read_line() -> String {
+        let mut input = String::new();
+        io::stdin().read_line(&mut input).unwrap();
+        input.trim().to_string()
+    }
+
+    pub fn run(&mut self) {
+        self.logger.log("Remove the propellers or secure the vehicle. Type yes to continue.");
+        if MotorBench::read_line() != "yes" {
+            self.logger.log("Bench cancelled.");
+            return;
+        }
+
+        self.logger.log(USAGE);
+        loop {
+            let line = MotorBench::read_line();
+            if line == "quit" {
+                break;
+            }
+            match BenchTest::parse(&line, self.pins.len().min(4)) {
+                Some(test) => self.run_test(test),
+                None => self.logger.error(USAGE),
+            }
+        }
+        self.motors.terminate();
+    }
+
+    pub fn run_test(&mut self, test: BenchTest) {
+        self.motors.arm();
+        while self.motors.is_arming() {
+            self.motors.refresh();
+            self.clock.sleep(Duration::from_millis(COMMAND_PERIOD_MS));
+        }
+        match test {
+            BenchTest::Spin {
+                motor,
+                power,
+                seconds,
+            } => {
+                self.logger.log(&format!(
+                    "Spinning motor {} (pin {}) at {} for {} s.",
+                    motor + 1,
+                    self.pins[motor],
+                    power,
+                    seconds
+                ));
+                self.hold(motor, power, seconds);
+            }
+            BenchTest::Order { power, seconds } => {
+                for motor in 0..self.pins.len().min(4) {
+                    self.logger.log(&format!(
+                        "Motor {} (pin {}). Check its position and spin direction.",
+                        motor + 1,
+                        self.pins[motor]
+                    ));
+                    self.hold(motor, power, seconds);
+                    self.hold(motor, IDLE_POWER, PAUSE_BETWEEN_MOTORS_MS as f64 / 1000.0);
+                }
+            }
+            BenchTest::Sweep {
+                motor,
+                from,
+                to,
+                step,
+                seconds,
+            } => {
+                self.logger.log(&format!(
+                    "Sweeping motor {} (pin {}) from {} to {}.",
+                    motor + 1,
+                    self.pins[motor],
+                    from,
+                    to
+                ));
+                let direction = if to >= from { 1.0 } else { -1.0 };
+                let mut power = from;
+                while (to - power) * direction >= 0.0 {
+                    self.hold(motor, power, seconds);
+                    power += step * direction;
+                }
+            }
+        };
+        self.motors.terminate();
+        self.logger.success("Test finished.");
+    }
+
+    // Holds one motor at a power, the others at idle, logging battery readings.
+    fn hold(&mut self, motor: usize, power: f64, seconds: f64) {
+        let start = self.clock.now();
+        let end = start + Duration::from_millis((seconds * 1000.0) as u64);
+        let mut next_sample = start;
+        let mut powers = [IDLE_POWER; 4];
+        powers[motor] = power;
+
+        while self.clock.now() < end {
+            self.motors.set_powers(powers);
+            let now = self.clock.now();
+            if now >= next_sample {
+                self.sample(now, motor);
+                next_sample += Duration::from_millis(SAMPLE_PERIOD_MS);
+            }
+            self.clock.sleep(Duration::from_millis(COMMAND_PERIOD_MS));
+        }
+    }
+
+    fn sample(&mut self, now: Timestamp, motor: usize) {
+        let (voltage, current) = match self.battery {
+            Some(ref mut battery) => (
+                battery.read_voltage().ok(),
+                battery.read_current().unwrap_or(None),
+            ),
+            None => (None, None),
+        };
+        // The slew limit means the motor may still be catching up with the command.
+        let command = self.motors.outputs()[motor];
+        let pin = self.pins[motor];
+        let written = match self.log {
+            Some(ref mut log) => log.write(now, motor, pin, command, voltage, current),
+            None => Ok(()),
+        };
+        match written {
+            Ok(()) => {}
+            Err(e) => self
+                .logger
+                .error(&format!("Couldn't write to the bench log: {}", e)),
+        };
+    }
+}
@@ -0,0 +1,126 @@
+use i2cdev::linux::LinuxI2CDevice;
+use spidev::Spidev;
+
+use configurations::config::{SerialCommunication, Sensor};
+use logger::ModuleLogger;
+
+use super::spi::{open_spidev, SpiProtocol, SpiRegisterDevice};
+
+const SPI_SPEED_HZ: u32 = 5_000_000;
+
+// Opens the I2C device described by a sensor entry in the configuration file.
+// A slave address of 0 means the chip's default address.
+pub fn get_i2c_device(sensor: &Sensor, default_address: u16) -> Result<LinuxI2CDevice, ()> {
+    let logger = ModuleLogger::new("Bus", None);
+
+    match sensor.serial {
+        SerialCommunication::I2C => {}
+        _ => {
+            logger.error(&format!(
+                "{} only supports I2C. Check your configuration file.",
+                sensor.name
+            ));
+            return Err(());
+        }
+    };
+
+    let address = sensor.address(default_address);
+    match LinuxI2CDevice::new(sensor.bus(), address) {
+        Ok(device) => Ok(device),
+        Err(_) => {
+            logger.error(&format!(
+                "Couldn't open {} at address {:#x} on {}.",
+                sensor.name,
+                address,
+                sensor.bus()
+            ));
+            Err(())
+        }
+    }
+}
+
+// Opens the spidev described by a sensor entry. The bus is the spidev path, which also
+// selects the chip select line.
+pub fn get_spi_device(
+    sensor: &Sensor,
+    protocol: SpiProtocol,
+) -> Result<SpiRegisterDevice<Spidev>, ()> {
+    let logger = ModuleLogger::new("Bus", None);
+
+    match sensor.serial {
+        SerialCommunication::SPI => {}
+        _ => {
+            logger.error(&format!("{} is not configured for SPI.", sensor.name));
+            return Err(());
+        }
+    };
+
+    match open_spidev(sensor.bus(), SPI_SPEED_HZ) {
+        Ok(spidev) => Ok(SpiRegisterDevice::new(spidev, protocol)),
+        Err(_) => {
+            logger.error(&format!("Couldn't open {} on {}.", sensor.name, sensor.bus()));
+            Err(())
+        }
+    }
+}
+
+// Picks the slowest supported output data rate that satisfies the requested update rate.
+pub fn select_rate<T: Copy>(
+    logger: &ModuleLogger,
+    sensor: &Sensor,
+    default_rate: i32,
+    supported: &[(i32, T)],
+) -> Result<T, ()> {
+    let requested = match sensor.update_rate {
+        Some(rate) => rate,
+        None => default_rate,
+    };
+    select_at_least(logger, sensor, "an update rate", "Hz", requested, supported)
+}
+
+// Picks the smallest supported measurement range that covers the requested full scale.
+pub fn select_full_scale<T: Copy>(
+    logger: &ModuleLogger,
+    sensor: &Sensor,
+    default_full_scale: i32,
+    unit: &str,
+    supported: &[(i32, T)],
+) -> Result<T, ()> {
+    let requested = match sensor.full_scale {
+        Some(full_scale) => full_scale,
+        None => default_full_scale,
+    };
+    select_at_least(logger, sensor, "a full scale", unit, requested, supported)
+}
+
+fn select_at_least<T: Copy>(
+    logger: &ModuleLogger,
+    sensor: &Sensor,
+    quantity: &str,
+    unit: &str,
+    requested: i32,
+    supported: &[(i32, T)],
+) -> Result<T, ()> {
+    if requested > 0 {
+        for &(value, setting) in supported {
+            if value >= requested {
+                return Ok(setting);
+            }
+        }
+    }
+
+    let values: Vec<String> = supported
+        .iter()
+        .map(|&(value, _)| value.to_string())
+        .collect();
+    logger.error(&format!(
+        "{} doesn't support {} of {} {}. Supported: {} {}.",
+        sensor.name,
+        quantity,
+        requested,
+        unit,
+        values.join(", "),
+        unit
+    ));
+    Err(())
+}
@@ -0,0 +1,931 @@
+use std::cmp::Ordering;
+use std::f64;
+use std::fs::File;
+use std::io::{Read, Write};
+
+use na::{DMatrix, DVector, Matrix3, Matrix4, Vector3, Vector4};
+use num::traits::Zero;
+
+const G_TO_MPSPS: f64 = 9.80665;
+// One second of samples at the calibration sample rate.
+pub const STATIONARY_WINDOW: usize = 50;
+// Standard deviation on every axis, in m/s^2. Sensor noise is well under this but
+// holding the drone by hand isn't.
+const STATIONARY_THRESHOLD: f64 = 0.1;
+// The measured gravity has to be within 30 degrees of an axis.
+const ORIENTATION_COSINE: f64 = 0.866;
+
+// Orientations for the six position calibration, with x forward, y left and z up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Orientation {
+    Level,
+    Inverted,
+    NoseUp,
+    NoseDown,
+    LeftSideDown,
+    RightSideDown,
+}
+
+impl Orientation {
+    pub fn all() -> [Orientation; 6] {
+        [
+            Orientation::Level,
+            Orientation::Inverted,
+            Orientation::NoseUp,
+            Orientation::NoseDown,
+            Orientation::LeftSideDown,
+            Orientation::RightSideDown,
+        ]
+    }
+
+    pub fn description(&self) -> &'static str {
+        match *self {
+            Orientation::Level => "level, right way up",
+            Orientation::Inverted => "upside down",
+            Orientation::NoseUp => "nose pointing up",
+            Orientation::NoseDown => "nose pointing down",
+            Orientation::LeftSideDown => "on its left side",
+            Orientation::RightSideDown => "on its right side",
+        }
+    }
+
+    // What a perfect accelerometer reads at rest in this orientation.
+    pub fn gravity(&self) -> Vector3<f64> {
+        match *self {
+            Orientation::Level => Vector3::new(0.0, 0.0, G_TO_MPSPS),
+            Orientation::Inverted => Vector3::new(0.0, 0.0, -G_TO_MPSPS),
+            Orientation::NoseUp => Vector3::new(G_TO_MPSPS, 0.0, 0.0),
+            Orientation::NoseDown => Vector3::new(-G_TO_MPSPS, 0.0, 0.0),
+            Orientation::LeftSideDown => Vector3::new(0.0, -G_TO_MPSPS, 0.0),
+            Orientation::RightSideDown => Vector3::new(0.0, G_TO_MPSPS, 0.0),
+        }
+    }
+
+    // The orientation whose gravity is closest to the reading. None if the reading
+    // isn't close to any axis.
+    pub fn detect(acceleration: &Vector3<f64>) -> Option<Orientation> {
+        let magnitude = acceleration.norm();
+        if magnitude == 0.0 {
+            return None;
+        }
+        Orientation::all()
+            .iter()
+            .find(|orientation| {
+                orientation.gravity().dot(acceleration) / (G_TO_MPSPS * magnitude)
+                    > ORIENTATION_COSINE
+            })
+            .cloned()
+    }
+}
+
+fn mean(samples: &[Vector3<f64>]) -> Vector3<f64> {
+    samples
+        .iter()
+        .fold(Vector3::zero(), |sum, sample| sum + *sample) / samples.len() as f64
+}
+
+// Largest standard deviation of any axis.
+fn deviation(samples: &[Vector3<f64>]) -> f64 {
+    let mean = mean(samples);
+    let variance = samples.iter().fold(Vector3::zero(), |sum, sample| {
+        let error = *sample - mean;
+        sum + error.component_mul(&error)
+    }) / samples.len() as f64;
+    variance.x.max(variance.y).max(variance.z).sqrt()
+}
+
+// Corrects a raw reading as correction * (raw - offsets).
+#[derive(Debug, Clone, Copy)]
+pub struct AccelerometerFit {
+    pub offsets: Vector3<f64>,
+    pub correction: Matrix3<f64>,
+    pub scale: Vector3<f64>, // Gain of each axis, 1 for a perfect sensor
+    pub misalignment: f64,   // Largest cross-axis sensitivity, as a fraction
+    pub residual: f64,       // RMS error of the corrected positions in m/s^2
+}
+
+impl AccelerometerFit {
+    pub fn apply(&self, raw: &Vector3<f64>) -> Vector3<f64> {
+        self.correction * (*raw - self.offsets)
+    }
+}
+
+// Collects the mean reading in each of the six orientations, taking a position
+// once the drone has been still for a window of samples.
+pub struct SixPositionCalibration {
+    window: Vec<Vector3<f64>>,
+    positions: Vec<(Orientation, Vector3<f64>)>,
+}
+
+impl SixPositionCalibration {
+    pub fn new() -> SixPositionCalibration {
+        SixPositionCalibration {
+            window: Vec::with_capacity(STATIONARY_WINDOW),
+            positions: Vec::new(),
+        }
+    }
+
+    // Returns the orientation when a new one is captured.
+    pub fn add_sample(&mut self, acceleration: Vector3<f64>) -> Option<Orientation> {
+        if self.window.len() == STATIONARY_WINDOW {
+            self.window.remove(0);
+        }
+        self.window.push(acceleration);
+        if self.window.len() < STATIONARY_WINDOW || deviation(&self.window) > STATIONARY_THRESHOLD
+        {
+            return None;
+        }
+
+        let average = mean(&self.window);
+        let orientation = Orientation::detect(&average)?;
+        if self.has(orientation) {
+            return None;
+        }
+        self.positions.push((orientation, average));
+        self.window.clear();
+        Some(orientation)
+    }
+
+    pub fn has(&self, orientation: Orientation) -> bool {
+        self.positions
+            .iter()
+            .any(|&(captured, _)| captured == orientation)
+    }
+
+    pub fn remaining(&self) -> Vec<Orientation> {
+        Orientation::all()
+            .iter()
+            .filter(|orientation| !self.has(**orientation))
+            .cloned()
+            .collect()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.positions.len() == 6
+    }
+
+    // Least squares fit of raw = A * gravity + offsets, one axis of raw at a time.
+    // The correction is the inverse of A.
+    pub fn fit(&self) -> Result<AccelerometerFit, String> {
+        if !self.is_complete() {
+            return Err(format!(
+                "Only {} of 6 orientations were captured.",
+                self.positions.len()
+            ));
+        }
+
+        let mut normal = Matrix4::zero();
+        let mut projections = [Vector4::zero(); 3];
+        for &(orientation, raw) in &self.positions {
+            let gravity = orientation.gravity();
+            let row = Vector4::new(gravity.x, gravity.y, gravity.z, 1.0);
+            normal += row * row.transpose();
+            for axis in 0..3 {
+                projections[axis] += row * raw[axis];
+            }
+        }
+        let normal_inverse = match normal.try_inverse() {
+            Some(inverse) => inverse,
+            None => return Err(String::from("The orientations don't determine a fit.")),
+        };
+        let rows: Vec<Vector4<f64>> = projections
+            .iter()
+            .map(|projection| normal_inverse * *projection)
+            .collect();
+
+        let sensitivity = Matrix3::from_fn(|r, c| rows[r][c]);
+        let offsets = Vector3::new(rows[0][3], rows[1][3], rows[2][3]);
+        let correction = match sensitivity.try_inverse() {
+            Some(correction) => correction,
+            None => return Err(String::from("The accelerometer has a dead axis.")),
+        };
+
+        let scale = Vector3::from_fn(|r, _| sensitivity[(r, r)]);
+        let mut misalignment: f64 = 0.0;
+        for r in 0..3 {
+            for c in 0..3 {
+                if r != c {
+                    misalignment = misalignment.max((sensitivity[(r, c)] / scale[r]).abs());
+                }
+            }
+        }
+
+        let mut fit = AccelerometerFit {
+            offsets: offsets,
+            correction: correction,
+            scale: scale,
+            misalignment: misalignment,
+            residual: 0.0,
+        };
+        let squared_error = self.positions
+            .iter()
+            .fold(0.0, |sum, &(orientation, raw)| {
+                sum + (fit.apply(&raw) - orientation.gravity()).norm_squared()
+            });
+        fit.residual = (squared_error / 6.0).sqrt();
+        Ok(fit)
+    }
+}
+
+// Magnetometer samples are binned by direction from the centre of the readings so
+// far: six cube faces split into quadrants.
+const COVERAGE_BINS: usize = 24;
+const SAMPLES_PER_BIN: usize = 3;
+pub const REQUIRED_COVERAGE: f64 = 0.9;
+pub const MIN_MAGNETOMETER_SAMPLES: usize = 150;
+// Samples closer than this fraction of the field to the previous one add nothing.
+const MIN_SAMPLE_SPACING: f64 = 0.02;
+// Samples further than this many scaled median deviations from the fitted field are
+// rejected, and the fit repeated.
+const OUTLIER_DEVIATIONS: f64 = 4.0;
+const MIN_OUTLIER_ERROR: f64 = 0.01;
+const MAX_OUTLIER_FRACTION: f64 = 0.2;
+const MAX_REJECTION_PASSES: usize = 3;
+// Largest ratio of the normal equations' eigenvalues for the full fit.
+const MAX_CONDITION: f64 = 1e10;
+// RMS error of the corrected field strength, as a fraction of it.
+const MAX_MAGNETOMETER_RESIDUAL: f64 = 0.05;
+// Soft iron this strong means the fit has gone wrong or the drone is next to metal.
+const MAX_AXIS_RATIO: f64 = 2.0;
+
+// Corrects a raw reading as correction * (raw - offsets), putting it on a sphere of
+// radius field_strength in the sensor's units.
+#[derive(Debug, Clone, Copy)]
+pub struct MagnetometerFit {
+    pub offsets: Vector3<f64>,
+    pub correction: Matrix3<f64>,
+    pub field_strength: f64,
+    pub axis_ratio: f64, // Longest over shortest axis of the fitted ellipsoid
+    pub residual: f64,   // RMS error of the corrected field strength, as a fraction
+    pub diagonal: bool,  // Fell back to a fit without cross-axis soft iron
+    pub samples: usize,
+    pub rejected: usize,
+}
+
+impl MagnetometerFit {
+    pub fn apply(&self, raw: &Vector3<f64>) -> Vector3<f64> {
+        self.correction * (*raw - self.offsets)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.residual > MAX_MAGNETOMETER_RESIDUAL {
+            return Err(format!(
+                "The corrected field strength varies by {:.1}%. Keep away from metal and rotate slowly.",
+                self.residual * 100.0
+            ));
+        }
+        if self.axis_ratio > MAX_AXIS_RATIO {
+            return Err(format!(
+                "The fitted ellipsoid is {:.2} times longer than it is wide.",
+                self.axis_ratio
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Least squares solution of rows * x = targets. None if the rows don't determine it.
+fn least_squares(rows: &[Vec<f64>], targets: &[f64]) -> Option<Vec<f64>> {
+    let size = rows[0].len();
+    let mut normal = DMatrix::from_element(size, size, 0.0);
+    let mut projection = DVector::from_element(size, 0.0);
+    for (row, target) in rows.iter().zip(targets.iter()) {
+        for r in 0..size {
+            projection[r] += row[r] * target;
+            for c in 0..size {
+                normal[(r, c)] += row[r] * row[c];
+            }
+        }
+    }
+
+    let eigenvalues = normal.clone().symmetric_eigen().eigenvalues;
+    let largest = eigenvalues.iter().fold(0.0f64, |largest, value| largest.max(value.abs()));
+    let smallest = eigenvalues
+        .iter()
+        .fold(largest, |smallest, value| smallest.min(value.abs()));
+    if smallest == 0.0 || largest / smallest > MAX_CONDITION {
+        return None;
+    }
+    let solution = normal.try_inverse()? * projection;
+    Some(solution.iter().cloned().collect())
+}
+
+fn solve_quadric(rows: &[Vec<f64>]) -> Option<Vec<f64>> {
+    least_squares(rows, &vec![1.0; rows.len()])
+}
+
+// Turns x^T A x + 2 g^T x = 1 into offsets and a correction onto a sphere. None if the
+// quadric isn't an ellipsoid.
+fn ellipsoid_correction(
+    quadratic: Matrix3<f64>,
+    linear: Vector3<f64>,
+) -> Option<(Vector3<f64>, Matrix3<f64>, f64, f64)> {
+    let offsets = -(quadratic.try_inverse()? * linear);
+    let scale = 1.0 + offsets.dot(&(quadratic * offsets));
+    if scale <= 0.0 {
+        return None;
+    }
+    let eigen = (quadratic / scale).symmetric_eigen();
+    if eigen.eigenvalues.iter().any(|value| *value <= 0.0) {
+        return None;
+    }
+
+    // Radii of the ellipsoid along its axes. Their geometric mean keeps the volume,
+    // so corrected readings stay in the sensor's units.
+    let radii: Vec<f64> = eigen.eigenvalues.iter().map(|value| 1.0 / value.sqrt()).collect();
+    let field_strength = (radii[0] * radii[1] * radii[2]).cbrt();
+    let longest = radii.iter().fold(0.0f64, |longest, radius| longest.max(*radius));
+    let shortest = radii.iter().fold(longest, |shortest, radius| shortest.min(*radius));
+
+    let stretch = Matrix3::from_fn(|r, c| if r == c { field_strength / radii[r] } else { 0.0 });
+    let axes = eigen.eigenvectors;
+    let correction = axes * stretch * axes.transpose();
+    Some((offsets, correction, field_strength, longest / shortest))
+}
+
+// Hard iron offsets and soft iron along any axes.
+fn fit_full(samples: &[Vector3<f64>]) -> Option<(Vector3<f64>, Matrix3<f64>, f64, f64)> {
+    let rows: Vec<Vec<f64>> = samples
+        .iter()
+        .map(|s| {
+            vec![
+                s.x * s.x,
+                s.y * s.y,
+                s.z * s.z,
+                2.0 * s.x * s.y,
+                2.0 * s.x * s.z,
+                2.0 * s.y * s.z,
+                2.0 * s.x,
+                2.0 * s.y,
+                2.0 * s.z,
+            ]
+        })
+        .collect();
+    let v = solve_quadric(&rows)?;
+    #[rustfmt_skip]
+    let quadratic = Matrix3::new(v[0], v[3], v[4],
+                                 v[3], v[1], v[5],
+                                 v[4], v[5], v[2]);
+    ellipsoid_correction(quadratic, Vector3::new(v[6], v[7], v[8]))
+}
+
+// Hard iron offsets and soft iron along the sensor axes only. Needs fewer
+// orientations to be well conditioned.
+fn fit_diagonal(samples: &[Vector3<f64>]) -> Option<(Vector3<f64>, Matrix3<f64>, f64, f64)> {
+    let rows: Vec<Vec<f64>> = samples
+        .iter()
+        .map(|s| {
+            vec![
+                s.x * s.x,
+                s.y * s.y,
+                s.z * s.z,
+                2.0 * s.x,
+                2.0 * s.y,
+                2.0 * s.z,
+            ]
+        })
+        .collect();
+    let v = solve_quadric(&rows)?;
+    let quadratic = Matrix3::from_fn(|r, c| if r == c { v[r] } else { 0.0 });
+    ellipsoid_correction(quadratic, Vector3::new(v[3], v[4], v[5]))
+}
+
+fn is_finite(sample: &Vector3<f64>) -> bool {
+    sample.iter().all(|value| value.is_finite())
+}
+
+// Collects magnetometer readings until they cover enough of the sphere.
+pub struct MagnetometerCalibration {
+    samples: Vec<Vector3<f64>>,
+    minimum: Vector3<f64>,
+    maximum: Vector3<f64>,
+}
+
+impl MagnetometerCalibration {
+    pub fn new() -> MagnetometerCalibration {
+        MagnetometerCalibration {
+            samples: Vec::new(),
+            minimum: Vector3::from_element(f64::INFINITY),
+            maximum: Vector3::from_element(f64::NEG_INFINITY),
+        }
+    }
+
+    // Samples that aren't finite are dropped.
+    pub fn from_samples(samples: Vec<Vector3<f64>>) -> MagnetometerCalibration {
+        let mut calibration = MagnetometerCalibration::new();
+        for sample in samples {
+            if is_finite(&sample) {
+                calibration.push(sample);
+            }
+        }
+        calibration
+    }
+
+    fn push(&mut self, sample: Vector3<f64>) {
+        self.minimum = Vector3::from_fn(|r, _| self.minimum[r].min(sample[r]));
+        self.maximum = Vector3::from_fn(|r, _| self.maximum[r].max(sample[r]));
+        self.samples.push(sample);
+    }
+
+    // Returns false if the sample was too close to the last one to be kept, or isn't
+    // finite.
+    pub fn add_sample(&mut self, sample: Vector3<f64>) -> bool {
+        if !is_finite(&sample) {
+            return false;
+        }
+        let spacing = match self.samples.last() {
+            Some(last) => (sample - *last).norm() / sample.norm().max(last.norm()),
+            None => 1.0,
+        };
+        if spacing < MIN_SAMPLE_SPACING {
+            return false;
+        }
+        self.push(sample);
+        true
+    }
+
+    pub fn samples(&self) -> &[Vector3<f64>] {
+        &self.samples
+    }
+
+    // Fraction of directions with enough samples, measured from the middle of the
+    // range seen on each axis.
+    pub fn coverage(&self) -> f64 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+        let centre = (self.minimum + self.maximum) / 2.0;
+        let mut counts = [0usize; COVERAGE_BINS];
+        for sample in &self.samples {
+            let direction = *sample - centre;
+            let axis = (0..3)
+                .max_by(|a, b| {
+                    direction[*a]
+                        .abs()
+                        .partial_cmp(&direction[*b].abs())
+                        .unwrap_or(Ordering::Equal)
+                })
+                .unwrap();
+            let face = axis * 2 + if direction[axis] < 0.0 { 1 } else { 0 };
+            let quadrant = (if direction[(axis + 1) % 3] < 0.0 { 1 } else { 0 })
+                + (if direction[(axis + 2) % 3] < 0.0 { 2 } else { 0 });
+            counts[face * 4 + quadrant] += 1;
+        }
+        counts.iter().filter(|count| **count >= SAMPLES_PER_BIN).count() as f64
+            / COVERAGE_BINS as f64
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.samples.len() >= MIN_MAGNETOMETER_SAMPLES && self.coverage() >= REQUIRED_COVERAGE
+    }
+
+    // Fits, drops samples far from the fitted sphere and fits again. Falls back to
+    // a diagonal fit if the full one is ill conditioned.
+    pub fn fit(&self) -> Result<MagnetometerFit, String> {
+        if self.samples.len() < MIN_MAGNETOMETER_SAMPLES {
+            return Err(format!(
+                "Only {} of {} samples.",
+                self.samples.len(),
+                MIN_MAGNETOMETER_SAMPLES
+            ));
+        }
+
+        let mut samples = self.samples.clone();
+        let mut pass = 0;
+        loop {
+            let (fit, diagonal) = match fit_full(&samples) {
+                Some(fit) => (fit, false),
+                None => match fit_diagonal(&samples) {
+                    Some(fit) => (fit, true),
+                    None => return Err(String::from("The samples don't fit an ellipsoid.")),
+                },
+            };
+            let (offsets, correction, field_strength, axis_ratio) = fit;
+            let errors: Vec<f64> = samples
+                .iter()
+                .map(|sample| (correction * (*sample - offsets)).norm() / field_strength - 1.0)
+                .collect();
+            let squared_error: f64 = errors.iter().map(|error| error * error).sum();
+            let residual = (squared_error / errors.len() as f64).sqrt();
+
+            let mut deviations: Vec<f64> = errors.iter().map(|error| error.abs()).collect();
+            deviations.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+            // Scaled so it estimates the standard deviation of normal errors.
+            let median_deviation = deviations[deviations.len() / 2] * 1.4826;
+            let limit = (OUTLIER_DEVIATIONS * median_deviation).max(MIN_OUTLIER_ERROR);
+            let kept: Vec<Vector3<f64>> = samples
+                .iter()
+                .zip(errors.iter())
+                .filter(|&(_, error)| error.abs() <= limit)
+                .map(|(sample, _)| *sample)
+                .collect();
+
+            pass += 1;
+            if kept.len() == samples.len() || pass == MAX_REJECTION_PASSES {
+                let rejected = self.samples.len() - samples.len();
+                if rejected as f64 > MAX_OUTLIER_FRACTION * self.samples.len() as f64 {
+                    return Err(format!(
+                        "{} of {} samples were outliers. Something magnetic is moving with the drone.",
+                        rejected,
+                        self.samples.len()
+                    ));
+                }
+                return Ok(MagnetometerFit {
+                    offsets: offsets,
+                    correction: correction,
+                    field_strength: field_strength,
+                    axis_ratio: axis_ratio,
+                    residual: residual,
+                    diagonal: diagonal,
+                    samples: samples.len(),
+                    rejected: rejected,
+                });
+            }
+            samples = kept;
+        }
+    }
+}
+
+fn write_file(path: &str, contents: &str) -> Result<(), String> {
+    match File::create(path).and_then(|mut file| file.write_all(contents.as_bytes())) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(format!("Couldn't write {}: {}", path, e)),
+    }
+}
+
+// Samples are saved one x,y,z per line so a calibration can be fitted again offline.
+pub fn write_samples(path: &str, samples: &[Vector3<f64>]) -> Result<(), String> {
+    let mut contents = String::from("x,y,z\n");
+    for sample in samples {
+        contents.push_str(&format!("{},{},{}\n", sample.x, sample.y, sample.z));
+    }
+    write_file(path, &contents)
+}
+
+pub fn read_samples(path: &str) -> Result<Vec<Vector3<f64>>, String> {
+    let mut contents = String::new();
+    match File::open(path).and_then(|mut file| file.read_to_string(&mut contents)) {
+        Ok(_) => {}
+        Err(e) => return Err(format!("Couldn't read {}: {}", path, e)),
+    };
+
+    let mut samples = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+        let values: Vec<f64> = fields.iter().filter_map(|field| field.parse().ok()).collect();
+        match values.len() {
+            3 if fields.len() == 3 => samples.push(Vector3::new(values[0], values[1], values[2])),
+            // Header and blank lines.
+            0 => {}
+            _ => return Err(format!("Line {} of {} isn't x,y,z.", number + 1, path)),
+        };
+    }
+    Ok(samples)
+}
+
+// Gyroscope and accelerometer averages against die temperature, logged while the
+// IMU warms up sitting still.
+pub const THERMAL_DEGREE: usize = 2;
+const MIN_THERMAL_SAMPLES: usize = 30;
+// Less than this and the fit is mostly noise.
+const MIN_THERMAL_SPAN: f64 = 3.0;
+// Standard deviation of a window in rad/s. The drone was bumped if it's over.
+const GYROSCOPE_STATIONARY_THRESHOLD: f64 = 0.02;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ThermalSample {
+    pub temperature: f64,
+    pub angular_rate: Vector3<f64>,
+    pub acceleration: Vector3<f64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ThermalFit {
+    pub reference_temperature: f64,
+    pub min_temperature: f64,
+    pub max_temperature: f64,
+    pub gyroscope_bias: Vector3<f64>, // At the reference temperature
+    pub gyroscope: [Vec<f64>; 3],     // First power and up, see Thermal
+    pub accelerometer: [Vec<f64>; 3],
+    pub gyroscope_residual: f64,     // RMS in rad/s
+    pub accelerometer_residual: f64, // RMS in m/s^2
+}
+
+pub struct ThermalCalibration {
+    samples: Vec<ThermalSample>,
+}
+
+// Polynomial of the given degree in difference, constant first.
+fn polynomial_fit(
+    differences: &[f64],
+    values: &[f64],
+    degree: usize,
+) -> Option<(Vec<f64>, f64)> {
+    let rows: Vec<Vec<f64>> = differences
+        .iter()
+        .map(|difference| (0..degree + 1).map(|power| difference.powi(power as i32)).collect())
+        .collect();
+    let coefficients = least_squares(&rows, values)?;
+    let squared_error = rows.iter().zip(values.iter()).fold(0.0, |sum, (row, value)| {
+        let fitted: f64 = row.iter()
+            .zip(coefficients.iter())
+            .map(|(term, coefficient)| term * coefficient)
+            .sum();
+        sum + (fitted - value) * (fitted - value)
+    });
+    Some((coefficients, (squared_error / values.len() as f64).sqrt()))
+}
+
+impl ThermalCalibration {
+    pub fn new() -> ThermalCalibration {
+        ThermalCalibration {
+            samples: Vec::new(),
+        }
+    }
+
+    // Averages a window of readings taken at one temperature. Returns false and
+    // drops the window if the drone moved.
+    pub fn add_window(
+        &mut self,
+        temperature: f64,
+        angular_rates: &[Vector3<f64>],
+        accelerations: &[Vector3<f64>],
+    ) -> bool {
+        if deviation(angular_rates) > GYROSCOPE_STATIONARY_THRESHOLD
+            || deviation(accelerations) > STATIONARY_THRESHOLD
+        {
+            return false;
+        }
+        self.samples.push(ThermalSample {
+            temperature: temperature,
+            angular_rate: mean(angular_rates),
+            acceleration: mean(accelerations),
+        });
+        true
+    }
+
+    pub fn samples(&self) -> &[ThermalSample] {
+        &self.samples
+    }
+
+    pub fn temperature_span(&self) -> f64 {
+        let (lowest, highest) = self.samples.iter().fold(
+            (f64::INFINITY, f64::NEG_INFINITY),
+            |(lowest, highest), sample| {
+                (
+                    lowest.min(sample.temperature),
+                    highest.max(sample.temperature),
+                )
+            },
+        );
+        (highest - lowest).max(0.0)
+    }
+
+    pub fn fit(&self, degree: usize) -> Result<ThermalFit, String> {
+        if self.samples.len() < MIN_THERMAL_SAMPLES {
+            return Err(format!(
+                "Only {} of {} samples.",
+                self.samples.len(),
+                MIN_THERMAL_SAMPLES
+            ));
+        }
+        let span = self.temperature_span();
+        if span < MIN_THERMAL_SPAN {
+            return Err(format!(
+                "The temperature only changed by {:.1} C. Start with the IMU colder.",
+                span
+            ));
+        }
+
+        // Centred on the mean temperature to keep the fit well conditioned.
+        let temperatures: Vec<f64> = self.samples.iter().map(|sample| sample.temperature).collect();
+        let reference = temperatures.iter().sum::<f64>() / temperatures.len() as f64;
+        let differences: Vec<f64> = temperatures
+            .iter()
+            .map(|temperature| temperature - reference)
+            .collect();
+
+        let mut gyroscope_bias = Vector3::zero();
+        let mut gyroscope = [Vec::new(), Vec::new(), Vec::new()];
+        let mut accelerometer = [Vec::new(), Vec::new(), Vec::new()];
+        let mut gyroscope_error: f64 = 0.0;
+        let mut accelerometer_error: f64 = 0.0;
+        for axis in 0..3 {
+            let rates: Vec<f64> = self.samples
+                .iter()
+                .map(|sample| sample.angular_rate[axis])
+                .collect();
+            let accelerations: Vec<f64> = self.samples
+                .iter()
+                .map(|sample| sample.acceleration[axis])
+                .collect();
+            let (rate_fit, rate_residual) = match polynomial_fit(&differences, &rates, degree) {
+                Some(fit) => fit,
+                None => return Err(String::from("The temperatures don't determine a fit.")),
+            };
+            let (acceleration_fit, acceleration_residual) =
+                match polynomial_fit(&differences, &accelerations, degree) {
+                    Some(fit) => fit,
+                    None => return Err(String::from("The temperatures don't determine a fit.")),
+                };
+
+            // The accelerometer constant is mostly gravity, so only its drift is kept.
+            gyroscope_bias[axis] = rate_fit[0];
+            gyroscope[axis] = rate_fit[1..].to_vec();
+            accelerometer[axis] = acceleration_fit[1..].to_vec();
+            gyroscope_error += rate_residual * rate_residual;
+            accelerometer_error += acceleration_residual * acceleration_residual;
+        }
+
+        Ok(ThermalFit {
+            reference_temperature: reference,
+            min_temperature: temperatures.iter().cloned().fold(f64::INFINITY, f64::min),
+            max_temperature: temperatures.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+            gyroscope_bias: gyroscope_bias,
+            gyroscope: gyroscope,
+            accelerometer: accelerometer,
+            gyroscope_residual: (gyroscope_error / 3.0).sqrt(),
+            accelerometer_residual: (accelerometer_error / 3.0).sqrt(),
+        })
+    }
+}
+
+pub fn write_thermal_samples(path: &str, samples: &[ThermalSample]) -> Result<(), String> {
+    let mut contents = String::from("temperature,gx,gy,gz,ax,ay,az\n");
+    for sample in samples {
+        contents.push_str(&format!(
+            "{},{},{},{},{},{},{}\n",
+            sample.temperature,
+            sample.angular_rate.x,
+            sample.angular_rate.y,
+            sample.angular_rate.z,
+            sample.acceleration.x,
+            sample.acceleration.y,
+            sample.acceleration.z
+        ));
+    }
+    write_file(path, &contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Offsets in m/s^2 and a sensitivity with gain and cross-axis errors like a real part.
+    fn sensor() -> (Vector3<f64>, Matrix3<f64>) {
+        let offsets = Vector3::new(0.3, -0.2, 0.5);
+        let sensitivity = Matrix3::new(1.02, 0.01, -0.02, -0.015, 0.97, 0.005, 0.02, -0.01, 1.01);
+        (offsets, sensitivity)
+    }
+
+    fn capture(
+        calibration: &mut SixPositionCalibration,
+        orientation: Orientation,
+    ) -> Option<Orientation> {
+        let (offsets, sensitivity) = sensor();
+        let raw = sensitivity * orientation.gravity() + offsets;
+        let mut captured = None;
+        for i in 0..STATIONARY_WINDOW {
+            // Well under the stationary threshold.
+            let noise = if i % 2 == 0 { 0.01 } else { -0.01 };
+            match calibration.add_sample(raw + Vector3::new(noise, -noise, noise)) {
+                Some(orientation) => captured = Some(orientation),
+                None => {}
+            };
+        }
+        captured
+    }
+
+    #[test]
+    fn orientations_are_detected_near_an_axis() {
+        for orientation in Orientation::all().iter() {
+            let tilted = orientation.gravity() + Vector3::new(1.0, 1.0, 1.0);
+            assert_eq!(Orientation::detect(&tilted), Some(*orientation));
+        }
+        // 45 degrees is too far from any axis.
+        assert_eq!(Orientation::detect(&Vector3::new(7.0, 0.0, 7.0)), None);
+        assert_eq!(Orientation::detect(&Vector3::zero()), None);
+    }
+
+    #[test]
+    fn six_positions_fit_offsets_scale_and_misalignment() {
+        let mut calibration = SixPositionCalibration::new();
+        for orientation in Orientation::all().iter() {
+            assert!(calibration.fit().is_err());
+            assert_eq!(capture(&mut calibration, *orientation), Some(*orientation));
+            // The same orientation again isn't a new position.
+            assert_eq!(capture(&mut calibration, *orientation), None);
+        }
+        assert!(calibration.is_complete());
+        assert!(calibration.remaining().is_empty());
+
+        let (offsets, sensitivity) = sensor();
+        let fit = calibration.fit().unwrap();
+        assert!((fit.offsets - offsets).norm() < 1e-9);
+        assert!((fit.scale - Vector3::new(1.02, 0.97, 1.01)).norm() < 1e-9);
+        assert!((fit.misalignment - 0.02 / 1.01).abs() < 1e-9);
+        assert!((fit.correction * sensitivity - Matrix3::identity()).norm() < 1e-9);
+        assert!(fit.residual < 1e-9);
+
+        // Any reading is corrected, not just the six positions.
+        let gravity = Vector3::new(3.0, -5.0, 7.8);
+        assert!((fit.apply(&(sensitivity * gravity + offsets)) - gravity).norm() < 1e-9);
+    }
+
+    #[test]
+    fn moving_samples_are_not_captured() {
+        let mut calibration = SixPositionCalibration::new();
+        let gravity = Orientation::Level.gravity();
+        for i in 0..STATIONARY_WINDOW * 2 {
+            let shake = if i % 2 == 0 { 0.5 } else { -0.5 };
+            assert_eq!(calibration.add_sample(gravity + Vector3::new(shake, 0.0, 0.0)), None);
+        }
+        assert_eq!(calibration.remaining().len(), 6);
+    }
+
+    // Evenly spread directions on the unit sphere.
+    fn directions(count: usize) -> Vec<Vector3<f64>> {
+        let golden_angle = f64::consts::PI * (3.0 - 5.0f64.sqrt());
+        (0..count)
+            .map(|i| {
+                let z = 1.0 - 2.0 * (i as f64 + 0.5) / count as f64;
+                let radius = (1.0 - z * z).sqrt();
+                let angle = golden_angle * i as f64;
+                Vector3::new(radius * angle.cos(), radius * angle.sin(), z)
+            })
+            .collect()
+    }
+
+    // Hard iron offsets and soft iron along tilted axes, in gauss.
+    fn magnetometer() -> (Vector3<f64>, Matrix3<f64>) {
+        let offsets = Vector3::new(0.12, -0.05, 0.2);
+        let soft_iron = Matrix3::new(1.2, 0.1, 0.0, 0.1, 0.9, 0.05, 0.0, 0.05, 1.0);
+        (offsets, soft_iron)
+    }
+
+    fn readings(count: usize) -> Vec<Vector3<f64>> {
+        let (offsets, soft_iron) = magnetometer();
+        directions(count)
+            .iter()
+            .map(|direction| soft_iron * (*direction * 0.45) + offsets)
+            .collect()
+    }
+
+    fn check_fit(fit: &MagnetometerFit, samples: &[Vector3<f64>]) {
+        let (offsets, soft_iron) = magnetometer();
+        assert!((fit.offsets - offsets).norm() < 1e-6);
+        assert!(fit.residual < 1e-6);
+        assert!(!fit.diagonal);
+        // The correction undoes the soft iron up to the field strength.
+        let undone = fit.correction * soft_iron * 0.45 / fit.field_strength;
+        assert!((undone * undone.transpose() - Matrix3::identity()).norm() < 1e-6);
+        for sample in samples {
+            assert!((fit.apply(sample).norm() / fit.field_strength - 1.0).abs() < 1e-6);
+        }
+        let singular_values = soft_iron.symmetric_eigen().eigenvalues;
+        let longest = singular_values.iter().cloned().fold(0.0, f64::max);
+        let shortest = singular_values.iter().cloned().fold(f64::INFINITY, f64::min);
+        assert!((fit.axis_ratio - longest / shortest).abs() < 1e-6);
+        assert!(fit.validate().is_ok());
+    }
+
+    #[test]
+    fn ellipsoid_fit_recovers_hard_and_soft_iron() {
+        let samples = readings(300);
+        let calibration = MagnetometerCalibration::from_samples(samples.clone());
+        assert_eq!(calibration.coverage(), 1.0);
+        assert!(calibration.is_complete());
+
+        let fit = calibration.fit().unwrap();
+        check_fit(&fit, &samples);
+        assert_eq!((fit.samples, fit.rejected), (300, 0));
+    }
+
+    #[test]
+    fn outliers_are_rejected_before_the_final_fit() {
+        let (offsets, _) = magnetometer();
+        let mut samples = readings(300);
+        let clean = samples.clone();
+        for sample in samples.iter_mut().step_by(30) {
+            *sample = (*sample - offsets) * 1.3 + offsets;
+        }
+        let fit = MagnetometerCalibration::from_samples(samples).fit().unwrap();
+        check_fit(&fit, &clean);
+        assert_eq!((fit.samples, fit.rejected), (290, 10));
+    }
+
+    #[test]
+    fn samples_that_are_not_finite_are_dropped() {
+        let mut samples = readings(300);
+        samples.push(Vector3::new(f64::NAN, 0.1, 0.2));
+        samples.push(Vector3::new(0.1, f64::INFINITY, 0.2));
+        let mut calibration = MagnetometerCalibration::from_samples(samples);
+        assert_eq!(calibration.samples().len(), 300);
+        assert!(!calibration.add_sample(Vector3::new(0.3, 0.2, f64::NAN)));
+        assert_eq!(calibration.coverage(), 1.0);
+        assert_eq!(calibration.fit().unwrap().samples, 300);
+
+        let too_few = MagnetometerCalibration::from_samples(readings(MIN_MAGNETOMETER_SAMPLES - 1));
+        assert!(too_few.fit().is_err());
+    }
+}
@@ -0,0 +1,550 @@
+use std::fmt;
+use std::io;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::thread::JoinHandle;
+
+use debug_server::serve_calibration;
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CalibrationStep {
+    Magnetometer,
+    Gyroscope,
+    Accelerometer,
+    Thermal,
+    Motors,
+}
+
+impl CalibrationStep {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CalibrationStep::Magnetometer => "magnetometer",
+            CalibrationStep::Gyroscope => "gyroscope",
+            CalibrationStep::Accelerometer => "accelerometer",
+            CalibrationStep::Thermal => "thermal",
+            CalibrationStep::Motors => "motors",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Acknowledgement {
+    Continue,
+    Skip,
+    Cancel,
+}
+
+impl Acknowledgement {
+    // An empty line continues, so pressing enter at the console works.
+    pub fn parse(text: &str) -> Option<Acknowledgement> {
+        match text.trim().to_lowercase().as_ref() {
+            "" | "continue" | "ok" => Some(Acknowledgement::Continue),
+            "skip" => Some(Acknowledgement::Skip),
+            "cancel" => Some(Acknowledgement::Cancel),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StepOutcome {
+    Completed,
+    Rejected(String),
+    Skipped,
+    Cancelled,
+}
+
+// What a step measured and whether it was kept. Metrics are named with their unit.
+#[derive(Debug, Clone)]
+pub struct StepReport {
+    pub step: CalibrationStep,
+    pub outcome: StepOutcome,
+    pub metrics: Vec<(String, f64)>,
+    pub warnings: Vec<String>,
+}
+
+impl StepReport {
+    pub fn new(step: CalibrationStep) -> StepReport {
+        StepReport {
+            step: step,
+            outcome: StepOutcome::Completed,
+            metrics: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    pub fn ended(step: CalibrationStep, outcome: StepOutcome) -> StepReport {
+        StepReport {
+            outcome: outcome,
+            ..StepReport::new(step)
+        }
+    }
+
+    pub fn metric(&mut self, name: &str, value: f64) {
+        self.metrics.push((String::from(name), value));
+    }
+
+    pub fn warn(&mut self, warning: &str) {
+        self.warnings.push(String::from(warning));
+    }
+
+    pub fn reject(&mut self, reason: &str) {
+        self.outcome = StepOutcome::Rejected(String::from(reason));
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.outcome == StepOutcome::Completed
+    }
+
+    pub fn to_json(&self) -> String {
+        let (outcome, reason) = match self.outcome {
+            StepOutcome::Completed => ("completed", None),
+            StepOutcome::Rejected(ref reason) => ("rejected", Some(reason)),
+            StepOutcome::Skipped => ("skipped", None),
+            StepOutcome::Cancelled => ("cancelled", None),
+        };
+        let metrics: Vec<String> = self.metrics
+            .iter()
+            .map(|&(ref name, value)| format!("{}:{}", json_string(name), json_number(value)))
+            .collect();
+        let warnings: Vec<String> = self.warnings.iter().map(|warning| json_string(warning)).collect();
+        format!(
+            "{{\"step\":\"{}\",\"outcome\":\"{}\",\"reason\":{},\"metrics\":{{{}}},\"warnings\":[{}]}}",
+            self.step.name(),
+            outcome,
+            match reason {
+                Some(reason) => json_string(reason),
+                None => String::from("null"),
+            },
+            metrics.join(","),
+            warnings.join(",")
+        )
+    }
+}
+
+impl fmt::Display for StepReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.outcome {
+            StepOutcome::Completed => writeln!(f, "Calibrated the {}.", self.step.name())?,
+            StepOutcome::Rejected(ref reason) => writeln!(
+                f,
+                "The {} calibration failed: {}",
+                self.step.name(),
+                reason
+            )?,
+            StepOutcome::Skipped => writeln!(f, "Skipped the {}.", self.step.name())?,
+            StepOutcome::Cancelled => writeln!(f, "Cancelled the {}.", self.step.name())?,
+        };
+        for &(ref name, value) in &self.metrics {
+            writeln!(f, "    {}: {:.4}", name, value)?;
+        }
+        for warning in &self.warnings {
+            writeln!(f, "    Warning: {}", warning)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CalibrationReport {
+    pub steps: Vec<StepReport>,
+    pub saved: bool,
+}
+
+impl CalibrationReport {
+    pub fn to_json(&self) -> String {
+        let steps: Vec<String> = self.steps.iter().map(|step| step.to_json()).collect();
+        format!(
+            "{{\"saved\":{},\"steps\":[{}]}}",
+            self.saved,
+            steps.join(",")
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum CalibrationEvent {
+    // Nothing happens until it's acknowledged.
+    Prompt {
+        step: CalibrationStep,
+        message: String,
+    },
+    Progress {
+        step: CalibrationStep,
+        message: String,
+        fraction: Option<f64>,
+    },
+    StepFinished(StepReport),
+    Finished(CalibrationReport),
+}
+
+impl CalibrationEvent {
+    pub fn to_json(&self) -> String {
+        match *self {
+            CalibrationEvent::Prompt { step, ref message } => format!(
+                "{{\"event\":\"prompt\",\"step\":\"{}\",\"message\":{}}}",
+                step.name(),
+                json_string(message)
+            ),
+            CalibrationEvent::Progress {
+                step,
+                ref message,
+                fraction,
+            } => format!(
+                "{{\"event\":\"progress\",\"step\":\"{}\",\"message\":{},\"fraction\":{}}}",
+                step.name(),
+                json_string(message),
+                match fraction {
+                    Some(fraction) => json_number(fraction),
+                    None => String::from("null"),
+                }
+            ),
+            CalibrationEvent::StepFinished(ref report) => {
+                format!("{{\"event\":\"step\",\"report\":{}}}", report.to_json())
+            }
+            CalibrationEvent::Finished(ref report) => {
+                format!("{{\"event\":\"finished\",\"report\":{}}}", report.to_json())
+            }
+        }
+    }
+}
+
+fn json_string(text: &str) -> String {
+    Value::String(String::from(text)).to_string()
+}
+
+// JSON has no NaN or infinity.
+fn json_number(value: f64) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        String::from("null")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SessionState {
+    Idle,
+    Prompting(CalibrationStep),
+    Running(CalibrationStep),
+    Finished,
+}
+
+// The calibration side of a session. Steps send prompts and progress through it and
+// wait for acknowledgements from whatever holds the other end: the console, the
+// debug server or a network link.
+pub struct CalibrationSession {
+    events: Sender<CalibrationEvent>,
+    acknowledgements: Receiver<Acknowledgement>,
+    state: SessionState,
+    reports: Vec<StepReport>,
+}
+
+// The other end of a session.
+pub struct CalibrationLink {
+    pub events: Receiver<CalibrationEvent>,
+    pub acknowledgements: Sender<Acknowledgement>,
+}
+
+impl CalibrationSession {
+    pub fn new() -> (CalibrationSession, CalibrationLink) {
+        let (event_tx, event_rx) = channel();
+        let (acknowledgement_tx, acknowledgement_rx) = channel();
+        let session = CalibrationSession {
+            events: event_tx,
+            acknowledgements: acknowledgement_rx,
+            state: SessionState::Idle,
+            reports: Vec::new(),
+        };
+        let link = CalibrationLink {
+            events: event_rx,
+            acknowledgements: acknowledgement_tx,
+        };
+        (session, link)
+    }
+
+    // Nobody listening is the same as being cancelled.
+    fn send(&mut self, event: CalibrationEvent) {
+        match self.events.send(event) {
+            Ok(()) => {}
+            Err(_) => self.state = SessionState::Finished,
+        };
+    }
+
+    // The step waiting for an acknowledgement or running, if any.
+    pub fn current_step(&self) -> Option<CalibrationStep> {
+        match self.state {
+            SessionState::Prompting(step) | SessionState::Running(step) => Some(step),
+            SessionState::Idle | SessionState::Finished => None,
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state == SessionState::Finished
+    }
+
+    // Waits for an acknowledgement. Continues sent before the prompt are dropped, so a
+    // stray enter doesn't skip ahead, but a skip or cancel sent meanwhile still counts.
+    pub fn prompt(&mut self, step: CalibrationStep, message: &str) -> Acknowledgement {
+        if self.is_cancelled() {
+            return Acknowledgement::Cancel;
+        }
+        let mut pending = None;
+        loop {
+            match self.acknowledgements.try_recv() {
+                Ok(Acknowledgement::Continue) => {}
+                Ok(Acknowledgement::Skip) => if pending.is_none() {
+                    pending = Some(Acknowledgement::Skip);
+                },
+                Ok(Acknowledgement::Cancel) | Err(TryRecvError::Disconnected) => {
+                    pending = Some(Acknowledgement::Cancel);
+                    break;
+                }
+                Err(TryRecvError::Empty) => break,
+            };
+        }
+        self.state = SessionState::Prompting(step);
+        let acknowledgement = match pending {
+            Some(acknowledgement) => acknowledgement,
+            None => {
+                self.send(CalibrationEvent::Prompt {
+                    step: step,
+                    message: String::from(message),
+                });
+                match self.acknowledgements.recv() {
+                    Ok(acknowledgement) => acknowledgement,
+                    Err(_) => Acknowledgement::Cancel,
+                }
+            }
+        };
+        self.state = match acknowledgement {
+            Acknowledgement::Cancel => SessionState::Finished,
+            _ => SessionState::Running(step),
+        };
+        acknowledgement
+    }
+
+    pub fn progress(&mut self, step: CalibrationStep, message: &str, fraction: Option<f64>) {
+        self.send(CalibrationEvent::Progress {
+            step: step,
+            message: String::from(message),
+            fraction: fraction,
+        });
+    }
+
+    // For long running steps to check between readings. Continue is ignored here.
+    pub fn interrupted(&mut self) -> Option<Acknowledgement> {
+        if self.is_cancelled() {
+            return Some(Acknowledgement::Cancel);
+        }
+        match self.acknowledgements.try_recv() {
+            Ok(Acknowledgement::Continue) => None,
+            Ok(Acknowledgement::Cancel) | Err(TryRecvError::Disconnected) => {
+                self.state = SessionState::Finished;
+                Some(Acknowledgement::Cancel)
+            }
+            Ok(Acknowledgement::Skip) => Some(Acknowledgement::Skip),
+            Err(TryRecvError::Empty) => None,
+        }
+    }
+
+    // Prompt for a step that ends it if it isn't acknowledged with continue.
+    pub fn confirm(&mut self, step: CalibrationStep, message: &str) -> Result<(), StepReport> {
+        match self.prompt(step, message) {
+            Acknowledgement::Continue => Ok(()),
+            Acknowledgement::Skip => Err(StepReport::ended(step, StepOutcome::Skipped)),
+            Acknowledgement::Cancel => Err(StepReport::ended(step, StepOutcome::Cancelled)),
+        }
+    }
+
+    // Between readings, ends the step if it was skipped or cancelled meanwhile.
+    pub fn check(&mut self, step: CalibrationStep) -> Result<(), StepReport> {
+        match self.interrupted() {
+            None => Ok(()),
+            Some(Acknowledgement::Skip) => Err(StepReport::ended(step, StepOutcome::Skipped)),
+            Some(_) => Err(StepReport::ended(step, StepOutcome::Cancelled)),
+        }
+    }
+
+    pub fn finish_step(&mut self, report: StepReport) {
+        if !self.is_cancelled() {
+            self.state = SessionState::Idle;
+        }
+        self.reports.push(report.clone());
+        self.send(CalibrationEvent::StepFinished(report));
+    }
+
+    pub fn finish(&mut self, saved: bool) -> CalibrationReport {
+        let report = CalibrationReport {
+            steps: self.reports.clone(),
+            saved: saved,
+        };
+        self.send(CalibrationEvent::Finished(report.clone()));
+        self.state = SessionState::Finished;
+        report
+    }
+}
+
+// Drives a session from the terminal: prints what it sends and answers prompts with
+// the next line typed. Returns when the session finishes.
+pub fn console_link(link: CalibrationLink) -> JoinHandle<Option<CalibrationReport>> {
+    thread::spawn(move || {
+        for event in link.events.iter() {
+            match event {
+                CalibrationEvent::Prompt { message, .. } => {
+                    println!("{}", message);
+                    println!("Press enter to continue, or type skip or cancel...");
+                    let acknowledgement = loop {
+                        let mut input = String::new();
+                        match io::stdin().read_line(&mut input) {
+                            Ok(0) | Err(_) => break Acknowledgement::Cancel,
+                            Ok(_) => {}
+                        };
+                        match Acknowledgement::parse(&input) {
+                            Some(acknowledgement) => break acknowledgement,
+                            None => println!("Press enter, or type skip or cancel."),
+                        };
+                    };
+                    match link.acknowledgements.send(acknowledgement) {
+                        Ok(()) => {}
+                        Err(_) => return None,
+                    };
+                }
+                CalibrationEvent::Progress { message, .. } => println!("{}", message),
+                CalibrationEvent::StepFinished(report) => print!("{}", report),
+                CalibrationEvent::Finished(report) => {
+                    if report.saved {
+                        println!("Calibrations saved.");
+                    } else {
+                        println!("Nothing was saved.");
+                    }
+                    return Some(report);
+                }
+            };
+        }
+        None
+    })
+}
+
+// Drives a session from a websocket client on the debug port, like the debug page.
+// Events are sent as JSON and the client's messages are read as acknowledgements.
+// The session is cancelled if the client leaves.
+pub fn network_link(
+    link: CalibrationLink,
+    port: u16,
+) -> Result<JoinHandle<Option<CalibrationReport>>, String> {
+    let (outgoing, incoming) = serve_calibration(port)?;
+    let CalibrationLink {
+        events,
+        acknowledgements,
+    } = link;
+    thread::spawn(move || {
+        for text in incoming.iter() {
+            match Acknowledgement::parse(&text) {
+                Some(acknowledgement) => match acknowledgements.send(acknowledgement) {
+                    Ok(()) => {}
+                    Err(_) => return,
+                },
+                None => {}
+            };
+        }
+    });
+    Ok(thread::spawn(move || {
+        for event in events.iter() {
+            let finished = match event {
+                CalibrationEvent::Finished(ref report) => Some(report.clone()),
+                _ => None,
+            };
+            match outgoing.send(event.to_json()) {
+                Ok(()) => {}
+                Err(_) => return None,
+            };
+            if finished.is_some() {
+                return finished;
+            }
+        }
+        None
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_continues_are_dropped_before_a_prompt() {
+        let (mut session, link) = CalibrationSession::new();
+        link.acknowledgements.send(Acknowledgement::Continue).unwrap();
+        link.acknowledgements.send(Acknowledgement::Continue).unwrap();
+        let answer = thread::spawn(move || {
+            match link.events.recv().unwrap() {
+                CalibrationEvent::Prompt { step, .. } => {
+                    assert_eq!(step, CalibrationStep::Gyroscope)
+                }
+                other => panic!("{:?}", other),
+            };
+            link.acknowledgements.send(Acknowledgement::Skip).unwrap();
+            link
+        });
+        let acknowledgement = session.prompt(CalibrationStep::Gyroscope, "Keep it still.");
+        assert_eq!(acknowledgement, Acknowledgement::Skip);
+        assert_eq!(session.current_step(), Some(CalibrationStep::Gyroscope));
+        answer.join().unwrap();
+    }
+
+    #[test]
+    fn a_cancel_sent_before_a_prompt_is_kept() {
+        let (mut session, link) = CalibrationSession::new();
+        link.acknowledgements.send(Acknowledgement::Skip).unwrap();
+        link.acknowledgements.send(Acknowledgement::Cancel).unwrap();
+        link.acknowledgements.send(Acknowledgement::Continue).unwrap();
+        assert_eq!(
+            session.prompt(CalibrationStep::Magnetometer, "Rotate it."),
+            Acknowledgement::Cancel
+        );
+        assert!(session.is_cancelled());
+        // Nothing was asked.
+        assert!(link.events.try_recv().is_err());
+        assert_eq!(
+            session.confirm(CalibrationStep::Gyroscope, "Keep it still.").unwrap_err().outcome,
+            StepOutcome::Cancelled
+        );
+    }
+
+    #[test]
+    fn a_skip_sent_before_a_prompt_answers_it() {
+        let (mut session, link) = CalibrationSession::new();
+        link.acknowledgements.send(Acknowledgement::Continue).unwrap();
+        link.acknowledgements.send(Acknowledgement::Skip).unwrap();
+        let report = session.confirm(CalibrationStep::Accelerometer, "Place it level.");
+        assert_eq!(report.unwrap_err().outcome, StepOutcome::Skipped);
+        assert!(!session.is_cancelled());
+    }
+
+    #[test]
+    fn a_closed_link_cancels() {
+        let (mut session, link) = CalibrationSession::new();
+        drop(link);
+        assert_eq!(
+            session.prompt(CalibrationStep::Thermal, "Start cold."),
+            Acknowledgement::Cancel
+        );
+        assert!(session.is_cancelled());
+    }
+
+    #[test]
+    fn events_are_json() {
+        let mut report = StepReport::new(CalibrationStep::Gyroscope);
+        report.metric("noise (rad/s)", 0.01);
+        report.metric("offset x (rad/s)", ::std::f64::NAN);
+        report.warn("Bumped \"once\".");
+        let json: Value =
+            ::serde_json::from_str(&CalibrationEvent::StepFinished(report).to_json()).unwrap();
+        assert_eq!(json["event"], "step");
+        assert_eq!(json["report"]["outcome"], "completed");
+        assert_eq!(json["report"]["metrics"]["noise (rad/s)"], 0.01);
+        assert!(json["report"]["metrics"]["offset x (rad/s)"].is_null());
+        assert_eq!(json["report"]["warnings"][0], "Bumped \"once\".");
+    }
+}
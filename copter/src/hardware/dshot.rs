@@ -0,0 +1,593 @@
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+
+use clock::{SharedClock, Timestamp};
+use configurations::Config;
+use configurations::config::{MotorProtocol, FULL_POWER, IDLE_POWER};
+use logger::ModuleLogger;
+
+use super::motors::{MotorCommand, MotorManager};
+use super::calibration_session::{CalibrationSession, CalibrationStep, StepOutcome, StepReport};
+use super::spi::SpiBus;
+#[cfg(target_arch = "arm")]
+use super::spi::open_spidev;
+
+// Throttle values 1 to 47 are reserved for special commands.
+pub const DSHOT_MIN_THROTTLE: u16 = 48;
+pub const DSHOT_MAX_THROTTLE: u16 = 2047;
+
+// ESCs arm after receiving motor stop for a while.
+const ARM_DURATION_MS: u64 = 2000;
+const FRAME_INTERVAL_MS: u64 = 1;
+// Beeps are long. The ESC ignores frames until the beep is done.
+const BEEP_DURATION_MS: u64 = 260;
+
+// Each DShot bit is sent as one SPI byte. 3/8 high is a 0 and 6/8 high is a 1,
+// which matches the 37.5% and 75% duty cycles of the protocol.
+const SPI_BITS_PER_DSHOT_BIT: u32 = 8;
+const SPI_ZERO_BIT: u8 = 0b1110_0000;
+const SPI_ONE_BIT: u8 = 0b1111_1100;
+// Low bytes after a frame so consecutive frames are separated.
+const SPI_FRAME_GAP_BYTES: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DShotSpeed {
+    DShot150,
+    DShot300,
+    DShot600,
+}
+
+impl DShotSpeed {
+    pub fn from_protocol(protocol: MotorProtocol) -> Option<DShotSpeed> {
+        match protocol {
+            MotorProtocol::DShot150 => Some(DShotSpeed::DShot150),
+            MotorProtocol::DShot300 => Some(DShotSpeed::DShot300),
+            MotorProtocol::DShot600 => Some(DShotSpeed::DShot600),
+            _ => None,
+        }
+    }
+
+    // Bits per second.
+    pub fn bitrate(&self) -> u32 {
+        match *self {
+            DShotSpeed::DShot150 => 150_000,
+            DShotSpeed::DShot300 => 300_000,
+            DShotSpeed::DShot600 => 600_000,
+        }
+    }
+
+    pub fn bit_period_ns(&self) -> u32 {
+        1_000_000_000 / self.bitrate()
+    }
+
+    // High time of a 1 bit.
+    pub fn t1h_ns(&self) -> u32 {
+        self.bit_period_ns() * 3 / 4
+    }
+
+    // High time of a 0 bit.
+    pub fn t0h_ns(&self) -> u32 {
+        self.bit_period_ns() * 3 / 8
+    }
+
+    pub fn frame_duration_ns(&self) -> u32 {
+        self.bit_period_ns() * 16
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DShotCommand {
+    MotorStop,
+    Beep(u8), // 1 to 5
+    EscInfo,
+    SpinDirection1,
+    SpinDirection2,
+    ThreeDModeOff,
+    ThreeDModeOn,
+    SaveSettings,
+    SpinDirectionNormal,
+    SpinDirectionReversed,
+}
+
+impl DShotCommand {
+    pub fn value(&self) -> u16 {
+        match *self {
+            DShotCommand::MotorStop => 0,
+            DShotCommand::Beep(tone) => tone.max(1).min(5) as u16,
+            DShotCommand::EscInfo => 6,
+            DShotCommand::SpinDirection1 => 7,
+            DShotCommand::SpinDirection2 => 8,
+            DShotCommand::ThreeDModeOff => 9,
+            DShotCommand::ThreeDModeOn => 10,
+            DShotCommand::SaveSettings => 12,
+            DShotCommand::SpinDirectionNormal => 20,
+            DShotCommand::SpinDirectionReversed => 21,
+        }
+    }
+
+    // Settings changes are only accepted after several identical frames.
+    pub fn repeat(&self) -> u32 {
+        match *self {
+            DShotCommand::MotorStop | DShotCommand::Beep(_) | DShotCommand::EscInfo => 1,
+            _ => 10,
+        }
+    }
+}
+
+// Four bit checksum over the 12 bit packet.
+pub fn checksum(packet: u16) -> u16 {
+    (packet ^ (packet >> 4) ^ (packet >> 8)) & 0x0F
+}
+
+// 11 bit value, 1 telemetry request bit, 4 bit checksum. Most significant bit first.
+pub fn encode_frame(value: u16, telemetry: bool) -> u16 {
+    let packet = ((value & 0x07FF) << 1) | (telemetry as u16);
+    (packet << 4) | checksum(packet)
+}
+
+// Returns the value and telemetry bit, or None if the checksum is wrong.
+pub fn decode_frame(frame: u16) -> Option<(u16, bool)> {
+    let packet = frame >> 4;
+    if checksum(packet) != frame & 0x0F {
+        return None;
+    }
+    Some((packet >> 1, packet & 1 == 1))
+}
+
+// Maps the IDLE_POWER to FULL_POWER motor power to a DShot throttle. Anything at or
+// below IDLE_POWER stops the motor, and so does a power that isn't a number.
+pub fn throttle_value(power: f64) -> u16 {
+    if !power.is_finite() || power <= IDLE_POWER {
+        return DShotCommand::MotorStop.value();
+    }
+    let fraction = ((power - IDLE_POWER) / (FULL_POWER - IDLE_POWER)).min(1.0);
+    let range = (DSHOT_MAX_THROTTLE - DSHOT_MIN_THROTTLE) as f64;
+    DSHOT_MIN_THROTTLE + (fraction * range).round() as u16
+}
+
+pub fn throttle_frame(power: f64) -> u16 {
+    encode_frame(throttle_value(power), false)
+}
+
+// Commands are only accepted with the telemetry bit set.
+pub fn command_frame(command: DShotCommand) -> u16 {
+    encode_frame(command.value(), true)
+}
+
+// SPI bytes for one frame, followed by the gap.
+pub fn spi_waveform(frame: u16) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(16 + SPI_FRAME_GAP_BYTES);
+    for bit in (0..16).rev() {
+        if frame & (1 << bit) != 0 {
+            bytes.push(SPI_ONE_BIT);
+        } else {
+            bytes.push(SPI_ZERO_BIT);
+        }
+    }
+    for _ in 0..SPI_FRAME_GAP_BYTES {
+        bytes.push(0);
+    }
+    bytes
+}
+
+pub fn spi_clock_hz(speed: DShotSpeed) -> u32 {
+    speed.bitrate() * SPI_BITS_PER_DSHOT_BIT
+}
+
+// Somewhere to put one frame per motor.
+pub trait DShotOutput {
+    fn write_frames(&mut self, frames: &[u16]) -> io::Result<()>;
+}
+
+// Each motor signal is the MOSI line of its own SPI bus.
+pub struct SpiDShotOutput<B: SpiBus> {
+    buses: Vec<B>,
+}
+
+impl<B: SpiBus> SpiDShotOutput<B> {
+    pub fn new(buses: Vec<B>) -> SpiDShotOutput<B> {
+        SpiDShotOutput { buses: buses }
+    }
+}
+
+impl<B: SpiBus> DShotOutput for SpiDShotOutput<B> {
+    fn write_frames(&mut self, frames: &[u16]) -> io::Result<()> {
+        for (bus, frame) in self.buses.iter_mut().zip(frames.iter()) {
+            let tx = spi_waveform(*frame);
+            let mut rx = vec![0u8; tx.len()];
+            bus.transfer(&tx, &mut rx)?;
+        }
+        Ok(())
+    }
+}
+
+// Keeps every frame written so encoding can be checked without hardware. Clones share
+// the frames, so they can be read after the output is given to a motor manager.
+#[derive(Clone)]
+pub struct MockDShotOutput {
+    frames: Rc<RefCell<Vec<Vec<u16>>>>,
+}
+
+impl MockDShotOutput {
+    pub fn new() -> MockDShotOutput {
+        MockDShotOutput {
+            frames: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    // Every write so far, one frame per motor in each.
+    pub fn frames(&self) -> Vec<Vec<u16>> {
+        self.frames.borrow().clone()
+    }
+}
+
+impl DShotOutput for MockDShotOutput {
+    fn write_frames(&mut self, frames: &[u16]) -> io::Result<()> {
+        self.frames.borrow_mut().push(frames.to_vec());
+        Ok(())
+    }
+}
+
+#[cfg(target_arch = "arm")]
+fn get_dshot_output(
+    logger: &ModuleLogger,
+    outputs: &Option<Vec<String>>,
+    motors: usize,
+    speed: DShotSpeed,
+) -> Result<Box<DShotOutput>, ()> {
+    let outputs = match *outputs {
+        Some(ref outputs) if outputs.len() == motors => outputs,
+        _ => {
+            logger.error(&format!(
+                "DShot needs one entry in dshot_outputs for each of the {} motors.",
+                motors
+            ));
+            return Err(());
+        }
+    };
+
+    let mut buses = Vec::new();
+    for path in outputs {
+        match open_spidev(path, spi_clock_hz(speed)) {
+            Ok(spidev) => buses.push(spidev),
+            Err(_) => {
+                logger.error(&format!("Couldn't open DShot output {}.", path));
+                return Err(());
+            }
+        }
+    }
+    Ok(Box::new(SpiDShotOutput::new(buses)))
+}
+
+#[cfg(not(target_arch = "arm"))]
+fn get_dshot_output(
+    _logger: &ModuleLogger,
+    _outputs: &Option<Vec<String>>,
+    _motors: usize,
+    _speed: DShotSpeed,
+) -> Result<Box<DShotOutput>, ()> {
+    Ok(Box::new(MockDShotOutput::new()))
+}
+
+pub struct DShotMotorManager {
+    output: Box<DShotOutput>,
+    speed: DShotSpeed,
+    motors: usize,
+    clock: SharedClock,
+    // Motor stop is written on every refresh until then, so arming and stopping
+    // don't hold up the hardware loop.
+    stop_until: Option<Timestamp>,
+    arming: bool,
+    last_frame: Option<Timestamp>,
+    logger: ModuleLogger,
+}
+
+impl DShotMotorManager {
+    pub fn new(config: &Config, clock: SharedClock) -> Result<DShotMotorManager, ()> {
+        let logger = ModuleLogger::new(
+            "Motors",
+            Some("Check your DShot outputs or change your configuration."),
+        );
+        logger.log("Initializing DShot Motor Manager.");
+
+        let speed = match config.hardware.motors.protocol {
+            Some(protocol) => match DShotSpeed::from_protocol(protocol) {
+                Some(speed) => speed,
+                None => {
+                    logger.error("Motor protocol is not DShot.");
+                    return Err(());
+                }
+            },
+            None => {
+                logger.error("Motor protocol is not DShot.");
+                return Err(());
+            }
+        };
+
+        let motors = config.hardware.motors.pins.len();
+        let output = get_dshot_output(
+            &logger,
+            &config.hardware.motors.dshot_outputs,
+            motors,
+            speed,
+        )?;
+        Ok(DShotMotorManager::with_output(output, speed, motors, clock))
+    }
+
+    pub fn with_output(
+        output: Box<DShotOutput>,
+        speed: DShotSpeed,
+        motors: usize,
+        clock: SharedClock,
+    ) -> DShotMotorManager {
+        DShotMotorManager {
+            output: output,
+            speed: speed,
+            motors: motors,
+            clock: clock,
+            stop_until: None,
+            arming: false,
+            last_frame: None,
+            logger: ModuleLogger::new("Motors", None),
+        }
+    }
+
+    pub fn speed(&self) -> DShotSpeed {
+        self.speed
+    }
+
+    fn write_frames(&mut self, frames: &[u16]) {
+        match self.output.write_frames(frames) {
+            Ok(()) => {}
+            Err(e) => {
+                self.logger.error("Couldn't write DShot frames.");
+                panic!(e.to_string());
+            }
+        }
+        self.last_frame = Some(self.clock.now());
+    }
+
+    // Starts writing motor stop. Frames go out on each refresh until the duration is up.
+    fn schedule_stop(&mut self, duration_ms: u64) {
+        self.stop_until = Some(self.clock.now() + Duration::from_millis(duration_ms));
+        self.last_frame = None;
+        self.refresh();
+    }
+
+    // Only valid while the motors are stopped.
+    pub fn send_command(&mut self, command: DShotCommand) {
+        let frames = vec![command_frame(command); self.motors];
+        for _ in 0..command.repeat() {
+            self.write_frames(&frames);
+            self.clock.sleep(Duration::from_millis(FRAME_INTERVAL_MS));
+        }
+        match command {
+            DShotCommand::Beep(_) => self.clock.sleep(Duration::from_millis(BEEP_DURATION_MS)),
+            _ => {}
+        };
+    }
+}
+
+impl MotorManager for DShotMotorManager {
+    fn arm(&mut self) {
+        self.logger.log("Arming Motors.");
+        self.arming = true;
+        self.schedule_stop(ARM_DURATION_MS);
+    }
+
+    // DShot throttle is digital, so the ESCs have no endpoints to learn.
+    fn calibrate(&mut self, session: &mut CalibrationSession) -> StepReport {
+        let mut report = StepReport::ended(CalibrationStep::Motors, StepOutcome::Skipped);
+        report.warn("DShot ESCs don't need throttle calibration.");
+        report
+    }
+
+    fn terminate(&mut self) {
+        self.logger.log("Terminating Motors.");
+        self.arming = false;
+        self.schedule_stop(ARM_DURATION_MS);
+        self.logger.success("Motors Off.");
+    }
+
+    fn refresh(&mut self) {
+        let stop_until = match self.stop_until {
+            Some(stop_until) => stop_until,
+            None => return,
+        };
+        let now = self.clock.now();
+        if now >= stop_until {
+            self.stop_until = None;
+            if self.arming {
+                self.arming = false;
+                self.logger.success("Motors Armed.");
+            }
+            return;
+        }
+        let due = match self.last_frame {
+            Some(last) => now.duration_since(last) >= Duration::from_millis(FRAME_INTERVAL_MS),
+            None => true,
+        };
+        if due {
+            let frames = vec![encode_frame(DShotCommand::MotorStop.value(), false); self.motors];
+            self.write_frames(&frames);
+        }
+    }
+
+    fn is_arming(&self) -> bool {
+        self.arming
+    }
+
+    // Powers are ignored while motor stop is scheduled.
+    fn set_powers(&mut self, powers: [f64; 4]) {
+        if self.stop_until.is_some() {
+            self.refresh();
+            return;
+        }
+        let frames: Vec<u16> = powers
+            .iter()
+            .take(self.motors)
+            .map(|power| throttle_frame(*power))
+            .collect();
+        self.write_frames(&frames);
+    }
+
+    fn process_command(&mut self, command: MotorCommand) {
+        match command {
+            MotorCommand::PowerDown => {
+                self.terminate();
+            }
+            MotorCommand::Arm => {
+                self.arm();
+            }
+            MotorCommand::Initialized => {}
+            MotorCommand::SetPower(m1, m2, m3, m4) => {
+                self.set_powers([m1, m2, m3, m4]);
+            }
+            MotorCommand::SetThrust(..) => {
+                self.logger.error("Thrust commands need a thrust model.");
+            }
+            MotorCommand::DShot(command) => {
+                self.send_command(command);
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clock::SimulatedClock;
+
+    const STOP_FRAME: u16 = 0x0000;
+
+    #[test]
+    fn checksum_covers_the_packet() {
+        // Throttle 1046 without telemetry, from the protocol description.
+        assert_eq!(encode_frame(1046, false), 0b1000_0010_1100_0110);
+        assert_eq!(checksum(0), 0);
+        for value in [0, 48, 1046, 2047].iter() {
+            for telemetry in [false, true].iter() {
+                let frame = encode_frame(*value, *telemetry);
+                assert_eq!(decode_frame(frame), Some((*value, *telemetry)));
+                // Any single flipped bit is caught.
+                for bit in 0..16 {
+                    assert_eq!(decode_frame(frame ^ (1 << bit)), None);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn telemetry_bit_is_below_the_checksum() {
+        assert_eq!(encode_frame(0, true) >> 4, 1);
+        assert_eq!(encode_frame(1046, true) & 0x10, 0x10);
+        assert_eq!(encode_frame(1046, false) & 0x10, 0);
+        assert_eq!(decode_frame(command_frame(DShotCommand::Beep(3))), Some((3, true)));
+        assert_eq!(
+            decode_frame(command_frame(DShotCommand::SpinDirectionReversed)),
+            Some((21, true))
+        );
+        assert_eq!(decode_frame(throttle_frame(2000.0)), Some((DSHOT_MAX_THROTTLE, false)));
+    }
+
+    #[test]
+    fn powers_map_to_throttle() {
+        let stop = DShotCommand::MotorStop.value();
+        assert_eq!(throttle_value(900.0), stop);
+        assert_eq!(throttle_value(1000.0), stop);
+        assert_eq!(throttle_value(1000.001), DSHOT_MIN_THROTTLE);
+        assert_eq!(throttle_value(1500.0), 1048);
+        assert_eq!(throttle_value(2000.0), DSHOT_MAX_THROTTLE);
+        assert_eq!(throttle_value(2500.0), DSHOT_MAX_THROTTLE);
+        // Never full throttle by accident.
+        assert_eq!(throttle_value(::std::f64::NAN), stop);
+        assert_eq!(throttle_value(::std::f64::INFINITY), stop);
+        assert_eq!(throttle_value(::std::f64::NEG_INFINITY), stop);
+    }
+
+    #[test]
+    fn frame_bits_are_sent_most_significant_first() {
+        let bytes = spi_waveform(0b1000_0010_1100_0110);
+        assert_eq!(bytes.len(), 16 + SPI_FRAME_GAP_BYTES);
+        let bits: Vec<u8> = bytes[..16]
+            .iter()
+            .map(|byte| match *byte {
+                SPI_ONE_BIT => 1,
+                SPI_ZERO_BIT => 0,
+                other => panic!("{:08b} isn't a DShot bit.", other),
+            })
+            .collect();
+        assert_eq!(bits, vec![1, 0, 0, 0, 0, 0, 1, 0, 1, 1, 0, 0, 0, 1, 1, 0]);
+        assert!(bytes[16..].iter().all(|byte| *byte == 0));
+        // 3/8 and 6/8 of each bit period is high.
+        assert_eq!(SPI_ZERO_BIT.count_ones(), 3);
+        assert_eq!(SPI_ONE_BIT.count_ones(), 6);
+        assert_eq!(spi_clock_hz(DShotSpeed::DShot600), 4_800_000);
+    }
+
+    #[test]
+    fn arming_writes_stop_without_blocking() {
+        let clock = SimulatedClock::shared();
+        let output = MockDShotOutput::new();
+        let mut motors = DShotMotorManager::with_output(
+            Box::new(output.clone()),
+            DShotSpeed::DShot300,
+            4,
+            clock.clone(),
+        );
+
+        // A simulated clock that isn't advanced would hang a sleeping arm.
+        motors.arm();
+        assert!(motors.is_arming());
+        assert_eq!(output.frames(), vec![vec![STOP_FRAME; 4]]);
+
+        // Powers are ignored until the ESCs are armed. Stop goes out once a frame interval.
+        motors.set_powers([1500.0; 4]);
+        assert_eq!(output.frames().len(), 1);
+        clock.advance(Duration::from_millis(FRAME_INTERVAL_MS));
+        motors.set_powers([1500.0; 4]);
+        motors.refresh();
+        assert_eq!(output.frames().len(), 2);
+        assert_eq!(output.frames()[1], vec![STOP_FRAME; 4]);
+
+        clock.advance(Duration::from_millis(ARM_DURATION_MS));
+        motors.refresh();
+        assert!(!motors.is_arming());
+        assert_eq!(output.frames().len(), 2);
+
+        motors.set_powers([1500.0, 2000.0, ::std::f64::NAN, 1000.0]);
+        assert_eq!(
+            output.frames()[2],
+            vec![
+                throttle_frame(1500.0),
+                throttle_frame(2000.0),
+                STOP_FRAME,
+                STOP_FRAME,
+            ]
+        );
+    }
+
+    #[test]
+    fn terminating_keeps_writing_stop() {
+        let clock = SimulatedClock::shared();
+        let output = MockDShotOutput::new();
+        let mut motors = DShotMotorManager::with_output(
+            Box::new(output.clone()),
+            DShotSpeed::DShot600,
+            2,
+            clock.clone(),
+        );
+        motors.set_powers([1500.0; 4]);
+
+        motors.terminate();
+        assert!(!motors.is_arming());
+        clock.advance(Duration::from_millis(FRAME_INTERVAL_MS));
+        motors.set_powers([1500.0; 4]);
+        let frames = output.frames();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0], vec![throttle_frame(1500.0); 2]);
+        assert_eq!(&frames[1..], &[vec![STOP_FRAME; 2], vec![STOP_FRAME; 2]]);
+    }
+}
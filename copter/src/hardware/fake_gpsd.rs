@@ -0,0 +1,405 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use clock::{SharedClock, Timestamp};
+use configurations::config::GpsProtocol;
+use logger::ModuleLogger;
+
+use super::gps::{GPSData, GpsDecoder, GpsFix};
+use super::gpsd::{parse_report, sky_report, tpv_report, GpsdReport};
+
+const FAKE_DEVICE: &str = "/dev/fake_gps";
+// Used when a log has no times.
+const DEFAULT_REPLAY_PERIOD: f64 = 1.0;
+const VERSION_REPORT: &str = concat!(
+    "{\"class\":\"VERSION\",\"release\":\"fake\",\"rev\":\"fake\",",
+    "\"proto_major\":3,\"proto_minor\":11}"
+);
+const DEVICES_REPORT: &str =
+    "{\"class\":\"DEVICES\",\"devices\":[{\"class\":\"DEVICE\",\"path\":\"/dev/fake_gps\"}]}";
+const WATCH_REPORT: &str = "{\"class\":\"WATCH\",\"enable\":true,\"json\":true}";
+
+// Report lines sent together, offset seconds after the replay starts.
+#[derive(Debug, Clone)]
+pub struct Epoch {
+    pub offset: f64,
+    pub lines: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct GpsdScript {
+    epochs: Vec<Epoch>,
+}
+
+// Offset of a report from the first time in the script, or from its index if the
+// script has no times.
+fn offset(time: Option<f64>, first_time: &mut Option<f64>, index: usize) -> f64 {
+    match time {
+        Some(time) => time - *first_time.get_or_insert(time),
+        None => index as f64 * DEFAULT_REPLAY_PERIOD,
+    }
+}
+
+impl GpsdScript {
+    pub fn from_fixes(fixes: &[GPSData]) -> GpsdScript {
+        let mut first_time = None;
+        let epochs = fixes
+            .iter()
+            .enumerate()
+            .map(|(index, data)| {
+                let mut lines: Vec<String> = sky_report(data, FAKE_DEVICE).into_iter().collect();
+                lines.push(tpv_report(data, FAKE_DEVICE));
+                Epoch {
+                    offset: offset(data.time, &mut first_time, index),
+                    lines: lines,
+                }
+            })
+            .collect();
+        GpsdScript { epochs: epochs }
+    }
+
+    // One JSON report per line, as saved by gpspipe -w. An epoch ends at each TPV.
+    pub fn from_gpsd_log(log: &str) -> GpsdScript {
+        let mut epochs = Vec::new();
+        let mut lines = Vec::new();
+        let mut first_time = None;
+        for line in log.lines() {
+            match parse_report(line) {
+                Some(GpsdReport::Tpv(tpv)) => {
+                    lines.push(line.to_string());
+                    let index = epochs.len();
+                    epochs.push(Epoch {
+                        offset: offset(tpv.time, &mut first_time, index),
+                        lines: lines.split_off(0),
+                    });
+                }
+                Some(GpsdReport::Sky(_)) => lines.push(line.to_string()),
+                // The server sends its own VERSION.
+                _ => {}
+            };
+        }
+        if !lines.is_empty() {
+            let offset = match epochs.last() {
+                Some(epoch) => epoch.offset + DEFAULT_REPLAY_PERIOD,
+                None => 0.0,
+            };
+            epochs.push(Epoch {
+                offset: offset,
+                lines: lines,
+            });
+        }
+        GpsdScript { epochs: epochs }
+    }
+
+    pub fn from_nmea_log(log: &[u8]) -> GpsdScript {
+        let mut decoder = GpsDecoder::new(GpsProtocol::NMEA);
+        GpsdScript::from_fixes(&decoder.decode(log, Timestamp::zero()))
+    }
+
+    // A CSV with time (s), latitude and longitude columns and optional altitude and
+    // satellites columns. Rows without a latitude have no fix.
+    pub fn from_track(track: &str) -> Result<GpsdScript, String> {
+        let mut lines = track.lines().filter(|line| !line.trim().is_empty());
+        let header: Vec<String> = match lines.next() {
+            Some(line) => line.split(',').map(|name| name.trim().to_lowercase()).collect(),
+            None => return Err(String::from("The track is empty.")),
+        };
+        let column = |name: &str| header.iter().position(|column| column == name);
+        let (time_column, latitude_column, longitude_column) =
+            match (column("time"), column("latitude"), column("longitude")) {
+                (Some(time), Some(latitude), Some(longitude)) => (time, latitude, longitude),
+                _ => {
+                    return Err(String::from(
+                        "The track needs time, latitude and longitude columns.",
+                    ))
+                }
+            };
+        let altitude_column = column("altitude");
+        let satellites_column = column("satellites");
+
+        let mut epochs = Vec::new();
+        for (number, line) in lines.enumerate() {
+            let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+            // Line 1 is the header.
+            let parse = |index: Option<usize>| -> Result<Option<f64>, String> {
+                match index.and_then(|index| fields.get(index)) {
+                    Some(field) if !field.is_empty() => match field.parse() {
+                        Ok(value) => Ok(Some(value)),
+                        Err(_) => Err(format!("Line {}: {} is not a number.", number + 2, field)),
+                    },
+                    _ => Ok(None),
+                }
+            };
+
+            let time = match parse(Some(time_column))? {
+                Some(time) => time,
+                None => return Err(format!("Line {}: missing time.", number + 2)),
+            };
+            let altitude = parse(altitude_column)?;
+            let mut data = GPSData {
+                satellites: parse(satellites_column)?.map(|satellites| satellites as u32),
+                ..GPSData::no_fix(Timestamp::zero())
+            };
+            match (parse(Some(latitude_column))?, parse(Some(longitude_column))?) {
+                (Some(latitude), Some(longitude)) => {
+                    data.latitude = latitude;
+                    data.longitude = longitude;
+                    data.altitude = altitude;
+                    data.fix_type = match altitude {
+                        Some(_) => GpsFix::Fix3D,
+                        None => GpsFix::Fix2D,
+                    };
+                }
+                _ => {}
+            };
+
+            let mut lines: Vec<String> = sky_report(&data, FAKE_DEVICE).into_iter().collect();
+            lines.push(tpv_report(&data, FAKE_DEVICE));
+            epochs.push(Epoch {
+                offset: time,
+                lines: lines,
+            });
+        }
+        Ok(GpsdScript { epochs: epochs })
+    }
+
+    // Picks the format from the first character: { for gpsd logs, $ for NMEA and
+    // anything else for a track.
+    pub fn load(path: &str) -> Result<GpsdScript, String> {
+        let mut contents = Vec::new();
+        match File::open(path).and_then(|mut file| file.read_to_end(&mut contents)) {
+            Ok(_) => {}
+            Err(e) => return Err(format!("Couldn't read {}: {}", path, e)),
+        };
+        let script = match contents.iter().find(|byte| !(**byte as char).is_whitespace()) {
+            Some(&b'$') => GpsdScript::from_nmea_log(&contents),
+            Some(&first) => match String::from_utf8(contents) {
+                Ok(ref log) if first == b'{' => GpsdScript::from_gpsd_log(log),
+                Ok(ref track) => GpsdScript::from_track(track)?,
+                Err(_) => return Err(format!("{} isn't a gpsd log, NMEA log or track.", path)),
+            },
+            None => return Err(format!("{} is empty.", path)),
+        };
+        if script.epochs.is_empty() {
+            return Err(format!("{} has no reports.", path));
+        }
+        Ok(script)
+    }
+
+    pub fn len(&self) -> usize {
+        self.epochs.len()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReplayOptions {
+    pub repeat: bool,
+    // Hang up on the client after this many epochs, to exercise reconnecting.
+    pub disconnect_after: Option<usize>,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> ReplayOptions {
+        ReplayOptions {
+            repeat: true,
+            disconnect_after: None,
+        }
+    }
+}
+
+// Speaks enough of the gpsd protocol for the GPS thread: VERSION on connect, and
+// the script as TPV and SKY reports once the client sends WATCH. Every client gets
+// the script from the start.
+pub struct FakeGpsd {
+    address: SocketAddr,
+}
+
+impl FakeGpsd {
+    // Listens on an unused local port.
+    pub fn start(
+        clock: SharedClock,
+        script: GpsdScript,
+        options: ReplayOptions,
+    ) -> io::Result<FakeGpsd> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+
+        thread::Builder::new()
+            .name(String::from("Fake gpsd"))
+            .spawn(move || {
+                let logger = ModuleLogger::new("Fake gpsd", None);
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(_) => continue,
+                    };
+                    let clock = clock.clone();
+                    let script = script.clone();
+                    thread::spawn(move || {
+                        let logger = ModuleLogger::new("Fake gpsd", None);
+                        match serve(stream, clock, &script, options) {
+                            Ok(()) => logger.log("Client disconnected."),
+                            Err(e) => logger.log(&format!("Client disconnected: {}", e)),
+                        }
+                    });
+                }
+                logger.error("Stopped listening.");
+            })?;
+
+        Ok(FakeGpsd { address: address })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+fn serve(
+    mut stream: TcpStream,
+    clock: SharedClock,
+    script: &GpsdScript,
+    options: ReplayOptions,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    writeln!(stream, "{}", VERSION_REPORT)?;
+
+    // Like gpsd, nothing is reported until the client asks to watch.
+    loop {
+        let mut command = String::new();
+        if reader.read_line(&mut command)? == 0 {
+            return Ok(());
+        }
+        if command.starts_with("?WATCH") {
+            break;
+        }
+    }
+    writeln!(stream, "{}", DEVICES_REPORT)?;
+    writeln!(stream, "{}", WATCH_REPORT)?;
+
+    let mut sent = 0;
+    let mut start = clock.now();
+    loop {
+        for epoch in &script.epochs {
+            let due = start + Duration::from_millis((epoch.offset.max(0.0) * 1000.0) as u64);
+            let now = clock.now();
+            if due > now {
+                clock.sleep(due.duration_since(now));
+            }
+            for line in &epoch.lines {
+                writeln!(stream, "{}", line)?;
+            }
+
+            sent += 1;
+            if Some(sent) == options.disconnect_after {
+                return Ok(());
+            }
+        }
+        if !options.repeat {
+            break;
+        }
+        // The next pass starts a period after the last epoch.
+        let length = match script.epochs.last() {
+            Some(epoch) => epoch.offset.max(0.0) + DEFAULT_REPLAY_PERIOD,
+            None => DEFAULT_REPLAY_PERIOD,
+        };
+        start = start + Duration::from_millis((length * 1000.0) as u64);
+    }
+
+    // Stay connected like a gpsd whose receiver went quiet.
+    let mut command = String::new();
+    while reader.read_line(&mut command)? > 0 {
+        command.clear();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::gps::gpsd_gps;
+    use clock::{Clock, SimulatedClock};
+    use std::sync::mpsc::{channel, Receiver, TryRecvError};
+
+    const STEP_MILLISECONDS: u64 = 100;
+    const MAX_SIMULATED_SECONDS: u64 = 60;
+
+    // Time only moves while nothing has arrived and some thread is sleeping on the clock,
+    // so the replay and the reconnect back-off are stepped through without waiting on them.
+    fn next_fix(clock: &SimulatedClock, gps_rx: &Receiver<GPSData>) -> GPSData {
+        let start = clock.now();
+        loop {
+            match gps_rx.try_recv() {
+                Ok(data) => return data,
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => panic!("The GPS thread stopped."),
+            };
+            assert!(
+                clock.now().duration_since(start) < Duration::from_secs(MAX_SIMULATED_SECONDS),
+                "No fix within {} simulated seconds.",
+                MAX_SIMULATED_SECONDS
+            );
+            clock.wait_for_sleepers(1);
+            clock.advance(Duration::from_millis(STEP_MILLISECONDS));
+        }
+    }
+
+    const TRACK: &str = "time,latitude,longitude,altitude,satellites
+0,47.620512,-122.349358,56.3,9
+1,47.620601,-122.349402,57.1,10
+2,47.620688,-122.349455,58.0,11
+";
+
+    #[test]
+    fn tracks_load_as_epochs() {
+        assert_eq!(GpsdScript::from_track(TRACK).unwrap().len(), 3);
+        assert!(GpsdScript::from_track("time,latitude\n0,47.6\n").is_err());
+        assert!(GpsdScript::from_track("time,latitude,longitude\n0,north,1\n").is_err());
+
+        // A row without a position is an epoch without a fix.
+        let script = GpsdScript::from_track("time,latitude,longitude\n0,,\n").unwrap();
+        assert_eq!(script.len(), 1);
+        assert!(script.epochs[0].lines[0].contains("\"mode\":1"));
+    }
+
+    #[test]
+    fn gps_thread_reconnects_after_a_disconnect() {
+        let clock = SimulatedClock::shared();
+        let script = GpsdScript::from_track(TRACK).unwrap();
+        let options = ReplayOptions {
+            repeat: false,
+            disconnect_after: Some(2),
+        };
+        let fake = FakeGpsd::start(clock.clone(), script, options).unwrap();
+
+        let (gps_tx, gps_rx) = channel();
+        let address = fake.address().to_string();
+        let thread_clock = clock.clone();
+        thread::spawn(move || gpsd_gps(thread_clock, gps_tx, address));
+
+        // Every connection starts the script again and ends after two epochs.
+        let expected = [
+            (47.620512, -122.349358, 56.3, 9),
+            (47.620601, -122.349402, 57.1, 10),
+            (47.620512, -122.349358, 56.3, 9),
+            (47.620601, -122.349402, 57.1, 10),
+        ];
+        let mut previous = None;
+        for &(latitude, longitude, altitude, satellites) in expected.iter() {
+            let data = next_fix(&clock, &gps_rx);
+            assert_eq!(data.fix_type, GpsFix::Fix3D);
+            assert_eq!(data.latitude, latitude);
+            assert_eq!(data.longitude, longitude);
+            assert_eq!(data.altitude, Some(altitude));
+            assert_eq!(data.satellites, Some(satellites));
+            match previous {
+                Some(previous) => assert!(data.received >= previous),
+                None => {}
+            };
+            previous = Some(data.received);
+        }
+    }
+}
@@ -0,0 +1,472 @@
+use std::f64::consts::PI;
+
+use na::Vector3;
+
+use configurations::config::Filters;
+use logger::ModuleLogger;
+
+const BUTTERWORTH_Q: f64 = 0.7071067811865476;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BiquadKind {
+    LowPass,
+    Notch,
+}
+
+// Second order IIR section, Direct Form II transposed.
+// Coefficients follow the RBJ audio EQ cookbook.
+#[derive(Debug, Clone, Copy)]
+pub struct Biquad {
+    kind: BiquadKind,
+    frequency: f64,
+    q: f64,
+    sample_rate: f64,
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    z1: f64,
+    z2: f64,
+}
+
+impl Biquad {
+    pub fn low_pass(cutoff: f64, sample_rate: f64) -> Result<Biquad, ()> {
+        Biquad::new(BiquadKind::LowPass, cutoff, BUTTERWORTH_Q, sample_rate)
+    }
+
+    pub fn notch(center: f64, q: f64, sample_rate: f64) -> Result<Biquad, ()> {
+        Biquad::new(BiquadKind::Notch, center, q, sample_rate)
+    }
+
+    fn new(kind: BiquadKind, frequency: f64, q: f64, sample_rate: f64) -> Result<Biquad, ()> {
+        let mut biquad = Biquad {
+            kind: kind,
+            frequency: frequency,
+            q: q,
+            sample_rate: sample_rate,
+            b0: 1.0,
+            b1: 0.0,
+            b2: 0.0,
+            a1: 0.0,
+            a2: 0.0,
+            z1: 0.0,
+            z2: 0.0,
+        };
+        biquad.compute_coefficients()?;
+        Ok(biquad)
+    }
+
+    fn compute_coefficients(&mut self) -> Result<(), ()> {
+        // The frequency has to be below Nyquist for the filter to be stable.
+        if self.sample_rate <= 0.0 || self.frequency <= 0.0
+            || self.frequency >= self.sample_rate / 2.0 || self.q <= 0.0
+        {
+            return Err(());
+        }
+
+        let omega = 2.0 * PI * self.frequency / self.sample_rate;
+        let cos_omega = omega.cos();
+        let alpha = omega.sin() / (2.0 * self.q);
+        let a0 = 1.0 + alpha;
+
+        let (b0, b1, b2) = match self.kind {
+            BiquadKind::LowPass => (
+                (1.0 - cos_omega) / 2.0,
+                1.0 - cos_omega,
+                (1.0 - cos_omega) / 2.0,
+            ),
+            BiquadKind::Notch => (1.0, -2.0 * cos_omega, 1.0),
+        };
+
+        self.b0 = b0 / a0;
+        self.b1 = b1 / a0;
+        self.b2 = b2 / a0;
+        self.a1 = -2.0 * cos_omega / a0;
+        self.a2 = (1.0 - alpha) / a0;
+        Ok(())
+    }
+
+    // A copy moved to another frequency. The state is kept so a tracking notch doesn't
+    // glitch.
+    pub fn with_frequency(&self, frequency: f64) -> Result<Biquad, ()> {
+        let mut biquad = *self;
+        biquad.frequency = frequency;
+        biquad.compute_coefficients()?;
+        Ok(biquad)
+    }
+
+    pub fn with_sample_rate(&self, sample_rate: f64) -> Result<Biquad, ()> {
+        let mut biquad = *self;
+        biquad.sample_rate = sample_rate;
+        biquad.compute_coefficients()?;
+        Ok(biquad)
+    }
+
+    // Left unchanged if the frequency isn't possible.
+    pub fn set_frequency(&mut self, frequency: f64) -> Result<(), ()> {
+        *self = self.with_frequency(frequency)?;
+        Ok(())
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f64) -> Result<(), ()> {
+        *self = self.with_sample_rate(sample_rate)?;
+        Ok(())
+    }
+
+    pub fn frequency(&self) -> f64 {
+        self.frequency
+    }
+
+    pub fn apply(&mut self, input: f64) -> f64 {
+        let output = self.b0 * input + self.z1;
+        self.z1 = self.b1 * input - self.a1 * output + self.z2;
+        self.z2 = self.b2 * input - self.a2 * output;
+        output
+    }
+
+    pub fn reset(&mut self) {
+        self.z1 = 0.0;
+        self.z2 = 0.0;
+    }
+
+    // Magnitude of the transfer function at a given frequency.
+    pub fn gain(&self, frequency: f64) -> f64 {
+        let omega = 2.0 * PI * frequency / self.sample_rate;
+        let (cos1, sin1) = (omega.cos(), omega.sin());
+        let (cos2, sin2) = ((2.0 * omega).cos(), (2.0 * omega).sin());
+
+        let numerator_re = self.b0 + self.b1 * cos1 + self.b2 * cos2;
+        let numerator_im = -self.b1 * sin1 - self.b2 * sin2;
+        let denominator_re = 1.0 + self.a1 * cos1 + self.a2 * cos2;
+        let denominator_im = -self.a1 * sin1 - self.a2 * sin2;
+
+        ((numerator_re * numerator_re + numerator_im * numerator_im)
+            / (denominator_re * denominator_re + denominator_im * denominator_im))
+            .sqrt()
+    }
+}
+
+// The same chain of biquads applied independently to x, y and z.
+#[derive(Debug, Clone)]
+pub struct AxisFilter {
+    stages: Vec<[Biquad; 3]>,
+    dynamic_stage: Option<usize>,
+}
+
+impl AxisFilter {
+    pub fn new() -> AxisFilter {
+        AxisFilter {
+            stages: Vec::new(),
+            dynamic_stage: None,
+        }
+    }
+
+    pub fn push(&mut self, biquad: Biquad) {
+        self.stages.push([biquad, biquad, biquad]);
+    }
+
+    pub fn push_dynamic_notch(&mut self, biquad: Biquad) {
+        self.push(biquad);
+        self.dynamic_stage = Some(self.stages.len() - 1);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    pub fn apply(&mut self, input: Vector3<f64>) -> Vector3<f64> {
+        let mut output = input;
+        for stage in &mut self.stages {
+            output = Vector3::new(
+                stage[0].apply(output.x),
+                stage[1].apply(output.y),
+                stage[2].apply(output.z),
+            );
+        }
+        output
+    }
+
+    // Every stage is recomputed before any is replaced, so a failure leaves the whole
+    // chain at the old rate.
+    pub fn with_sample_rate(&self, sample_rate: f64) -> Result<AxisFilter, ()> {
+        let mut stages = Vec::with_capacity(self.stages.len());
+        for stage in &self.stages {
+            stages.push([
+                stage[0].with_sample_rate(sample_rate)?,
+                stage[1].with_sample_rate(sample_rate)?,
+                stage[2].with_sample_rate(sample_rate)?,
+            ]);
+        }
+        Ok(AxisFilter {
+            stages: stages,
+            dynamic_stage: self.dynamic_stage,
+        })
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f64) -> Result<(), ()> {
+        *self = self.with_sample_rate(sample_rate)?;
+        Ok(())
+    }
+
+    // Does nothing without a dynamic notch.
+    pub fn set_dynamic_notch_frequency(&mut self, frequency: f64) -> Result<(), ()> {
+        match self.dynamic_stage {
+            Some(index) => {
+                let stage = &mut self.stages[index];
+                *stage = [
+                    stage[0].with_frequency(frequency)?,
+                    stage[1].with_frequency(frequency)?,
+                    stage[2].with_frequency(frequency)?,
+                ];
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn gain(&self, frequency: f64) -> f64 {
+        self.stages
+            .iter()
+            .fold(1.0, |gain, stage| gain * stage[0].gain(frequency))
+    }
+}
+
+// Gyroscope and accelerometer filter chains built from the configuration file.
+pub struct ImuFilters {
+    pub gyroscope: AxisFilter,
+    pub accelerometer: AxisFilter,
+    sample_rate: f64,
+    dynamic_notch_range: Option<(f64, f64)>,
+}
+
+impl ImuFilters {
+    pub fn new(filters: &Option<Filters>, sample_rate: f64) -> Result<ImuFilters, ()> {
+        let logger = ModuleLogger::new("Filters", None);
+        let mut imu_filters = ImuFilters {
+            gyroscope: AxisFilter::new(),
+            accelerometer: AxisFilter::new(),
+            sample_rate: sample_rate,
+            dynamic_notch_range: None,
+        };
+
+        let filters = match *filters {
+            Some(ref filters) => filters,
+            None => return Ok(imu_filters),
+        };
+
+        let nyquist_error = |name: &str, frequency: f32| {
+            logger.error(&format!(
+                "{} at {} Hz must be below half the sample rate ({} Hz).",
+                name,
+                frequency,
+                sample_rate / 2.0
+            ));
+        };
+
+        match filters.gyroscope_lowpass_hz {
+            Some(cutoff) => match Biquad::low_pass(cutoff as f64, sample_rate) {
+                Ok(biquad) => imu_filters.gyroscope.push(biquad),
+                Err(()) => {
+                    nyquist_error("Gyroscope low pass", cutoff);
+                    return Err(());
+                }
+            },
+            None => {}
+        };
+
+        match filters.accelerometer_lowpass_hz {
+            Some(cutoff) => match Biquad::low_pass(cutoff as f64, sample_rate) {
+                Ok(biquad) => imu_filters.accelerometer.push(biquad),
+                Err(()) => {
+                    nyquist_error("Accelerometer low pass", cutoff);
+                    return Err(());
+                }
+            },
+            None => {}
+        };
+
+        for notch in &filters.notches {
+            match Biquad::notch(notch.center_hz as f64, notch.q as f64, sample_rate) {
+                Ok(biquad) => {
+                    imu_filters.gyroscope.push(biquad);
+                    if notch.accelerometer {
+                        imu_filters.accelerometer.push(biquad);
+                    }
+                }
+                Err(()) => {
+                    nyquist_error("Notch", notch.center_hz);
+                    return Err(());
+                }
+            }
+        }
+
+        match filters.dynamic_notch {
+            Some(ref dynamic_notch) => {
+                match Biquad::notch(
+                    dynamic_notch.min_hz as f64,
+                    dynamic_notch.q as f64,
+                    sample_rate,
+                ) {
+                    Ok(biquad) => imu_filters.gyroscope.push_dynamic_notch(biquad),
+                    Err(()) => {
+                        nyquist_error("Dynamic notch", dynamic_notch.min_hz);
+                        return Err(());
+                    }
+                }
+                if dynamic_notch.max_hz as f64 >= sample_rate / 2.0 {
+                    nyquist_error("Dynamic notch maximum", dynamic_notch.max_hz);
+                    return Err(());
+                }
+                imu_filters.dynamic_notch_range =
+                    Some((dynamic_notch.min_hz as f64, dynamic_notch.max_hz as f64));
+            }
+            None => {}
+        };
+
+        Ok(imu_filters)
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    // Recomputes every coefficient for the sample rate the scheduler actually achieves.
+    // Both chains are rebuilt before either is replaced.
+    pub fn set_sample_rate(&mut self, sample_rate: f64) -> Result<(), ()> {
+        let gyroscope = self.gyroscope.with_sample_rate(sample_rate)?;
+        let accelerometer = self.accelerometer.with_sample_rate(sample_rate)?;
+        self.gyroscope = gyroscope;
+        self.accelerometer = accelerometer;
+        self.sample_rate = sample_rate;
+        Ok(())
+    }
+
+    // Motor noise scales with motor speed. Throttle is between 0 and 1.
+    pub fn track_throttle(&mut self, throttle: f64) -> Result<(), ()> {
+        match self.dynamic_notch_range {
+            Some((min_hz, max_hz)) => {
+                let throttle = throttle.max(0.0).min(1.0);
+                let frequency = min_hz + (max_hz - min_hz) * throttle;
+                self.gyroscope.set_dynamic_notch_frequency(frequency)
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn filter_gyroscope(&mut self, angular_rate: Vector3<f64>) -> Vector3<f64> {
+        self.gyroscope.apply(angular_rate)
+    }
+
+    pub fn filter_accelerometer(&mut self, acceleration: Vector3<f64>) -> Vector3<f64> {
+        self.accelerometer.apply(acceleration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num::traits::Zero;
+
+    const SAMPLE_RATE: f64 = 1000.0;
+
+    // Amplitude of a filtered sine once the filter has settled.
+    fn measured_gain(filter: &mut Biquad, frequency: f64) -> f64 {
+        let samples = (SAMPLE_RATE * 2.0) as usize;
+        let mut peak: f64 = 0.0;
+        for i in 0..samples {
+            let t = i as f64 / SAMPLE_RATE;
+            let output = filter.apply((2.0 * PI * frequency * t).sin());
+            if i > samples / 2 {
+                peak = peak.max(output.abs());
+            }
+        }
+        peak
+    }
+
+    #[test]
+    fn low_pass_response() {
+        let mut filter = Biquad::low_pass(50.0, SAMPLE_RATE).unwrap();
+
+        assert!((filter.gain(0.0) - 1.0).abs() < 1e-9);
+        assert!((filter.gain(5.0) - 1.0).abs() < 0.01);
+        // Butterworth, 3 dB down at the cutoff.
+        assert!((filter.gain(50.0) - 0.5f64.sqrt()).abs() < 0.01);
+        // Second order, 12 dB per octave well above the cutoff.
+        assert!(filter.gain(400.0) < 0.03);
+
+        assert!((measured_gain(&mut filter, 5.0) - 1.0).abs() < 0.02);
+        filter.reset();
+        assert!(measured_gain(&mut filter, 400.0) < 0.03);
+    }
+
+    #[test]
+    fn notch_response() {
+        let mut filter = Biquad::notch(120.0, 5.0, SAMPLE_RATE).unwrap();
+
+        assert!(filter.gain(120.0) < 1e-6);
+        assert!((filter.gain(10.0) - 1.0).abs() < 0.01);
+        assert!((filter.gain(400.0) - 1.0).abs() < 0.05);
+
+        assert!(measured_gain(&mut filter, 120.0) < 0.01);
+        filter.reset();
+        assert!((measured_gain(&mut filter, 10.0) - 1.0).abs() < 0.02);
+    }
+
+    #[test]
+    fn rejects_frequencies_above_nyquist() {
+        assert!(Biquad::low_pass(500.0, SAMPLE_RATE).is_err());
+        assert!(Biquad::notch(100.0, 0.0, SAMPLE_RATE).is_err());
+
+        let mut filter = Biquad::low_pass(50.0, SAMPLE_RATE).unwrap();
+        assert!(filter.set_sample_rate(80.0).is_err());
+        assert_eq!(filter.gain(50.0), Biquad::low_pass(50.0, SAMPLE_RATE).unwrap().gain(50.0));
+    }
+
+    #[test]
+    fn chain_gain_is_the_product_of_its_stages() {
+        let mut filter = AxisFilter::new();
+        filter.push(Biquad::low_pass(100.0, SAMPLE_RATE).unwrap());
+        filter.push(Biquad::notch(200.0, 3.0, SAMPLE_RATE).unwrap());
+
+        assert!(filter.gain(200.0) < 1e-6);
+        let expected = Biquad::low_pass(100.0, SAMPLE_RATE).unwrap().gain(150.0)
+            * Biquad::notch(200.0, 3.0, SAMPLE_RATE).unwrap().gain(150.0);
+        assert!((filter.gain(150.0) - expected).abs() < 1e-12);
+
+        let output = (0..2000).fold(Vector3::zero(), |_, _| {
+            filter.apply(Vector3::new(1.0, -2.0, 0.5))
+        });
+        assert!((output - Vector3::new(1.0, -2.0, 0.5)).norm() < 1e-6);
+    }
+
+    // The low pass is fine at 300 Hz but the notch at 200 Hz isn't, so nothing changes.
+    #[test]
+    fn failed_sample_rate_change_keeps_every_stage() {
+        let mut filter = AxisFilter::new();
+        filter.push(Biquad::low_pass(50.0, SAMPLE_RATE).unwrap());
+        filter.push(Biquad::notch(200.0, 3.0, SAMPLE_RATE).unwrap());
+        let before = filter.gain(50.0);
+
+        assert!(filter.set_sample_rate(300.0).is_err());
+        assert_eq!(filter.gain(50.0), before);
+        assert_eq!(filter.stages[0][0].sample_rate, SAMPLE_RATE);
+
+        assert!(filter.set_sample_rate(800.0).is_ok());
+        assert_eq!(filter.stages[0][0].sample_rate, 800.0);
+        assert_eq!(filter.stages[1][2].sample_rate, 800.0);
+    }
+
+    #[test]
+    fn dynamic_notch_follows_throttle() {
+        let mut filter = AxisFilter::new();
+        filter.push_dynamic_notch(Biquad::notch(100.0, 3.0, SAMPLE_RATE).unwrap());
+
+        filter.set_dynamic_notch_frequency(250.0).unwrap();
+        assert!(filter.gain(250.0) < 1e-6);
+        assert!(filter.gain(100.0) > 0.5);
+
+        assert!(filter.set_dynamic_notch_frequency(600.0).is_err());
+        assert!(filter.gain(250.0) < 1e-6);
+    }
+}
@@ -1,22 +1,65 @@
-use unbounded_gpsd::GpsdConnection;
-use unbounded_gpsd::types::{Response, TpvResponse};
 use wifilocation::{get_api_key_from_file, get_towers, WifiGPS};
+use serial;
+use serial::{BaudRate, CharSize, FlowControl, Parity, SerialPort, StopBits, SystemPort};
 
+use clock::{SharedClock, Timestamp};
 use logger::{FlightLogger, ModuleLogger};
 use configurations::Config;
+use configurations::config::{GpsBackend, GpsProtocol, GpsReceiver};
 
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::thread;
 use std::ops::Drop;
-use std::io::Result;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
 use std::string::String;
 use std::cmp::PartialEq;
 
 use std::time::{Duration, Instant};
 
+use super::fake_gpsd::{FakeGpsd, GpsdScript, ReplayOptions};
+use super::gpsd::{GpsdSession, WATCH_COMMAND};
+use super::nmea::{NmeaParser, NmeaSentence};
+use super::ubx;
+use super::ubx::{parse_frame, UbxMessage, UbxParser};
+
+const DEFAULT_SERIAL_PORT: &str = "/dev/ttyS0";
+const DEFAULT_BAUD_RATE: u32 = 9600;
+const SERIAL_TIMEOUT_MS: u64 = 1000;
+const SERIAL_RETRY_SECONDS: u64 = 5;
+const GPSD_TIMEOUT_SECONDS: u64 = 10;
+const GPSD_RETRY_SECONDS: u64 = 5;
+#[cfg(not(target_arch = "arm"))]
+const MOCK_GPS_PERIOD_SECONDS: u64 = 20;
+pub const SECONDS_PER_DAY: f64 = 86400.0;
+
 //Add wifi location?
 //https://crates.io/crates/wifilocation
 
+// Ordered from worst to best, so a check can ask for at least a 3D fix.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum GpsFix {
+    NoFix,
+    Fix2D,
+    Fix3D,
+    DGPS, // 3D with differential corrections
+    RTKFloat,
+    RTKFixed,
+}
+
+impl GpsFix {
+    pub fn has_position(&self) -> bool {
+        *self != GpsFix::NoFix
+    }
+
+    pub fn has_altitude(&self) -> bool {
+        *self >= GpsFix::Fix3D
+    }
+}
+
+// Position, velocity and their errors are only meaningful if fix_type has a position.
+// Reports without a fix still carry satellites, DOP and time when the receiver has them.
 #[derive(Debug, Clone, Copy)]
 pub struct GPSData {
     pub latitude: f64,
@@ -31,10 +74,16 @@ pub struct GPSData {
     pub climb_err: Option<f64>,
     pub track: Option<f64>,
     pub track_err: Option<f64>,
+    pub fix_type: GpsFix,
+    pub satellites: Option<u32>,
+    pub hdop: Option<f64>,
+    pub vdop: Option<f64>,
+    pub time: Option<f64>, // UTC seconds since the Unix epoch
+    pub received: Timestamp,
 }
 
 impl GPSData {
-    pub fn zeros() -> GPSData {
+    pub fn no_fix(received: Timestamp) -> GPSData {
         GPSData {
             latitude: 0.0,
             lat_err: None,
@@ -48,73 +97,384 @@ impl GPSData {
             climb_err: None,
             track: None,
             track_err: None,
+            fix_type: GpsFix::NoFix,
+            satellites: None,
+            hdop: None,
+            vdop: None,
+            time: None,
+            received: received,
         }
     }
-}
 
-impl PartialEq for GPSData {
-    fn eq(&self, other: &GPSData) -> bool {
-        self.latitude == other.latitude && self.longitude == other.longitude
-            && self.altitude == other.altitude && self.speed == other.speed
-            && self.climb == other.climb && self.track == other.track
+    pub fn has_position(&self) -> bool {
+        self.fix_type.has_position()
     }
 }
 
-pub fn get_gps() -> Receiver<GPSData> {
-    let (gps_tx, gps_rx): (Sender<GPSData>, Receiver<GPSData>) = channel();
-    let logger = ModuleLogger::new("GPS", None);
+// Seconds since the Unix epoch for a UTC date and time of day.
+pub fn utc_seconds(year: i32, month: u32, day: u32, seconds_of_day: f64) -> f64 {
+    // Days from civil, with March as the first month so leap days come last.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let month = month as i32;
+    let shifted_month = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * shifted_month + 2) / 5 + day as i32 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era as i64 * 146097 + day_of_era as i64 - 719468;
+    days as f64 * SECONDS_PER_DAY + seconds_of_day
+}
 
-    #[cfg(target_arch = "arm")]
-    thread::Builder::new()
-        .name("GPS Thread".to_string())
-        .spawn(move || {
-            let gps_logger = ModuleLogger::new("GPS", None);
-            let config = Config::new().unwrap();
-            gps_logger.log("Initializing GPS");
-
-            let mut gps_connection = GpsdConnection::new("localhost:2947").unwrap();
-            gps_connection.watch(true).unwrap();
-            gps_connection.set_read_timeout(None).unwrap();
-            // let mut wifi_gps = WifiGPS::new(get_api_key_from_file("./geolocation_api_key.key").unwrap());
-
-            loop {
-                let mut data = GPSData::zeros();
-                match get_location(&mut gps_connection) {
-                    Some(gps_data) => {
-                        data = gps_data;
+// Turns NMEA or UBX bytes from a receiver into fixes. Bytes can be split anywhere,
+// so recorded streams decode the same as live ones. UBX acknowledgements are
+// handled with either protocol since the receiver is always configured over UBX.
+pub struct GpsDecoder {
+    protocol: GpsProtocol,
+    nmea: NmeaParser,
+    ubx: UbxParser,
+    // Latest values from the messages that complete a fix.
+    fix: Option<GpsFix>,
+    hdop: Option<f64>,
+    vdop: Option<f64>,
+    speed: Option<f64>,
+    track: Option<f64>,
+    date: Option<(i32, u32, u32)>,
+    date_time_of_day: Option<f64>,
+    received: Timestamp,
+    logger: ModuleLogger,
+}
+
+impl GpsDecoder {
+    pub fn new(protocol: GpsProtocol) -> GpsDecoder {
+        GpsDecoder {
+            protocol: protocol,
+            nmea: NmeaParser::new(),
+            ubx: UbxParser::new(),
+            fix: None,
+            hdop: None,
+            vdop: None,
+            speed: None,
+            track: None,
+            date: None,
+            date_time_of_day: None,
+            received: Timestamp::zero(),
+            logger: ModuleLogger::new("GPS", None),
+        }
+    }
+
+    // Reports are stamped with the time the bytes were received.
+    pub fn decode(&mut self, bytes: &[u8], received: Timestamp) -> Vec<GPSData> {
+        self.received = received;
+        let mut fixes = Vec::new();
+        for &byte in bytes {
+            let sentence = match self.nmea.push(byte) {
+                Some(sentence) if self.protocol == GpsProtocol::NMEA => Some(sentence),
+                _ => None,
+            };
+            match sentence.and_then(|sentence| self.add_sentence(sentence)) {
+                Some(data) => fixes.push(data),
+                None => {}
+            };
+
+            let message = self.ubx.push(byte).and_then(|frame| parse_frame(&frame));
+            match message.and_then(|message| self.add_ubx_message(message)) {
+                Some(data) => fixes.push(data),
+                None => {}
+            };
+        }
+        fixes
+    }
+
+    // Fixes are made on GGA using the latest RMC, GSA and VTG.
+    fn add_sentence(&mut self, sentence: NmeaSentence) -> Option<GPSData> {
+        match sentence {
+            NmeaSentence::GGA {
+                time_of_day,
+                latitude,
+                longitude,
+                quality,
+                satellites,
+                hdop,
+                altitude,
+            } => {
+                let fix_type = match quality {
+                    1 => match (self.fix, altitude, satellites) {
+                        (Some(GpsFix::Fix2D), _, _) => GpsFix::Fix2D,
+                        (Some(GpsFix::Fix3D), _, _) => GpsFix::Fix3D,
+                        (_, Some(_), Some(satellites)) if satellites >= 4 => GpsFix::Fix3D,
+                        _ => GpsFix::Fix2D,
+                    },
+                    2 => GpsFix::DGPS,
+                    4 => GpsFix::RTKFixed,
+                    5 => GpsFix::RTKFloat,
+                    // Dead reckoning, manual and simulated positions aren't fixes.
+                    _ => GpsFix::NoFix,
+                };
+                let time = match (time_of_day, self.date, self.date_time_of_day) {
+                    (Some(time), Some((year, month, day)), Some(date_time)) => {
+                        // The date comes from the last RMC, which may be from before midnight.
+                        let rollover = if time + SECONDS_PER_DAY / 2.0 < date_time {
+                            SECONDS_PER_DAY
+                        } else {
+                            0.0
+                        };
+                        Some(utc_seconds(year, month, day, time) + rollover)
                     }
-                    None => {}
+                    _ => None,
+                };
+
+                let (latitude, longitude) = match (latitude, longitude) {
+                    (Some(latitude), Some(longitude)) if fix_type.has_position() => {
+                        (latitude, longitude)
+                    }
+                    _ => {
+                        return Some(GPSData {
+                            satellites: satellites,
+                            hdop: hdop.or(self.hdop),
+                            time: time,
+                            ..GPSData::no_fix(self.received)
+                        })
+                    }
+                };
+
+                Some(GPSData {
+                    latitude: latitude,
+                    lat_err: None,
+                    longitude: longitude,
+                    lon_err: None,
+                    altitude: if fix_type.has_altitude() {
+                        altitude
+                    } else {
+                        None
+                    },
+                    alt_err: None,
+                    speed: self.speed,
+                    speed_err: None,
+                    climb: None,
+                    climb_err: None,
+                    track: self.track,
+                    track_err: None,
+                    fix_type: fix_type,
+                    satellites: satellites,
+                    hdop: hdop.or(self.hdop),
+                    vdop: self.vdop,
+                    time: time,
+                    received: self.received,
+                })
+            }
+            NmeaSentence::RMC {
+                time_of_day,
+                valid,
+                speed,
+                track,
+                date,
+                ..
+            } => {
+                if valid {
+                    self.speed = speed;
+                    self.track = track;
                 }
+                self.date = date;
+                self.date_time_of_day = time_of_day;
+                None
+            }
+            NmeaSentence::GSA {
+                fix, hdop, vdop, ..
+            } => {
+                self.fix = Some(fix);
+                self.hdop = hdop;
+                self.vdop = vdop;
+                None
+            }
+            NmeaSentence::VTG { track, speed } => {
+                self.speed = speed;
+                self.track = track;
+                None
+            }
+        }
+    }
 
-                // let towers = get_towers();
-                // match wifi_gps.get_location(towers) {
-                //     Ok(wifi_data) => if wifi_data.accuracy < 10.0 {
-                //         data.latitude = wifi_data.location.lat;
-                //         data.longitude = wifi_data.location.lng;
-                //     },
-                //     Err(e) => {}
-                // }
-
-                if data != GPSData::zeros() {
-                    gps_tx.send(data);
+    // Fixes are made on NAV-PVT using the latest NAV-DOP.
+    fn add_ubx_message(&mut self, message: UbxMessage) -> Option<GPSData> {
+        match message {
+            UbxMessage::NavDop(dop) => {
+                self.hdop = Some(dop.hdop);
+                self.vdop = Some(dop.vdop);
+                None
+            }
+            UbxMessage::NavPvt(pvt) if self.protocol == GpsProtocol::UBX => {
+                let time = pvt.time
+                    .map(|(year, month, day, seconds)| utc_seconds(year, month, day, seconds));
+                if !pvt.fix.has_position() {
+                    return Some(GPSData {
+                        satellites: Some(pvt.satellites),
+                        hdop: self.hdop,
+                        vdop: self.vdop,
+                        time: time,
+                        ..GPSData::no_fix(self.received)
+                    });
                 }
-                thread::sleep_ms(100);
+                Some(GPSData {
+                    latitude: pvt.latitude,
+                    lat_err: Some(pvt.horizontal_accuracy),
+                    longitude: pvt.longitude,
+                    lon_err: Some(pvt.horizontal_accuracy),
+                    altitude: if pvt.fix.has_altitude() {
+                        Some(pvt.altitude)
+                    } else {
+                        None
+                    },
+                    alt_err: Some(pvt.vertical_accuracy),
+                    speed: Some(pvt.ground_speed),
+                    speed_err: Some(pvt.speed_accuracy),
+                    climb: Some(pvt.climb),
+                    climb_err: Some(pvt.speed_accuracy),
+                    track: Some(pvt.heading),
+                    track_err: Some(pvt.heading_accuracy),
+                    fix_type: pvt.fix,
+                    satellites: Some(pvt.satellites),
+                    hdop: self.hdop,
+                    vdop: self.vdop,
+                    time: time,
+                    received: self.received,
+                })
+            }
+            UbxMessage::Nak(class, id) => {
+                self.logger.error(&format!(
+                    "The receiver rejected UBX message {:#04x} {:#04x}.",
+                    class, id
+                ));
+                None
             }
-        });
+            _ => None,
+        }
+    }
+}
+
+// UBX messages that set up the receiver. Receivers that don't speak UBX ignore them.
+pub fn receiver_configuration(receiver: &GpsReceiver) -> Vec<Vec<u8>> {
+    let mut messages = Vec::new();
+    match receiver.protocol {
+        Some(GpsProtocol::UBX) => {
+            messages.push(ubx::cfg_msg(ubx::CLASS_NAV, ubx::NAV_PVT, 1));
+            messages.push(ubx::cfg_msg(ubx::CLASS_NAV, ubx::NAV_DOP, 1));
+        }
+        _ => {}
+    };
+    match receiver.update_rate {
+        Some(rate) if rate > 0 => messages.push(ubx::cfg_rate(rate as u32)),
+        _ => {}
+    };
+    match receiver.dynamic_model {
+        Some(model) => messages.push(ubx::cfg_nav5(model)),
+        None => {}
+    };
+    messages
+}
 
-    /*--------- Mock GPS -----------*/
-    // Would be nice to move this into mock.rs
+fn open_serial_port(logger: &ModuleLogger, receiver: &GpsReceiver) -> Result<SystemPort, ()> {
+    let path = match receiver.port {
+        Some(ref port) => port.clone(),
+        None => String::from(DEFAULT_SERIAL_PORT),
+    };
+    let baud_rate = match receiver.baud_rate {
+        Some(baud_rate) => baud_rate,
+        None => DEFAULT_BAUD_RATE,
+    };
 
-    #[cfg(not(target_arch = "arm"))]
-    thread::spawn(move || {
-        let gps_logger = ModuleLogger::new("GPS", None);
-        gps_logger.log("Initializing GPS");
-        gps_tx.send(GPSData::zeros());
-        loop {
-            thread::sleep(Duration::from_secs(20));
-            gps_tx.send(GPSData::zeros());
+    let mut port = match serial::open(&path) {
+        Ok(port) => port,
+        Err(e) => {
+            logger.error(&format!("Couldn't open the GPS on {}: {}", path, e));
+            return Err(());
         }
+    };
+    let configured = port.reconfigure(&|settings| {
+        settings.set_baud_rate(BaudRate::from_speed(baud_rate as usize))?;
+        settings.set_char_size(CharSize::Bits8);
+        settings.set_parity(Parity::ParityNone);
+        settings.set_stop_bits(StopBits::Stop1);
+        settings.set_flow_control(FlowControl::FlowNone);
+        Ok(())
     });
+    match configured.and_then(|_| port.set_timeout(Duration::from_millis(SERIAL_TIMEOUT_MS))) {
+        Ok(()) => Ok(port),
+        Err(e) => {
+            logger.error(&format!("Couldn't configure {} at {} baud: {}", path, baud_rate, e));
+            Err(())
+        }
+    }
+}
+
+// Reads the receiver until the hardware loop stops listening. The port is reopened
+// if it goes away.
+fn serial_gps(clock: SharedClock, gps_tx: Sender<GPSData>, receiver: GpsReceiver) {
+    let logger = ModuleLogger::new("GPS", None);
+    let protocol = match receiver.protocol {
+        Some(protocol) => protocol,
+        None => GpsProtocol::NMEA,
+    };
+    logger.log(&format!("Initializing serial GPS using {:?}.", protocol));
+
+    'connection: loop {
+        let mut port = match open_serial_port(&logger, &receiver) {
+            Ok(port) => port,
+            Err(()) => {
+                clock.sleep(Duration::from_secs(SERIAL_RETRY_SECONDS));
+                continue 'connection;
+            }
+        };
+        for message in receiver_configuration(&receiver) {
+            match port.write_all(&message) {
+                Ok(()) => {}
+                Err(e) => logger.error(&format!("Couldn't configure the receiver: {}", e)),
+            };
+        }
+
+        let mut decoder = GpsDecoder::new(protocol);
+        let mut buffer = [0u8; 256];
+        let mut silent = false;
+        loop {
+            match port.read(&mut buffer) {
+                Ok(count) => {
+                    silent = false;
+                    for data in decoder.decode(&buffer[..count], clock.now()) {
+                        match gps_tx.send(data) {
+                            Ok(()) => {}
+                            Err(_) => break 'connection,
+                        };
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                    if !silent {
+                        logger.error("No data from the GPS. Check the port and baud rate.");
+                        silent = true;
+                    }
+                }
+                Err(e) => {
+                    logger.error(&format!("Lost the GPS: {}", e));
+                    clock.sleep(Duration::from_secs(SERIAL_RETRY_SECONDS));
+                    continue 'connection;
+                }
+            };
+        }
+    }
+}
+
+pub fn get_gps(config: &Config, clock: SharedClock) -> Receiver<GPSData> {
+    let (gps_tx, gps_rx): (Sender<GPSData>, Receiver<GPSData>) = channel();
+    let logger = ModuleLogger::new("GPS", None);
+
+    match config.hardware.gps_receiver {
+        Some(ref receiver) if receiver.backend == GpsBackend::Serial => {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name("GPS Thread".to_string())
+                .spawn(move || serial_gps(clock, gps_tx, receiver))
+                .unwrap();
+        }
+        ref receiver => start_gpsd(clock, gps_tx, receiver.clone()),
+    };
 
     /*--------- Check that GPS is tracking -----------*/
     // match gps_rx.recv_timeout(Duration::from_secs(60)) {
@@ -128,115 +488,300 @@ pub fn get_gps() -> Receiver<GPSData> {
     gps_rx
 }
 
-fn get_location(gps_connection: &mut GpsdConnection) -> Option<GPSData> {
-    match gps_connection.get_response() {
-        Ok(response) => {
-            return process_gps_response(response);
-        }
-        Err(e) => {
-            return None;
+fn watch_gpsd(address: &str) -> io::Result<BufReader<TcpStream>> {
+    let mut stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(Some(Duration::from_secs(GPSD_TIMEOUT_SECONDS)))?;
+    stream.write_all(WATCH_COMMAND.as_bytes())?;
+    Ok(BufReader::new(stream))
+}
+
+// Follows gpsd until the hardware loop stops listening, reconnecting if gpsd goes
+// away or goes quiet.
+pub fn gpsd_gps(clock: SharedClock, gps_tx: Sender<GPSData>, address: String) {
+    let gps_logger = ModuleLogger::new("GPS", None);
+    gps_logger.log(&format!("Initializing GPS from gpsd at {}.", address));
+    // let mut wifi_gps = WifiGPS::new(get_api_key_from_file("./geolocation_api_key.key").unwrap());
+
+    let mut connected = true;
+    loop {
+        let reader = match watch_gpsd(&address) {
+            Ok(reader) => reader,
+            Err(e) => {
+                // Only report the first failure, gpsd may be down for a while.
+                if connected {
+                    gps_logger.error(&format!("Couldn't connect to gpsd at {}: {}", address, e));
+                    connected = false;
+                }
+                clock.sleep(Duration::from_secs(GPSD_RETRY_SECONDS));
+                continue;
+            }
+        };
+        connected = true;
+
+        let mut session = GpsdSession::new();
+        for line in reader.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    gps_logger.error(&format!("Lost gpsd: {}", e));
+                    break;
+                }
+            };
+
+            // let towers = get_towers();
+            // match wifi_gps.get_location(towers) {
+            //     Ok(wifi_data) => if wifi_data.accuracy < 10.0 {
+            //         data.latitude = wifi_data.location.lat;
+            //         data.longitude = wifi_data.location.lng;
+            //     },
+            //     Err(e) => {}
+            // }
+
+            match session.add_line(&line, clock.now()) {
+                Some(data) => match gps_tx.send(data) {
+                    Ok(()) => {}
+                    Err(_) => return,
+                },
+                None => {}
+            };
         }
+        gps_logger.error("gpsd disconnected.");
+        clock.sleep(Duration::from_secs(GPSD_RETRY_SECONDS));
     }
 }
 
-fn process_gps_response(response: Response) -> Option<GPSData> {
-    match response {
-        Response::Tpv(tpv_response) => process_tpv_response(tpv_response),
-        _ => None,
-    }
-}
-
-fn process_tpv_response(response: TpvResponse) -> Option<GPSData> {
-    match response {
-        TpvResponse::Fix3D {
-            device,
-            time,
-            mode,
-            time_err,
-            lat,
-            lat_err,
-            lon,
-            lon_err,
-            alt,
-            alt_err,
-            track,
-            track_err,
-            speed,
-            speed_err,
-            climb,
-            climb_err,
-        } => Some(GPSData {
-            latitude: lat,
-            lat_err: lat_err,
-            longitude: lon,
-            lon_err: lon_err,
-            altitude: Some(alt),
-            alt_err: alt_err,
-            speed: Some(speed),
-            speed_err: speed_err,
-            climb: Some(climb),
-            climb_err: climb_err,
-            track: track,
-            track_err: track_err,
-        }),
-        TpvResponse::Fix2D {
-            device,
-            time,
-            mode,
-            time_err,
-            lat,
-            lat_err,
-            lon,
-            lon_err,
-            track,
-            track_err,
-            speed,
-            speed_err,
-        } => Some(GPSData {
-            latitude: lat,
-            lat_err: lat_err,
-            longitude: lon,
-            lon_err: lon_err,
-            altitude: None,
-            alt_err: None,
-            speed: Some(speed),
-            speed_err: speed_err,
-            climb: None,
-            climb_err: None,
-            track: track,
-            track_err: track_err,
-        }),
-        TpvResponse::LatLonOnly {
-            device,
-            time,
-            mode,
-            time_err,
-            lat,
-            lat_err,
-            lon,
-            lon_err,
-            alt,
-            alt_err,
-            track,
-            track_err,
-            speed,
-            speed_err,
-            climb,
-            climb_err,
-        } => Some(GPSData {
-            latitude: lat,
-            lat_err: lat_err,
-            longitude: lon,
-            lon_err: lon_err,
-            altitude: alt,
-            alt_err: alt_err,
-            speed: speed,
-            speed_err: speed_err,
-            climb: climb,
-            climb_err: climb_err,
-            track: track,
-            track_err: track_err,
-        }),
-        _ => None,
+// With replay set, the track or log is served by a fake gpsd and read through the
+// same client as the real one.
+fn start_gpsd(clock: SharedClock, gps_tx: Sender<GPSData>, receiver: Option<GpsReceiver>) {
+    let logger = ModuleLogger::new("GPS", None);
+    let (address, replay) = match receiver {
+        Some(receiver) => (receiver.gpsd_address, receiver.replay),
+        None => (None, None),
+    };
+
+    let address = match replay {
+        Some(path) => {
+            let script = match GpsdScript::load(&path) {
+                Ok(script) => script,
+                Err(e) => {
+                    logger.error(&format!("Couldn't load the GPS replay: {}", e));
+                    return;
+                }
+            };
+            logger.log(&format!("Replaying {} GPS reports from {}.", script.len(), path));
+            match FakeGpsd::start(clock.clone(), script, ReplayOptions::default()) {
+                Ok(fake_gpsd) => fake_gpsd.address().to_string(),
+                Err(e) => {
+                    logger.error(&format!("Couldn't start the fake gpsd: {}", e));
+                    return;
+                }
+            }
+        }
+        None => match address {
+            Some(address) => address,
+            // Development machines rarely run gpsd.
+            #[cfg(not(target_arch = "arm"))]
+            None => {
+                mock_gps(clock, gps_tx);
+                return;
+            }
+            #[cfg(target_arch = "arm")]
+            None => super::gpsd::DEFAULT_GPSD_ADDRESS.to_string(),
+        },
+    };
+
+    thread::Builder::new()
+        .name("GPS Thread".to_string())
+        .spawn(move || gpsd_gps(clock, gps_tx, address))
+        .unwrap();
+}
+
+// Reports without a fix, so the hardware loop runs the same as with a receiver indoors.
+#[cfg(not(target_arch = "arm"))]
+fn mock_gps(clock: SharedClock, gps_tx: Sender<GPSData>) {
+    thread::Builder::new()
+        .name("GPS Thread".to_string())
+        .spawn(move || {
+            let gps_logger = ModuleLogger::new("GPS", None);
+            gps_logger.log("Initializing mock GPS. Set gps_receiver to use gpsd.");
+            while gps_tx.send(GPSData::no_fix(clock.now())).is_ok() {
+                clock.sleep(Duration::from_secs(MOCK_GPS_PERIOD_SECONDS));
+            }
+        })
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::nmea;
+
+    const GSA: &str = "$GPGSA,A,3,04,05,,09,12,,,24,,,,,2.5,1.3,2.1*39\r\n";
+    const GGA: &str = "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47\r\n";
+    const RMC: &str = "$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230324,003.1,W*61\r\n";
+
+    // Decodes the stream in chunks of each size, which have to give the same fixes.
+    fn decode_in_chunks(protocol: GpsProtocol, stream: &[u8]) -> Vec<GPSData> {
+        let mut decoded: Option<Vec<GPSData>> = None;
+        for size in [1, 2, 7, 64, stream.len()].iter() {
+            let mut decoder = GpsDecoder::new(protocol);
+            let mut fixes = Vec::new();
+            for chunk in stream.chunks(*size) {
+                fixes.extend(decoder.decode(chunk, Timestamp::from_seconds(1.0)));
+            }
+            match decoded {
+                Some(ref decoded) => assert_eq!(format!("{:?}", fixes), format!("{:?}", decoded)),
+                None => {}
+            };
+            decoded = Some(fixes);
+        }
+        decoded.unwrap()
+    }
+
+    fn put_i32(payload: &mut [u8], offset: usize, value: i32) {
+        for i in 0..4 {
+            payload[offset + i] = (value >> (8 * i)) as u8;
+        }
+    }
+
+    // 2024-03-23 12:35:19.5 at 48.1173 N 11.5167 E, 545.4 m, 3D with 12 satellites.
+    fn nav_pvt() -> Vec<u8> {
+        let mut payload = vec![0u8; 92];
+        payload[4] = 0xE8; // 2024
+        payload[5] = 0x07;
+        payload[6] = 3;
+        payload[7] = 23;
+        payload[8] = 12;
+        payload[9] = 35;
+        payload[10] = 19;
+        payload[11] = 0x07; // Date, time and fully resolved
+        put_i32(&mut payload, 16, 500_000_000);
+        payload[20] = 3;
+        payload[21] = 0x01; // gnssFixOK
+        payload[23] = 12;
+        put_i32(&mut payload, 24, 115_166_667);
+        put_i32(&mut payload, 28, 481_173_000);
+        put_i32(&mut payload, 36, 545_400);
+        put_i32(&mut payload, 40, 1_500);
+        put_i32(&mut payload, 44, 2_500);
+        put_i32(&mut payload, 56, -300); // Down, so climbing
+        put_i32(&mut payload, 60, 11_520);
+        put_i32(&mut payload, 64, 8_440_000);
+        put_i32(&mut payload, 68, 200);
+        put_i32(&mut payload, 72, 150_000);
+        ubx::encode_frame(ubx::CLASS_NAV, ubx::NAV_PVT, &payload)
+    }
+
+    fn nav_dop() -> Vec<u8> {
+        let mut payload = vec![0u8; 18];
+        payload[6] = 250; // pDOP 2.5
+        payload[10] = 210; // vDOP 2.1
+        payload[12] = 130; // hDOP 1.3
+        ubx::encode_frame(ubx::CLASS_NAV, ubx::NAV_DOP, &payload)
+    }
+
+    #[test]
+    fn nmea_fixes_from_split_streams() {
+        let mut stream = String::new();
+        stream.push_str(RMC);
+        stream.push_str(GSA);
+        stream.push_str(GGA);
+        // Corrupted in transit or cut short, so no fix.
+        stream.push_str(&GGA.replace("545.4", "546.4"));
+        stream.push_str(&GGA.replace("*47", "*"));
+        stream.push_str(&GGA[..40]);
+        stream.push_str(&GGA.replace("123519", "123520").replace("*47", "*4D"));
+        // Lost the fix, still reported.
+        stream.push_str("$GPGGA,123521,,,,,0,03,,,M,,M,,*63\r\n");
+
+        let fixes = decode_in_chunks(GpsProtocol::NMEA, stream.as_bytes());
+        assert_eq!(fixes.len(), 3);
+        let fix = fixes[0];
+        assert_eq!(fix.fix_type, GpsFix::Fix3D);
+        assert!((fix.latitude - (48.0 + 7.038 / 60.0)).abs() < 1e-9);
+        assert!((fix.longitude - (11.0 + 31.0 / 60.0)).abs() < 1e-9);
+        assert_eq!(fix.altitude, Some(545.4));
+        assert_eq!(fix.satellites, Some(8));
+        assert_eq!(fix.hdop, Some(0.9));
+        assert_eq!(fix.vdop, Some(2.1));
+        assert!((fix.speed.unwrap() - 22.4 * 0.514444).abs() < 1e-9);
+        assert_eq!(fix.track, Some(84.4));
+        let time = utc_seconds(2024, 3, 23, 12.0 * 3600.0 + 35.0 * 60.0 + 19.0);
+        assert_eq!(fix.time, Some(time));
+        assert_eq!(fix.received, Timestamp::from_seconds(1.0));
+        assert_eq!(fixes[1].time, Some(time + 1.0));
+        assert!(!fixes[2].has_position());
+        assert_eq!(fixes[2].satellites, Some(3));
+        assert_eq!(fixes[2].time, Some(time + 2.0));
+
+        // UBX mode doesn't make fixes from NMEA.
+        assert!(decode_in_chunks(GpsProtocol::UBX, stream.as_bytes()).is_empty());
+    }
+
+    #[test]
+    fn ubx_fixes_from_split_streams() {
+        let mut stream = Vec::new();
+        stream.extend_from_slice(GGA.as_bytes());
+        stream.extend_from_slice(&nav_dop());
+        // A flipped payload byte fails the checksum.
+        let mut corrupted = nav_pvt();
+        corrupted[40] ^= 0x01;
+        stream.extend_from_slice(&corrupted);
+        // Noise, including a stray sync byte, before the good frame.
+        stream.extend_from_slice(&[0x00, 0xB5, 0x00, 0x62]);
+        stream.extend_from_slice(&nav_pvt());
+        stream.extend_from_slice(RMC.as_bytes());
+
+        let fixes = decode_in_chunks(GpsProtocol::UBX, &stream);
+        assert_eq!(fixes.len(), 1);
+        let fix = fixes[0];
+        assert_eq!(fix.fix_type, GpsFix::Fix3D);
+        assert!((fix.latitude - 48.1173).abs() < 1e-9);
+        assert!((fix.longitude - 11.5166667).abs() < 1e-9);
+        assert_eq!(fix.altitude, Some(545.4));
+        assert_eq!(fix.lat_err, Some(1.5));
+        assert_eq!(fix.alt_err, Some(2.5));
+        assert_eq!(fix.climb, Some(0.3));
+        assert_eq!(fix.speed, Some(11.52));
+        assert!((fix.track.unwrap() - 84.4).abs() < 1e-9);
+        assert_eq!(fix.satellites, Some(12));
+        assert!((fix.hdop.unwrap() - 1.3).abs() < 1e-9);
+        assert!((fix.vdop.unwrap() - 2.1).abs() < 1e-9);
+        let time = utc_seconds(2024, 3, 23, 12.0 * 3600.0 + 35.0 * 60.0 + 19.5);
+        assert!((fix.time.unwrap() - time).abs() < 1e-6);
+
+        // Without gnssFixOK the position isn't trusted.
+        let mut no_fix = nav_pvt();
+        no_fix[6 + 21] = 0;
+        let (a, b) = ubx::checksum(&no_fix[2..6 + 92]);
+        no_fix[6 + 92] = a;
+        no_fix[6 + 93] = b;
+        let fixes = decode_in_chunks(GpsProtocol::UBX, &no_fix);
+        assert_eq!(fixes.len(), 1);
+        assert!(!fixes[0].has_position());
+        assert_eq!(fixes[0].satellites, Some(12));
+    }
+
+    #[test]
+    fn two_dimensional_fixes_have_no_altitude() {
+        let body = "GPGSA,A,2,04,05,,09,12,,,24,,,,,2.5,1.3,2.1";
+        let gsa = format!("${}*{:02X}\r\n", body, nmea::checksum(body.as_bytes()));
+        let stream = format!("{}{}", gsa, GGA);
+        let fixes = decode_in_chunks(GpsProtocol::NMEA, stream.as_bytes());
+        assert_eq!(fixes.len(), 1);
+        assert_eq!(fixes[0].fix_type, GpsFix::Fix2D);
+        assert!(fixes[0].has_position());
+        assert_eq!(fixes[0].altitude, None);
+
+        let mut fix_2d = nav_pvt();
+        fix_2d[6 + 20] = 2;
+        let (a, b) = ubx::checksum(&fix_2d[2..6 + 92]);
+        fix_2d[6 + 92] = a;
+        fix_2d[6 + 93] = b;
+        let fixes = decode_in_chunks(GpsProtocol::UBX, &fix_2d);
+        assert_eq!(fixes.len(), 1);
+        assert_eq!(fixes[0].fix_type, GpsFix::Fix2D);
+        assert!(fixes[0].has_position());
+        assert_eq!(fixes[0].altitude, None);
     }
 }
@@ -0,0 +1,496 @@
+use serde_json;
+use serde_json::Value;
+
+use clock::Timestamp;
+use logger::ModuleLogger;
+
+use super::gps::{utc_seconds, GPSData, GpsFix, SECONDS_PER_DAY};
+
+pub const DEFAULT_GPSD_ADDRESS: &str = "localhost:2947";
+pub const WATCH_COMMAND: &str = "?WATCH={\"enable\":true,\"json\":true};\n";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tpv {
+    pub device: Option<String>,
+    pub mode: u64,           // 0 or 1 no fix, 2 for 2D, 3 for 3D
+    pub status: Option<u64>, // 2 DGPS, 3 RTK fixed, 4 RTK float
+    pub time: Option<f64>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub altitude: Option<f64>,
+    pub latitude_error: Option<f64>,
+    pub longitude_error: Option<f64>,
+    pub altitude_error: Option<f64>,
+    pub speed: Option<f64>,
+    pub speed_error: Option<f64>,
+    pub climb: Option<f64>,
+    pub climb_error: Option<f64>,
+    pub track: Option<f64>,
+    pub track_error: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sky {
+    pub hdop: Option<f64>,
+    pub vdop: Option<f64>,
+    pub pdop: Option<f64>,
+    pub visible: Option<u32>,
+    pub used: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GpsdReport {
+    Version(String),
+    Tpv(Tpv),
+    Sky(Sky),
+}
+
+impl Tpv {
+    pub fn fix(&self) -> GpsFix {
+        match (self.mode, self.status) {
+            (2, _) => GpsFix::Fix2D,
+            (3, Some(2)) => GpsFix::DGPS,
+            (3, Some(3)) => GpsFix::RTKFixed,
+            (3, Some(4)) => GpsFix::RTKFloat,
+            (3, _) => GpsFix::Fix3D,
+            _ => GpsFix::NoFix,
+        }
+    }
+}
+
+// gpsd sends UTC times like 2018-10-19T12:30:15.120Z. None for anything shorter or
+// not laid out like that.
+pub fn parse_time(time: &str) -> Option<f64> {
+    if !time.ends_with('Z') {
+        return None;
+    }
+    let year: i32 = time.get(0..4)?.parse().ok()?;
+    let month: u32 = time.get(5..7)?.parse().ok()?;
+    let day: u32 = time.get(8..10)?.parse().ok()?;
+    let hours: f64 = time.get(11..13)?.parse().ok()?;
+    let minutes: f64 = time.get(14..16)?.parse().ok()?;
+    let seconds: f64 = time.get(17..time.len() - 1)?.parse().ok()?;
+    Some(utc_seconds(
+        year,
+        month,
+        day,
+        hours * 3600.0 + minutes * 60.0 + seconds,
+    ))
+}
+
+// Inverse of utc_seconds. Year, month and day from days since the Unix epoch.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = if days >= 0 { days } else { days - 146096 } / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month as u32, day as u32)
+}
+
+pub fn format_time(time: f64) -> String {
+    // Rounded to the millisecond first so 59.9995 carries into the next minute.
+    let millis = (time * 1000.0).round() as i64;
+    let days = (millis as f64 / (SECONDS_PER_DAY * 1000.0)).floor() as i64;
+    let (year, month, day) = civil_from_days(days);
+    let millis_of_day = millis - days * SECONDS_PER_DAY as i64 * 1000;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        millis_of_day / 3_600_000,
+        millis_of_day / 60_000 % 60,
+        millis_of_day / 1000 % 60,
+        millis_of_day % 1000
+    )
+}
+
+// gpsd leaves out what it doesn't know, and JSON has no NaN.
+fn push_number(fields: &mut Vec<String>, name: &str, value: Option<f64>) {
+    match value {
+        Some(value) if value.is_finite() => fields.push(format!("\"{}\":{}", name, value)),
+        _ => {}
+    };
+}
+
+// The TPV gpsd would send for the data.
+pub fn tpv_report(data: &GPSData, device: &str) -> String {
+    let (mode, status) = match data.fix_type {
+        GpsFix::NoFix => (1, None),
+        GpsFix::Fix2D => (2, None),
+        GpsFix::Fix3D => (3, None),
+        GpsFix::DGPS => (3, Some(2)),
+        GpsFix::RTKFixed => (3, Some(3)),
+        GpsFix::RTKFloat => (3, Some(4)),
+    };
+    let mut fields = vec![
+        String::from("\"class\":\"TPV\""),
+        format!("\"device\":\"{}\"", device),
+        format!("\"mode\":{}", mode),
+    ];
+    match status {
+        Some(status) => fields.push(format!("\"status\":{}", status)),
+        None => {}
+    };
+    match data.time {
+        Some(time) => fields.push(format!("\"time\":\"{}\"", format_time(time))),
+        None => {}
+    };
+    if data.has_position() {
+        push_number(&mut fields, "lat", Some(data.latitude));
+        push_number(&mut fields, "lon", Some(data.longitude));
+        push_number(&mut fields, "alt", data.altitude);
+        push_number(&mut fields, "epx", data.lon_err);
+        push_number(&mut fields, "epy", data.lat_err);
+        push_number(&mut fields, "epv", data.alt_err);
+        push_number(&mut fields, "speed", data.speed);
+        push_number(&mut fields, "eps", data.speed_err);
+        push_number(&mut fields, "climb", data.climb);
+        push_number(&mut fields, "epc", data.climb_err);
+        push_number(&mut fields, "track", data.track);
+        push_number(&mut fields, "epd", data.track_err);
+    }
+    format!("{{{}}}", fields.join(","))
+}
+
+// The SKY gpsd would send for the data. None if there's nothing to report.
+pub fn sky_report(data: &GPSData, device: &str) -> Option<String> {
+    if data.satellites.is_none() && data.hdop.is_none() && data.vdop.is_none() {
+        return None;
+    }
+    let mut fields = vec![
+        String::from("\"class\":\"SKY\""),
+        format!("\"device\":\"{}\"", device),
+    ];
+    push_number(&mut fields, "hdop", data.hdop);
+    push_number(&mut fields, "vdop", data.vdop);
+    push_number(&mut fields, "uSat", data.satellites.map(|used| used as f64));
+    Some(format!("{{{}}}", fields.join(",")))
+}
+
+fn parse_tpv(report: &Value) -> Tpv {
+    let number = |name: &str| report[name].as_f64();
+    Tpv {
+        device: report["device"].as_str().map(String::from),
+        mode: report["mode"].as_u64().unwrap_or(0),
+        status: report["status"].as_u64(),
+        time: report["time"].as_str().and_then(parse_time),
+        latitude: number("lat"),
+        longitude: number("lon"),
+        // Newer gpsd splits altitude into altMSL and altHAE.
+        altitude: number("altMSL").or(number("alt")),
+        latitude_error: number("epy"),
+        longitude_error: number("epx"),
+        altitude_error: number("epv"),
+        speed: number("speed"),
+        speed_error: number("eps"),
+        climb: number("climb"),
+        climb_error: number("epc"),
+        track: number("track"),
+        track_error: number("epd"),
+    }
+}
+
+fn parse_sky(report: &Value) -> Sky {
+    let satellites = report["satellites"].as_array();
+    Sky {
+        hdop: report["hdop"].as_f64(),
+        vdop: report["vdop"].as_f64(),
+        pdop: report["pdop"].as_f64(),
+        visible: match report["nSat"].as_u64() {
+            Some(visible) => Some(visible as u32),
+            None => satellites.map(|satellites| satellites.len() as u32),
+        },
+        used: match report["uSat"].as_u64() {
+            Some(used) => Some(used as u32),
+            None => satellites.map(|satellites| {
+                satellites
+                    .iter()
+                    .filter(|satellite| satellite["used"].as_bool() == Some(true))
+                    .count() as u32
+            }),
+        },
+    }
+}
+
+// One line of gpsd JSON. None for classes we don't use or lines that aren't JSON.
+pub fn parse_report(line: &str) -> Option<GpsdReport> {
+    let report: Value = serde_json::from_str(line).ok()?;
+    match report["class"].as_str() {
+        Some("VERSION") => Some(GpsdReport::Version(
+            report["release"].as_str().unwrap_or("unknown").to_string(),
+        )),
+        Some("TPV") => Some(GpsdReport::Tpv(parse_tpv(&report))),
+        Some("SKY") => Some(GpsdReport::Sky(parse_sky(&report))),
+        _ => None,
+    }
+}
+
+// Turns gpsd reports into GPSData. Every TPV makes a report, with the satellites and
+// DOP from the latest SKY.
+pub struct GpsdSession {
+    sky: Option<Sky>,
+    device: Option<String>,
+    logger: ModuleLogger,
+}
+
+impl GpsdSession {
+    pub fn new() -> GpsdSession {
+        GpsdSession {
+            sky: None,
+            device: None,
+            logger: ModuleLogger::new("GPS", None),
+        }
+    }
+
+    pub fn add_line(&mut self, line: &str, received: Timestamp) -> Option<GPSData> {
+        match parse_report(line)? {
+            GpsdReport::Version(release) => {
+                self.logger.log(&format!("Connected to gpsd {}.", release));
+                None
+            }
+            GpsdReport::Sky(sky) => {
+                self.sky = Some(sky);
+                None
+            }
+            GpsdReport::Tpv(tpv) => {
+                if tpv.device.is_some() && tpv.device != self.device {
+                    self.logger.log(&format!(
+                        "Receiving fixes from {}.",
+                        tpv.device.as_ref().unwrap()
+                    ));
+                    self.device = tpv.device.clone();
+                }
+                Some(self.gps_data(&tpv, received))
+            }
+        }
+    }
+
+    fn gps_data(&self, tpv: &Tpv, received: Timestamp) -> GPSData {
+        let (satellites, hdop, vdop) = match self.sky {
+            Some(ref sky) => (sky.used, sky.hdop, sky.vdop),
+            None => (None, None, None),
+        };
+        let no_fix = GPSData {
+            satellites: satellites,
+            hdop: hdop,
+            vdop: vdop,
+            time: tpv.time,
+            ..GPSData::no_fix(received)
+        };
+
+        let fix_type = tpv.fix();
+        let (latitude, longitude) = match (tpv.latitude, tpv.longitude) {
+            (Some(latitude), Some(longitude)) if fix_type.has_position() => (latitude, longitude),
+            _ => return no_fix,
+        };
+        GPSData {
+            latitude: latitude,
+            lat_err: tpv.latitude_error,
+            longitude: longitude,
+            lon_err: tpv.longitude_error,
+            altitude: if fix_type.has_altitude() {
+                tpv.altitude
+            } else {
+                None
+            },
+            alt_err: tpv.altitude_error,
+            speed: tpv.speed,
+            speed_err: tpv.speed_error,
+            climb: tpv.climb,
+            climb_err: tpv.climb_error,
+            track: tpv.track,
+            track_err: tpv.track_error,
+            fix_type: fix_type,
+            ..no_fix
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Recorded from gpsd 3.17 with a u-blox receiver.
+    const VERSION: &str = concat!(
+        "{\"class\":\"VERSION\",\"release\":\"3.17\",\"rev\":\"3.17\",",
+        "\"proto_major\":3,\"proto_minor\":12}"
+    );
+    const TPV: &str = concat!(
+        "{\"class\":\"TPV\",\"device\":\"/dev/ttyACM0\",\"mode\":3,",
+        "\"time\":\"2018-10-19T12:30:15.120Z\",\"ept\":0.005,\"lat\":47.620512,",
+        "\"lon\":-122.349358,\"alt\":56.3,\"epx\":3.2,\"epy\":4.1,\"epv\":9.2,",
+        "\"track\":271.5,\"speed\":0.21,\"climb\":-0.1,\"eps\":8.2,\"epc\":18.4}"
+    );
+    const TPV_NO_FIX: &str = "{\"class\":\"TPV\",\"device\":\"/dev/ttyACM0\",\"mode\":1}";
+    const SKY: &str = concat!(
+        "{\"class\":\"SKY\",\"device\":\"/dev/ttyACM0\",\"xdop\":0.71,\"ydop\":0.93,",
+        "\"vdop\":1.46,\"tdop\":0.87,\"hdop\":1.17,\"gdop\":2.04,\"pdop\":1.87,",
+        "\"satellites\":[{\"PRN\":5,\"el\":31,\"az\":86,\"ss\":36,\"used\":true},",
+        "{\"PRN\":13,\"el\":6,\"az\":167,\"ss\":0,\"used\":false},",
+        "{\"PRN\":15,\"el\":43,\"az\":263,\"ss\":30,\"used\":true}]}"
+    );
+
+    #[test]
+    fn times_parse_to_unix_seconds() {
+        assert_eq!(parse_time("1970-01-01T00:00:00.000Z"), Some(0.0));
+        let time = parse_time("2018-10-19T12:30:15.120Z").unwrap();
+        assert!((time - 1539952215.12).abs() < 1e-6);
+        assert_eq!(parse_time("2018-10-19T12:30:15Z"), Some(1539952215.0));
+    }
+
+    #[test]
+    fn short_or_odd_times_are_missing() {
+        assert_eq!(parse_time(""), None);
+        assert_eq!(parse_time("Z"), None);
+        assert_eq!(parse_time("2018-10-19T12:30Z"), None);
+        assert_eq!(parse_time("2018-10-19T12:30:15.120"), None);
+        assert_eq!(parse_time("2018-10-19T12:30:xxZ"), None);
+        // Multi-byte characters where the digits should be.
+        assert_eq!(parse_time("2018-1é-19T12:30:15.120Z"), None);
+        assert_eq!(parse_time("2018-10-19T12:3é:15Z"), None);
+        assert_eq!(parse_time("ééééééééééZ"), None);
+    }
+
+    #[test]
+    fn recorded_reports_parse() {
+        assert_eq!(parse_report(VERSION), Some(GpsdReport::Version(String::from("3.17"))));
+        assert_eq!(parse_report("{\"class\":\"DEVICES\",\"devices\":[]}"), None);
+        assert_eq!(parse_report("not json"), None);
+
+        let tpv = match parse_report(TPV) {
+            Some(GpsdReport::Tpv(tpv)) => tpv,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(tpv.device, Some(String::from("/dev/ttyACM0")));
+        assert_eq!(tpv.fix(), GpsFix::Fix3D);
+        assert_eq!(tpv.latitude, Some(47.620512));
+        assert_eq!(tpv.longitude, Some(-122.349358));
+        assert_eq!(tpv.altitude, Some(56.3));
+        assert_eq!(tpv.latitude_error, Some(4.1));
+        assert_eq!(tpv.longitude_error, Some(3.2));
+        assert_eq!(tpv.climb, Some(-0.1));
+        assert_eq!(tpv.time, parse_time("2018-10-19T12:30:15.120Z"));
+
+        // Newer gpsd reports altMSL next to alt.
+        match parse_report("{\"class\":\"TPV\",\"mode\":3,\"alt\":80.1,\"altMSL\":56.3}") {
+            Some(GpsdReport::Tpv(tpv)) => assert_eq!(tpv.altitude, Some(56.3)),
+            other => panic!("{:?}", other),
+        };
+
+        assert_eq!(
+            parse_report(SKY),
+            Some(GpsdReport::Sky(Sky {
+                hdop: Some(1.17),
+                vdop: Some(1.46),
+                pdop: Some(1.87),
+                visible: Some(3),
+                used: Some(2),
+            }))
+        );
+        // nSat and uSat win over counting the satellites.
+        match parse_report("{\"class\":\"SKY\",\"nSat\":14,\"uSat\":9,\"satellites\":[]}") {
+            Some(GpsdReport::Sky(sky)) => assert_eq!((sky.visible, sky.used), (Some(14), Some(9))),
+            other => panic!("{:?}", other),
+        };
+    }
+
+    #[test]
+    fn fix_follows_mode_and_status() {
+        let fix = |mode: u64, status: Option<u64>| {
+            Tpv {
+                mode: mode,
+                status: status,
+                ..match parse_report(TPV_NO_FIX) {
+                    Some(GpsdReport::Tpv(tpv)) => tpv,
+                    other => panic!("{:?}", other),
+                }
+            }.fix()
+        };
+        assert_eq!(fix(0, None), GpsFix::NoFix);
+        assert_eq!(fix(1, None), GpsFix::NoFix);
+        assert_eq!(fix(2, None), GpsFix::Fix2D);
+        assert_eq!(fix(2, Some(2)), GpsFix::Fix2D);
+        assert_eq!(fix(3, None), GpsFix::Fix3D);
+        assert_eq!(fix(3, Some(1)), GpsFix::Fix3D);
+        assert_eq!(fix(3, Some(2)), GpsFix::DGPS);
+        assert_eq!(fix(3, Some(3)), GpsFix::RTKFixed);
+        assert_eq!(fix(3, Some(4)), GpsFix::RTKFloat);
+    }
+
+    #[test]
+    fn sessions_combine_tpv_with_the_latest_sky() {
+        let mut session = GpsdSession::new();
+        let received = Timestamp::from_seconds(2.0);
+        assert!(session.add_line(VERSION, received).is_none());
+
+        let data = session.add_line(TPV_NO_FIX, received).unwrap();
+        assert!(!data.has_position());
+        assert_eq!(data.satellites, None);
+
+        assert!(session.add_line(SKY, received).is_none());
+        let data = session.add_line(TPV, received).unwrap();
+        assert_eq!(data.fix_type, GpsFix::Fix3D);
+        assert_eq!(data.latitude, 47.620512);
+        assert_eq!(data.altitude, Some(56.3));
+        assert_eq!(data.satellites, Some(2));
+        assert_eq!(data.hdop, Some(1.17));
+        assert_eq!(data.vdop, Some(1.46));
+        assert_eq!(data.received, received);
+
+        // A 2D fix has no altitude.
+        let data = session.add_line(&TPV.replace("\"mode\":3", "\"mode\":2"), received);
+        assert_eq!(data.unwrap().altitude, None);
+    }
+
+    #[test]
+    fn times_format_to_the_millisecond() {
+        assert_eq!(format_time(0.0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(format_time(1539952215.12), "2018-10-19T12:30:15.120Z");
+        // Rounding carries into the minute and the day.
+        assert_eq!(format_time(59.9995), "1970-01-01T00:01:00.000Z");
+        assert_eq!(format_time(86399.9999), "1970-01-02T00:00:00.000Z");
+        let time = 1539952215.12;
+        assert!((parse_time(&format_time(time)).unwrap() - time).abs() < 1e-6);
+    }
+
+    #[test]
+    fn reports_leave_out_unknown_numbers() {
+        let data = GPSData {
+            latitude: 47.620512,
+            longitude: -122.349358,
+            altitude: Some(::std::f64::NAN),
+            speed: Some(::std::f64::INFINITY),
+            climb: Some(-0.1),
+            fix_type: GpsFix::Fix3D,
+            satellites: Some(9),
+            hdop: Some(::std::f64::NAN),
+            vdop: Some(1.46),
+            ..GPSData::no_fix(Timestamp::zero())
+        };
+        let tpv = tpv_report(&data, "/dev/ttyACM0");
+        assert!(!tpv.contains("alt") && !tpv.contains("speed"));
+        match parse_report(&tpv) {
+            Some(GpsdReport::Tpv(tpv)) => {
+                assert_eq!(tpv.latitude, Some(47.620512));
+                assert_eq!(tpv.climb, Some(-0.1));
+                assert_eq!(tpv.altitude, None);
+            }
+            other => panic!("{:?}", other),
+        };
+        match parse_report(&sky_report(&data, "/dev/ttyACM0").unwrap()) {
+            Some(GpsdReport::Sky(sky)) => assert_eq!((sky.hdop, sky.vdop), (None, Some(1.46))),
+            other => panic!("{:?}", other),
+        };
+    }
+}
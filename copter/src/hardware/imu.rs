@@ -1,52 +1,129 @@
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::path::{Path, PathBuf};
 use std::thread::sleep;
 use std::time::Duration;
-use std::io;
 
 use i2cdev_lsm9ds0::*;
-use i2csensors::{Accelerometer, Gyroscope, Magnetometer};
+use i2csensors::{Accelerometer, Gyroscope, Magnetometer, Thermometer};
+use i2cdev::core::I2CDevice;
 use i2cdev::linux::{LinuxI2CDevice, LinuxI2CError};
+use spidev::Spidev;
 
-use na::{try_inverse, zero, Matrix, Matrix3, Matrix4, MatrixArray, MatrixMN, MatrixN, MatrixVec,
-         Vector, Vector3, VectorN};
-use na::{U100, U3, U4, U9};
-use na::geometry::UnitQuaternion;
-use typenum::U200;
+use na::{Matrix3, Vector3};
 use num::traits::Zero;
 
-type Matrix200x9 = Matrix<f64, U200, U9, MatrixArray<f64, U200, U9>>;
-type Vector9 = VectorN<f64, U9>;
-type Vector200 = VectorN<f64, U200>;
-
-use configurations::{Calibrations, Config, Ellipsoid, Simple};
+use configurations::{CalibrationError, Calibrations, Config, Ellipsoid, Record, Simple, Thermal};
+use configurations::calibrations::calibrations_path;
+use configurations::config::{Hardware, Sensor, SerialCommunication};
+use clock::{SharedClock, Timestamp};
 use logger::ModuleLogger;
 
 const G_TO_MPSPS: f64 = 9.80665;
+const DEFAULT_ACCELEROMETER_FULL_SCALE: i32 = 4;
+const DEFAULT_GYROSCOPE_RATE: i32 = 95;
+const DEFAULT_ACCELEROMETER_RATE: i32 = 100;
+// Ten minutes at the calibration sample rate.
+const MAX_MAGNETOMETER_READINGS: usize = 12000;
+const MAGNETOMETER_SAMPLES_FILE: &str = "magnetometer_samples.csv";
+// Anywhere on the surface, in gauss.
+const MIN_EARTH_FIELD: f64 = 0.25;
+const MAX_EARTH_FIELD: f64 = 0.65;
+// RMS error over the six positions in m/s^2, about 1.5 degrees of tilt.
+const MAX_ACCELEROMETER_RESIDUAL: f64 = 0.25;
+const GYROSCOPE_READINGS: usize = 200;
+// RMS of the readings in rad/s. The drone was bumped if it's over.
+const MAX_GYROSCOPE_NOISE: f64 = 0.05;
+const DIE_TEMPERATURE_PERIOD_MS: u64 = 1000;
+const THERMAL_SAMPLES_FILE: &str = "thermal_samples.csv";
+const THERMAL_WINDOW_READINGS: usize = 50;
+const MAX_THERMAL_MINUTES: u64 = 30;
+// Warming less than this in a minute means the IMU has settled, in degrees C.
+const SETTLED_TEMPERATURE_CHANGE: f64 = 0.1;
+
+const LSM9DS0_GYROSCOPE_ADDRESS: u16 = 0x6B;
+const LSM9DS0_ACCELEROMETER_MAGNETOMETER_ADDRESS: u16 = 0x1D;
+const LSM9DS0_CTRL_REG5_XM: u8 = 0x24;
+const LSM9DS0_TEMP_EN: u8 = 0x80;
+const LSM9DS0_OUT_TEMP_L_XM: u8 = 0x05;
+// Auto increment for multiple byte reads.
+const LSM9DS0_MULTIPLE_READ: u8 = 0x80;
 
 use super::mock::MockSensor;
+use super::calibration::{write_samples, write_thermal_samples, MagnetometerCalibration,
+                         Orientation, SixPositionCalibration, ThermalCalibration,
+                         THERMAL_DEGREE};
+use super::calibration_session::{CalibrationReport, CalibrationSession, CalibrationStep,
+                                 StepOutcome, StepReport};
+use super::bus::{get_i2c_device, get_spi_device, select_full_scale, select_rate};
+use super::spi::{SpiProtocol, SpiRegisterDevice};
+
+// What the sensors were set to, which can be above what was configured.
+#[derive(Debug, Clone, Copy)]
+struct SensorSettings {
+    gyroscope_rate: i32,           // Hz
+    accelerometer_rate: i32,       // Hz
+    accelerometer_full_scale: i32, // g
+}
+
+impl SensorSettings {
+    // The mock runs at whatever is configured.
+    #[cfg(not(target_arch = "arm"))]
+    fn requested(hardware: &Hardware) -> SensorSettings {
+        SensorSettings {
+            gyroscope_rate: hardware.gyroscope.update_rate.unwrap_or(DEFAULT_GYROSCOPE_RATE),
+            accelerometer_rate: hardware
+                .accelerometer
+                .update_rate
+                .unwrap_or(DEFAULT_ACCELEROMETER_RATE),
+            accelerometer_full_scale: hardware
+                .accelerometer
+                .full_scale
+                .unwrap_or(DEFAULT_ACCELEROMETER_FULL_SCALE),
+        }
+    }
+}
+
+type SharedSensors = (
+    Rc<RefCell<Gyroscope<Error = LinuxI2CError>>>,
+    Rc<RefCell<Accelerometer<Error = LinuxI2CError>>>,
+    Rc<RefCell<Magnetometer<Error = LinuxI2CError>>>,
+);
 
 pub struct IMU {
     gyroscope: Rc<RefCell<Gyroscope<Error = LinuxI2CError>>>,
     gyroscope_offsets: Vector3<f64>,
     accelerometer: Rc<RefCell<Accelerometer<Error = LinuxI2CError>>>,
     accelerometer_offsets: Vector3<f64>,
+    accelerometer_correction: Matrix3<f64>,
     magnetometer: Rc<RefCell<Magnetometer<Error = LinuxI2CError>>>,
     magnetometer_offsets: Vector3<f64>,
-    magnetometer_rotation: Matrix3<f64>,
-    magnetometer_gains: Vector3<f64>,
+    magnetometer_correction: Matrix3<f64>,
+    thermometer: Option<Rc<RefCell<Thermometer<Error = LinuxI2CError>>>>,
+    die_temperature: Option<f64>,
+    die_temperature_read: Option<Timestamp>,
+    gyroscope_thermal: Option<Thermal>,
+    accelerometer_thermal: Option<Thermal>,
+    // The configured sensors, recorded with each calibration
+    gyroscope_record: Record,
+    accelerometer_record: Record,
+    magnetometer_record: Record,
+    directory: PathBuf, // Calibrations and samples are kept with the configuration
+    settings: SensorSettings,
+    clock: SharedClock,
     logger: ModuleLogger,
     // calibrations: Calibrations,
 }
 
 impl IMU {
-    pub fn new() -> Result<IMU, ()> {
+    pub fn new(config: &Config, clock: SharedClock) -> Result<IMU, ()> {
         let logger = ModuleLogger::new("IMU", None);
-        let config = Config::new().unwrap();
 
         let mut gyroscope: Option<Rc<RefCell<Gyroscope<Error = LinuxI2CError>>>> = None;
         let mut accelerometer: Option<Rc<RefCell<Accelerometer<Error = LinuxI2CError>>>> = None;
         let mut magnetometer: Option<Rc<RefCell<Magnetometer<Error = LinuxI2CError>>>> = None;
+        let mut thermometer: Option<Rc<RefCell<Thermometer<Error = LinuxI2CError>>>> = None;
+        let mut settings: Option<SensorSettings> = None;
 
         #[cfg(not(target_arch = "arm"))]
         {
@@ -55,18 +132,26 @@ impl IMU {
             gyroscope = Some(mock_sensor.clone());
             accelerometer = Some(mock_sensor.clone());
             magnetometer = Some(mock_sensor.clone());
+            thermometer = Some(mock_sensor.clone());
+            settings = Some(SensorSettings::requested(&config.hardware));
         }
 
         #[cfg(target_arch = "arm")]
         match config.hardware.gyroscope.name.as_ref() {
             "LSM9DS0" => {
                 logger.log("Initializing LSM9DS0.");
-                match get_lsm9ds0() {
-                    Ok(lsm9ds0) => {
-                        let lsm9ds0_ref = Rc::new(RefCell::new(lsm9ds0));
-                        gyroscope = Some(lsm9ds0_ref.clone());
-                        accelerometer = Some(lsm9ds0_ref.clone());
-                        magnetometer = Some(lsm9ds0_ref.clone());
+                let lsm9ds0 = match config.hardware.gyroscope.serial {
+                    SerialCommunication::SPI => get_lsm9ds0_spi(&logger, &config.hardware)
+                        .map(|(lsm9ds0, settings)| (share_sensors(lsm9ds0), settings)),
+                    _ => get_lsm9ds0_i2c(&logger, &config.hardware)
+                        .map(|(lsm9ds0, settings)| (share_sensors(lsm9ds0), settings)),
+                };
+                match lsm9ds0 {
+                    Ok(((lsm9ds0_gyro, lsm9ds0_accel, lsm9ds0_mag), lsm9ds0_settings)) => {
+                        gyroscope = Some(lsm9ds0_gyro);
+                        accelerometer = Some(lsm9ds0_accel);
+                        magnetometer = Some(lsm9ds0_mag);
+                        settings = Some(lsm9ds0_settings);
                     }
                     Err(()) => {
                         logger.error(
@@ -75,6 +160,14 @@ impl IMU {
                         return Err(());
                     }
                 }
+                // Optional, only temperature compensation needs it.
+                thermometer = match get_lsm9ds0_thermometer(&config.hardware) {
+                    Ok(thermometer) => Some(thermometer),
+                    Err(()) => {
+                        logger.error("Couldn't read the LSM9DS0 die temperature.");
+                        None
+                    }
+                };
             }
             _ => {
                 logger.error("Unknown gyroscope model. Check your configuration file.");
@@ -105,27 +198,114 @@ impl IMU {
             gyroscope_offsets: Vector3::zero(),
             accelerometer: accelerometer.unwrap().clone(),
             accelerometer_offsets: Vector3::zero(),
+            accelerometer_correction: Matrix3::identity(),
             magnetometer: magnetometer.unwrap().clone(),
             magnetometer_offsets: Vector3::zero(),
-            magnetometer_rotation: Matrix3::zero(),
-            magnetometer_gains: Vector3::zero(),
+            magnetometer_correction: Matrix3::identity(),
+            thermometer: thermometer,
+            die_temperature: None,
+            die_temperature_read: None,
+            gyroscope_thermal: None,
+            accelerometer_thermal: None,
+            gyroscope_record: Record::new(&config.hardware.gyroscope, LSM9DS0_GYROSCOPE_ADDRESS),
+            accelerometer_record: Record::new(
+                &config.hardware.accelerometer,
+                LSM9DS0_ACCELEROMETER_MAGNETOMETER_ADDRESS,
+            ),
+            magnetometer_record: magnetometer_record(&config.hardware),
+            directory: config.directory.clone(),
+            settings: settings.unwrap(),
+            clock: clock,
             logger: logger,
         };
 
-        // Read calibrations
-        let calibs = Calibrations::new().unwrap();
-        if calibs.gyroscope.is_some() {
-            imu.gyroscope_offsets = calibs.gyroscope.unwrap().get_offsets();
-        }
-        if calibs.accelerometer.is_some() {
-            imu.accelerometer_offsets = calibs.accelerometer.unwrap().get_offsets();
-        }
-        if calibs.magnetometer.is_some() {
-            let mag_calibs = calibs.magnetometer.unwrap();
-            imu.magnetometer_offsets = mag_calibs.get_offsets();
-            imu.magnetometer_rotation = mag_calibs.get_rotation();
-            imu.magnetometer_gains = mag_calibs.get_gains();
-        }
+        // Read calibrations. A corrupt file is left for recalibrating to replace.
+        let calibs = match Calibrations::load(&calibrations_path(&config.directory)) {
+            Ok(calibs) => calibs,
+            Err(CalibrationError::Missing) => {
+                imu.logger
+                    .error("The sensors aren't calibrated. Run the sensor calibration.");
+                Calibrations::default()
+            }
+            Err(e) => {
+                imu.logger.error(&format!("{} The sensors are uncalibrated.", e));
+                Calibrations::default()
+            }
+        };
+        let hardware = &config.hardware;
+        check_record(
+            &imu.logger,
+            "gyroscope",
+            calibs.gyroscope.as_ref().and_then(|calib| calib.record.as_ref()),
+            &hardware.gyroscope,
+            LSM9DS0_GYROSCOPE_ADDRESS,
+        );
+        check_record(
+            &imu.logger,
+            "accelerometer",
+            calibs.accelerometer.as_ref().and_then(|calib| calib.record.as_ref()),
+            &hardware.accelerometer,
+            LSM9DS0_ACCELEROMETER_MAGNETOMETER_ADDRESS,
+        );
+        check_record(
+            &imu.logger,
+            "magnetometer",
+            calibs.magnetometer.as_ref().and_then(|calib| calib.record.as_ref()),
+            &hardware.magnetometer,
+            LSM9DS0_ACCELEROMETER_MAGNETOMETER_ADDRESS,
+        );
+        check_record(
+            &imu.logger,
+            "gyroscope temperature",
+            calibs.gyroscope_thermal.as_ref().and_then(|calib| calib.record.as_ref()),
+            &hardware.gyroscope,
+            LSM9DS0_GYROSCOPE_ADDRESS,
+        );
+        check_record(
+            &imu.logger,
+            "accelerometer temperature",
+            calibs.accelerometer_thermal.as_ref().and_then(|calib| calib.record.as_ref()),
+            &hardware.accelerometer,
+            LSM9DS0_ACCELEROMETER_MAGNETOMETER_ADDRESS,
+        );
+
+        match calibs.gyroscope {
+            Some(ref gyro_calibs) => imu.gyroscope_offsets = gyro_calibs.get_offsets(),
+            None => {}
+        };
+        match calibs.accelerometer {
+            Some(ref accel_calibs) => {
+                imu.accelerometer_offsets = accel_calibs.get_offsets();
+                match accel_calibs.get_correction() {
+                    Some(correction) => imu.accelerometer_correction = correction,
+                    None => imu.logger.error(
+                        "Accelerometer calibration only has offsets. Recalibrate to correct scale and misalignment.",
+                    ),
+                };
+            }
+            None => {}
+        };
+        match (&calibs.gyroscope_thermal, &calibs.accelerometer_thermal) {
+            (&None, &None) => {}
+            _ if imu.thermometer.is_none() => imu.logger.error(
+                "There's a temperature calibration but no die temperature. Drift won't be compensated.",
+            ),
+            _ => {
+                imu.gyroscope_thermal = calibs.gyroscope_thermal.clone();
+                imu.accelerometer_thermal = calibs.accelerometer_thermal.clone();
+            }
+        };
+        match calibs.magnetometer {
+            Some(ref mag_calibs) => {
+                imu.magnetometer_offsets = mag_calibs.get_offsets();
+                match mag_calibs.get_correction() {
+                    Some(correction) => imu.magnetometer_correction = correction,
+                    None => imu.logger
+                        .error("Magnetometer calibration is degenerate. Only the offsets are used."),
+                };
+            }
+            None => {}
+        };
 
         // Test IMU readings
         sleep(Duration::from_millis(50));
@@ -164,7 +344,8 @@ impl IMU {
         Ok(imu)
     }
 
-    fn read_gyroscope_raw(&mut self) -> Result<Vector3<f64>, ()> {
+    // Raw readings are before any calibration, the way the sensor measured them.
+    pub fn read_gyroscope_raw(&mut self) -> Result<Vector3<f64>, ()> {
         match self.gyroscope.borrow_mut().angular_rate_reading() {
             Ok(angular_rate) => Ok(Vector3::new(
                 // Right hand rule. LSM9DS0 is opposite for roll and yaw...
@@ -179,11 +360,11 @@ impl IMU {
         }
     }
 
-    fn read_accelerometer_raw(&mut self) -> Result<Vector3<f64>, ()> {
+    pub fn read_accelerometer_raw(&mut self) -> Result<Vector3<f64>, ()> {
         match self.accelerometer.borrow_mut().acceleration_reading() {
             Ok(acceleration) => Ok(Vector3::new(
-                acceleration.x as f64,
-                -acceleration.y as f64,
+                (acceleration.x as f64) * G_TO_MPSPS,
+                (-acceleration.y as f64) * G_TO_MPSPS,
                 (acceleration.z as f64) * G_TO_MPSPS,
             )),
             Err(_) => {
@@ -207,181 +388,783 @@ impl IMU {
         }
     }
 
+    // The die temperature is read at most once a period, it changes slowly.
+    pub fn read_die_temperature(&mut self) -> Option<f64> {
+        let now = self.clock.now();
+        match self.die_temperature_read {
+            Some(read)
+                if now.duration_since(read) < Duration::from_millis(DIE_TEMPERATURE_PERIOD_MS) =>
+            {
+                return self.die_temperature;
+            }
+            _ => {}
+        };
+        let reading = match self.thermometer {
+            Some(ref thermometer) => thermometer.borrow_mut().temperature_celsius(),
+            None => return None,
+        };
+        self.die_temperature_read = Some(now);
+        match reading {
+            Ok(temperature) => self.die_temperature = Some(temperature as f64),
+            Err(_) => self.logger.error("Couldn't read the IMU die temperature."),
+        };
+        self.die_temperature
+    }
+
+    // Raw readings as they would be at the reference temperature. Calibrations that
+    // come after the temperature calibration are taken from these.
+    fn read_gyroscope_at_reference(&mut self) -> Result<Vector3<f64>, ()> {
+        let temperature = if self.gyroscope_thermal.is_some() {
+            self.read_die_temperature()
+        } else {
+            None
+        };
+        let drift = thermal_drift(&self.gyroscope_thermal, temperature);
+        Ok(self.read_gyroscope_raw()? - drift)
+    }
+
+    fn read_accelerometer_at_reference(&mut self) -> Result<Vector3<f64>, ()> {
+        let temperature = if self.accelerometer_thermal.is_some() {
+            self.read_die_temperature()
+        } else {
+            None
+        };
+        let drift = thermal_drift(&self.accelerometer_thermal, temperature);
+        Ok(self.read_accelerometer_raw()? - drift)
+    }
+
+    // Largest raw acceleration the accelerometer can measure in m/s^2.
+    pub fn accelerometer_full_scale(&self) -> f64 {
+        self.settings.accelerometer_full_scale as f64 * G_TO_MPSPS
+    }
+
+    // Output data rates in Hz. Sampling faster only repeats readings.
+    pub fn gyroscope_rate(&self) -> u32 {
+        self.settings.gyroscope_rate as u32
+    }
+
+    pub fn accelerometer_rate(&self) -> u32 {
+        self.settings.accelerometer_rate as u32
+    }
+
     pub fn read_gyroscope(&mut self) -> Result<Vector3<f64>, ()> {
-        match self.read_gyroscope_raw() {
+        match self.read_gyroscope_at_reference() {
             Ok(angular_rate_raw) => Ok(angular_rate_raw - self.gyroscope_offsets),
             Err(_) => Err(()),
         }
     }
     pub fn read_accelerometer(&mut self) -> Result<Vector3<f64>, ()> {
-        match self.read_accelerometer_raw() {
-            Ok(acceleration_raw) => Ok(acceleration_raw - self.accelerometer_offsets),
+        match self.read_accelerometer_at_reference() {
+            Ok(acceleration_raw) => {
+                Ok(self.accelerometer_correction * (acceleration_raw - self.accelerometer_offsets))
+            }
             Err(_) => Err(()),
         }
     }
     pub fn read_magnetometer(&mut self) -> Result<Vector3<f64>, ()> {
         match self.read_magnetometer_raw() {
             Ok(magnetic_reading_raw) => {
-                let offset_corrected = magnetic_reading_raw - self.magnetometer_offsets;
-                let rotation_corrected = self.magnetometer_rotation * offset_corrected;
-                Ok(rotation_corrected.component_div(&self.magnetometer_gains))
+                Ok(self.magnetometer_correction * (magnetic_reading_raw - self.magnetometer_offsets))
             }
             Err(_) => Err(()),
         }
     }
 
-    pub fn calibrate_sensors(&mut self) {
-        let mut calibrations = Calibrations::new().unwrap();
-        // self.calibrate_magnetometer(&mut calibrations);
-        self.calibrate_gyroscope(&mut calibrations);
-        self.calibrate_accelerometer(&mut calibrations);
+    // Runs the steps in order and saves what completed, unless the session was
+    // cancelled.
+    pub fn calibrate_sensors(&mut self, session: &mut CalibrationSession) -> CalibrationReport {
+        let mut calibrations = calibrations_to_update(&self.directory, &self.logger);
+        let mut completed = false;
+        let steps = [
+            CalibrationStep::Magnetometer,
+            CalibrationStep::Gyroscope,
+            CalibrationStep::Accelerometer,
+        ];
+        for step in steps.iter() {
+            let report = match *step {
+                CalibrationStep::Magnetometer => {
+                    self.calibrate_magnetometer(session, &mut calibrations)
+                }
+                CalibrationStep::Gyroscope => self.calibrate_gyroscope(session, &mut calibrations),
+                _ => self.calibrate_accelerometer(session, &mut calibrations),
+            };
+            completed = completed || report.is_completed();
+            session.finish_step(report);
+            if session.is_cancelled() {
+                break;
+            }
+        }
 
-        calibrations.save().unwrap();
+        let saved = completed && !session.is_cancelled()
+            && save_calibrations(&calibrations, &self.directory, &self.logger);
+        session.finish(saved)
     }
 
-    fn calibrate_magnetometer(&mut self, calibs: &mut Calibrations) {
-        println!("You are now going to calibrate the magnetometer.");
-        println!("Keep the drone several feet away from any metal.");
-        println!("During the calibration spin the drone to get samples from every direction.");
-        println!("Press enter to begin...");
-        let mut input = String::new();
-        io::stdin().read_line(&mut input).unwrap();
+    fn calibrate_magnetometer(
+        &mut self,
+        session: &mut CalibrationSession,
+        calibs: &mut Calibrations,
+    ) -> StepReport {
+        let step = CalibrationStep::Magnetometer;
+        match session.confirm(
+            step,
+            "Keep the drone several feet away from any metal, then slowly turn it to point every side in every direction.",
+        ) {
+            Ok(()) => {}
+            Err(report) => return report,
+        };
 
-        let mut D = Matrix200x9::new_random();
+        let mut calibration = MagnetometerCalibration::new();
+        let mut reported = 0;
+        let mut readings = 0;
+        let mut gave_up = false;
+        while !calibration.is_complete() {
+            match session.check(step) {
+                Ok(()) => {}
+                Err(report) => return report,
+            };
+            if readings == MAX_MAGNETOMETER_READINGS {
+                gave_up = true;
+                break;
+            }
+            readings += 1;
 
-        println!("3...");
-        sleep(Duration::from_secs(1));
-        println!("2...");
-        sleep(Duration::from_secs(1));
-        println!("1...");
-        sleep(Duration::from_secs(1));
-        println!("Go!");
-        for i in 0..200 {
             let magnetic_reading = self.read_magnetometer_raw().unwrap();
-            let D_i = Vector9::from_fn(|r, c| match r {
-                0 => magnetic_reading.x * magnetic_reading.x,
-                1 => magnetic_reading.y * magnetic_reading.y,
-                2 => magnetic_reading.z * magnetic_reading.z,
-                3 => 2.0 * magnetic_reading.x * magnetic_reading.y,
-                4 => 2.0 * magnetic_reading.x * magnetic_reading.z,
-                5 => 2.0 * magnetic_reading.y * magnetic_reading.z,
-                6 => 2.0 * magnetic_reading.x,
-                7 => 2.0 * magnetic_reading.y,
-                8 => 2.0 * magnetic_reading.z,
-                _ => 0.0,
-            });
-
-            D.row_mut(i).copy_from(&D_i.transpose());
-
-            sleep(Duration::from_millis(100));
+            if calibration.add_sample(magnetic_reading) {
+                let coverage = (calibration.coverage() * 100.0) as u32;
+                if coverage / 10 > reported / 10 {
+                    session.progress(
+                        step,
+                        &format!("Coverage: {}%", coverage),
+                        Some(calibration.coverage()),
+                    );
+                    reported = coverage;
+                }
+            }
+            self.clock.sleep(Duration::from_millis(50));
+        }
+
+        let temperature = self.read_die_temperature();
+        let mut record = self.magnetometer_record.renewed();
+        record.temperature = temperature;
+        let mut report = fit_magnetometer(&calibration, record, calibs);
+        if gave_up {
+            report.warn(&format!(
+                "Gave up at {:.0}% coverage.",
+                calibration.coverage() * 100.0
+            ));
+        }
+        match write_samples(
+            &self.directory.join(MAGNETOMETER_SAMPLES_FILE).to_string_lossy(),
+            calibration.samples(),
+        ) {
+            Ok(()) => {}
+            Err(e) => report.warn(&e),
+        };
+        report
+    }
+
+    fn calibrate_gyroscope(
+        &mut self,
+        session: &mut CalibrationSession,
+        calibs: &mut Calibrations,
+    ) -> StepReport {
+        let step = CalibrationStep::Gyroscope;
+        match session.confirm(step, "Place the drone on a level surface.") {
+            Ok(()) => {}
+            Err(report) => return report,
+        };
+        for count in (1..4).rev() {
+            session.progress(step, &format!("{}...", count), None);
+            self.clock.sleep(Duration::from_secs(1));
         }
+        session.progress(step, "Go!", None);
 
-        // Rotated ellipsoid fitting
-        let mut ones: Vector200 = Vector200::from_element(1.0);
+        let mut readings = Vec::with_capacity(GYROSCOPE_READINGS);
+        for _ in 0..GYROSCOPE_READINGS {
+            match session.check(step) {
+                Ok(()) => {}
+                Err(report) => return report,
+            };
+            readings.push(self.read_gyroscope_at_reference().unwrap());
+            self.clock.sleep(Duration::from_millis(20));
+        }
+        let offsets =
+            readings.iter().fold(Vector3::zero(), |sum, reading| sum + reading) / readings.len() as f64;
+        let noise = readings.iter().fold(0.0, |sum, reading| {
+            sum + (reading - offsets).norm_squared()
+        }) / readings.len() as f64;
 
-        // v[9x1] = (D^T D)^(-1)D^T
-        let v = (D.transpose() * D).try_inverse().unwrap() * (&D.transpose() * ones);
+        let mut report = StepReport::new(step);
+        report.metric("offset x (rad/s)", offsets.x);
+        report.metric("offset y (rad/s)", offsets.y);
+        report.metric("offset z (rad/s)", offsets.z);
+        report.metric("noise (rad/s)", noise.sqrt());
+        if noise.sqrt() > MAX_GYROSCOPE_NOISE {
+            report.reject("The drone moved while the gyroscope was sampled.");
+            return report;
+        }
+        let mut gyroscope = Simple::new(offsets);
+        let template = self.gyroscope_record.clone();
+        gyroscope.record = Some(self.record(&template, Some(noise.sqrt())));
+        calibs.gyroscope = Some(gyroscope);
+        report
+    }
 
-        // Auxillary matrices
-        #[rustfmt_skip]
-        let A_4: Matrix4<f64> = Matrix4::new(v.data[0], v.data[3], v.data[4], v.data[6], 
-                                             v.data[3], v.data[1], v.data[5], v.data[7], 
-                                             v.data[4], v.data[5], v.data[2], v.data[8], 
-                                             v.data[6], v.data[7], v.data[8], -1.0,
-        );
+    fn calibrate_accelerometer(
+        &mut self,
+        session: &mut CalibrationSession,
+        calibs: &mut Calibrations,
+    ) -> StepReport {
+        let step = CalibrationStep::Accelerometer;
+        let orientations: Vec<&str> = Orientation::all()
+            .iter()
+            .map(|orientation| orientation.description())
+            .collect();
+        match session.confirm(
+            step,
+            &format!(
+                "Hold the drone still in each of these orientations, in any order: {}. Each one is taken after a second without moving.",
+                orientations.join(", ")
+            ),
+        ) {
+            Ok(()) => {}
+            Err(report) => return report,
+        };
 
-        #[rustfmt_skip]
-        let A_3: Matrix3<f64> = Matrix3::new(v.data[0],v.data[3],v.data[4],
-                                             v.data[3],v.data[1],v.data[5],
-                                             v.data[4],v.data[5],v.data[2],
-        );
-        let v_ghi: Vector3<f64> = Vector3::new(v.data[6], v.data[7], v.data[8]);
-
-        // Compute offsets
-        let offsets = -1.0 * A_3.try_inverse().unwrap() * v_ghi;
-
-        // More auxillary matrices
-        #[rustfmt_skip]
-        let T: Matrix4<f64> = Matrix4::new(1.0,0.0,0.0,0.0,
-                                           0.0,1.0,0.0,0.0,
-                                           0.0,0.0,1.0,0.0,
-                                           offsets.data[0],offsets.data[1],offsets.data[2],1.0);
-        let B_4 = T * A_4 * T.transpose();
-
-        let b_44 = -1.0 * B_4.data[15];
-        let B_3: Matrix3<f64> = B_4.fixed_resize(0.0) / b_44;
-
-        // Compute gains and rotation
-        let eigen_decomp = B_3.symmetric_eigen();
-        let gains: Vector3<f64> =
-            Vector3::from_fn(|r, c| (1.0 / eigen_decomp.eigenvalues.data[r]).sqrt());
-        let rotation: Matrix3<f64> = eigen_decomp.eigenvectors.try_inverse().unwrap();
-
-        calibs.magnetometer = Some(Ellipsoid::new(offsets, rotation, gains));
-    }
-
-    fn calibrate_gyroscope(&mut self, calibs: &mut Calibrations) {
-        println!("Now calibrating the gyroscope.");
-        println!("Place the drone on a level surface.");
-        println!("Press enter to begin...");
-        let mut input = String::new();
-        io::stdin().read_line(&mut input).unwrap();
-        println!("3...");
-        sleep(Duration::from_secs(1));
-        println!("2...");
-        sleep(Duration::from_secs(1));
-        println!("1...");
-        sleep(Duration::from_secs(1));
-        println!("Go!");
-
-        let mut offsets = self.read_gyroscope_raw().unwrap();
-        for i in 0..200 {
-            offsets += self.read_gyroscope_raw().unwrap();
-            sleep(Duration::from_millis(20));
+        let mut calibration = SixPositionCalibration::new();
+        while !calibration.is_complete() {
+            match session.check(step) {
+                Ok(()) => {}
+                Err(report) => return report,
+            };
+            let acceleration = self.read_accelerometer_at_reference().unwrap();
+            match calibration.add_sample(acceleration) {
+                Some(orientation) => {
+                    let remaining: Vec<&str> = calibration
+                        .remaining()
+                        .iter()
+                        .map(|orientation| orientation.description())
+                        .collect();
+                    let mut message = format!("Got {}.", orientation.description());
+                    if !remaining.is_empty() {
+                        message.push_str(&format!(" Still to do: {}.", remaining.join(", ")));
+                    }
+                    session.progress(
+                        step,
+                        &message,
+                        Some(1.0 - remaining.len() as f64 / orientations.len() as f64),
+                    );
+                }
+                None => {}
+            };
+            self.clock.sleep(Duration::from_millis(20));
+        }
+
+        let mut report = StepReport::new(step);
+        let fit = match calibration.fit() {
+            Ok(fit) => fit,
+            Err(e) => {
+                report.reject(&e);
+                return report;
+            }
+        };
+        report.metric("offset x (m/s^2)", fit.offsets.x);
+        report.metric("offset y (m/s^2)", fit.offsets.y);
+        report.metric("offset z (m/s^2)", fit.offsets.z);
+        report.metric("scale x", fit.scale.x);
+        report.metric("scale y", fit.scale.y);
+        report.metric("scale z", fit.scale.z);
+        report.metric("misalignment (%)", fit.misalignment * 100.0);
+        report.metric("residual (m/s^2)", fit.residual);
+        if fit.residual > MAX_ACCELEROMETER_RESIDUAL {
+            report.reject(
+                "The residual is too large. Hold the drone square to a flat surface in each orientation and try again.",
+            );
+            return report;
+        }
+
+        let mut ellipsoid = Ellipsoid::new(fit.offsets, fit.correction, Vector3::from_element(1.0));
+        ellipsoid.residual = Some(fit.residual);
+        let template = self.accelerometer_record.clone();
+        ellipsoid.record = Some(self.record(&template, Some(fit.residual)));
+        calibs.accelerometer = Some(ellipsoid);
+        report
+    }
+
+    // Where and when a calibration was taken, for checking it still applies.
+    fn record(&mut self, template: &Record, residual: Option<f64>) -> Record {
+        let mut record = template.renewed();
+        record.temperature = self.read_die_temperature();
+        record.residual = residual;
+        record
+    }
+
+    // Needs a cold start, the drift is logged while the IMU warms up. Run the other
+    // calibrations afterwards, they're taken at the reference temperature.
+    pub fn calibrate_thermal(
+        &mut self,
+        session: &mut CalibrationSession,
+        calibs: &mut Calibrations,
+    ) -> StepReport {
+        let step = CalibrationStep::Thermal;
+        if self.thermometer.is_none() {
+            return StepReport::ended(
+                step,
+                StepOutcome::Rejected(String::from(
+                    "There's no die temperature, so drift can't be calibrated.",
+                )),
+            );
         }
+        match session.confirm(
+            step,
+            &format!(
+                "Start with the drone cold, for example straight out of a fridge. Place it on a level surface and don't touch it until it's done. This takes until the IMU stops warming up, at most {} minutes.",
+                MAX_THERMAL_MINUTES
+            ),
+        ) {
+            Ok(()) => {}
+            Err(report) => return report,
+        };
+
+        let mut calibration = ThermalCalibration::new();
+        let start = self.clock.now();
+        let deadline = start + Duration::from_secs(MAX_THERMAL_MINUTES * 60);
+        let mut minute_start = start;
+        let mut minute_temperature = None;
+        let mut rejected = 0;
+        loop {
+            match session.check(step) {
+                Ok(()) => {}
+                Err(report) => return report,
+            };
+            let mut angular_rates = Vec::with_capacity(THERMAL_WINDOW_READINGS);
+            let mut accelerations = Vec::with_capacity(THERMAL_WINDOW_READINGS);
+            for _ in 0..THERMAL_WINDOW_READINGS {
+                angular_rates.push(self.read_gyroscope_raw().unwrap());
+                accelerations.push(self.read_accelerometer_raw().unwrap());
+                self.clock.sleep(Duration::from_millis(20));
+            }
+            // Keeps trying while the thermometer is failing, but not past the deadline.
+            let temperature = match self.read_die_temperature() {
+                Some(temperature) => temperature,
+                None if self.clock.now() < deadline => continue,
+                None => break,
+            };
+            if !calibration.add_window(temperature, &angular_rates, &accelerations) {
+                rejected += 1;
+            }
 
-        offsets /= 201.0;
-
-        calibs.gyroscope = Some(Simple::new(offsets));
-    }
-
-    fn calibrate_accelerometer(&mut self, calibs: &mut Calibrations) {
-        println!("Now calibrating accelerometer.");
-        println!("Place the drone on a level surface.");
-        println!("Press enter to begin...");
-        let mut input = String::new();
-        io::stdin().read_line(&mut input).unwrap();
-        println!("3...");
-        sleep(Duration::from_secs(1));
-        println!("2...");
-        sleep(Duration::from_secs(1));
-        println!("1...");
-        sleep(Duration::from_secs(1));
-        println!("Go!");
-
-        let mut offsets = self.read_accelerometer_raw().unwrap();
-        for i in 0..200 {
-            offsets += self.read_accelerometer_raw().unwrap();
-            sleep(Duration::from_millis(20));
+            let now = self.clock.now();
+            if now.duration_since(minute_start) < Duration::from_secs(60) {
+                continue;
+            }
+            let minutes = now.duration_since(start).as_secs() / 60;
+            session.progress(
+                step,
+                &format!(
+                    "{} minutes: {:.1} C, {:.1} C range, {} samples, {} rejected for moving",
+                    minutes,
+                    temperature,
+                    calibration.temperature_span(),
+                    calibration.samples().len(),
+                    rejected
+                ),
+                Some(minutes as f64 / MAX_THERMAL_MINUTES as f64),
+            );
+            let settled = match minute_temperature {
+                Some(previous) => (temperature - previous).abs() < SETTLED_TEMPERATURE_CHANGE,
+                None => false,
+            };
+            if settled || now >= deadline {
+                break;
+            }
+            minute_start = now;
+            minute_temperature = Some(temperature);
         }
 
-        offsets /= 201.0;
-        offsets = offsets - Vector3::new(0.0, 0.0, G_TO_MPSPS);
+        let mut report = StepReport::new(step);
+        report.metric("samples", calibration.samples().len() as f64);
+        report.metric("rejected windows", rejected as f64);
+        match write_thermal_samples(
+            &self.directory.join(THERMAL_SAMPLES_FILE).to_string_lossy(),
+            calibration.samples(),
+        ) {
+            Ok(()) => {}
+            Err(e) => report.warn(&e),
+        };
+        let fit = match calibration.fit(THERMAL_DEGREE) {
+            Ok(fit) => fit,
+            Err(e) => {
+                report.reject(&e);
+                return report;
+            }
+        };
+        report.metric("reference temperature (C)", fit.reference_temperature);
+        report.metric("min temperature (C)", fit.min_temperature);
+        report.metric("max temperature (C)", fit.max_temperature);
+        report.metric("gyroscope bias x (rad/s)", fit.gyroscope_bias.x);
+        report.metric("gyroscope bias y (rad/s)", fit.gyroscope_bias.y);
+        report.metric("gyroscope bias z (rad/s)", fit.gyroscope_bias.z);
+        report.metric("gyroscope residual (rad/s)", fit.gyroscope_residual);
+        report.metric("accelerometer residual (m/s^2)", fit.accelerometer_residual);
+
+        // The records say when the fit finished, at the warmest temperature.
+        let template = self.gyroscope_record.clone();
+        let gyroscope_record = self.record(&template, Some(fit.gyroscope_residual));
+        let template = self.accelerometer_record.clone();
+        let accelerometer_record = self.record(&template, Some(fit.accelerometer_residual));
+        let thermal = |coefficients: &[Vec<f64>; 3], record: &Record| Thermal {
+            reference_temperature: fit.reference_temperature,
+            min_temperature: fit.min_temperature,
+            max_temperature: fit.max_temperature,
+            x: coefficients[0].clone(),
+            y: coefficients[1].clone(),
+            z: coefficients[2].clone(),
+            record: Some(record.clone()),
+        };
+        calibs.gyroscope_thermal = Some(thermal(&fit.gyroscope, &gyroscope_record));
+        calibs.accelerometer_thermal = Some(thermal(&fit.accelerometer, &accelerometer_record));
+        let mut gyroscope = Simple::new(fit.gyroscope_bias);
+        gyroscope.record = Some(Record {
+            temperature: Some(fit.reference_temperature),
+            ..gyroscope_record
+        });
+        calibs.gyroscope = Some(gyroscope);
+        self.gyroscope_thermal = calibs.gyroscope_thermal.clone();
+        self.accelerometer_thermal = calibs.accelerometer_thermal.clone();
+        self.gyroscope_offsets = fit.gyroscope_bias;
+        report
+    }
+}
+
+// Drift isn't compensated until the die temperature has been read.
+fn thermal_drift(thermal: &Option<Thermal>, temperature: Option<f64>) -> Vector3<f64> {
+    match (thermal, temperature) {
+        (&Some(ref thermal), Some(temperature)) => thermal.get_drift(temperature),
+        _ => Vector3::zero(),
+    }
+}
+
+// The configured magnetometer, as recorded with its calibration.
+pub fn magnetometer_record(hardware: &Hardware) -> Record {
+    Record::new(&hardware.magnetometer, LSM9DS0_ACCELEROMETER_MAGNETOMETER_ADDRESS)
+}
+
+// Shared by live calibration and fitting saved samples. Leaves the calibration
+// alone if the fit fails validation.
+pub fn fit_magnetometer(
+    calibration: &MagnetometerCalibration,
+    mut record: Record,
+    calibs: &mut Calibrations,
+) -> StepReport {
+    let mut report = StepReport::new(CalibrationStep::Magnetometer);
+    report.metric("coverage (%)", calibration.coverage() * 100.0);
+    let fit = match calibration.fit() {
+        Ok(fit) => fit,
+        Err(e) => {
+            report.reject(&e);
+            return report;
+        }
+    };
+    report.metric("offset x (gauss)", fit.offsets.x);
+    report.metric("offset y (gauss)", fit.offsets.y);
+    report.metric("offset z (gauss)", fit.offsets.z);
+    report.metric("field strength (gauss)", fit.field_strength);
+    report.metric("axis ratio", fit.axis_ratio);
+    report.metric("residual (%)", fit.residual * 100.0);
+    report.metric("samples", fit.samples as f64);
+    report.metric("rejected samples", fit.rejected as f64);
+    if fit.diagonal {
+        report.warn("The full fit was ill conditioned, so soft iron is only corrected along the sensor axes.");
+    }
+    if fit.field_strength < MIN_EARTH_FIELD || fit.field_strength > MAX_EARTH_FIELD {
+        report.reject(&format!(
+            "The field is {:.3} gauss but Earth's is {} to {}. Check for nearby metal.",
+            fit.field_strength, MIN_EARTH_FIELD, MAX_EARTH_FIELD
+        ));
+        return report;
+    }
+    match fit.validate() {
+        Ok(()) => {}
+        Err(e) => {
+            report.reject(&e);
+            return report;
+        }
+    };
+
+    let mut ellipsoid = Ellipsoid::new(fit.offsets, fit.correction, Vector3::from_element(1.0));
+    ellipsoid.residual = Some(fit.residual);
+    record.residual = Some(fit.residual);
+    ellipsoid.record = Some(record);
+    calibs.magnetometer = Some(ellipsoid);
+    report
+}
+
+// Recalibrating starts over from corrupt calibrations rather than failing. The
+// corrupt file is kept in the history when the new ones are saved.
+pub fn calibrations_to_update(config_directory: &Path, logger: &ModuleLogger) -> Calibrations {
+    match Calibrations::load(&calibrations_path(config_directory)) {
+        Ok(calibrations) => calibrations,
+        Err(CalibrationError::Missing) => Calibrations::default(),
+        Err(e) => {
+            logger.error(&format!("{} Starting over.", e));
+            Calibrations::default()
+        }
+    }
+}
+
+// False if the calibrations couldn't be saved.
+pub fn save_calibrations(
+    calibrations: &Calibrations,
+    config_directory: &Path,
+    logger: &ModuleLogger,
+) -> bool {
+    match calibrations.save(config_directory) {
+        Ok(()) => true,
+        Err(e) => {
+            logger.error(&format!("Couldn't save the calibrations: {}", e));
+            false
+        }
+    }
+}
+
+// Calibrations from before records can't be checked.
+fn check_record(
+    logger: &ModuleLogger,
+    name: &str,
+    record: Option<&Record>,
+    sensor: &Sensor,
+    default_address: u16,
+) {
+    match record.and_then(|record| record.mismatch(sensor, default_address)) {
+        Some(mismatch) => logger.error(&format!(
+            "The {} calibration doesn't match the configured sensor: {}. Recalibrate.",
+            name, mismatch
+        )),
+        None => {}
+    };
+}
+
+// The die temperature of the accelerometer/magnetometer, through its own handle so
+// the driver doesn't need to know about it.
+pub struct LSM9DS0Thermometer<T: I2CDevice> {
+    device: T,
+}
 
-        calibs.accelerometer = Some(Ellipsoid::new(offsets, Matrix3::zero(), Vector3::zero()));
+impl<T: I2CDevice> LSM9DS0Thermometer<T> {
+    // The driver writes CTRL_REG5_XM while it's set up, so this has to come after.
+    pub fn new(mut device: T) -> Result<LSM9DS0Thermometer<T>, T::Error> {
+        let control = device.smbus_read_byte_data(LSM9DS0_CTRL_REG5_XM)?;
+        device.smbus_write_byte_data(LSM9DS0_CTRL_REG5_XM, control | LSM9DS0_TEMP_EN)?;
+        Ok(LSM9DS0Thermometer { device: device })
     }
 }
 
+impl<T: I2CDevice> Thermometer for LSM9DS0Thermometer<T> {
+    type Error = T::Error;
+
+    // 12 bit two's complement, 8 per degree with 21 C at zero.
+    fn temperature_celsius(&mut self) -> Result<f32, T::Error> {
+        let data = self.device
+            .smbus_read_i2c_block_data(LSM9DS0_OUT_TEMP_L_XM | LSM9DS0_MULTIPLE_READ, 2)?;
+        let raw = (((data[1] as u16) << 8 | data[0] as u16) << 4) as i16 >> 4;
+        Ok(21.0 + raw as f32 / 8.0)
+    }
+}
+
+#[cfg(target_arch = "arm")]
+fn get_lsm9ds0_thermometer(
+    hardware: &Hardware,
+) -> Result<Rc<RefCell<Thermometer<Error = LinuxI2CError>>>, ()> {
+    match hardware.accelerometer.serial {
+        SerialCommunication::SPI => {
+            let device = get_spi_device(&hardware.accelerometer, SpiProtocol::lsm9ds0())?;
+            match LSM9DS0Thermometer::new(device) {
+                Ok(thermometer) => Ok(Rc::new(RefCell::new(thermometer))),
+                Err(_) => Err(()),
+            }
+        }
+        _ => {
+            let device = get_i2c_device(
+                &hardware.accelerometer,
+                LSM9DS0_ACCELEROMETER_MAGNETOMETER_ADDRESS,
+            )?;
+            match LSM9DS0Thermometer::new(device) {
+                Ok(thermometer) => Ok(Rc::new(RefCell::new(thermometer))),
+                Err(_) => Err(()),
+            }
+        }
+    }
+}
+
+fn share_sensors<S>(sensor: S) -> SharedSensors
+where
+    S: Gyroscope<Error = LinuxI2CError>
+        + Accelerometer<Error = LinuxI2CError>
+        + Magnetometer<Error = LinuxI2CError>
+        + 'static,
+{
+    let sensor_ref = Rc::new(RefCell::new(sensor));
+    (sensor_ref.clone(), sensor_ref.clone(), sensor_ref.clone())
+}
+
+#[cfg(target_arch = "arm")]
+fn get_lsm9ds0_i2c(
+    logger: &ModuleLogger,
+    hardware: &Hardware,
+) -> Result<(LSM9DS0<LinuxI2CDevice>, SensorSettings), ()> {
+    let gyro = get_i2c_device(&hardware.gyroscope, LSM9DS0_GYROSCOPE_ADDRESS)?;
+    let accel = get_i2c_device(
+        &hardware.accelerometer,
+        LSM9DS0_ACCELEROMETER_MAGNETOMETER_ADDRESS,
+    )?;
+    get_lsm9ds0(logger, hardware, gyro, accel)
+}
+
+// The gyroscope and the accelerometer/magnetometer each have their own chip select,
+// so their buses name two different spidev devices.
+#[cfg(target_arch = "arm")]
+fn get_lsm9ds0_spi(
+    logger: &ModuleLogger,
+    hardware: &Hardware,
+) -> Result<(LSM9DS0<SpiRegisterDevice<Spidev>>, SensorSettings), ()> {
+    let gyro = get_spi_device(&hardware.gyroscope, SpiProtocol::lsm9ds0())?;
+    let accel = get_spi_device(&hardware.accelerometer, SpiProtocol::lsm9ds0())?;
+    get_lsm9ds0(logger, hardware, gyro, accel)
+}
+
 #[cfg(target_arch = "arm")]
-fn get_lsm9ds0() -> Result<LSM9DS0<LinuxI2CDevice>, ()> {
+fn get_lsm9ds0<T: I2CDevice<Error = LinuxI2CError> + Sized>(
+    logger: &ModuleLogger,
+    hardware: &Hardware,
+    gyro: T,
+    accel: T,
+) -> Result<(LSM9DS0<T>, SensorSettings), ()> {
+    if hardware.gyroscope.serial != hardware.accelerometer.serial
+        || hardware.accelerometer.serial != hardware.magnetometer.serial
+    {
+        logger.error("LSM9DS0 gyroscope, accelerometer and magnetometer must use the same serial communication.");
+        return Err(());
+    }
+
+    let (gyro_rate, gyro_data_rate, gyro_bandwidth) = select_rate(
+        logger,
+        &hardware.gyroscope,
+        DEFAULT_GYROSCOPE_RATE,
+        &[
+            (95, (95, LSM9DS0GyroscopeDataRate::Hz95, LSM9DS0GyroscopeBandwidth::BW1)),
+            (190, (190, LSM9DS0GyroscopeDataRate::Hz190, LSM9DS0GyroscopeBandwidth::BW2)),
+            (380, (380, LSM9DS0GyroscopeDataRate::Hz380, LSM9DS0GyroscopeBandwidth::BW3)),
+            (760, (760, LSM9DS0GyroscopeDataRate::Hz760, LSM9DS0GyroscopeBandwidth::BW4)),
+        ],
+    )?;
+
+    let (accel_rate, accel_data_rate, accel_bandwidth) = select_rate(
+        logger,
+        &hardware.accelerometer,
+        DEFAULT_ACCELEROMETER_RATE,
+        &[
+            (
+                100,
+                (
+                    100,
+                    LSM9DS0AccelerometerUpdateRate::Hz100,
+                    LSM9DS0AccelerometerFilterBandwidth::Hz50,
+                ),
+            ),
+            (
+                200,
+                (
+                    200,
+                    LSM9DS0AccelerometerUpdateRate::Hz200,
+                    LSM9DS0AccelerometerFilterBandwidth::Hz50,
+                ),
+            ),
+            (
+                400,
+                (
+                    400,
+                    LSM9DS0AccelerometerUpdateRate::Hz400,
+                    LSM9DS0AccelerometerFilterBandwidth::Hz194,
+                ),
+            ),
+            (
+                800,
+                (
+                    800,
+                    LSM9DS0AccelerometerUpdateRate::Hz800,
+                    LSM9DS0AccelerometerFilterBandwidth::Hz194,
+                ),
+            ),
+            (
+                1600,
+                (
+                    1600,
+                    LSM9DS0AccelerometerUpdateRate::Hz1600,
+                    LSM9DS0AccelerometerFilterBandwidth::Hz773,
+                ),
+            ),
+        ],
+    )?;
+
+    let gyro_full_scale = select_full_scale(
+        logger,
+        &hardware.gyroscope,
+        500,
+        "dps",
+        &[
+            (245, LSM9DS0GyroscopeFS::dps245),
+            (500, LSM9DS0GyroscopeFS::dps500),
+            (2000, LSM9DS0GyroscopeFS::dps2000),
+        ],
+    )?;
+
+    let (accel_range, accel_full_scale) = select_full_scale(
+        logger,
+        &hardware.accelerometer,
+        DEFAULT_ACCELEROMETER_FULL_SCALE,
+        "g",
+        &[
+            (2, (2, LSM9DS0AccelerometerFS::g2)),
+            (4, (4, LSM9DS0AccelerometerFS::g4)),
+            (6, (6, LSM9DS0AccelerometerFS::g6)),
+            (8, (8, LSM9DS0AccelerometerFS::g8)),
+            (16, (16, LSM9DS0AccelerometerFS::g16)),
+        ],
+    )?;
+
+    let mag_data_rate = select_rate(
+        logger,
+        &hardware.magnetometer,
+        50,
+        &[
+            (50, LSM9DS0MagnetometerUpdateRate::Hz50),
+            (100, LSM9DS0MagnetometerUpdateRate::Hz100),
+        ],
+    )?;
+
+    // The accelerometer and magnetometer share a single slave on the LSM9DS0.
+    let accel_address = hardware
+        .accelerometer
+        .address(LSM9DS0_ACCELEROMETER_MAGNETOMETER_ADDRESS);
+    let mag_address = hardware
+        .magnetometer
+        .address(LSM9DS0_ACCELEROMETER_MAGNETOMETER_ADDRESS);
+    if accel_address != mag_address || hardware.accelerometer.bus() != hardware.magnetometer.bus()
+    {
+        logger.error("LSM9DS0 accelerometer and magnetometer must use the same bus and address.");
+        return Err(());
+    }
+
     let gyro_settings = LSM9DS0GyroscopeSettings {
-        DR: LSM9DS0GyroscopeDataRate::Hz95,
-        BW: LSM9DS0GyroscopeBandwidth::BW1,
+        DR: gyro_data_rate,
+        BW: gyro_bandwidth,
         power_mode: LSM9DS0PowerMode::Normal,
         zen: true,
         yen: true,
         xen: true,
-        sensitivity: LSM9DS0GyroscopeFS::dps500,
+        sensitivity: gyro_full_scale,
         continuous_update: true,
         high_pass_filter_enabled: true,
         high_pass_filter_mode: Some(LSM9DS0GyroscopeHighPassFilterMode::NormalMode),
@@ -390,23 +1173,26 @@ fn get_lsm9ds0() -> Result<LSM9DS0<LinuxI2CDevice>, ()> {
 
     let accel_mag_settings = LSM9DS0AccelerometerMagnetometerSettings {
         continuous_update: true,
-        accelerometer_data_rate: LSM9DS0AccelerometerUpdateRate::Hz100,
-        accelerometer_anti_alias_filter_bandwidth: LSM9DS0AccelerometerFilterBandwidth::Hz50,
+        accelerometer_data_rate: accel_data_rate,
+        accelerometer_anti_alias_filter_bandwidth: accel_bandwidth,
         azen: true,
         ayen: true,
         axen: true,
-        accelerometer_sensitivity: LSM9DS0AccelerometerFS::g4,
+        accelerometer_sensitivity: accel_full_scale,
         magnetometer_resolution: LSM9DS0MagnetometerResolution::Low,
-        magnetometer_data_rate: LSM9DS0MagnetometerUpdateRate::Hz50,
+        magnetometer_data_rate: mag_data_rate,
         magnetometer_low_power_mode: false,
         magnetometer_mode: LSM9DS0MagnetometerMode::ContinuousConversion,
         magnetometer_sensitivity: LSM9DS0MagnetometerFS::gauss2,
     };
 
-    let (gyro, accel) = get_default_lsm9ds0_linux_i2c_devices().unwrap();
-
+    let settings = SensorSettings {
+        gyroscope_rate: gyro_rate,
+        accelerometer_rate: accel_rate,
+        accelerometer_full_scale: accel_range,
+    };
     match LSM9DS0::new(accel, gyro, gyro_settings, accel_mag_settings) {
-        Ok(lsm9ds0) => Ok(lsm9ds0),
+        Ok(lsm9ds0) => Ok((lsm9ds0, settings)),
         Err(_) => Err(()),
     }
 }
@@ -563,3 +1349,38 @@ fn get_lsm9ds0() -> Result<LSM9DS0<LinuxI2CDevice>, ()> {
 //         }
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hardware::spi::FakeSpidev;
+
+    #[test]
+    fn lsm9ds0_thermometer_over_spi() {
+        let mut bus = FakeSpidev::new(SpiProtocol::lsm9ds0());
+        bus.registers[LSM9DS0_CTRL_REG5_XM as usize] = 0x10;
+        let device = SpiRegisterDevice::new(bus, SpiProtocol::lsm9ds0());
+
+        let mut thermometer = LSM9DS0Thermometer::new(device).unwrap();
+        // The other bits of CTRL_REG5_XM are kept.
+        assert_eq!(
+            thermometer.device.bus().registers[LSM9DS0_CTRL_REG5_XM as usize],
+            0x10 | LSM9DS0_TEMP_EN
+        );
+
+        thermometer.device.bus().registers[LSM9DS0_OUT_TEMP_L_XM as usize] = 0x20;
+        thermometer.device.bus().registers[LSM9DS0_OUT_TEMP_L_XM as usize + 1] = 0x00;
+        assert_eq!(thermometer.temperature_celsius().unwrap(), 25.0);
+
+        // 12 bit two's complement, -16.
+        thermometer.device.bus().registers[LSM9DS0_OUT_TEMP_L_XM as usize] = 0xF0;
+        thermometer.device.bus().registers[LSM9DS0_OUT_TEMP_L_XM as usize + 1] = 0x0F;
+        assert_eq!(thermometer.temperature_celsius().unwrap(), 19.0);
+
+        // Read then write of CTRL_REG5_XM, then one auto incremented read per reading.
+        let transfers = &thermometer.device.bus().transfers;
+        assert_eq!(transfers[0], vec![0xA4, 0x00]);
+        assert_eq!(transfers[1], vec![0x24, 0x90]);
+        assert_eq!(transfers[2], vec![0xC5, 0x00, 0x00]);
+    }
+}
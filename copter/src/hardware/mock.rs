@@ -1,6 +1,7 @@
-use i2csensors::{Accelerometer, Barometer, Gyroscope, Magnetometer, Vec3};
+use i2csensors::{Accelerometer, Barometer, Gyroscope, Magnetometer, Thermometer, Vec3};
 use i2cdev::linux::LinuxI2CError;
 use super::motors::{MotorCommand, MotorManager};
+use super::calibration_session::{CalibrationSession, CalibrationStep, StepReport};
 
 pub type MockSensorError = LinuxI2CError;
 
@@ -19,6 +20,13 @@ impl Barometer for MockSensor {
     }
 }
 
+impl Thermometer for MockSensor {
+    type Error = MockSensorError;
+    fn temperature_celsius(&mut self) -> Result<f32, Self::Error> {
+        Ok(25.0)
+    }
+}
+
 impl Gyroscope for MockSensor {
     type Error = MockSensorError;
 
@@ -56,5 +64,7 @@ impl MotorManager for MockSensor {
     fn terminate(&mut self) {}
     fn set_powers(&mut self, powers: [f64; 4]) {}
     fn process_command(&mut self, command: MotorCommand) {}
-    fn calibrate(&mut self) {}
+    fn calibrate(&mut self, session: &mut CalibrationSession) -> StepReport {
+        StepReport::new(CalibrationStep::Motors)
+    }
 }
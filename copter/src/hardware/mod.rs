@@ -2,41 +2,80 @@
 // pub mod sensors;
 
 
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
 use std::thread;
-use std::thread::{sleep, Builder, JoinHandle};
+use std::thread::{Builder, JoinHandle};
 use std::default::Default;
+use std::time::Duration as StdDuration;
 
 use na::Vector3;
 use na::geometry::UnitQuaternion;
 use num::traits::Zero;
 
-use time::{Duration, PreciseTime};
+use time::Duration;
 use i2csensors::{Accelerometer, Barometer, Gyroscope, Magnetometer};
 
-use logger::ModuleLogger;
-// use configurations::Config;
+use clock::{RealClock, SharedClock, Timestamp, Timestamped};
+use logger::{FlightLogger, ModuleLogger};
+use configurations::Config;
+use configurations::config::{FULL_POWER, IDLE_POWER};
 
 mod barometer;
 mod imu;
 mod motors;
+mod dshot;
+mod pwm;
+mod safety;
+mod bench;
+mod thrust;
 mod gps;
+mod gpsd;
+mod fake_gpsd;
+mod nmea;
+mod ubx;
 mod battery;
 mod mock;
+mod bus;
+mod spi;
+mod scheduler;
+mod filters;
+mod vibration;
+mod calibration;
+mod calibration_session;
 
 use self::barometer::BarometerThermometer;
-use self::imu::IMU;
-use self::motors::{MotorManager, SerialMotorManager};
+use self::imu::{calibrations_to_update, fit_magnetometer, magnetometer_record, save_calibrations,
+                IMU};
+use self::calibration::{read_samples, MagnetometerCalibration};
+use self::motors::{get_motor_manager, MotorManager};
+use self::safety::SafeMotorManager;
+use self::bench::MotorBench;
+use self::thrust::ThrustMixer;
 use self::gps::get_gps;
-use self::battery::{BatteryMonitor, BatteryStatus};
+use self::battery::BatteryMonitor;
+use self::scheduler::Scheduler;
+use self::filters::ImuFilters;
+use self::vibration::VibrationMonitor;
 
 pub use self::motors::MotorCommand;
+pub use self::dshot::DShotCommand;
 pub use self::gps::GPSData;
-
-const MILLISECONDS_PER_SECOND: i64 = 1000;
-const LOOP_FREQUENCY: i64 = 75; // 95 Hz Loop
-
-pub fn initialize_hardware() -> (
+pub use self::vibration::VibrationReport;
+pub use self::battery::{BatteryReading, BatteryStatus};
+pub use self::calibration_session::{console_link, network_link, Acknowledgement,
+                                    CalibrationEvent, CalibrationLink, CalibrationReport,
+                                    CalibrationSession};
+
+const DEFAULT_IMU_RATE: u32 = 100;
+const DEFAULT_MAGNETOMETER_RATE: u32 = 20;
+const DEFAULT_BAROMETER_RATE: u32 = 50;
+const DEFAULT_BATTERY_RATE: u32 = 5;
+const REPORT_PERIOD_SECONDS: u64 = 5;
+const VIBRATION_REPORT_RATE: u32 = 1;
+// Retune the filters when the achieved IMU rate drifts this far from the assumed one.
+const SAMPLE_RATE_TOLERANCE: f64 = 0.05;
+
+pub fn initialize_hardware(config: Config, clock: SharedClock) -> (
     JoinHandle<()>,
     Receiver<PredictionReading>,
     Receiver<UpdateReading>,
@@ -48,6 +87,7 @@ pub fn initialize_hardware() -> (
     let (motor_tx, motor_rx): (Sender<MotorCommand>, Receiver<MotorCommand>) = channel();
     let (control_tx, control_rx): (Sender<()>, Receiver<()>) = channel();
 
+    let hardware_clock = clock.clone();
     let hardware_handle: JoinHandle<()> = Builder::new()
         .name(String::from("Hardware Thread"))
         .spawn(move || {
@@ -57,7 +97,7 @@ pub fn initialize_hardware() -> (
             );
             hardware_logger.log("Initializing hardware.");
 
-            let mut barometer = match BarometerThermometer::new() {
+            let mut barometer = match BarometerThermometer::new(&config) {
                 Ok(barometer) => barometer,
                 Err(_) => {
                     hardware_logger.error("Barometer initialization failed.");
@@ -66,7 +106,7 @@ pub fn initialize_hardware() -> (
             };
             hardware_logger.success("Barometer initialized.");
 
-            let mut imu = match IMU::new() {
+            let mut imu = match IMU::new(&config, hardware_clock.clone()) {
                 Ok(imu) => imu,
                 Err(_) => {
                     hardware_logger.error("IMU initialization failed.");
@@ -75,7 +115,9 @@ pub fn initialize_hardware() -> (
             };
             hardware_logger.success("IMU initialized.");
 
-            let mut motor_manager = match SerialMotorManager::new() {
+            let mut motor_manager = match get_motor_manager(&config, hardware_clock.clone())
+                .and_then(|motors| SafeMotorManager::new(&config, motors, hardware_clock.clone()))
+            {
                 Ok(motors) => motors,
                 Err(_) => {
                     hardware_logger.error("Motor initialization failed.");
@@ -85,27 +127,30 @@ pub fn initialize_hardware() -> (
             hardware_logger.success("Motors initialized.");
 
 
-            let mut gps_rx = get_gps();
+            let mut gps_rx = get_gps(&config, hardware_clock.clone());
             hardware_logger.success("GPS started.");
 
-            /*
-            let battery_monitor = match BatteryMonitor::new() {
-                Ok(monitor) => monitor,
+            // Flying without a battery monitor is allowed, just not monitored.
+            let mut battery_monitor = match BatteryMonitor::new(&config) {
+                Ok(monitor) => {
+                    hardware_logger.success("Battery monitor initialized.");
+                    Some(monitor)
+                }
                 Err(()) => {
-                    hardware_logger.error("Battery monitor initialization failed.");
-                    panic!("Battery monitor initialization failed.");
+                    hardware_logger.error("Battery monitor unavailable. Battery won't be monitored.");
+                    None
                 }
             };
 
-            hardware_logger.success("Battery monitor initialized.");
-            */
-
             hardware_logger.success("All hardware initialized successfully.");
 
             hardware_loop(
+                &config,
+                hardware_clock,
                 &mut barometer,
                 &mut imu,
                 &mut motor_manager,
+                &mut battery_monitor,
                 gps_rx,
                 pred_tx.clone(),
                 update_tx.clone(),
@@ -115,15 +160,31 @@ pub fn initialize_hardware() -> (
         })
         .unwrap();
 
-    sleep(Duration::seconds(1).to_std().unwrap());
+    clock.sleep(Duration::seconds(1).to_std().unwrap());
     (hardware_handle, pred_rx, update_rx, motor_tx, control_tx)
 }
 
-pub fn calibrate_sensors() {
+// The calibrations take a session so they can be driven from the console or remotely.
+pub fn calibrate_sensors(config: &Config, session: &mut CalibrationSession) -> CalibrationReport {
+    let hardware_logger =
+        ModuleLogger::new("Hardware", Some("Failed to calibrate hardware. Exiting."));
+
+    let mut imu = match IMU::new(config, RealClock::shared()) {
+        Ok(imu) => imu,
+        Err(_) => {
+            hardware_logger.error("IMU initialization failed.");
+            panic!("IMU initialization failed.");
+        }
+    };
+
+    imu.calibrate_sensors(session)
+}
+
+pub fn calibrate_thermal(config: &Config, session: &mut CalibrationSession) -> CalibrationReport {
     let hardware_logger =
         ModuleLogger::new("Hardware", Some("Failed to calibrate hardware. Exiting."));
 
-    let mut imu = match IMU::new() {
+    let mut imu = match IMU::new(config, RealClock::shared()) {
         Ok(imu) => imu,
         Err(_) => {
             hardware_logger.error("IMU initialization failed.");
@@ -131,14 +192,71 @@ pub fn calibrate_sensors() {
         }
     };
 
-    imu.calibrate_sensors();
+    let mut calibrations = calibrations_to_update(&config.directory, &hardware_logger);
+    let report = imu.calibrate_thermal(session, &mut calibrations);
+    let completed = report.is_completed();
+    session.finish_step(report);
+    let saved =
+        completed && save_calibrations(&calibrations, &config.directory, &hardware_logger);
+    session.finish(saved)
 }
 
-pub fn calibrate_motors() {
+// Fits magnetometer samples saved by an earlier calibration, without the hardware.
+pub fn calibrate_magnetometer_from_file(config: &Config, path: &str) {
     let hardware_logger =
         ModuleLogger::new("Hardware", Some("Failed to calibrate hardware. Exiting."));
 
-    let mut motor_manager = match SerialMotorManager::new() {
+    let samples = match read_samples(path) {
+        Ok(samples) => samples,
+        Err(e) => {
+            hardware_logger.error(&e);
+            return;
+        }
+    };
+    hardware_logger.log(&format!("Fitting {} samples from {}.", samples.len(), path));
+
+    let mut calibrations = calibrations_to_update(&config.directory, &hardware_logger);
+    let report = fit_magnetometer(
+        &MagnetometerCalibration::from_samples(samples),
+        magnetometer_record(&config.hardware),
+        &mut calibrations,
+    );
+    print!("{}", report);
+    if report.is_completed() {
+        save_calibrations(&calibrations, &config.directory, &hardware_logger);
+    }
+}
+
+pub fn calibrate_motors(config: &Config, session: &mut CalibrationSession) -> CalibrationReport {
+    let hardware_logger =
+        ModuleLogger::new("Hardware", Some("Failed to calibrate hardware. Exiting."));
+
+    let clock = RealClock::shared();
+    let mut motor_manager = match get_motor_manager(config, clock.clone())
+        .and_then(|motors| SafeMotorManager::new(config, motors, clock))
+    {
+        Ok(motors) => motors,
+        Err(_) => {
+            hardware_logger.error("Motor initialization failed.");
+            panic!("Motor initialization failed.");
+        }
+    };
+
+    let report = motor_manager.calibrate(session);
+    // The ESCs keep their calibration, there's nothing to save.
+    let completed = report.is_completed();
+    session.finish_step(report);
+    session.finish(completed)
+}
+
+// Spins motors from the command line to check wiring and measure thrust curves.
+pub fn motor_bench(config: &Config) {
+    let hardware_logger = ModuleLogger::new("Hardware", Some("Failed to start the bench. Exiting."));
+    let clock = RealClock::shared();
+
+    let motor_manager = match get_motor_manager(config, clock.clone())
+        .and_then(|motors| SafeMotorManager::new(config, motors, clock.clone()))
+    {
         Ok(motors) => motors,
         Err(_) => {
             hardware_logger.error("Motor initialization failed.");
@@ -146,13 +264,28 @@ pub fn calibrate_motors() {
         }
     };
 
-    motor_manager.calibrate();
+    let battery_monitor = match BatteryMonitor::new(config) {
+        Ok(monitor) => Some(monitor),
+        Err(()) => {
+            hardware_logger.error("Battery monitor unavailable. Voltage won't be logged.");
+            None
+        }
+    };
+
+    let mut bench = MotorBench::new(
+        motor_manager,
+        battery_monitor,
+        config.hardware.motors.pins.clone(),
+        clock,
+    );
+    bench.run();
 }
 
 #[derive(Debug)]
 pub struct PredictionReading {
     pub angular_rate: Vector3<f64>,
     pub acceleration: Vector3<f64>,
+    pub timestamp: Timestamp,
 }
 
 impl Default for PredictionReading {
@@ -160,16 +293,24 @@ impl Default for PredictionReading {
         PredictionReading {
             angular_rate: Vector3::zero(),
             acceleration: Vector3::zero(),
+            timestamp: Timestamp::zero(),
         }
     }
 }
 
+// Slower sensors keep the time they were sampled at, which is earlier than the
+// IMU timestamp of the update they are attached to.
 #[derive(Debug)]
 pub struct UpdateReading {
     pub acceleration: Vector3<f64>,
-    pub magnetic_reading: Option<Vector3<f64>>,
-    pub pressure: f64,
-    pub gps_information: Option<GPSData>,
+    pub magnetic_reading: Option<Timestamped<Vector3<f64>>>,
+    pub pressure: Option<Timestamped<f64>>,
+    pub temperature: Option<Timestamped<f64>>,
+    pub imu_temperature: Option<Timestamped<f64>>,
+    pub gps_information: Option<Timestamped<GPSData>>,
+    pub vibration: Option<VibrationReport>,
+    pub battery: Option<Timestamped<BatteryReading>>,
+    pub timestamp: Timestamp,
 }
 
 impl Default for UpdateReading {
@@ -177,16 +318,89 @@ impl Default for UpdateReading {
         UpdateReading {
             acceleration: Vector3::zero(),
             magnetic_reading: None,
-            pressure: 0.0,
+            pressure: None,
+            temperature: None,
+            imu_temperature: None,
             gps_information: None,
+            vibration: None,
+            battery: None,
+            timestamp: Timestamp::zero(),
+        }
+    }
+}
+
+fn configured_rate(update_rate: Option<i32>, default_rate: u32) -> u32 {
+    match update_rate {
+        Some(rate) if rate > 0 => rate as u32,
+        _ => default_rate,
+    }
+}
+
+// Thrust commands become power commands here so every motor manager only sees powers.
+fn apply_thrust_model(
+    logger: &ModuleLogger,
+    command: MotorCommand,
+    thrust_mixer: &Option<ThrustMixer>,
+    voltage: Option<f64>,
+) -> Option<MotorCommand> {
+    match command {
+        MotorCommand::SetThrust(t1, t2, t3, t4) => match *thrust_mixer {
+            Some(ref mixer) => {
+                let powers = mixer.commands([t1, t2, t3, t4], voltage);
+                Some(MotorCommand::SetPower(
+                    powers[0],
+                    powers[1],
+                    powers[2],
+                    powers[3],
+                ))
+            }
+            None => {
+                logger.error("Thrust command ignored. No thrust_models are configured.");
+                None
+            }
+        },
+        command => Some(command),
+    }
+}
+
+// Average throttle between 0 and 1 for a motor command, if it sets motor powers.
+fn command_throttle(command: &MotorCommand) -> Option<f64> {
+    match *command {
+        MotorCommand::SetPower(m1, m2, m3, m4) => {
+            Some(((m1 + m2 + m3 + m4) / 4.0 - IDLE_POWER) / (FULL_POWER - IDLE_POWER))
+        }
+        _ => None,
+    }
+}
+
+// Waits for a motor command until the timeout. A simulated clock is advanced by the
+// timeout instead of blocking.
+fn receive_motor_command(
+    clock: &SharedClock,
+    motor_rx: &Receiver<MotorCommand>,
+    timeout: StdDuration,
+) -> Result<MotorCommand, RecvTimeoutError> {
+    if clock.is_real_time() {
+        return motor_rx.recv_timeout(timeout);
+    }
+
+    match motor_rx.try_recv() {
+        Ok(command) => Ok(command),
+        Err(TryRecvError::Empty) => {
+            clock.sleep(timeout);
+            Err(RecvTimeoutError::Timeout)
         }
+        Err(TryRecvError::Disconnected) => Err(RecvTimeoutError::Disconnected),
     }
 }
 
 fn hardware_loop(
+    config: &Config,
+    clock: SharedClock,
     barometer: &mut BarometerThermometer,
     imu: &mut IMU,
-    motor_manager: &mut SerialMotorManager,
+    motor_manager: &mut SafeMotorManager,
+    battery_monitor: &mut Option<BatteryMonitor>,
     gps_rx: Receiver<GPSData>,
     prediction_tx: Sender<PredictionReading>,
     update_tx: Sender<UpdateReading>,
@@ -195,75 +409,263 @@ fn hardware_loop(
 ) {
     let hardware_logger = ModuleLogger::new("Hardware", None);
 
-    let mut loop_count = 0;
+    // Every sensor is sampled at its own rate. Motor commands are written as soon as
+    // they arrive instead of once per loop.
+    let mut scheduler =
+        Scheduler::new(clock.clone(), StdDuration::from_secs(REPORT_PERIOD_SECONDS));
+    let imu_rate = configured_rate(config.hardware.gyroscope.update_rate, DEFAULT_IMU_RATE);
+    let imu_task = scheduler.add_task("IMU", imu_rate);
+    let magnetometer_task = scheduler.add_task(
+        "Magnetometer",
+        configured_rate(
+            config.hardware.magnetometer.update_rate,
+            DEFAULT_MAGNETOMETER_RATE,
+        ),
+    );
+    let barometer_task = scheduler.add_task(
+        "Barometer",
+        configured_rate(config.hardware.barometer.update_rate, DEFAULT_BAROMETER_RATE),
+    );
+    // Vibration is sampled raw at each sensor's own rate, apart from the IMU task.
+    let gyroscope_vibration_task = scheduler.add_task("Gyroscope vibration", imu.gyroscope_rate());
+    let accelerometer_vibration_task =
+        scheduler.add_task("Accelerometer vibration", imu.accelerometer_rate());
+    let vibration_task = scheduler.add_task("Vibration", VIBRATION_REPORT_RATE);
+    let battery_task = scheduler.add_task(
+        "Battery",
+        configured_rate(config.hardware.battery.update_rate, DEFAULT_BATTERY_RATE),
+    );
+
+    let flight_logger = if config.debug.logging {
+        Some(FlightLogger::new())
+    } else {
+        None
+    };
+    let mut vibration_monitor = VibrationMonitor::new(
+        imu.gyroscope_rate() as f64,
+        imu.accelerometer_rate() as f64,
+        imu.accelerometer_full_scale(),
+    );
+
+    let thrust_mixer = match ThrustMixer::new(
+        &config.hardware.motors.thrust_models,
+        config.hardware.motors.pins.len(),
+    ) {
+        Ok(thrust_mixer) => thrust_mixer,
+        Err(()) => {
+            hardware_logger.error("Invalid thrust model. Thrust commands will be ignored.");
+            None
+        }
+    };
+    // The thrust model isn't voltage compensated until the battery is measured.
+    let mut battery_voltage: Option<f64> = None;
+
+    let mut imu_filters = match ImuFilters::new(&config.hardware.filters, imu_rate as f64) {
+        Ok(imu_filters) => imu_filters,
+        Err(()) => {
+            hardware_logger.error("Invalid IMU filter configuration. Filters disabled.");
+            ImuFilters::new(&None, imu_rate as f64).unwrap()
+        }
+    };
+
+    let mut magnetic_reading: Option<Timestamped<Vector3<f64>>> = None;
+    let mut pressure: Option<Timestamped<f64>> = None;
+    let mut temperature: Option<Timestamped<f64>> = None;
+    let mut imu_temperature: Option<Timestamped<f64>> = None;
+    let mut gps_information: Option<Timestamped<GPSData>> = None;
+    let mut vibration: Option<VibrationReport> = None;
+    let mut battery: Option<Timestamped<BatteryReading>> = None;
+    let mut motors_connected = true;
+
     'hardware: loop {
-        loop_count += 1;
+        let now = clock.now();
 
-        let start_time = PreciseTime::now();
+        if scheduler.run_if_due(magnetometer_task, now) {
+            let reading = imu.read_magnetometer().unwrap();
+            magnetic_reading = Some(Timestamped::new(reading, clock.now()));
+        }
 
-        let angular_rate = imu.read_gyroscope().unwrap();
-        let acceleration = imu.read_accelerometer().unwrap();
+        if scheduler.run_if_due(barometer_task, now) {
+            let reading = barometer.read_pressure() as f64;
+            pressure = Some(Timestamped::new(reading, clock.now()));
+            let reading = barometer.read_temperature() as f64;
+            temperature = Some(Timestamped::new(reading, clock.now()));
+            imu_temperature = imu.read_die_temperature()
+                .map(|reading| Timestamped::new(reading, clock.now()));
+        }
 
-        let prediction_reading = PredictionReading {
-            angular_rate: angular_rate,
-            acceleration: acceleration,
+        match gps_rx.try_recv() {
+            Ok(gps_data) => {
+                gps_information = Some(Timestamped::new(gps_data, gps_data.received));
+            }
+            Err(_) => {}
         };
 
-        match prediction_tx.send(prediction_reading) {
-            Ok(_) => {}
-            Err(_) => {
-                hardware_logger.error("Failed to send predictive readings.");
-            }
+        if scheduler.run_if_due(battery_task, now) {
+            let reading = match *battery_monitor {
+                Some(ref mut monitor) => monitor.update(clock.now()),
+                None => Ok(None),
+            };
+            match reading {
+                Ok(Some(reading)) => {
+                    battery_voltage = Some(reading.voltage);
+                    match flight_logger {
+                        Some(ref flight_logger) => {
+                            flight_logger.log(now.as_seconds(), &reading.to_string())
+                        }
+                        None => {}
+                    };
+                    battery = Some(Timestamped::new(reading, clock.now()));
+                }
+                Ok(None) => {}
+                Err(()) => battery_voltage = None,
+            };
         }
 
-        let gps_information: Option<GPSData> = match gps_rx.try_recv() {
-            Ok(gps_data) => Some(gps_data),
-            Err(_) => None,
-        };
+        if scheduler.run_if_due(gyroscope_vibration_task, now) {
+            match imu.read_gyroscope_raw() {
+                Ok(raw_angular_rate) => vibration_monitor.add_gyroscope_sample(raw_angular_rate),
+                Err(()) => {}
+            };
+        }
 
-        let pressure = barometer.read_pressure();
-        let mut magnetic_reading: Option<Vector3<f64>> = None;
-        if loop_count % 4 == 0 {
-            magnetic_reading = Some(imu.read_magnetometer().unwrap());
+        if scheduler.run_if_due(accelerometer_vibration_task, now) {
+            match imu.read_accelerometer_raw() {
+                Ok(raw_acceleration) => {
+                    vibration_monitor.add_accelerometer_sample(raw_acceleration)
+                }
+                Err(()) => {}
+            };
         }
 
-        let mut update_reading = UpdateReading {
-            acceleration: acceleration,
-            magnetic_reading: magnetic_reading,
-            pressure: pressure as f64,
-            gps_information: gps_information,
-        };
+        if scheduler.run_if_due(vibration_task, now) {
+            vibration = vibration_monitor.report();
+            match (&vibration, &flight_logger) {
+                (&Some(ref report), &Some(ref flight_logger)) => {
+                    flight_logger.log(now.as_seconds(), &report.to_string());
+                }
+                _ => {}
+            };
+            match vibration {
+                Some(ref report) if report.accelerometer_clipping > 0 => {
+                    hardware_logger.error(&format!(
+                        "Accelerometer clipped {} times. Check vibration damping.",
+                        report.accelerometer_clipping
+                    ));
+                }
+                _ => {}
+            };
+        }
 
-        match update_tx.send(update_reading) {
-            Ok(_) => {}
-            Err(_) => {
-                hardware_logger.error("Failed to send update readings.");
-            }
-        };
+        if scheduler.run_if_due(imu_task, now) {
+            let raw_angular_rate = imu.read_gyroscope().unwrap();
+            let raw_acceleration = imu.read_accelerometer().unwrap();
+            let timestamp = clock.now();
 
-        match motor_rx.recv() {
-            Ok(command) => {
-                motor_manager.process_command(command);
-            }
-            Err(_) => {
-                hardware_logger.error("Failed to receive motor commands");
+            let angular_rate = imu_filters.filter_gyroscope(raw_angular_rate);
+            let acceleration = imu_filters.filter_accelerometer(raw_acceleration);
+
+            let prediction_reading = PredictionReading {
+                angular_rate: angular_rate,
+                acceleration: acceleration,
+                timestamp: timestamp,
+            };
+
+            match prediction_tx.send(prediction_reading) {
+                Ok(_) => {}
+                Err(_) => {
+                    hardware_logger.error("Failed to send predictive readings.");
+                }
             }
-        };
 
-        let finish_time = PreciseTime::now();
-        let diff = Duration::milliseconds(MILLISECONDS_PER_SECOND / LOOP_FREQUENCY)
-            - start_time.to(finish_time);
-        if diff > Duration::zero() {
-            sleep(diff.to_std().unwrap());
+            // Slower sensors are attached to the next IMU update after they are sampled.
+            let update_reading = UpdateReading {
+                acceleration: acceleration,
+                magnetic_reading: magnetic_reading.take(),
+                pressure: pressure.take(),
+                temperature: temperature.take(),
+                imu_temperature: imu_temperature.take(),
+                gps_information: gps_information.take(),
+                vibration: vibration.take(),
+                battery: battery.take(),
+                timestamp: timestamp,
+            };
+
+            match update_tx.send(update_reading) {
+                Ok(_) => {}
+                Err(_) => {
+                    hardware_logger.error("Failed to send update readings.");
+                }
+            };
         }
 
+        match scheduler.report_if_due(clock.now()) {
+            Some(statistics) => {
+                let measured_rate = statistics[imu_task].rate;
+                let assumed_rate = imu_filters.sample_rate();
+                if (measured_rate - assumed_rate).abs() > assumed_rate * SAMPLE_RATE_TOLERANCE {
+                    match imu_filters.set_sample_rate(measured_rate) {
+                        Ok(()) => hardware_logger.log(&format!(
+                            "IMU filters retuned for {:.1} Hz.",
+                            measured_rate
+                        )),
+                        Err(()) => hardware_logger.error(&format!(
+                            "IMU filters can't run at {:.1} Hz.",
+                            measured_rate
+                        )),
+                    }
+                }
+            }
+            None => {}
+        };
+
         match control_rx.try_recv() {
             Ok(_) => {
                 hardware_logger.log("Stopping hardware.");
-                sleep(Duration::seconds(2).to_std().unwrap());
+                clock.sleep(Duration::seconds(2).to_std().unwrap());
                 break 'hardware;
             }
             Err(_) => {}
         };
+
+        motor_manager.check_timeout();
+        motor_manager.refresh();
+
+        // Wait for the next sensor deadline, writing motor commands as they come in.
+        let timeout = scheduler.time_until_next_deadline(clock.now());
+        if motors_connected {
+            match receive_motor_command(&clock, &motor_rx, timeout) {
+                Ok(command) => {
+                    match apply_thrust_model(
+                        &hardware_logger,
+                        command,
+                        &thrust_mixer,
+                        battery_voltage,
+                    ) {
+                        Some(command) => {
+                            match command_throttle(&command) {
+                                Some(throttle) => match imu_filters.track_throttle(throttle) {
+                                    Ok(()) => {}
+                                    Err(()) => hardware_logger.error(&format!(
+                                        "The dynamic notch can't follow throttle {}.",
+                                        throttle
+                                    )),
+                                },
+                                None => {}
+                            };
+                            motor_manager.process_command(command);
+                        }
+                        None => {}
+                    };
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    hardware_logger.error("Failed to receive motor commands");
+                    motors_connected = false;
+                }
+            };
+        } else {
+            clock.sleep(timeout);
+        }
     }
 }
@@ -1,5 +1,7 @@
+use clock::SharedClock;
 use logger::{FlightLogger, ModuleLogger};
 use configurations::Config;
+use configurations::config::{MotorProtocol, DEFAULT_I2C_BUS, FULL_POWER, IDLE_POWER};
 
 use debug_server;
 
@@ -8,49 +10,113 @@ use std::thread::sleep;
 
 use std::time::Duration;
 
-const MAX_VALUE: f64 = 2000.0;
-const MIN_VALUE: f64 = 1000.0;
+use super::bus::get_i2c_device;
+use super::calibration_session::{CalibrationSession, CalibrationStep, StepReport};
+use super::dshot::{DShotCommand, DShotMotorManager, DShotSpeed};
+use super::pwm::{pca9685_prescale, pca9685_timing, Pca9685Timing, PulseProtocol};
+
+const PCA9685_ADDRESS: u16 = 0x40;
 
 pub enum MotorCommand {
     PowerDown,
     Arm,
+    Initialized, // The attitude estimate is ready. Arm is refused until then
     SetPower(f64, f64, f64, f64),
+    SetThrust(f64, f64, f64, f64), // Newtons. Converted to powers by the thrust model
+    DShot(DShotCommand),           // Ignored by analog protocols
 }
 
 pub trait MotorManager {
     fn arm(&mut self);
-    fn calibrate(&mut self);
+    fn calibrate(&mut self, session: &mut CalibrationSession) -> StepReport;
     fn terminate(&mut self);
     fn set_powers(&mut self, powers: [f64; 4]);
     fn process_command(&mut self, command: MotorCommand);
+
+    // Called every hardware loop iteration, for outputs that keep writing between
+    // commands.
+    fn refresh(&mut self) {}
+
+    // Arming can take a while. Powers are ignored until it's done.
+    fn is_arming(&self) -> bool {
+        false
+    }
+}
+
+// The analog protocol and PCA9685 timing from the configuration file.
+fn configured_pulse_protocol(
+    logger: &ModuleLogger,
+    config: &Config,
+) -> Result<(PulseProtocol, Pca9685Timing), ()> {
+    let protocol = match config.hardware.motors.protocol {
+        Some(protocol) => protocol,
+        None => MotorProtocol::PWM,
+    };
+    let pulse_protocol = match PulseProtocol::from_protocol(protocol) {
+        Some(pulse_protocol) => pulse_protocol,
+        None => {
+            logger.error(&format!("{:?} is not a PCA9685 protocol.", protocol));
+            return Err(());
+        }
+    };
+
+    let rate = match config.hardware.motors.serial_controller {
+        Some(ref controller) => match controller.update_rate {
+            Some(rate) => rate,
+            None => pulse_protocol.default_rate,
+        },
+        None => pulse_protocol.default_rate,
+    };
+    let timing = pca9685_timing(logger, &pulse_protocol, rate)?;
+    logger.log(&format!(
+        "{} at {:.1} Hz, {:.0} throttle steps.",
+        pulse_protocol.name, timing.frequency, timing.throttle_steps
+    ));
+    Ok((pulse_protocol, timing))
 }
 
 #[cfg(target_arch = "arm")]
 pub struct SerialMotorManager {
     pub motors: Vec<u8>,
     device: PCA9685,
+    protocol: PulseProtocol,
     logger: ModuleLogger,
 }
 
 #[cfg(target_arch = "arm")]
 impl SerialMotorManager {
-    pub fn new() -> Result<SerialMotorManager, ()> {
-        let config = Config::new().unwrap();
+    pub fn new(config: &Config) -> Result<SerialMotorManager, ()> {
         let logger = ModuleLogger::new("Motors", Some("Check if your serial pwm controller is properly connected or change your configuration."));
         logger.log("Initializing Motor Manager.");
-        let device = match LinuxI2CDevice::new("/dev/i2c-1", 0x40) {
-            Ok(device) => device,
-            Err(e) => {
-                return Err(());
-            }
+        let (protocol, timing) = configured_pulse_protocol(&logger, config)?;
+        let device = match config.hardware.motors.serial_controller {
+            Some(ref controller) => get_i2c_device(controller, PCA9685_ADDRESS)?,
+            None => match LinuxI2CDevice::new(DEFAULT_I2C_BUS, PCA9685_ADDRESS) {
+                Ok(device) => device,
+                Err(_) => {
+                    return Err(());
+                }
+            },
         };
+        // The driver works out its own prescale from a whole frequency. It has to be the
+        // one checked above, or the pulses are timed against the wrong period.
+        let frequency = timing.frequency.round() as u16;
+        let driver_prescale = pca9685_prescale(frequency as f64);
+        if driver_prescale != timing.prescale {
+            logger.error(&format!(
+                "The PCA9685 driver would use prescale {} instead of {} at {:.1} Hz. Choose another update_rate.",
+                driver_prescale, timing.prescale, timing.frequency
+            ));
+            return Err(());
+        }
         let mut pca9685 = PCA9685::new(device, 50).unwrap();
         pca9685.set_all_duty_cycle(0).unwrap();
-        pca9685.set_frequency(100).unwrap();
+        pca9685.set_frequency(frequency).unwrap();
         sleep(Duration::from_millis(10));
         Ok(SerialMotorManager {
-            motors: config.hardware.motors.pins,
+            motors: config.hardware.motors.pins.clone(),
             device: pca9685,
+            protocol: protocol,
             logger: logger,
         })
     }
@@ -60,7 +126,7 @@ impl SerialMotorManager {
 impl MotorManager for SerialMotorManager {
     fn arm(&mut self) {
         self.logger.log("Arming Motors.");
-        match self.device.set_all_pulse_length(MIN_VALUE) {
+        match self.device.set_all_pulse_length(self.protocol.pulse_length(IDLE_POWER)) {
             Ok(()) => {}
             Err(e) => {
                 self.logger.error("Couldn't arm motors.");
@@ -86,7 +152,8 @@ impl MotorManager for SerialMotorManager {
 
     fn set_powers(&mut self, powers: [f64; 4]) {
         for i in 0..self.motors.len() {
-            match self.device.set_pulse_length(self.motors[i], powers[i]) {
+            let pulse_length = self.protocol.pulse_length(powers[i]);
+            match self.device.set_pulse_length(self.motors[i], pulse_length) {
                 Ok(_) => {}
                 Err(e) => {
                     self.logger.error("Couldn't set motor power.");
@@ -104,20 +171,68 @@ impl MotorManager for SerialMotorManager {
             MotorCommand::Arm => {
                 self.arm();
             }
+            MotorCommand::Initialized => {}
             MotorCommand::SetPower(m1, m2, m3, m4) => {
                 self.set_powers([m1, m2, m3, m4]);
             }
+            MotorCommand::SetThrust(..) => {
+                self.logger.error("Thrust commands need a thrust model.");
+            }
+            MotorCommand::DShot(_) => {
+                self.logger.error("DShot commands need a DShot motor protocol.");
+            }
         };
     }
 
-    fn calibrate(&mut self) {
+    // ESCs learn their throttle range from full throttle at power up followed by
+    // idle, so the battery is connected partway through.
+    fn calibrate(&mut self, session: &mut CalibrationSession) -> StepReport {
+        let step = CalibrationStep::Motors;
         self.device.set_all_duty_cycle(0);
-        self.device.set_all_pulse_length(MAX_VALUE);
-        sleep(Duration::from_secs(3));
-        self.device.set_all_pulse_length(MIN_VALUE);
+        match session.confirm(step, "Remove the propellers and disconnect the battery.") {
+            Ok(()) => {}
+            Err(report) => return report,
+        };
+        self.device.set_all_pulse_length(self.protocol.pulse_length(FULL_POWER));
+        match session.confirm(
+            step,
+            "Connect the battery and wait for the ESCs to beep for full throttle.",
+        ) {
+            Ok(()) => {}
+            Err(report) => {
+                self.device.set_all_duty_cycle(0);
+                return report;
+            }
+        };
+        self.device.set_all_pulse_length(self.protocol.pulse_length(IDLE_POWER));
+        session.progress(step, "Setting idle throttle.", None);
         sleep(Duration::from_secs(3));
         self.device.set_all_duty_cycle(0);
         sleep(Duration::from_secs(1));
+
+        let mut report = StepReport::new(step);
+        report.metric("max power", FULL_POWER);
+        report.metric("min power", IDLE_POWER);
+        report
+    }
+}
+
+// Picks the motor manager for the configured ESC protocol.
+pub fn get_motor_manager(config: &Config, clock: SharedClock) -> Result<Box<MotorManager>, ()> {
+    let dshot = match config.hardware.motors.protocol {
+        Some(protocol) => DShotSpeed::from_protocol(protocol).is_some(),
+        None => false,
+    };
+    if dshot {
+        match DShotMotorManager::new(config, clock) {
+            Ok(manager) => Ok(Box::new(manager)),
+            Err(()) => Err(()),
+        }
+    } else {
+        match SerialMotorManager::new(config) {
+            Ok(manager) => Ok(Box::new(manager)),
+            Err(()) => Err(()),
+        }
     }
 }
 
@@ -129,9 +244,11 @@ pub struct SerialMotorManager {}
 
 #[cfg(not(target_arch = "arm"))]
 impl SerialMotorManager {
-    pub fn new() -> Result<SerialMotorManager, ()> {
+    pub fn new(config: &Config) -> Result<SerialMotorManager, ()> {
         let logger = ModuleLogger::new("Motors", Some("Check if your serial pwm controller is properly connected or change your configuration."));
         logger.log("Initializing Motor Manager.");
+        // Catch protocol mistakes before the configuration reaches the vehicle.
+        configured_pulse_protocol(&logger, config)?;
         Ok(SerialMotorManager {})
     }
 }
@@ -142,7 +259,9 @@ impl MotorManager for SerialMotorManager {
     fn terminate(&mut self) {}
     fn set_powers(&mut self, powers: [f64; 4]) {}
     fn process_command(&mut self, command: MotorCommand) {}
-    fn calibrate(&mut self) {}
+    fn calibrate(&mut self, session: &mut CalibrationSession) -> StepReport {
+        StepReport::new(CalibrationStep::Motors)
+    }
 }
 
 
@@ -0,0 +1,294 @@
+use super::gps::GpsFix;
+
+// NMEA 0183 allows 82 characters. Some receivers go over, so allow a little more.
+const MAX_SENTENCE_LENGTH: usize = 120;
+const KNOTS_TO_MPS: f64 = 0.514444;
+const KPH_TO_MPS: f64 = 1.0 / 3.6;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NmeaSentence {
+    GGA {
+        time_of_day: Option<f64>, // UTC seconds since midnight
+        latitude: Option<f64>,
+        longitude: Option<f64>,
+        quality: u8, // 0 is no fix
+        satellites: Option<u32>,
+        hdop: Option<f64>,
+        altitude: Option<f64>, // Above mean sea level
+    },
+    RMC {
+        time_of_day: Option<f64>,
+        valid: bool,
+        latitude: Option<f64>,
+        longitude: Option<f64>,
+        speed: Option<f64>, // m/s
+        track: Option<f64>, // Degrees from true north
+        date: Option<(i32, u32, u32)>,
+    },
+    GSA {
+        fix: GpsFix,
+        pdop: Option<f64>,
+        hdop: Option<f64>,
+        vdop: Option<f64>,
+    },
+    VTG {
+        track: Option<f64>,
+        speed: Option<f64>,
+    },
+}
+
+fn number(field: &str) -> Option<f64> {
+    if field.is_empty() {
+        None
+    } else {
+        field.parse().ok()
+    }
+}
+
+// ddmm.mmmm or dddmm.mmmm with a hemisphere.
+fn coordinate(value: &str, hemisphere: &str) -> Option<f64> {
+    let value = number(value)?;
+    let degrees = (value / 100.0).trunc();
+    let coordinate = degrees + (value - degrees * 100.0) / 60.0;
+    match hemisphere {
+        "N" | "E" => Some(coordinate),
+        "S" | "W" => Some(-coordinate),
+        _ => None,
+    }
+}
+
+// hhmmss.ss
+fn time_of_day(field: &str) -> Option<f64> {
+    let hours: f64 = field.get(0..2)?.parse().ok()?;
+    let minutes: f64 = field.get(2..4)?.parse().ok()?;
+    let seconds: f64 = field.get(4..)?.parse().ok()?;
+    Some(hours * 3600.0 + minutes * 60.0 + seconds)
+}
+
+// ddmmyy
+fn date(field: &str) -> Option<(i32, u32, u32)> {
+    if field.len() != 6 {
+        return None;
+    }
+    let day: u32 = field.get(0..2)?.parse().ok()?;
+    let month: u32 = field.get(2..4)?.parse().ok()?;
+    let year: i32 = field.get(4..6)?.parse().ok()?;
+    Some((2000 + year, month, day))
+}
+
+pub fn checksum(body: &[u8]) -> u8 {
+    body.iter().fold(0, |checksum, byte| checksum ^ byte)
+}
+
+// Parses one sentence, with or without the line ending. None if the checksum is
+// missing or wrong, or the sentence isn't one we use.
+pub fn parse_sentence(sentence: &str) -> Option<NmeaSentence> {
+    let sentence = sentence.trim();
+    if !sentence.starts_with('$') {
+        return None;
+    }
+    let star = sentence.rfind('*')?;
+    let body = &sentence[1..star];
+    let expected = u8::from_str_radix(&sentence[star + 1..], 16).ok()?;
+    if checksum(body.as_bytes()) != expected {
+        return None;
+    }
+
+    let fields: Vec<&str> = body.split(',').collect();
+    // Any talker: GP, GN, GL, GA, BD...
+    if fields[0].len() != 5 {
+        return None;
+    }
+    let field = |index: usize| fields.get(index).cloned().unwrap_or("");
+
+    match fields[0].get(2..)? {
+        "GGA" => Some(NmeaSentence::GGA {
+            time_of_day: time_of_day(field(1)),
+            latitude: coordinate(field(2), field(3)),
+            longitude: coordinate(field(4), field(5)),
+            quality: field(6).parse().unwrap_or(0),
+            satellites: field(7).parse().ok(),
+            hdop: number(field(8)),
+            altitude: number(field(9)),
+        }),
+        "RMC" => Some(NmeaSentence::RMC {
+            time_of_day: time_of_day(field(1)),
+            valid: field(2) == "A",
+            latitude: coordinate(field(3), field(4)),
+            longitude: coordinate(field(5), field(6)),
+            speed: number(field(7)).map(|knots| knots * KNOTS_TO_MPS),
+            track: number(field(8)),
+            date: date(field(9)),
+        }),
+        "GSA" => Some(NmeaSentence::GSA {
+            fix: match field(2) {
+                "2" => GpsFix::Fix2D,
+                "3" => GpsFix::Fix3D,
+                _ => GpsFix::NoFix,
+            },
+            pdop: number(field(15)),
+            hdop: number(field(16)),
+            vdop: number(field(17)),
+        }),
+        "VTG" => Some(NmeaSentence::VTG {
+            track: number(field(1)),
+            speed: number(field(7)).map(|kph| kph * KPH_TO_MPS),
+        }),
+        _ => None,
+    }
+}
+
+// Picks sentences out of a byte stream. Anything that isn't printable ASCII, such as
+// UBX frames on the same port, ends the current sentence.
+pub struct NmeaParser {
+    buffer: Vec<u8>,
+    in_sentence: bool,
+}
+
+impl NmeaParser {
+    pub fn new() -> NmeaParser {
+        NmeaParser {
+            buffer: Vec::with_capacity(MAX_SENTENCE_LENGTH),
+            in_sentence: false,
+        }
+    }
+
+    pub fn push(&mut self, byte: u8) -> Option<NmeaSentence> {
+        match byte {
+            b'$' => {
+                self.buffer.clear();
+                self.buffer.push(byte);
+                self.in_sentence = true;
+                None
+            }
+            b'\r' => None,
+            b'\n' if self.in_sentence => {
+                self.in_sentence = false;
+                match String::from_utf8(self.buffer.clone()) {
+                    Ok(sentence) => parse_sentence(&sentence),
+                    Err(_) => None,
+                }
+            }
+            32..=126 if self.in_sentence && self.buffer.len() < MAX_SENTENCE_LENGTH => {
+                self.buffer.push(byte);
+                None
+            }
+            _ => {
+                self.in_sentence = false;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GGA: &str = "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47";
+    const RMC: &str = "$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230324,003.1,W*61";
+
+    fn push_all(parser: &mut NmeaParser, bytes: &[u8]) -> Vec<NmeaSentence> {
+        bytes.iter().filter_map(|byte| parser.push(*byte)).collect()
+    }
+
+    #[test]
+    fn recorded_sentences_parse() {
+        match parse_sentence(GGA) {
+            Some(NmeaSentence::GGA {
+                time_of_day,
+                latitude,
+                longitude,
+                quality,
+                satellites,
+                hdop,
+                altitude,
+            }) => {
+                assert_eq!(time_of_day, Some(12.0 * 3600.0 + 35.0 * 60.0 + 19.0));
+                assert!((latitude.unwrap() - (48.0 + 7.038 / 60.0)).abs() < 1e-9);
+                assert!((longitude.unwrap() - (11.0 + 31.0 / 60.0)).abs() < 1e-9);
+                assert_eq!(quality, 1);
+                assert_eq!(satellites, Some(8));
+                assert_eq!(hdop, Some(0.9));
+                assert_eq!(altitude, Some(545.4));
+            }
+            other => panic!("{:?}", other),
+        };
+        match parse_sentence(RMC) {
+            Some(NmeaSentence::RMC {
+                valid,
+                speed,
+                track,
+                date,
+                ..
+            }) => {
+                assert!(valid);
+                assert!((speed.unwrap() - 22.4 * KNOTS_TO_MPS).abs() < 1e-9);
+                assert_eq!(track, Some(84.4));
+                assert_eq!(date, Some((2024, 3, 23)));
+            }
+            other => panic!("{:?}", other),
+        };
+        // Southern and western hemispheres are negative.
+        match parse_sentence("$GNGGA,000001.00,3351.000,S,15112.000,W,2,12,0.6,10.0,M,,M,,*57") {
+            Some(NmeaSentence::GGA {
+                latitude,
+                longitude,
+                quality,
+                ..
+            }) => {
+                assert!((latitude.unwrap() + 33.85).abs() < 1e-9);
+                assert!((longitude.unwrap() + 151.2).abs() < 1e-9);
+                assert_eq!(quality, 2);
+            }
+            other => panic!("{:?}", other),
+        };
+    }
+
+    #[test]
+    fn bad_checksums_are_dropped() {
+        assert_eq!(parse_sentence(&GGA.replace("*47", "*48")), None);
+        assert_eq!(parse_sentence(&GGA.replace("545.4", "545.5")), None);
+        assert_eq!(parse_sentence(&GGA.replace("*47", "")), None);
+        assert_eq!(parse_sentence(&GGA.replace("*47", "*zz")), None);
+        assert_eq!(parse_sentence(&GGA[1..]), None);
+    }
+
+    #[test]
+    fn short_or_odd_fields_are_missing() {
+        assert_eq!(time_of_day("1235"), None);
+        assert_eq!(time_of_day("12é519"), None);
+        assert_eq!(date("23039"), None);
+        assert_eq!(date("2é039"), None);
+        assert_eq!(coordinate("4807.038", "X"), None);
+        assert_eq!(parse_sentence("$GPé*00"), None);
+    }
+
+    #[test]
+    fn sentences_are_picked_out_of_a_stream() {
+        let mut stream = Vec::new();
+        stream.extend_from_slice(b"5.4,M,46.9,M,,*47\r\n");
+        stream.extend_from_slice(GGA.as_bytes());
+        stream.extend_from_slice(b"\r\n");
+        // A UBX frame in between ends nothing that matters.
+        stream.extend_from_slice(&[0xB5, 0x62, 0x01, 0x07, 0x00, 0x00, 0x08, 0x19]);
+        stream.extend_from_slice(GGA.replace("*47", "*46").as_bytes());
+        stream.extend_from_slice(b"\r\n");
+        // Cut off by a binary byte, then a good one.
+        stream.extend_from_slice(&RMC.as_bytes()[..20]);
+        stream.push(0xB5);
+        stream.extend_from_slice(&RMC.as_bytes()[20..]);
+        stream.extend_from_slice(b"\r\n");
+        stream.extend_from_slice(RMC.as_bytes());
+        stream.extend_from_slice(b"\r\n");
+
+        let mut parser = NmeaParser::new();
+        let sentences = push_all(&mut parser, &stream);
+        assert_eq!(sentences, vec![parse_sentence(GGA).unwrap(), parse_sentence(RMC).unwrap()]);
+
+        // Overlong sentences are dropped.
+        let long = format!("{}{}\r\n", "$GPTXT,", "x".repeat(MAX_SENTENCE_LENGTH));
+        assert!(push_all(&mut parser, long.as_bytes()).is_empty());
+        assert_eq!(push_all(&mut parser, format!("{}\r\n", GGA).as_bytes()).len(), 1);
+    }
+}
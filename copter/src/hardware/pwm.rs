@@ -0,0 +1,143 @@
+use configurations::config::{MotorProtocol, FULL_POWER, IDLE_POWER};
+use logger::ModuleLogger;
+
+// Motor commands are always IDLE_POWER to FULL_POWER and are scaled to the protocol's
+// pulse range.
+const PCA9685_OSCILLATOR_HZ: f64 = 25_000_000.0;
+const PCA9685_STEPS: f64 = 4096.0;
+const PCA9685_MIN_PRESCALE: u32 = 3;
+const PCA9685_MAX_PRESCALE: u32 = 255;
+// Fewer distinct pulse lengths than this between idle and full throttle is too coarse to fly.
+const MIN_THROTTLE_STEPS: f64 = 100.0;
+
+// Analog ESC protocols differ only in pulse range and how often a pulse may be sent.
+#[derive(Debug, Clone, Copy)]
+pub struct PulseProtocol {
+    pub name: &'static str,
+    pub min_pulse_us: f64,
+    pub max_pulse_us: f64,
+    pub min_rate: i32,
+    pub max_rate: i32,
+    pub default_rate: i32,
+}
+
+impl PulseProtocol {
+    pub fn from_protocol(protocol: MotorProtocol) -> Option<PulseProtocol> {
+        match protocol {
+            MotorProtocol::PWM => Some(PulseProtocol {
+                name: "PWM",
+                min_pulse_us: 1000.0,
+                max_pulse_us: 2000.0,
+                min_rate: 50,
+                max_rate: 490,
+                default_rate: 100,
+            }),
+            MotorProtocol::OneShot125 => Some(PulseProtocol {
+                name: "OneShot125",
+                min_pulse_us: 125.0,
+                max_pulse_us: 250.0,
+                min_rate: 50,
+                max_rate: 4000,
+                default_rate: 1000,
+            }),
+            MotorProtocol::OneShot42 => Some(PulseProtocol {
+                name: "OneShot42",
+                min_pulse_us: 125.0 / 3.0,
+                max_pulse_us: 250.0 / 3.0,
+                min_rate: 50,
+                max_rate: 12000,
+                default_rate: 1500,
+            }),
+            MotorProtocol::Multishot => Some(PulseProtocol {
+                name: "Multishot",
+                min_pulse_us: 5.0,
+                max_pulse_us: 25.0,
+                min_rate: 50,
+                max_rate: 32000,
+                default_rate: 1500,
+            }),
+            _ => None,
+        }
+    }
+
+    // Pulse length in microseconds for a 1000 to 2000 motor command.
+    pub fn pulse_length(&self, command: f64) -> f64 {
+        let fraction = ((command - IDLE_POWER) / (FULL_POWER - IDLE_POWER))
+            .max(0.0)
+            .min(1.0);
+        self.min_pulse_us + fraction * (self.max_pulse_us - self.min_pulse_us)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Pca9685Timing {
+    pub prescale: u32,
+    // The prescaler only allows some frequencies, so this differs from the requested rate.
+    pub frequency: f64,
+    pub step_us: f64,
+    pub throttle_steps: f64,
+}
+
+pub fn pca9685_prescale(frequency: f64) -> u32 {
+    (PCA9685_OSCILLATOR_HZ / (PCA9685_STEPS * frequency)).round() as u32 - 1
+}
+
+pub fn pca9685_frequency(prescale: u32) -> f64 {
+    PCA9685_OSCILLATOR_HZ / (PCA9685_STEPS * (prescale + 1) as f64)
+}
+
+// Checks that the PCA9685 can produce the protocol at the requested rate with enough
+// throttle resolution.
+pub fn pca9685_timing(
+    logger: &ModuleLogger,
+    protocol: &PulseProtocol,
+    rate: i32,
+) -> Result<Pca9685Timing, ()> {
+    if rate < protocol.min_rate || rate > protocol.max_rate {
+        logger.error(&format!(
+            "{} supports {} to {} Hz, not {} Hz.",
+            protocol.name, protocol.min_rate, protocol.max_rate, rate
+        ));
+        return Err(());
+    }
+
+    let max_frequency = pca9685_frequency(PCA9685_MIN_PRESCALE);
+    let min_frequency = pca9685_frequency(PCA9685_MAX_PRESCALE);
+    if (rate as f64) < min_frequency.ceil() || (rate as f64) > max_frequency.floor() {
+        logger.error(&format!(
+            "PCA9685 can't output {} Hz. Supported rates: {} to {} Hz.",
+            rate,
+            min_frequency.ceil(),
+            max_frequency.floor()
+        ));
+        return Err(());
+    }
+
+    let prescale = pca9685_prescale(rate as f64);
+    let frequency = pca9685_frequency(prescale);
+    let period_us = 1_000_000.0 / frequency;
+    if protocol.max_pulse_us >= period_us {
+        logger.error(&format!(
+            "{} pulses up to {} us don't fit in a {:.0} us period at {:.1} Hz.",
+            protocol.name, protocol.max_pulse_us, period_us, frequency
+        ));
+        return Err(());
+    }
+
+    let step_us = period_us / PCA9685_STEPS;
+    let throttle_steps = (protocol.max_pulse_us - protocol.min_pulse_us) / step_us;
+    if throttle_steps < MIN_THROTTLE_STEPS {
+        logger.error(&format!(
+            "{} at {:.1} Hz only has {:.0} throttle steps. Increase the update rate.",
+            protocol.name, frequency, throttle_steps
+        ));
+        return Err(());
+    }
+
+    Ok(Pca9685Timing {
+        prescale: prescale,
+        frequency: frequency,
+        step_us: step_us,
+        throttle_steps: throttle_steps,
+    })
+}
@@ -0,0 +1,278 @@
+use std::time::Duration;
+
+use clock::{SharedClock, Timestamp};
+use configurations::Config;
+use configurations::config::{FULL_POWER, IDLE_POWER};
+use logger::ModuleLogger;
+
+use super::motors::{MotorCommand, MotorManager};
+use super::calibration_session::{CalibrationSession, CalibrationStep, StepOutcome, StepReport};
+
+const DEFAULT_COMMAND_TIMEOUT_MS: u64 = 500;
+// How often motors_off mode prints the motor powers it would have written.
+const BENCH_LOG_PERIOD_MS: u64 = 1000;
+
+// Sits between the motor commands and any MotorManager. Outputs above idle need the
+// motors to be armed, are clamped and slew limited, and stop if commands stop arriving.
+pub struct SafeMotorManager {
+    motors: Box<MotorManager>,
+    clock: SharedClock,
+    armed: bool,
+    initialized: bool, // Arm commands are refused until the flight controller is ready
+    motors_off: bool,
+    min_power: f64,
+    max_power: f64,
+    max_slew_rate: Option<f64>,
+    command_timeout: Duration,
+    outputs: [f64; 4],
+    last_output: Timestamp,
+    last_command: Timestamp,
+    last_bench_log: Option<Timestamp>,
+    refused_logged: bool,
+    logger: ModuleLogger,
+}
+
+impl SafeMotorManager {
+    pub fn new(
+        config: &Config,
+        motors: Box<MotorManager>,
+        clock: SharedClock,
+    ) -> Result<SafeMotorManager, ()> {
+        let logger = ModuleLogger::new("Motor Safety", None);
+        let motor_config = &config.hardware.motors;
+
+        let min_power = match motor_config.min_power {
+            Some(power) => power as f64,
+            None => IDLE_POWER,
+        };
+        let max_power = match motor_config.max_power {
+            Some(power) => power as f64,
+            None => FULL_POWER,
+        };
+        if min_power < IDLE_POWER || max_power > FULL_POWER || min_power >= max_power {
+            logger.error(&format!(
+                "Motor power limits {} to {} must be increasing and within {} to {}.",
+                min_power, max_power, IDLE_POWER, FULL_POWER
+            ));
+            return Err(());
+        }
+
+        let max_slew_rate = match motor_config.max_slew_rate {
+            Some(rate) if rate <= 0.0 => {
+                logger.error("max_slew_rate must be positive.");
+                return Err(());
+            }
+            Some(rate) => Some(rate as f64),
+            None => None,
+        };
+
+        let command_timeout = Duration::from_millis(match motor_config.command_timeout_ms {
+            Some(timeout) => timeout,
+            None => DEFAULT_COMMAND_TIMEOUT_MS,
+        });
+
+        let motors_off = config.debug.motors_off;
+        if motors_off {
+            logger.log("motors_off is set. Motor outputs will be logged instead of written.");
+        }
+
+        let now = clock.now();
+        Ok(SafeMotorManager {
+            motors: motors,
+            clock: clock,
+            armed: false,
+            initialized: false,
+            motors_off: motors_off,
+            min_power: min_power,
+            max_power: max_power,
+            max_slew_rate: max_slew_rate,
+            command_timeout: command_timeout,
+            outputs: [IDLE_POWER; 4],
+            last_output: now,
+            last_command: now,
+            last_bench_log: None,
+            refused_logged: false,
+            logger: logger,
+        })
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.armed
+    }
+
+    pub fn outputs(&self) -> [f64; 4] {
+        self.outputs
+    }
+
+    // Called every hardware loop iteration. Stops and disarms the motors if the
+    // flight controller has gone quiet.
+    pub fn check_timeout(&mut self) {
+        if !self.armed {
+            return;
+        }
+        let now = self.clock.now();
+        if now.duration_since(self.last_command) > self.command_timeout {
+            self.logger.error(&format!(
+                "No motor command for {:.0} ms. Stopping motors.",
+                now.seconds_since(self.last_command) * 1000.0
+            ));
+            self.stop(now);
+        }
+    }
+
+    fn stop(&mut self, now: Timestamp) {
+        self.armed = false;
+        self.outputs = [IDLE_POWER; 4];
+        self.last_output = now;
+        let outputs = self.outputs;
+        self.write(outputs, now);
+    }
+
+    // Limits the change of each motor since the last output.
+    fn slew_limit(&self, target: f64, previous: f64, dt: f64) -> f64 {
+        match self.max_slew_rate {
+            Some(rate) => {
+                let max_step = rate * dt;
+                previous + (target - previous).max(-max_step).min(max_step)
+            }
+            None => target,
+        }
+    }
+
+    fn write(&mut self, powers: [f64; 4], now: Timestamp) {
+        if !self.motors_off {
+            self.motors.set_powers(powers);
+            return;
+        }
+
+        let due = match self.last_bench_log {
+            Some(last) => now.duration_since(last) >= Duration::from_millis(BENCH_LOG_PERIOD_MS),
+            None => true,
+        };
+        if due {
+            self.logger.log(&format!(
+                "motors_off: {:.0}, {:.0}, {:.0}, {:.0} ({}).",
+                powers[0],
+                powers[1],
+                powers[2],
+                powers[3],
+                if self.armed { "armed" } else { "disarmed" }
+            ));
+            self.last_bench_log = Some(now);
+        }
+    }
+}
+
+impl MotorManager for SafeMotorManager {
+    fn arm(&mut self) {
+        let now = self.clock.now();
+        if self.motors_off {
+            self.logger.log("motors_off: arming skipped.");
+        } else {
+            self.motors.arm();
+        }
+        self.armed = true;
+        self.refused_logged = false;
+        self.outputs = [IDLE_POWER; 4];
+        self.last_output = now;
+        self.last_command = now;
+    }
+
+    fn calibrate(&mut self, session: &mut CalibrationSession) -> StepReport {
+        if self.armed {
+            self.logger.error("Disarm the motors before calibrating.");
+            return StepReport::ended(
+                CalibrationStep::Motors,
+                StepOutcome::Rejected(String::from("Disarm the motors before calibrating.")),
+            );
+        }
+        if self.motors_off {
+            self.logger.log("motors_off: calibration skipped.");
+            let mut report = StepReport::ended(CalibrationStep::Motors, StepOutcome::Skipped);
+            report.warn("motors_off is set.");
+            return report;
+        }
+        self.motors.calibrate(session)
+    }
+
+    fn terminate(&mut self) {
+        self.armed = false;
+        self.outputs = [IDLE_POWER; 4];
+        if self.motors_off {
+            self.logger.log("motors_off: terminate skipped.");
+        } else {
+            self.motors.terminate();
+        }
+    }
+
+    fn set_powers(&mut self, powers: [f64; 4]) {
+        let now = self.clock.now();
+        self.last_command = now;
+
+        if !self.armed {
+            if powers.iter().any(|power| *power > IDLE_POWER) && !self.refused_logged {
+                self.logger.error("Motors are not armed. Holding them at idle.");
+                self.refused_logged = true;
+            }
+            self.outputs = [IDLE_POWER; 4];
+            self.last_output = now;
+            self.write([IDLE_POWER; 4], now);
+            return;
+        }
+
+        let dt = now.seconds_since(self.last_output);
+        let mut outputs = [IDLE_POWER; 4];
+        for i in 0..4 {
+            let target = powers[i].max(self.min_power).min(self.max_power);
+            outputs[i] = self.slew_limit(target, self.outputs[i], dt);
+        }
+        self.outputs = outputs;
+        self.last_output = now;
+        self.write(outputs, now);
+    }
+
+    fn refresh(&mut self) {
+        if !self.motors_off {
+            self.motors.refresh();
+        }
+    }
+
+    fn is_arming(&self) -> bool {
+        !self.motors_off && self.motors.is_arming()
+    }
+
+    fn process_command(&mut self, command: MotorCommand) {
+        match command {
+            MotorCommand::PowerDown => {
+                self.terminate();
+            }
+            MotorCommand::Arm => {
+                if self.initialized {
+                    self.arm();
+                } else {
+                    self.logger
+                        .error("Arm refused. The flight controller hasn't initialized.");
+                }
+            }
+            MotorCommand::Initialized => {
+                self.initialized = true;
+            }
+            MotorCommand::SetPower(m1, m2, m3, m4) => {
+                self.set_powers([m1, m2, m3, m4]);
+            }
+            MotorCommand::SetThrust(..) => {
+                self.logger.error("Thrust commands need a thrust model.");
+            }
+            MotorCommand::DShot(command) => {
+                // Special commands reconfigure the ESCs and are only safe while stopped.
+                if self.armed {
+                    self.logger.error("DShot commands need the motors to be disarmed.");
+                } else if self.motors_off {
+                    self.logger.log(&format!("motors_off: DShot command {:?} skipped.", command));
+                } else {
+                    self.motors.process_command(MotorCommand::DShot(command));
+                }
+            }
+        };
+    }
+}
@@ -0,0 +1,176 @@
+use std::time::Duration;
+
+use clock::{SharedClock, Timestamp};
+use logger::ModuleLogger;
+
+const NANOSECONDS_PER_SECOND: u64 = 1_000_000_000;
+const MICROSECONDS_PER_SECOND: f64 = 1000000.0;
+
+fn duration_to_microseconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * MICROSECONDS_PER_SECOND
+        + (duration.subsec_nanos() as f64) / 1000.0
+}
+
+// A periodic job in the hardware loop, e.g. sampling one sensor.
+struct Task {
+    name: String,
+    period: Duration,
+    next_run: Timestamp,
+    runs: u32,
+    overruns: u32,
+    total_jitter: Duration,
+    max_jitter: Duration,
+}
+
+impl Task {
+    fn new(name: &str, rate: u32, start: Timestamp) -> Task {
+        let rate = if rate == 0 { 1 } else { rate as u64 };
+        Task {
+            name: String::from(name),
+            period: Duration::new(0, (NANOSECONDS_PER_SECOND / rate) as u32),
+            next_run: start,
+            runs: 0,
+            overruns: 0,
+            total_jitter: Duration::new(0, 0),
+            max_jitter: Duration::new(0, 0),
+        }
+    }
+
+    fn run(&mut self, now: Timestamp) {
+        // Jitter is how late the task started compared to its deadline.
+        let jitter = now.duration_since(self.next_run);
+        self.total_jitter += jitter;
+        if jitter > self.max_jitter {
+            self.max_jitter = jitter;
+        }
+        self.runs += 1;
+
+        self.next_run += self.period;
+        // Missed at least one whole period. Skip ahead instead of bursting to catch up.
+        if self.next_run <= now {
+            while self.next_run <= now {
+                self.overruns += 1;
+                self.next_run += self.period;
+            }
+        }
+    }
+
+    fn reset_statistics(&mut self) {
+        self.runs = 0;
+        self.overruns = 0;
+        self.total_jitter = Duration::new(0, 0);
+        self.max_jitter = Duration::new(0, 0);
+    }
+}
+
+pub type TaskId = usize;
+
+#[derive(Debug, Clone)]
+pub struct TaskStatistics {
+    pub name: String,
+    pub rate: f64,
+    pub mean_jitter_us: f64,
+    pub max_jitter_us: f64,
+    pub overruns: u32,
+}
+
+// Runs each task at its own rate. The hardware loop asks which tasks are due,
+// and sleeps (or waits on other work) until the next deadline.
+pub struct Scheduler {
+    tasks: Vec<Task>,
+    report_period: Duration,
+    last_report: Timestamp,
+    clock: SharedClock,
+    logger: ModuleLogger,
+}
+
+impl Scheduler {
+    pub fn new(clock: SharedClock, report_period: Duration) -> Scheduler {
+        Scheduler {
+            tasks: Vec::new(),
+            report_period: report_period,
+            last_report: clock.now(),
+            clock: clock,
+            logger: ModuleLogger::new("Scheduler", None),
+        }
+    }
+
+    pub fn add_task(&mut self, name: &str, rate: u32) -> TaskId {
+        self.tasks.push(Task::new(name, rate, self.clock.now()));
+        self.tasks.len() - 1
+    }
+
+    // Returns true and marks the task as run if its deadline has passed.
+    pub fn run_if_due(&mut self, id: TaskId, now: Timestamp) -> bool {
+        let task = &mut self.tasks[id];
+        if now >= task.next_run {
+            task.run(now);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn next_deadline(&self) -> Timestamp {
+        let mut deadline = self.last_report + self.report_period;
+        for task in &self.tasks {
+            if task.next_run < deadline {
+                deadline = task.next_run;
+            }
+        }
+        deadline
+    }
+
+    pub fn time_until_next_deadline(&self, now: Timestamp) -> Duration {
+        self.next_deadline().duration_since(now)
+    }
+
+    pub fn statistics(&self, now: Timestamp) -> Vec<TaskStatistics> {
+        let elapsed = duration_to_microseconds(now.duration_since(self.last_report))
+            / MICROSECONDS_PER_SECOND;
+        self.tasks
+            .iter()
+            .map(|task| TaskStatistics {
+                name: task.name.clone(),
+                rate: if elapsed > 0.0 {
+                    task.runs as f64 / elapsed
+                } else {
+                    0.0
+                },
+                mean_jitter_us: if task.runs > 0 {
+                    duration_to_microseconds(task.total_jitter) / task.runs as f64
+                } else {
+                    0.0
+                },
+                max_jitter_us: duration_to_microseconds(task.max_jitter),
+                overruns: task.overruns,
+            })
+            .collect()
+    }
+
+    // Logs the achieved rate, jitter and overruns of every task once per report period.
+    pub fn report_if_due(&mut self, now: Timestamp) -> Option<Vec<TaskStatistics>> {
+        if now.duration_since(self.last_report) < self.report_period {
+            return None;
+        }
+
+        let statistics = self.statistics(now);
+        for stats in &statistics {
+            let message = format!(
+                "{}: {:.1} Hz, jitter mean {:.0} us max {:.0} us, {} overruns.",
+                stats.name, stats.rate, stats.mean_jitter_us, stats.max_jitter_us, stats.overruns
+            );
+            if stats.overruns > 0 {
+                self.logger.error(&message);
+            } else {
+                self.logger.log(&message);
+            }
+        }
+
+        for task in &mut self.tasks {
+            task.reset_statistics();
+        }
+        self.last_report = now;
+        Some(statistics)
+    }
+}
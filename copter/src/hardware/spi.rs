@@ -0,0 +1,408 @@
+use std::io;
+
+use i2cdev::core::I2CDevice;
+use i2cdev::linux::LinuxI2CError;
+use spidev::{Spidev, SpidevOptions, SpidevTransfer, SPI_MODE_3};
+
+// The sensor drivers are written against I2CDevice. SpiRegisterDevice speaks the same
+// register protocol over SPI so the drivers can be reused unchanged.
+
+pub trait SpiBus {
+    // Full duplex transfer. tx and rx have the same length.
+    fn transfer(&mut self, tx: &[u8], rx: &mut [u8]) -> io::Result<()>;
+}
+
+impl SpiBus for Spidev {
+    fn transfer(&mut self, tx: &[u8], rx: &mut [u8]) -> io::Result<()> {
+        let mut transfer = SpidevTransfer::read_write(tx, rx);
+        Spidev::transfer(self, &mut transfer)
+    }
+}
+
+pub fn open_spidev(path: &str, max_speed_hz: u32) -> io::Result<Spidev> {
+    let mut spidev = Spidev::open(path)?;
+    let options = SpidevOptions::new()
+        .bits_per_word(8)
+        .max_speed_hz(max_speed_hz)
+        .mode(SPI_MODE_3)
+        .build();
+    spidev.configure(&options)?;
+    Ok(spidev)
+}
+
+// How a chip encodes the first byte of an SPI transaction.
+#[derive(Debug, Clone, Copy)]
+pub struct SpiProtocol {
+    pub register_mask: u8,
+    pub read_flag: u8,
+    pub auto_increment_flag: u8,
+    // The I2C sub-address bit that requests auto increment, if any.
+    pub i2c_auto_increment_flag: u8,
+    // Multi byte writes repeat the control byte before every data byte.
+    pub paired_writes: bool,
+}
+
+impl SpiProtocol {
+    // Bit 7 is R/W and bit 6 is MS (address auto increment).
+    pub fn lsm9ds0() -> SpiProtocol {
+        SpiProtocol {
+            register_mask: 0x3F,
+            read_flag: 0x80,
+            auto_increment_flag: 0x40,
+            i2c_auto_increment_flag: 0x80,
+            paired_writes: false,
+        }
+    }
+
+    // Bit 7 is R/W. Reads always auto increment.
+    pub fn bmp280() -> SpiProtocol {
+        SpiProtocol {
+            register_mask: 0x7F,
+            read_flag: 0x80,
+            auto_increment_flag: 0x00,
+            i2c_auto_increment_flag: 0x00,
+            paired_writes: true,
+        }
+    }
+
+    fn read_command(&self, register: u8, length: usize) -> u8 {
+        let mut command = (register & self.register_mask) | self.read_flag;
+        if length > 1 {
+            command |= self.auto_increment_flag;
+        }
+        command
+    }
+
+    fn write_command(&self, register: u8, length: usize) -> u8 {
+        let mut command = (register & self.register_mask) & !self.read_flag;
+        if length > 1 {
+            command |= self.auto_increment_flag;
+        }
+        command
+    }
+
+    fn strip_i2c_flags(&self, register: u8) -> u8 {
+        register & !self.i2c_auto_increment_flag
+    }
+}
+
+pub struct SpiRegisterDevice<B: SpiBus> {
+    bus: B,
+    protocol: SpiProtocol,
+    register: u8,
+}
+
+impl<B: SpiBus> SpiRegisterDevice<B> {
+    pub fn new(bus: B, protocol: SpiProtocol) -> SpiRegisterDevice<B> {
+        SpiRegisterDevice {
+            bus: bus,
+            protocol: protocol,
+            register: 0,
+        }
+    }
+
+    pub fn bus(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    pub fn read_registers(&mut self, register: u8, data: &mut [u8]) -> io::Result<()> {
+        let register = self.protocol.strip_i2c_flags(register);
+        let mut tx = vec![0u8; data.len() + 1];
+        let mut rx = vec![0u8; data.len() + 1];
+        tx[0] = self.protocol.read_command(register, data.len());
+        self.bus.transfer(&tx, &mut rx)?;
+        data.copy_from_slice(&rx[1..]);
+        Ok(())
+    }
+
+    pub fn write_registers(&mut self, register: u8, values: &[u8]) -> io::Result<()> {
+        let register = self.protocol.strip_i2c_flags(register);
+        let tx: Vec<u8> = if self.protocol.paired_writes {
+            let mut tx = Vec::with_capacity(values.len() * 2);
+            for (i, value) in values.iter().enumerate() {
+                tx.push(self.protocol.write_command(register.wrapping_add(i as u8), 1));
+                tx.push(*value);
+            }
+            tx
+        } else {
+            let mut tx = Vec::with_capacity(values.len() + 1);
+            tx.push(self.protocol.write_command(register, values.len()));
+            tx.extend_from_slice(values);
+            tx
+        };
+        let mut rx = vec![0u8; tx.len()];
+        self.bus.transfer(&tx, &mut rx)
+    }
+}
+
+fn unsupported(operation: &str) -> LinuxI2CError {
+    LinuxI2CError::from(io::Error::new(
+        io::ErrorKind::Other,
+        format!("{} is not supported over SPI", operation),
+    ))
+}
+
+impl<B: SpiBus> I2CDevice for SpiRegisterDevice<B> {
+    type Error = LinuxI2CError;
+
+    // Plain I2C reads continue from the register pointer set by the previous write.
+    fn read(&mut self, data: &mut [u8]) -> Result<(), Self::Error> {
+        let register = self.register;
+        self.read_registers(register, data)?;
+        self.register = register.wrapping_add(data.len() as u8);
+        Ok(())
+    }
+
+    // A single byte write only moves the register pointer, as it would over I2C.
+    fn write(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.register = data[0];
+        if data.len() > 1 {
+            self.write_registers(data[0], &data[1..])?;
+        }
+        Ok(())
+    }
+
+    fn smbus_write_quick(&mut self, _bit: bool) -> Result<(), Self::Error> {
+        Err(unsupported("SMBus quick write"))
+    }
+
+    fn smbus_read_byte_data(&mut self, register: u8) -> Result<u8, Self::Error> {
+        let mut data = [0u8; 1];
+        self.read_registers(register, &mut data)?;
+        Ok(data[0])
+    }
+
+    fn smbus_write_byte_data(&mut self, register: u8, value: u8) -> Result<(), Self::Error> {
+        self.write_registers(register, &[value])?;
+        Ok(())
+    }
+
+    fn smbus_read_block_data(&mut self, _register: u8) -> Result<Vec<u8>, Self::Error> {
+        Err(unsupported("SMBus block read"))
+    }
+
+    fn smbus_read_i2c_block_data(&mut self, register: u8, len: u8) -> Result<Vec<u8>, Self::Error> {
+        let mut data = vec![0u8; len as usize];
+        self.read_registers(register, &mut data)?;
+        Ok(data)
+    }
+
+    fn smbus_write_block_data(&mut self, register: u8, values: &[u8]) -> Result<(), Self::Error> {
+        self.write_registers(register, values)?;
+        Ok(())
+    }
+
+    fn smbus_process_block(&mut self, _register: u8, _values: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        Err(unsupported("SMBus block process call"))
+    }
+}
+
+/* ---------- Fake spidev ----------------*/
+// Emulates a chip's register file behind an SPI bus so the register level
+// logic can be exercised without hardware.
+
+#[cfg(test)]
+pub struct FakeSpidev {
+    pub registers: [u8; 128],
+    pub transfers: Vec<Vec<u8>>,
+    protocol: SpiProtocol,
+}
+
+#[cfg(test)]
+impl FakeSpidev {
+    pub fn new(protocol: SpiProtocol) -> FakeSpidev {
+        FakeSpidev {
+            registers: [0; 128],
+            transfers: Vec::new(),
+            protocol: protocol,
+        }
+    }
+
+    fn register_index(&self, command: u8, offset: usize) -> usize {
+        let register = (command & self.protocol.register_mask) as usize;
+        let increments = self.protocol.auto_increment_flag == 0
+            || command & self.protocol.auto_increment_flag != 0;
+        if increments {
+            (register + offset) % self.registers.len()
+        } else {
+            register
+        }
+    }
+}
+
+#[cfg(test)]
+impl SpiBus for FakeSpidev {
+    fn transfer(&mut self, tx: &[u8], rx: &mut [u8]) -> io::Result<()> {
+        if tx.len() != rx.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "tx and rx buffers differ in length",
+            ));
+        }
+        self.transfers.push(tx.to_vec());
+        if tx.is_empty() {
+            return Ok(());
+        }
+
+        let command = tx[0];
+        if command & self.protocol.read_flag != 0 {
+            for i in 1..rx.len() {
+                rx[i] = self.registers[self.register_index(command, i - 1)];
+            }
+        } else if self.protocol.paired_writes {
+            for pair in tx.chunks(2) {
+                if pair.len() == 2 {
+                    let index = self.register_index(pair[0], 0);
+                    self.registers[index] = pair[1];
+                }
+            }
+        } else {
+            for i in 1..tx.len() {
+                let index = self.register_index(command, i - 1);
+                self.registers[index] = tx[i];
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LSM9DS0_CTRL_REG1_G: u8 = 0x20;
+    const LSM9DS0_OUT_X_L_G: u8 = 0x28;
+    const LSM9DS0_WHO_AM_I_G: u8 = 0x0F;
+    const LSM9DS0_MULTIPLE_READ: u8 = 0x80;
+    const BMP280_CHIP_ID: u8 = 0xD0;
+    const BMP280_CALIBRATION: u8 = 0x88;
+    const BMP280_CTRL_MEAS: u8 = 0xF4;
+    const BMP280_CONFIG: u8 = 0xF5;
+
+    fn lsm9ds0() -> SpiRegisterDevice<FakeSpidev> {
+        SpiRegisterDevice::new(FakeSpidev::new(SpiProtocol::lsm9ds0()), SpiProtocol::lsm9ds0())
+    }
+
+    fn bmp280() -> SpiRegisterDevice<FakeSpidev> {
+        SpiRegisterDevice::new(FakeSpidev::new(SpiProtocol::bmp280()), SpiProtocol::bmp280())
+    }
+
+    #[test]
+    fn lsm9ds0_single_register_read_and_write() {
+        let mut device = lsm9ds0();
+        device.bus().registers[LSM9DS0_WHO_AM_I_G as usize] = 0xD4;
+
+        assert_eq!(device.smbus_read_byte_data(LSM9DS0_WHO_AM_I_G).unwrap(), 0xD4);
+        device
+            .smbus_write_byte_data(LSM9DS0_CTRL_REG1_G, 0x0F)
+            .unwrap();
+
+        assert_eq!(device.bus().registers[LSM9DS0_CTRL_REG1_G as usize], 0x0F);
+        assert_eq!(
+            device.bus().transfers,
+            vec![vec![0x8F, 0x00], vec![0x20, 0x0F]]
+        );
+    }
+
+    // The driver asks for auto increment with the I2C sub-address bit, which is the MS
+    // bit over SPI.
+    #[test]
+    fn lsm9ds0_block_read_sets_auto_increment() {
+        let mut device = lsm9ds0();
+        for i in 0..6 {
+            device.bus().registers[LSM9DS0_OUT_X_L_G as usize + i] = i as u8 + 1;
+        }
+
+        let data = device
+            .smbus_read_i2c_block_data(LSM9DS0_OUT_X_L_G | LSM9DS0_MULTIPLE_READ, 6)
+            .unwrap();
+
+        assert_eq!(data, vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(device.bus().transfers[0][0], 0xE8);
+        assert_eq!(device.bus().transfers[0].len(), 7);
+    }
+
+    // Plain reads continue from the register the previous write pointed at.
+    #[test]
+    fn lsm9ds0_plain_read_follows_register_pointer() {
+        let mut device = lsm9ds0();
+        for i in 0..6 {
+            device.bus().registers[LSM9DS0_OUT_X_L_G as usize + i] = 0x10 + i as u8;
+        }
+
+        device
+            .write(&[LSM9DS0_OUT_X_L_G | LSM9DS0_MULTIPLE_READ])
+            .unwrap();
+        let mut first = [0u8; 2];
+        device.read(&mut first).unwrap();
+        let mut second = [0u8; 2];
+        device.read(&mut second).unwrap();
+
+        assert_eq!(first, [0x10, 0x11]);
+        assert_eq!(second, [0x12, 0x13]);
+        // Moving the pointer doesn't touch the bus.
+        assert_eq!(device.bus().transfers.len(), 2);
+    }
+
+    #[test]
+    fn lsm9ds0_multi_byte_write() {
+        let mut device = lsm9ds0();
+
+        device
+            .write(&[LSM9DS0_CTRL_REG1_G, 0x0F, 0x00, 0x08])
+            .unwrap();
+
+        assert_eq!(device.bus().transfers, vec![vec![0x60, 0x0F, 0x00, 0x08]]);
+        assert_eq!(&device.bus().registers[0x20..0x23], &[0x0F, 0x00, 0x08]);
+    }
+
+    #[test]
+    fn bmp280_reads_chip_id_and_calibration() {
+        let mut device = bmp280();
+        device.bus().registers[(BMP280_CHIP_ID & 0x7F) as usize] = 0x58;
+        for i in 0..24 {
+            device.bus().registers[(BMP280_CALIBRATION & 0x7F) as usize + i] = i as u8;
+        }
+
+        assert_eq!(device.smbus_read_byte_data(BMP280_CHIP_ID).unwrap(), 0x58);
+        let calibration = device
+            .smbus_read_i2c_block_data(BMP280_CALIBRATION, 24)
+            .unwrap();
+
+        assert_eq!(calibration, (0..24).collect::<Vec<u8>>());
+        assert_eq!(device.bus().transfers[0][0], 0xD0);
+        assert_eq!(device.bus().transfers[1][0], 0x88);
+    }
+
+    // Every register written repeats the control byte, with the R/W bit cleared.
+    #[test]
+    fn bmp280_writes_are_paired() {
+        let mut device = bmp280();
+
+        device
+            .smbus_write_byte_data(BMP280_CTRL_MEAS, 0x27)
+            .unwrap();
+        device
+            .smbus_write_block_data(BMP280_CTRL_MEAS, &[0x57, 0x10])
+            .unwrap();
+
+        assert_eq!(
+            device.bus().transfers,
+            vec![vec![0x74, 0x27], vec![0x74, 0x57, 0x75, 0x10]]
+        );
+        assert_eq!(device.bus().registers[(BMP280_CTRL_MEAS & 0x7F) as usize], 0x57);
+        assert_eq!(device.bus().registers[(BMP280_CONFIG & 0x7F) as usize], 0x10);
+    }
+
+    #[test]
+    fn unsupported_operations_fail() {
+        let mut device = bmp280();
+
+        assert!(device.smbus_write_quick(true).is_err());
+        assert!(device.smbus_read_block_data(BMP280_CHIP_ID).is_err());
+        assert!(device.bus().transfers.is_empty());
+    }
+}
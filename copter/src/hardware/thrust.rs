@@ -0,0 +1,171 @@
+use configurations::config::{ThrustModel, FULL_POWER, IDLE_POWER};
+use configurations::thrust::{command_fraction, evaluate_polynomial};
+use logger::ModuleLogger;
+
+// Points checked when making sure a polynomial only increases.
+const MONOTONIC_CHECK_POINTS: usize = 100;
+const INVERSION_ITERATIONS: usize = 40;
+
+#[derive(Debug, Clone)]
+enum Curve {
+    Polynomial(Vec<f64>),
+    Table(Vec<(f64, f64)>),
+}
+
+// Thrust of one motor as a function of its command and the battery voltage.
+#[derive(Debug, Clone)]
+pub struct MotorThrust {
+    curve: Curve,
+    reference_voltage: Option<f64>,
+}
+
+impl MotorThrust {
+    pub fn from_config(logger: &ModuleLogger, model: &ThrustModel) -> Result<MotorThrust, ()> {
+        let curve = match (&model.polynomial, &model.table) {
+            (&Some(ref coefficients), &None) if !coefficients.is_empty() => {
+                Curve::Polynomial(coefficients.iter().map(|c| *c as f64).collect())
+            }
+            (&None, &Some(ref table)) if table.len() >= 2 => {
+                Curve::Table(table.iter().map(|&(c, t)| (c as f64, t as f64)).collect())
+            }
+            _ => {
+                logger.error("A thrust model needs a polynomial or a table of at least two points.");
+                return Err(());
+            }
+        };
+
+        let reference_voltage = match model.reference_voltage {
+            Some(voltage) if voltage <= 0.0 => {
+                logger.error("Thrust model reference_voltage must be positive.");
+                return Err(());
+            }
+            Some(voltage) => Some(voltage as f64),
+            None => None,
+        };
+
+        let thrust = MotorThrust {
+            curve: curve,
+            reference_voltage: reference_voltage,
+        };
+        // The inverse only exists if more command always means more thrust.
+        if !thrust.is_increasing() {
+            logger.error("Thrust model must increase with the motor command.");
+            return Err(());
+        }
+        Ok(thrust)
+    }
+
+    fn is_increasing(&self) -> bool {
+        match self.curve {
+            Curve::Table(ref table) => table
+                .windows(2)
+                .all(|pair| pair[1].0 > pair[0].0 && pair[1].1 >= pair[0].1),
+            Curve::Polynomial(_) => {
+                let step = (FULL_POWER - IDLE_POWER) / MONOTONIC_CHECK_POINTS as f64;
+                (0..MONOTONIC_CHECK_POINTS).all(|i| {
+                    let command = IDLE_POWER + step * i as f64;
+                    self.reference_thrust(command + step) >= self.reference_thrust(command)
+                })
+            }
+        }
+    }
+
+    // Thrust at the reference voltage.
+    fn reference_thrust(&self, command: f64) -> f64 {
+        match self.curve {
+            Curve::Polynomial(ref coefficients) => {
+                evaluate_polynomial(coefficients, command_fraction(command))
+            }
+            Curve::Table(ref table) => {
+                let command = command.max(table[0].0).min(table[table.len() - 1].0);
+                for pair in table.windows(2) {
+                    if command <= pair[1].0 {
+                        let fraction = (command - pair[0].0) / (pair[1].0 - pair[0].0);
+                        return pair[0].1 + fraction * (pair[1].1 - pair[0].1);
+                    }
+                }
+                table[table.len() - 1].1
+            }
+        }
+    }
+
+    // At a fixed command thrust scales with the square of the battery voltage.
+    fn voltage_scale(&self, voltage: Option<f64>) -> f64 {
+        match (self.reference_voltage, voltage) {
+            (Some(reference), Some(voltage)) if voltage > 0.0 => {
+                (voltage / reference) * (voltage / reference)
+            }
+            _ => 1.0,
+        }
+    }
+
+    pub fn thrust(&self, command: f64, voltage: Option<f64>) -> f64 {
+        self.reference_thrust(command) * self.voltage_scale(voltage)
+    }
+
+    // The command that produces the thrust, clamped to what the motor can do.
+    pub fn command(&self, thrust: f64, voltage: Option<f64>) -> f64 {
+        let target = thrust / self.voltage_scale(voltage);
+        if target <= self.reference_thrust(IDLE_POWER) {
+            return IDLE_POWER;
+        }
+        if target >= self.reference_thrust(FULL_POWER) {
+            return FULL_POWER;
+        }
+
+        let (mut low, mut high) = (IDLE_POWER, FULL_POWER);
+        for _ in 0..INVERSION_ITERATIONS {
+            let middle = (low + high) / 2.0;
+            if self.reference_thrust(middle) < target {
+                low = middle;
+            } else {
+                high = middle;
+            }
+        }
+        (low + high) / 2.0
+    }
+}
+
+// Converts per motor thrust in newtons to motor commands.
+pub struct ThrustMixer {
+    motors: Vec<MotorThrust>,
+}
+
+impl ThrustMixer {
+    // None if no thrust models are configured.
+    pub fn new(
+        models: &Option<Vec<ThrustModel>>,
+        motors: usize,
+    ) -> Result<Option<ThrustMixer>, ()> {
+        let logger = ModuleLogger::new("Thrust", None);
+        let models = match *models {
+            Some(ref models) => models,
+            None => return Ok(None),
+        };
+
+        let mut curves = Vec::new();
+        for model in models {
+            curves.push(MotorThrust::from_config(&logger, model)?);
+        }
+        let curves = match curves.len() {
+            1 => vec![curves[0].clone(); motors],
+            count if count == motors => curves,
+            count => {
+                logger.error(&format!(
+                    "Found {} thrust models for {} motors. Use one per motor or one for all.",
+                    count, motors
+                ));
+                return Err(());
+            }
+        };
+        Ok(Some(ThrustMixer { motors: curves }))
+    }
+
+    pub fn commands(&self, thrusts: [f64; 4], voltage: Option<f64>) -> [f64; 4] {
+        let mut commands = [IDLE_POWER; 4];
+        for (i, motor) in self.motors.iter().enumerate().take(4) {
+            commands[i] = motor.command(thrusts[i], voltage);
+        }
+        commands
+    }
+}
@@ -0,0 +1,258 @@
+use configurations::config::GpsDynamicModel;
+
+use super::gps::GpsFix;
+
+const SYNC_1: u8 = 0xB5;
+const SYNC_2: u8 = 0x62;
+const HEADER_LENGTH: usize = 6;
+// Longer frames are treated as corrupt. Nothing we use comes close.
+const MAX_PAYLOAD_LENGTH: usize = 1024;
+
+pub const CLASS_NAV: u8 = 0x01;
+pub const CLASS_ACK: u8 = 0x05;
+pub const CLASS_CFG: u8 = 0x06;
+pub const NAV_DOP: u8 = 0x04;
+pub const NAV_PVT: u8 = 0x07;
+pub const ACK_NAK: u8 = 0x00;
+pub const ACK_ACK: u8 = 0x01;
+pub const CFG_MSG: u8 = 0x01;
+pub const CFG_RATE: u8 = 0x08;
+pub const CFG_NAV5: u8 = 0x24;
+
+const NAV_PVT_LENGTH: usize = 92;
+const NAV_DOP_LENGTH: usize = 18;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UbxFrame {
+    pub class: u8,
+    pub id: u8,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NavPvt {
+    pub time: Option<(i32, u32, u32, f64)>, // UTC year, month, day, seconds since midnight
+    pub fix: GpsFix,
+    pub satellites: u32,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: f64,            // Above mean sea level
+    pub horizontal_accuracy: f64, // m
+    pub vertical_accuracy: f64,   // m
+    pub ground_speed: f64,        // m/s
+    pub climb: f64,               // m/s
+    pub heading: f64,             // Degrees, of motion
+    pub speed_accuracy: f64,      // m/s
+    pub heading_accuracy: f64,    // Degrees
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NavDop {
+    pub pdop: f64,
+    pub hdop: f64,
+    pub vdop: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UbxMessage {
+    NavPvt(NavPvt),
+    NavDop(NavDop),
+    Ack(u8, u8), // Class and id of the acknowledged message
+    Nak(u8, u8),
+}
+
+fn u16_at(payload: &[u8], offset: usize) -> u16 {
+    payload[offset] as u16 | (payload[offset + 1] as u16) << 8
+}
+
+fn u32_at(payload: &[u8], offset: usize) -> u32 {
+    u16_at(payload, offset) as u32 | (u16_at(payload, offset + 2) as u32) << 16
+}
+
+fn i32_at(payload: &[u8], offset: usize) -> i32 {
+    u32_at(payload, offset) as i32
+}
+
+// 8 bit Fletcher checksum over the class, id, length and payload.
+pub fn checksum(bytes: &[u8]) -> (u8, u8) {
+    bytes.iter().fold((0u8, 0u8), |(a, b), byte| {
+        let a = a.wrapping_add(*byte);
+        (a, b.wrapping_add(a))
+    })
+}
+
+pub fn encode_frame(class: u8, id: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![
+        SYNC_1,
+        SYNC_2,
+        class,
+        id,
+        payload.len() as u8,
+        (payload.len() >> 8) as u8,
+    ];
+    frame.extend_from_slice(payload);
+    let (a, b) = checksum(&frame[2..]);
+    frame.push(a);
+    frame.push(b);
+    frame
+}
+
+// Output rate of a message on the port the command arrives on.
+pub fn cfg_msg(class: u8, id: u8, rate: u8) -> Vec<u8> {
+    encode_frame(CLASS_CFG, CFG_MSG, &[class, id, rate])
+}
+
+pub fn cfg_rate(rate_hz: u32) -> Vec<u8> {
+    let period_ms = (1000 / rate_hz.max(1)) as u16;
+    // One navigation solution per measurement, aligned to GPS time.
+    encode_frame(
+        CLASS_CFG,
+        CFG_RATE,
+        &[period_ms as u8, (period_ms >> 8) as u8, 1, 0, 1, 0],
+    )
+}
+
+pub fn cfg_nav5(model: GpsDynamicModel) -> Vec<u8> {
+    let model = match model {
+        GpsDynamicModel::Portable => 0,
+        GpsDynamicModel::Stationary => 2,
+        GpsDynamicModel::Pedestrian => 3,
+        GpsDynamicModel::Automotive => 4,
+        GpsDynamicModel::Sea => 5,
+        GpsDynamicModel::Airborne1G => 6,
+        GpsDynamicModel::Airborne2G => 7,
+        GpsDynamicModel::Airborne4G => 8,
+    };
+    // Only the dynamic model is applied, the rest of the payload is ignored.
+    let mut payload = [0u8; 36];
+    payload[0] = 0x01;
+    payload[2] = model;
+    encode_frame(CLASS_CFG, CFG_NAV5, &payload)
+}
+
+fn parse_nav_pvt(payload: &[u8]) -> NavPvt {
+    let valid = payload[11];
+    let flags = payload[21];
+    let time = if valid & 0x03 == 0x03 {
+        let seconds = payload[8] as f64 * 3600.0 + payload[9] as f64 * 60.0
+            + payload[10] as f64 + i32_at(payload, 16) as f64 * 1e-9;
+        Some((
+            u16_at(payload, 4) as i32,
+            payload[6] as u32,
+            payload[7] as u32,
+            seconds,
+        ))
+    } else {
+        None
+    };
+    // gnssFixOK has to be set for the fix type to be trusted.
+    let fix = match (flags & 0x01, payload[20]) {
+        (0, _) => GpsFix::NoFix,
+        (_, 2) => GpsFix::Fix2D,
+        (_, 3) | (_, 4) => match (flags >> 6, flags & 0x02) {
+            (2, _) => GpsFix::RTKFixed,
+            (1, _) => GpsFix::RTKFloat,
+            (_, 0) => GpsFix::Fix3D,
+            _ => GpsFix::DGPS,
+        },
+        _ => GpsFix::NoFix,
+    };
+
+    NavPvt {
+        time: time,
+        fix: fix,
+        satellites: payload[23] as u32,
+        longitude: i32_at(payload, 24) as f64 * 1e-7,
+        latitude: i32_at(payload, 28) as f64 * 1e-7,
+        altitude: i32_at(payload, 36) as f64 / 1000.0,
+        horizontal_accuracy: u32_at(payload, 40) as f64 / 1000.0,
+        vertical_accuracy: u32_at(payload, 44) as f64 / 1000.0,
+        climb: -i32_at(payload, 56) as f64 / 1000.0,
+        ground_speed: i32_at(payload, 60) as f64 / 1000.0,
+        heading: i32_at(payload, 64) as f64 * 1e-5,
+        speed_accuracy: u32_at(payload, 68) as f64 / 1000.0,
+        heading_accuracy: u32_at(payload, 72) as f64 * 1e-5,
+    }
+}
+
+fn parse_nav_dop(payload: &[u8]) -> NavDop {
+    NavDop {
+        pdop: u16_at(payload, 6) as f64 * 0.01,
+        vdop: u16_at(payload, 10) as f64 * 0.01,
+        hdop: u16_at(payload, 12) as f64 * 0.01,
+    }
+}
+
+// None for messages we don't use or that are too short.
+pub fn parse_frame(frame: &UbxFrame) -> Option<UbxMessage> {
+    let payload = &frame.payload;
+    match (frame.class, frame.id) {
+        (CLASS_NAV, NAV_PVT) if payload.len() >= NAV_PVT_LENGTH => {
+            Some(UbxMessage::NavPvt(parse_nav_pvt(payload)))
+        }
+        (CLASS_NAV, NAV_DOP) if payload.len() >= NAV_DOP_LENGTH => {
+            Some(UbxMessage::NavDop(parse_nav_dop(payload)))
+        }
+        (CLASS_ACK, ACK_ACK) if payload.len() >= 2 => {
+            Some(UbxMessage::Ack(payload[0], payload[1]))
+        }
+        (CLASS_ACK, ACK_NAK) if payload.len() >= 2 => {
+            Some(UbxMessage::Nak(payload[0], payload[1]))
+        }
+        _ => None,
+    }
+}
+
+// Picks UBX frames out of a byte stream, skipping anything between them.
+pub struct UbxParser {
+    buffer: Vec<u8>,
+    length: usize,
+}
+
+impl UbxParser {
+    pub fn new() -> UbxParser {
+        UbxParser {
+            buffer: Vec::new(),
+            length: 0,
+        }
+    }
+
+    pub fn push(&mut self, byte: u8) -> Option<UbxFrame> {
+        match self.buffer.len() {
+            0 if byte != SYNC_1 => return None,
+            1 if byte != SYNC_2 => {
+                self.buffer.clear();
+                if byte == SYNC_1 {
+                    self.buffer.push(byte);
+                }
+                return None;
+            }
+            _ => self.buffer.push(byte),
+        };
+
+        if self.buffer.len() == HEADER_LENGTH {
+            self.length = u16_at(&self.buffer, 4) as usize;
+            if self.length > MAX_PAYLOAD_LENGTH {
+                self.buffer.clear();
+            }
+            return None;
+        }
+        if self.buffer.len() < HEADER_LENGTH + self.length + 2 {
+            return None;
+        }
+
+        let end = HEADER_LENGTH + self.length;
+        let (a, b) = checksum(&self.buffer[2..end]);
+        let frame = if a == self.buffer[end] && b == self.buffer[end + 1] {
+            Some(UbxFrame {
+                class: self.buffer[2],
+                id: self.buffer[3],
+                payload: self.buffer[HEADER_LENGTH..end].to_vec(),
+            })
+        } else {
+            None
+        };
+        self.buffer.clear();
+        frame
+    }
+}
@@ -0,0 +1,301 @@
+use std::f64::consts::PI;
+use std::fmt;
+
+use na::Vector3;
+use num::traits::Zero;
+
+// Must be a power of two for the FFT.
+pub const WINDOW_SIZE: usize = 256;
+// Samples within this fraction of full scale count as clipped.
+const CLIPPING_THRESHOLD: f64 = 0.98;
+
+// In place iterative radix-2 Cooley-Tukey FFT.
+pub fn fft(re: &mut [f64], im: &mut [f64]) {
+    let n = re.len();
+    assert!(n.is_power_of_two() && im.len() == n);
+
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut length = 2;
+    while length <= n {
+        let angle = -2.0 * PI / length as f64;
+        let (w_re, w_im) = (angle.cos(), angle.sin());
+        for start in (0..n).filter(|start| start % length == 0) {
+            let (mut t_re, mut t_im) = (1.0, 0.0);
+            for k in 0..length / 2 {
+                let a = start + k;
+                let b = a + length / 2;
+                let b_re = re[b] * t_re - im[b] * t_im;
+                let b_im = re[b] * t_im + im[b] * t_re;
+                re[b] = re[a] - b_re;
+                im[b] = im[a] - b_im;
+                re[a] += b_re;
+                im[a] += b_im;
+                let next_re = t_re * w_re - t_im * w_im;
+                t_im = t_re * w_im + t_im * w_re;
+                t_re = next_re;
+            }
+        }
+        length <<= 1;
+    }
+}
+
+// Single sided amplitude spectrum of a Hann windowed signal with its mean removed.
+// Bin i is at i * sample_rate / samples.len().
+pub fn amplitude_spectrum(samples: &[f64]) -> Vec<f64> {
+    let n = samples.len();
+    let mean = samples.iter().sum::<f64>() / n as f64;
+
+    let mut re: Vec<f64> = samples
+        .iter()
+        .enumerate()
+        .map(|(i, sample)| {
+            let window = 0.5 - 0.5 * (2.0 * PI * i as f64 / (n - 1) as f64).cos();
+            (sample - mean) * window
+        })
+        .collect();
+    let mut im = vec![0.0; n];
+    fft(&mut re, &mut im);
+
+    // The Hann window has a coherent gain of 0.5.
+    (0..n / 2 + 1)
+        .map(|i| {
+            let scale = if i == 0 || i == n / 2 { 1.0 } else { 2.0 };
+            scale * (re[i] * re[i] + im[i] * im[i]).sqrt() / (n as f64 * 0.5)
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Peak {
+    pub frequency: f64,
+    pub amplitude: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AxisVibration {
+    pub rms: f64,
+    pub peak: Peak,
+}
+
+fn analyze_axis(samples: &[f64], sample_rate: f64) -> AxisVibration {
+    let n = samples.len() as f64;
+    let mean = samples.iter().sum::<f64>() / n;
+    let rms = (samples.iter().map(|s| (s - mean) * (s - mean)).sum::<f64>() / n).sqrt();
+
+    let spectrum = amplitude_spectrum(samples);
+    let mut peak = Peak {
+        frequency: 0.0,
+        amplitude: 0.0,
+    };
+    // Skip the DC bin.
+    for (i, amplitude) in spectrum.iter().enumerate().skip(1) {
+        if *amplitude > peak.amplitude {
+            peak = Peak {
+                frequency: i as f64 * sample_rate / n,
+                amplitude: *amplitude,
+            };
+        }
+    }
+
+    AxisVibration {
+        rms: rms,
+        peak: peak,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VibrationReport {
+    pub gyroscope_rate: f64,
+    pub gyroscope: [AxisVibration; 3],
+    pub accelerometer_rate: f64,
+    pub accelerometer: [AxisVibration; 3],
+    pub accelerometer_clipping: u32,
+}
+
+impl fmt::Display for VibrationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "vibration")?;
+        for &(name, axes) in [("gyro", &self.gyroscope), ("accel", &self.accelerometer)].iter() {
+            for (axis, vibration) in ["x", "y", "z"].iter().zip(axes.iter()) {
+                write!(
+                    f,
+                    ", {}_{}_rms={:.4}, {}_{}_peak_hz={:.1}, {}_{}_peak={:.4}",
+                    name,
+                    axis,
+                    vibration.rms,
+                    name,
+                    axis,
+                    vibration.peak.frequency,
+                    name,
+                    axis,
+                    vibration.peak.amplitude
+                )?;
+            }
+        }
+        write!(f, ", accel_clipping={}", self.accelerometer_clipping)
+    }
+}
+
+// The most recent samples of one sensor, taken at its output data rate.
+struct Window {
+    samples: Vec<Vector3<f64>>,
+    next: usize,
+    filled: bool,
+    sample_rate: f64,
+}
+
+impl Window {
+    fn new(sample_rate: f64) -> Window {
+        Window {
+            samples: vec![Vector3::zero(); WINDOW_SIZE],
+            next: 0,
+            filled: false,
+            sample_rate: sample_rate,
+        }
+    }
+
+    fn add(&mut self, sample: Vector3<f64>) {
+        self.samples[self.next] = sample;
+        self.next = (self.next + 1) % WINDOW_SIZE;
+        if self.next == 0 {
+            self.filled = true;
+        }
+    }
+
+    fn analyze(&self) -> [AxisVibration; 3] {
+        let mut axes = [AxisVibration {
+            rms: 0.0,
+            peak: Peak {
+                frequency: 0.0,
+                amplitude: 0.0,
+            },
+        }; 3];
+        for axis in 0..3 {
+            let samples: Vec<f64> = (0..WINDOW_SIZE)
+                .map(|i| self.samples[(self.next + i) % WINDOW_SIZE][axis])
+                .collect();
+            axes[axis] = analyze_axis(&samples, self.sample_rate);
+        }
+        axes
+    }
+}
+
+// Keeps the most recent raw IMU samples and summarizes them on request. Samples are
+// taken before calibration and filtering, at the rate each sensor outputs them, so
+// the spectra show what the sensor saw and clipping is against what it can measure.
+pub struct VibrationMonitor {
+    gyroscope: Window,
+    accelerometer: Window,
+    accelerometer_full_scale: f64,
+    clipping: u32,
+}
+
+impl VibrationMonitor {
+    // Rates are in Hz, full scale is the raw range in m/s^2.
+    pub fn new(
+        gyroscope_rate: f64,
+        accelerometer_rate: f64,
+        accelerometer_full_scale: f64,
+    ) -> VibrationMonitor {
+        VibrationMonitor {
+            gyroscope: Window::new(gyroscope_rate),
+            accelerometer: Window::new(accelerometer_rate),
+            accelerometer_full_scale: accelerometer_full_scale,
+            clipping: 0,
+        }
+    }
+
+    pub fn add_gyroscope_sample(&mut self, raw_angular_rate: Vector3<f64>) {
+        self.gyroscope.add(raw_angular_rate);
+    }
+
+    pub fn add_accelerometer_sample(&mut self, raw_acceleration: Vector3<f64>) {
+        let limit = self.accelerometer_full_scale * CLIPPING_THRESHOLD;
+        if raw_acceleration.iter().any(|a| a.abs() >= limit) {
+            self.clipping += 1;
+        }
+        self.accelerometer.add(raw_acceleration);
+    }
+
+    // Returns None until both windows have been filled. Resets the clipping count.
+    pub fn report(&mut self) -> Option<VibrationReport> {
+        if !self.gyroscope.filled || !self.accelerometer.filled {
+            return None;
+        }
+
+        let report = VibrationReport {
+            gyroscope_rate: self.gyroscope.sample_rate,
+            gyroscope: self.gyroscope.analyze(),
+            accelerometer_rate: self.accelerometer.sample_rate,
+            accelerometer: self.accelerometer.analyze(),
+            accelerometer_clipping: self.clipping,
+        };
+        self.clipping = 0;
+        Some(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f64, amplitude: f64, sample_rate: f64, i: usize) -> f64 {
+        amplitude * (2.0 * PI * frequency * i as f64 / sample_rate).sin()
+    }
+
+    #[test]
+    fn peaks_are_at_each_sensors_rate() {
+        // Each sensor's bins are spaced by its own rate.
+        let mut monitor = VibrationMonitor::new(760.0, 400.0, 4.0 * 9.80665);
+        for i in 0..WINDOW_SIZE {
+            monitor.add_gyroscope_sample(Vector3::new(sine(95.0, 1.0, 760.0, i), 0.0, 0.0));
+            let acceleration = Vector3::new(0.0, sine(50.0, 2.0, 400.0, i), 9.80665);
+            monitor.add_accelerometer_sample(acceleration);
+            if i < WINDOW_SIZE - 1 {
+                assert!(monitor.report().is_none());
+            }
+        }
+        let report = monitor.report().unwrap();
+
+        let bin = 760.0 / WINDOW_SIZE as f64;
+        assert!((report.gyroscope[0].peak.frequency - 95.0).abs() <= bin);
+        assert!((report.gyroscope[0].peak.amplitude - 1.0).abs() < 0.2);
+        assert!((report.gyroscope[0].rms - 1.0 / 2.0f64.sqrt()).abs() < 0.05);
+        let bin = 400.0 / WINDOW_SIZE as f64;
+        assert!((report.accelerometer[1].peak.frequency - 50.0).abs() <= bin);
+        assert!((report.accelerometer[1].peak.amplitude - 2.0).abs() < 0.4);
+        // Gravity is constant, it only shows in the mean.
+        assert!(report.accelerometer[2].rms < 1e-9);
+        assert_eq!(report.accelerometer_clipping, 0);
+    }
+
+    #[test]
+    fn clipping_is_against_the_raw_range() {
+        let full_scale = 2.0 * 9.80665;
+        let mut monitor = VibrationMonitor::new(100.0, 100.0, full_scale);
+        for _ in 0..WINDOW_SIZE {
+            monitor.add_gyroscope_sample(Vector3::zero());
+            monitor.add_accelerometer_sample(Vector3::new(0.0, 0.0, 9.80665));
+        }
+        monitor.add_accelerometer_sample(Vector3::new(-full_scale, 0.0, 9.80665));
+        monitor.add_accelerometer_sample(Vector3::new(0.0, 0.0, full_scale * 0.99));
+        monitor.add_accelerometer_sample(Vector3::new(0.0, full_scale * 0.9, 9.80665));
+
+        assert_eq!(monitor.report().unwrap().accelerometer_clipping, 2);
+        // Counted again from the last report.
+        assert_eq!(monitor.report().unwrap().accelerometer_clipping, 0);
+    }
+}
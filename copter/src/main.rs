@@ -16,7 +16,9 @@ extern crate i2cdev_lsm303d;
 extern crate i2cdev_lsm9ds0;
 extern crate i2csensors;
 extern crate pca9685;
-extern crate unbounded_gpsd;
+extern crate serial;
+extern crate serde_json;
+extern crate spidev;
 extern crate wifilocation;
 // extern crate rust_pigpio;
 
@@ -26,9 +28,11 @@ extern crate nalgebra as na;
 extern crate num;
 extern crate typenum;
 
+use std::env;
 use std::io;
 use std::io::Read;
 use std::process::exit;
+use std::sync::mpsc::Sender;
 
 use na::{Matrix3, MatrixN, Vector3};
 use na::U20;
@@ -37,13 +41,17 @@ type Matrix100 = MatrixN<f32, U20>;
 
 use logger::ModuleLogger;
 
-use configurations::Calibrations;
+use configurations::{Calibrations, Config, ParameterStore};
+use configurations::layers::{Arguments, USAGE};
 
+mod clock;
 mod hardware;
 mod flight;
 
-use hardware::{initialize_hardware, MotorCommand};
-use flight::start_flight_controller;
+use clock::RealClock;
+use hardware::{console_link, initialize_hardware, network_link, CalibrationReport,
+               CalibrationSession, MotorCommand};
+use flight::{start_flight_controller, FlightMode};
 
 pub type PredictionReading = hardware::PredictionReading;
 pub type UpdateReading = hardware::UpdateReading;
@@ -59,36 +67,188 @@ pub enum Control {
 fn main() {
     let logger = ModuleLogger::new("Main", None);
 
+    // The configuration is merged once here and passed to whatever needs it.
+    let args: Vec<String> = env::args().skip(1).collect();
+    let arguments = match Arguments::parse(&args) {
+        Ok(ref arguments) if !arguments.rest.is_empty() => {
+            logger.error(&format!("Unknown arguments {}.", arguments.rest.join(" ")));
+            println!("{}", USAGE);
+            exit(1);
+        }
+        Ok(arguments) => arguments,
+        Err(e) => {
+            logger.error(&e);
+            println!("{}", USAGE);
+            exit(1);
+        }
+    };
+    if arguments.print_config {
+        match Config::effective(&arguments) {
+            Ok(effective) => print!("{}", effective),
+            Err(e) => {
+                logger.error(&e);
+                exit(1);
+            }
+        };
+        exit(0);
+    }
+
+    let config = match Config::new(&arguments) {
+        Ok(config) => config,
+        Err(e) => {
+            logger.error(&e);
+            exit(1);
+        }
+    };
+    if !check_config(&logger, &config) {
+        exit(1);
+    }
+
     logger.log("Enter: Start flight.");
     logger.log("sensors.");
+    logger.log("magnetometer <samples file>.");
+    logger.log("thermal.");
     logger.log("motors.");
+    logger.log("bench.");
 
     let mut input = String::new();
     io::stdin().read_line(&mut input).unwrap();
     match input.trim().as_ref() {
         "sensors" => {
-            hardware::calibrate_sensors();
+            calibrate(&config, hardware::calibrate_sensors);
+        }
+        "thermal" => {
+            calibrate(&config, hardware::calibrate_thermal);
         }
         "motors" => {
-            hardware::calibrate_motors();
+            calibrate(&config, hardware::calibrate_motors);
+        }
+        "bench" => {
+            hardware::motor_bench(&config);
+        }
+        command if command.starts_with("magnetometer ") => {
+            let path = command["magnetometer ".len()..].trim();
+            hardware::calibrate_magnetometer_from_file(&config, path);
         }
         _ => {
-            start_flight();
+            start_flight(config);
+        }
+    };
+}
+
+// Every problem is listed, so the file can be fixed in one go.
+fn check_config(logger: &ModuleLogger, config: &Config) -> bool {
+    match config.validate() {
+        Ok(()) => true,
+        Err(errors) => {
+            logger.error(&format!(
+                "Found {} problems in the configuration:",
+                errors.len()
+            ));
+            for error in errors {
+                logger.error(&error.to_string());
+            }
+            false
         }
+    }
+}
+
+// Prompts are answered from the debug port with live_debugging on, at the terminal
+// otherwise.
+fn calibrate(
+    config: &Config,
+    calibration: fn(&Config, &mut CalibrationSession) -> CalibrationReport,
+) {
+    let logger = ModuleLogger::new("Main", None);
+    let (mut session, link) = CalibrationSession::new();
+    let handle = if config.debug.live_debugging {
+        let port = config.debug.debug_websocket_port as u16;
+        match network_link(link, port) {
+            Ok(handle) => {
+                logger.log(&format!("Waiting for a calibration client on port {}.", port));
+                handle
+            }
+            Err(e) => {
+                logger.error(&e);
+                return;
+            }
+        }
+    } else {
+        console_link(link)
+    };
+    calibration(config, &mut session);
+    handle.join().unwrap();
+}
+
+// Returns when an empty line is entered.
+fn tune_from_console(
+    logger: &ModuleLogger,
+    parameters: &ParameterStore,
+    mode_tx: &Sender<FlightMode>,
+) {
+    logger.log(
+        "arm, disarm, list, get <name>, set <name> <value>, save. Press enter to terminate.",
+    );
+    loop {
+        let mut input = String::new();
+        io::stdin().read_line(&mut input).unwrap();
+        let words: Vec<&str> = input.split_whitespace().collect();
+        if words.is_empty() {
+            return;
+        }
+        match (words[0], words.len()) {
+            // The flight controller refuses to arm if it didn't initialize.
+            ("arm", 1) => send_mode(logger, mode_tx, FlightMode::Hold),
+            ("disarm", 1) => send_mode(logger, mode_tx, FlightMode::Off),
+            ("list", 1) => for parameter in parameters.list() {
+                logger.log(&parameter.to_string());
+            },
+            ("get", 2) => match parameters.get(words[1]) {
+                Ok(value) => logger.log(&format!("{} = {}", words[1], value)),
+                Err(e) => logger.error(&e),
+            },
+            ("set", 3) => match words[2].parse() {
+                Ok(value) => match parameters.set(words[1], value) {
+                    Ok(()) => {}
+                    Err(e) => logger.error(&e),
+                },
+                Err(_) => logger.error(&format!("{} isn't a number.", words[2])),
+            },
+            ("save", 1) => match parameters.persist() {
+                Ok(path) => logger.success(&format!("Saved to {}.", path.display())),
+                Err(e) => logger.error(&e),
+            },
+            _ => logger.error(&format!("Unknown command {}.", input.trim())),
+        };
+    }
+}
+
+fn send_mode(logger: &ModuleLogger, mode_tx: &Sender<FlightMode>, mode: FlightMode) {
+    match mode_tx.send(mode) {
+        Ok(()) => {}
+        Err(_) => logger.error("The flight controller has stopped."),
     };
 }
 
-fn start_flight() {
+fn start_flight(config: Config) {
     let logger = ModuleLogger::new("Main", None);
+    let clock = RealClock::shared();
 
-    let (hardware_join_handle, pred_rx, update_rx, motor_tx, hardware_control_tx) =
-        initialize_hardware();
-    start_flight_controller(pred_rx, update_rx, motor_tx);
+    let parameters = ParameterStore::from_config(&config);
 
+    let (hardware_join_handle, pred_rx, update_rx, motor_tx, hardware_control_tx) =
+        initialize_hardware(config.clone(), clock.clone());
+    let mode_tx = start_flight_controller(
+        &config,
+        pred_rx,
+        update_rx,
+        motor_tx,
+        parameters.clone(),
+        clock,
+    );
 
-    logger.log("Press enter to terminate.");
-    let mut input = String::new();
-    io::stdin().read_line(&mut input).unwrap();
+    tune_from_console(&logger, &parameters, &mode_tx);
+    send_mode(&logger, &mode_tx, FlightMode::Shutdown);
 
     hardware_control_tx.send(()).unwrap();
 